@@ -151,5 +151,18 @@ worker.addEventListener('message', (e) => {
       // bspReport.value += reportData;
       i++;
     }
+  } else if (e.data[0] == 2) {
+    let [cmd, filename, message] = e.data;
+
+    // remove the progress bars
+    let entry = csxFiles.get(filename);
+    entry.progressList.innerHTML = "";
+    entry.progressList.hidden = true;
+    // Show why the conversion failed
+    let title = entry.cardElement.querySelector(".card-title") as HTMLHeadingElement;
+    title.innerHTML = `${filename} <p class="text-right">Failed</p>`;
+    let reason = document.createElement("p");
+    reason.textContent = message;
+    title.after(reason);
   }
 });
\ No newline at end of file
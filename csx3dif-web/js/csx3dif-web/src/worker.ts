@@ -8,9 +8,14 @@ init_panic_hook();
 addEventListener("message", async (event) => {
     let [f, filename, engine, version, mb, bsp, ptep, plep] = event.data;
     let csxfile = await f.text();
-    let convert_results = convert_csx(csxfile, engine, version, mb, bsp, ptep, plep, (current: number, total: number, status: string, finishStatus: string) => {
-        // console.log(`${current} / ${total} - ${status} - ${finishStatus}`);
-        postMessage([0, filename, current, total, status, finishStatus]);
-    });
-    postMessage([1, filename, convert_results]);
+    try {
+        let convert_results = convert_csx(csxfile, engine, version, mb, bsp, ptep, plep, (current: number, total: number, status: string, finishStatus: string) => {
+            // console.log(`${current} / ${total} - ${status} - ${finishStatus}`);
+            postMessage([0, filename, current, total, status, finishStatus]);
+        });
+        postMessage([1, filename, convert_results]);
+    } catch (error) {
+        // The conversion errors are thrown as their message
+        postMessage([2, filename, String(error)]);
+    }
 });
\ No newline at end of file
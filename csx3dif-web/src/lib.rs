@@ -1,6 +1,6 @@
 use csx::builder::ProgressEventListener;
-use csx::convert_csx_to_dif;
-use csx::set_convert_configuration;
+use csx::bsp::ConvertConfig;
+use csx::convert_csx_to_dif_with_config;
 use dif::io::EngineVersion;
 use js_sys::Array;
 use serde::Serialize;
@@ -37,6 +37,7 @@ pub struct BSPReport {
     pub total: usize,
     pub surface_area_percentage: f32,
     pub balance_factor: i32,
+    pub passed: bool,
 }
 
 #[derive(Serialize)]
@@ -55,7 +56,7 @@ pub fn convert_csx(
     epsilon_point: f32,
     epsilon_plane: f32,
     js_callback: js_sys::Function,
-) -> JsValue {
+) -> Result<JsValue, JsValue> {
     let engine_ver = match engine_ver_str {
         "MBG" => EngineVersion::MBG,
         "TGE" => EngineVersion::TGE,
@@ -64,26 +65,28 @@ pub fn convert_csx(
         _ => EngineVersion::Unknown,
     };
 
-    unsafe {
-        set_convert_configuration(
-            mb,
-            epsilon_point,
-            epsilon_plane,
-            match bsp_type {
-                0 => csx::bsp::SplitMethod::Exhaustive,
-                1 => csx::bsp::SplitMethod::Fast,
-                2.. => csx::bsp::SplitMethod::None,
-            },
-        )
+    // Each conversion gets its own config, so the workers can convert at the same time
+    let config = ConvertConfig {
+        mb_only: mb,
+        point_epsilon: epsilon_point,
+        plane_epsilon: epsilon_plane,
+        split_method: match bsp_type {
+            0 => csx::bsp::SplitMethod::Exhaustive,
+            1 => csx::bsp::SplitMethod::Fast,
+            2.. => csx::bsp::SplitMethod::None,
+        },
+        ..ConvertConfig::DEFAULT
     };
 
     let mut silent_listener = JSListener { js_callback };
-    let (results, reports) = convert_csx_to_dif(
+    let (results, reports, _, _, _) = convert_csx_to_dif_with_config(
         csxbuf.to_owned(),
         engine_ver,
         interior_version,
+        &config,
         &mut silent_listener,
-    );
+    )
+    .map_err(|e| JsValue::from_str(&e.to_string()))?;
     let reports_wasm = reports
         .iter()
         .map(|r| BSPReport {
@@ -91,6 +94,7 @@ pub fn convert_csx(
             total: r.total,
             surface_area_percentage: r.hit_area_percentage,
             balance_factor: r.balance_factor,
+            passed: r.passed,
         })
         .collect::<Vec<_>>();
 
@@ -104,5 +108,5 @@ pub fn convert_csx(
         bsp_reports: reports_wasm,
     };
 
-    serde_wasm_bindgen::to_value(&output_val).unwrap()
+    Ok(serde_wasm_bindgen::to_value(&output_val).unwrap())
 }
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Any input has to either parse or give an error, never panic
+    let _ = csx::csx::try_parse_scene(data);
+});
@@ -1,10 +1,20 @@
-use std::{cell::RefCell, collections::HashSet, sync::Mutex, vec};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    vec,
+};
 
 use cgmath::{InnerSpace, Vector3};
-use dif::types::{PlaneF, Point3F};
+use dif::types::{BoxF, PlaneF, Point3F};
 use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 
-use crate::{builder::ProgressEventListener, csx::Brush};
+use crate::{
+    builder::{CoordBinMode, ProgressEventListener},
+    csx::{Brush, DETAIL_BRUSH_TYPE},
+    light::AttenuationModel,
+    lightmap::{BlurKind, ShadowBias},
+};
 use rayon::prelude::*;
 
 #[derive(PartialEq, PartialOrd, Eq, Ord, Copy, Clone)]
@@ -14,15 +24,155 @@ pub enum SplitMethod {
     None,
 }
 
-pub struct BSPConfig {
+// Every option of a conversion, passed by reference down the whole pipeline. Every builder gets its
+// own copy, so conversions with different settings can run at the same time.
+#[derive(Clone)]
+pub struct ConvertConfig {
+    // Only export what Marble Blast reads, no lightmaps, emit strings or plane normals of the hulls
+    pub mb_only: bool,
     pub split_method: SplitMethod,
-    pub epsilon: f32,
+    pub point_epsilon: f32,
+    pub plane_epsilon: f32,
+    // The sampling BSP rates this many of the splitters of each node, picked by an rng seeded with
+    // the seed so the same seed always builds the same tree
+    pub bsp_sample_count: usize,
+    pub bsp_seed: u64,
+    // The nodes this deep aren't split any further, so the brush soups can't blow up the tree
+    pub bsp_max_depth: Option<u32>,
+    // Build the BSP of the sub-objects with a different split method, None uses split_method
+    pub subobject_split_method: Option<SplitMethod>,
+    // Reverse the winding of the faces whose winding opposes their plane normal
+    pub fix_windings: bool,
+    // Snap the world space vertices to a grid of this size, 0 disables snapping
+    pub vertex_snap: f32,
+    // Snap the face normals within this many degrees of an axis to that axis, 0 disables snapping
+    pub axis_snap_angle: f32,
+    // Weld the brush vertices within this distance of each other into one, so walls made of
+    // separate brushes share their points and planes. 0 disables welding.
+    pub weld_tolerance: f32,
+    // Only convert the brushes that overlap these bounds, None converts every brush
+    pub clip_bounds: Option<BoxF>,
+    // Split the interiors before their winding indices exceed this count, for engines and tools
+    // that store them in a narrower type than the u32 of the DIF format
+    pub max_winding_indices: usize,
+    // Put the interiors split off due to the face limit in the main DIF instead of separate DIFs
+    pub merge_interiors: bool,
+    // Only give the interiors of this detail level collision, None gives every detail level
+    // collision. The sub-objects always have collision.
+    pub collision_detail_level: Option<usize>,
+    // Every engine version uses the coordbins to find the convex hulls to collide with, only
+    // disable them for interiors that are never collided with
+    pub generate_coordbins: bool,
+    // The plane to lay the coordbins out in, None uses the one the engine expects
+    pub coord_bin_mode: Option<CoordBinMode>,
+    // Put every winding point in the surface fan masks, including the collinear ones
+    pub full_fan_mask: bool,
+    // Compute the point visibilities by casting rays instead of marking every point visible
+    pub compute_point_visibility: bool,
+    // Sort the material list by name, so it stays the same however the brushes are ordered
+    pub sort_materials: bool,
+    // Force the texgen of the faces with these materials to the given plane_x and plane_y
+    pub texgen_overrides: Vec<(String, PlaneF, PlaneF)>,
+    // Light the lightmaps with the lights of the scene, otherwise they are filled with the flat
+    // ambient color
+    pub bake_lighting: bool,
+    // Bake the lightmaps of the sub-objects, otherwise they only get the ambient color
+    pub subobject_lightmaps: bool,
+    // Materials that don't receive baked lighting, their surfaces share a fully bright lightmap
+    pub unlit_materials: Vec<String>,
+    // Bounce the baked light off the lit surfaces this many times, 0 only bakes the direct light
+    pub gi_bounces: u32,
+    // Average this many shadow rays around each light into soft shadows, 1 gives hard shadows
+    pub shadow_samples: u32,
+    // How far off the surfaces the shadow rays end, in world units or as a fraction of the lumels
+    pub shadow_bias: ShadowBias,
+    // How the point and omni lights fade out between their radii
+    pub attenuation: AttenuationModel,
+    // Give the lightmaps a map of the direction of the incoming light, for the bumped surfaces of
+    // TGEA and T3D. MBG and TGE don't read them.
+    pub generate_dir_maps: bool,
+    // Width and height of the lightmap atlases, a power of two between 64 and 2048
+    pub lightmap_size: u32,
+    // Bake the lightmaps at 1/downscale resolution and upscale them, faster but blurrier
+    pub lightmap_downscale: u32,
+    // Texels of padding around each packed lightmap, so filtering doesn't bleed the neighbours in
+    pub lightmap_gutter: u32,
+    // Let the lightmap packer turn the lightmaps of tall surfaces sideways
+    pub rotate_lightmaps: bool,
+    // The filter the lightmaps are blurred with once baked
+    pub lightmap_blur: BlurKind,
+    // Warn about likely authoring mistakes in the scene, such as lights placed inside brushes
+    pub strict: bool,
+    // Don't produce any DIF if the conversion gave any warnings
+    pub deny_warnings: bool,
+    // Warn about the materials that aren't in this list, None skips the check
+    pub materials_manifest: Option<Vec<String>>,
+    // The percentage of the surface area the BSP raycasts must hit for a report to pass
+    pub coverage_threshold: f32,
+    // Report the details that only help debugging the conversion, like the seed of the sampling BSP
+    pub verbose: bool,
 }
 
-pub static mut BSP_CONFIG: BSPConfig = BSPConfig {
-    split_method: SplitMethod::Fast,
-    epsilon: 1e-4,
-};
+impl ConvertConfig {
+    pub const DEFAULT: ConvertConfig = ConvertConfig {
+        mb_only: true,
+        split_method: SplitMethod::Fast,
+        point_epsilon: 1e-6,
+        plane_epsilon: 1e-5,
+        bsp_sample_count: 32,
+        bsp_seed: 42,
+        bsp_max_depth: None,
+        subobject_split_method: None,
+        fix_windings: false,
+        vertex_snap: 0.0,
+        axis_snap_angle: 0.0,
+        weld_tolerance: 0.0,
+        clip_bounds: None,
+        max_winding_indices: u32::MAX as usize,
+        merge_interiors: false,
+        collision_detail_level: None,
+        generate_coordbins: true,
+        coord_bin_mode: None,
+        full_fan_mask: false,
+        compute_point_visibility: false,
+        sort_materials: false,
+        texgen_overrides: Vec::new(),
+        bake_lighting: true,
+        subobject_lightmaps: true,
+        unlit_materials: Vec::new(),
+        gi_bounces: 0,
+        shadow_samples: 1,
+        shadow_bias: ShadowBias::World(0.1),
+        attenuation: AttenuationModel::Linear,
+        generate_dir_maps: false,
+        lightmap_size: 256,
+        lightmap_downscale: 1,
+        lightmap_gutter: 1,
+        rotate_lightmaps: false,
+        lightmap_blur: BlurKind::Box(1),
+        strict: false,
+        deny_warnings: false,
+        materials_manifest: None,
+        coverage_threshold: 95.0,
+        verbose: false,
+    };
+}
+
+impl Default for ConvertConfig {
+    fn default() -> Self {
+        ConvertConfig::DEFAULT
+    }
+}
+
+// The planes are the same within the plane epsilon, the float error of the plane math makes exact
+// comparisons miss planes that are the same
+fn planes_coincide(a: &PlaneF, b: &PlaneF, config: &ConvertConfig) -> bool {
+    let epsilon = config.plane_epsilon;
+    (a.distance - b.distance).abs() < epsilon
+        && (a.normal.x - b.normal.x).abs() < epsilon
+        && (a.normal.y - b.normal.y).abs() < epsilon
+        && (a.normal.z - b.normal.z).abs() < epsilon
+}
 
 #[derive(Clone)]
 pub struct CSXBrush {
@@ -37,6 +187,7 @@ impl CSXBrush {
         plane_id: usize,
         plane_list: &[PlaneF],
         considered_planes: &Mutex<RefCell<HashSet<usize>>>,
+        config: &ConvertConfig,
     ) -> (i32, i32, i32, i32, i32) {
         let mut flipped_plane = plane_list[plane_id as usize].clone();
         flipped_plane.normal *= -1.0;
@@ -57,9 +208,7 @@ impl CSXBrush {
                     return (0, 1, 0, 1, 0);
                 }
                 let face_value = &plane_list[face.plane_id as usize];
-                if face_value.distance == flipped_plane.distance
-                    && face_value.normal == flipped_plane.normal
-                {
+                if planes_coincide(face_value, &flipped_plane, config) {
                     considered_planes
                         .lock()
                         .unwrap()
@@ -95,13 +244,13 @@ impl CSXBrush {
         let mut back = 0;
         let mut splits = 0;
         let mut tiny_windings = 0;
-        if max_front > unsafe { BSP_CONFIG.epsilon } {
+        if max_front > config.plane_epsilon {
             front = 1;
         }
-        if min_back < -unsafe { BSP_CONFIG.epsilon } {
+        if min_back < -config.plane_epsilon {
             back = 1;
         }
-        if max_front > unsafe { BSP_CONFIG.epsilon } && min_back < -unsafe { BSP_CONFIG.epsilon } {
+        if max_front > config.plane_epsilon && min_back < -config.plane_epsilon {
             splits = 1;
         }
         if (max_front > 0.0 && max_front < 1.0) || (min_back < 0.0 && min_back > -1.0) {
@@ -110,7 +259,7 @@ impl CSXBrush {
         (front, back, splits, 0, tiny_windings)
     }
 
-    fn split(&self, plane: usize, plane_list: &[PlaneF]) -> [CSXBrush; 2] {
+    fn split(&self, plane: usize, plane_list: &[PlaneF], config: &ConvertConfig) -> [CSXBrush; 2] {
         let mut front_brush = self.clone();
         let mut back_brush = self.clone();
 
@@ -122,8 +271,8 @@ impl CSXBrush {
             }
         }
 
-        back_brush.clip_plane(plane, plane_list, false);
-        front_brush.clip_plane(plane, plane_list, true);
+        back_brush.clip_plane(plane, plane_list, false, config);
+        front_brush.clip_plane(plane, plane_list, true, config);
 
         let mut plane_in_front = false;
         let mut plane_in_back = false;
@@ -149,7 +298,13 @@ impl CSXBrush {
         return [front_brush, back_brush];
     }
 
-    fn clip_plane(&mut self, plane: usize, plane_list: &[PlaneF], flip_face: bool) {
+    fn clip_plane(
+        &mut self,
+        plane: usize,
+        plane_list: &[PlaneF],
+        flip_face: bool,
+        config: &ConvertConfig,
+    ) {
         let mut new_vertices = self.vertices.clone();
         let mut new_faces: Vec<CSXFace> = vec![];
         let mut plane_value = plane_list[plane].clone();
@@ -165,18 +320,18 @@ impl CSXBrush {
                 let v2 = &self.vertices[face.indices[(i + 1) % face.indices.len()] as usize];
                 let d1 = v1.dot(plane_value.normal) + plane_value.distance;
                 let d2 = v2.dot(plane_value.normal) + plane_value.distance;
-                if d1 > unsafe { BSP_CONFIG.epsilon } {
+                if d1 > config.plane_epsilon {
                     // Ignore
                 }
-                if d1 <= unsafe { BSP_CONFIG.epsilon } {
+                if d1 <= config.plane_epsilon {
                     // Keep
                     new_indices.push(face.indices[i]);
                 }
-                if d1.abs() < unsafe { BSP_CONFIG.epsilon } {
+                if d1.abs() < config.plane_epsilon {
                     _points_on_plane += 1;
                 }
-                if (d1 > unsafe { BSP_CONFIG.epsilon } && d2 < -unsafe { BSP_CONFIG.epsilon })
-                    || (d1 < -unsafe { BSP_CONFIG.epsilon } && d2 > unsafe { BSP_CONFIG.epsilon })
+                if (d1 > config.plane_epsilon && d2 < -config.plane_epsilon)
+                    || (d1 < -config.plane_epsilon && d2 > config.plane_epsilon)
                 {
                     let t = (-plane_value.distance - plane_value.normal.dot(*v1))
                         / plane_value.normal.dot(v2 - v1);
@@ -189,7 +344,7 @@ impl CSXBrush {
             //     new_indices.clear();
             // }
             // Sanity check
-            let test_epsilon = unsafe { BSP_CONFIG.epsilon * 10.0 };
+            let test_epsilon = config.plane_epsilon * 10.0;
             for idx in new_indices.iter() {
                 let pt = new_vertices[*idx as usize];
                 let d = plane_value.normal.dot(pt) + plane_value.distance;
@@ -203,6 +358,7 @@ impl CSXBrush {
                     indices: new_indices,
                     id: face.id,
                     used_plane: face.used_plane || face.plane_id == plane,
+                    detail: face.detail,
                 });
             }
         }
@@ -210,7 +366,7 @@ impl CSXBrush {
         self.faces = new_faces;
     }
 
-    fn _classify_score(&self, plane: &PlaneF) -> i32 {
+    fn _classify_score(&self, plane: &PlaneF, config: &ConvertConfig) -> i32 {
         self.faces
             .iter()
             .map(|f| {
@@ -220,9 +376,9 @@ impl CSXBrush {
                 f.indices.iter().for_each(|i| {
                     let pt = self.vertices[*i as usize];
                     let face_dot = pt.dot(plane.normal) + plane.distance;
-                    if face_dot > unsafe { BSP_CONFIG.epsilon } {
+                    if face_dot > config.plane_epsilon {
                         front_count += 1;
-                    } else if face_dot < unsafe { -BSP_CONFIG.epsilon } {
+                    } else if face_dot < -config.plane_epsilon {
                         back_count += 1;
                     } else {
                         on_count += 1;
@@ -248,6 +404,14 @@ pub struct CSXFace {
     indices: Vec<i32>,
     pub id: i32,
     used_plane: bool,
+    // Faces of detail brushes never split the BSP, they only end up in the leaves
+    detail: bool,
+}
+
+impl CSXFace {
+    fn can_split(&self) -> bool {
+        !self.used_plane && !self.detail
+    }
 }
 
 pub struct CSXBSPNode {
@@ -269,7 +433,18 @@ impl CSXBSPNode {
         }
     }
 
-    fn height(&self) -> i32 {
+    pub fn node_count(&self) -> usize {
+        let mut count = 1;
+        if let Some(ref front) = self.front {
+            count += front.node_count();
+        }
+        if let Some(ref back) = self.back {
+            count += back.node_count();
+        }
+        count
+    }
+
+    pub fn height(&self) -> i32 {
         let mut value = 0;
         if let Some(ref front) = self.front {
             value = std::cmp::max(value, front.height());
@@ -291,16 +466,21 @@ impl CSXBSPNode {
         value
     }
 
+    #[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+    // `depth` is how deep this node is, and `capped` is set if the max depth stops a split
     fn split(
         &mut self,
         plane_list: &[PlaneF],
         used_planes: &mut HashSet<usize>,
+        depth: u32,
+        capped: &mut bool,
+        config: &ConvertConfig,
         progress_report_callback: &mut dyn ProgressEventListener,
     ) {
         let mut unused_planes = false;
         for brush in self.brush_list.iter() {
             for face in brush.faces.iter() {
-                if !face.used_plane {
+                if face.can_split() {
                     unused_planes = true;
                     break;
                 }
@@ -313,23 +493,29 @@ impl CSXBSPNode {
         let mut remaining_faces = 0;
         for brush in self.brush_list.iter() {
             for face in brush.faces.iter() {
-                if !face.used_plane {
+                if face.can_split() {
                     remaining_faces += 1;
                 }
                 total_faces += 1;
             }
         }
+        if unused_planes && config.bsp_max_depth.is_some_and(|max| depth >= max) {
+            // Leave the remaining brushes in a solid leaf, the raycasts still hit their surfaces
+            *capped = true;
+            self.solid = true;
+            return;
+        }
         if unused_planes && self.plane_index == None {
-            let split_plane = match unsafe { &BSP_CONFIG.split_method } {
-                SplitMethod::Fast => self.select_best_splitter(plane_list),
-                SplitMethod::Exhaustive => self.select_best_splitter_new(plane_list),
+            let split_plane = match config.split_method {
+                SplitMethod::Fast => self.select_best_splitter(plane_list, config),
+                SplitMethod::Exhaustive => self.select_best_splitter_new(plane_list, config),
                 _ => {
                     panic!("Should never reach here!")
                 }
             };
             if let Some(split_plane) = split_plane {
                 // Do split
-                self.split_brush_list(split_plane, plane_list);
+                self.split_brush_list(split_plane, plane_list, config);
                 self.plane_index = Some(split_plane);
 
                 if !used_planes.contains(&split_plane) {
@@ -351,7 +537,14 @@ impl CSXBSPNode {
                                 }
                             })
                         });
-                        n.split(plane_list, used_planes, progress_report_callback);
+                        n.split(
+                            plane_list,
+                            used_planes,
+                            depth + 1,
+                            capped,
+                            config,
+                            progress_report_callback,
+                        );
                     }
                     None => {}
                 };
@@ -364,7 +557,14 @@ impl CSXBSPNode {
                                 }
                             })
                         });
-                        n.split(plane_list, used_planes, progress_report_callback);
+                        n.split(
+                            plane_list,
+                            used_planes,
+                            depth + 1,
+                            capped,
+                            config,
+                            progress_report_callback,
+                        );
                     }
                     None => {}
                 };
@@ -372,7 +572,7 @@ impl CSXBSPNode {
         }
     }
 
-    fn split_brush_list(&mut self, plane_id: usize, plane_list: &[PlaneF]) {
+    fn split_brush_list(&mut self, plane_id: usize, plane_list: &[PlaneF], config: &ConvertConfig) {
         let mut front_brushes: Vec<CSXBrush> = vec![];
         let mut back_brushes: Vec<CSXBrush> = vec![];
         let mut front_solid = self.solid;
@@ -389,7 +589,7 @@ impl CSXBSPNode {
         assert!(plane_in_brush, "Not in brush??");
 
         self.brush_list.iter().for_each(|b| {
-            let [front_brush, back_brush] = b.split(plane_id, plane_list);
+            let [front_brush, back_brush] = b.split(plane_id, plane_list, config);
             if front_brush.faces.len() > 1 {
                 let mut no_more_insertables = true;
                 for face in front_brush.faces.iter() {
@@ -440,7 +640,11 @@ impl CSXBSPNode {
         self.brush_list.clear();
     }
 
-    fn select_best_splitter_new(&self, plane_list: &[PlaneF]) -> Option<usize> {
+    fn select_best_splitter_new(
+        &self,
+        plane_list: &[PlaneF],
+        config: &ConvertConfig,
+    ) -> Option<usize> {
         use std::f32::consts::PI;
         let mut vector_planes: Vec<(Vector3<f32>, Vec<usize>)> = vec![];
         // Create semi sphere unit vectors
@@ -456,7 +660,7 @@ impl CSXBSPNode {
         let mut used_faces: HashSet<usize> = HashSet::new();
         self.brush_list.iter().for_each(|b| {
             b.faces.iter().for_each(|f| {
-                if !f.used_plane && !used_faces.contains(&f.plane_id) {
+                if f.can_split() && !used_faces.contains(&f.plane_id) {
                     used_faces.insert(f.plane_id);
                     let mut max_dot = -1.0;
                     let mut max_index = None;
@@ -487,7 +691,7 @@ impl CSXBSPNode {
             .collect::<Vec<_>>();
 
         let val = least_depth_planes.par_iter().max_by_key(|&&p_idx| {
-            self.calc_plane_rating(p_idx, plane_list)
+            self.calc_plane_rating(p_idx, plane_list, config)
             // self.brush_list
             //     .par_iter()
             //     .map(|b| b.classify_score(&plane_list[**p_idx]))
@@ -499,22 +703,22 @@ impl CSXBSPNode {
         }
     }
 
-    fn select_best_splitter(&self, plane_list: &[PlaneF]) -> Option<usize> {
-        let mut rng = StdRng::seed_from_u64(42);
+    fn select_best_splitter(&self, plane_list: &[PlaneF], config: &ConvertConfig) -> Option<usize> {
+        let mut rng = StdRng::seed_from_u64(config.bsp_seed);
 
         let chosen_planes = self
             .brush_list
             .iter()
             .flat_map(|b| b.faces.iter())
-            .filter(|f| !f.used_plane)
+            .filter(|f| f.can_split())
             .map(|f| f.plane_id)
             .collect::<Vec<_>>();
         // Intersect this_planes and unused_planes
         let max_plane = chosen_planes
-            .choose_multiple(&mut rng, 32)
+            .choose_multiple(&mut rng, config.bsp_sample_count)
             .collect::<Vec<_>>()
             .into_par_iter()
-            .max_by_key(|&&p| self.calc_plane_rating(p, plane_list));
+            .max_by_key(|&&p| self.calc_plane_rating(p, plane_list, config));
 
         match max_plane {
             Some(&x) => Some(x),
@@ -522,16 +726,22 @@ impl CSXBSPNode {
         }
     }
 
-    fn calc_plane_rating(&self, plane_id: usize, plane_list: &[PlaneF]) -> i32 {
+    #[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+    fn calc_plane_rating(
+        &self,
+        plane_id: usize,
+        plane_list: &[PlaneF],
+        config: &ConvertConfig,
+    ) -> i32 {
         let plane = &plane_list[plane_id as usize];
         let mut zero_count = 0;
-        if plane.normal.x.abs() < unsafe { BSP_CONFIG.epsilon } {
+        if plane.normal.x.abs() < config.plane_epsilon {
             zero_count += 1;
         }
-        if plane.normal.y.abs() < unsafe { BSP_CONFIG.epsilon } {
+        if plane.normal.y.abs() < config.plane_epsilon {
             zero_count += 1;
         }
-        if plane.normal.z.abs() < unsafe { BSP_CONFIG.epsilon } {
+        if plane.normal.z.abs() < config.plane_epsilon {
             zero_count += 1;
         }
         let axial = zero_count == 2;
@@ -539,7 +749,7 @@ impl CSXBSPNode {
         let (front, back, splits, coplanar, tiny_windings) = self
             .brush_list
             .par_iter()
-            .map(|b| b.calculate_split_rating(plane_id, plane_list, &considered_planes))
+            .map(|b| b.calculate_split_rating(plane_id, plane_list, &considered_planes, config))
             .reduce(
                 || (0, 0, 0, 0, 0),
                 |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2, a.3 + b.3, a.4 + b.4),
@@ -643,8 +853,24 @@ impl CSXBSPNode {
     }
 }
 
+// Rough cost of building the BSP, every split rates its candidate planes against every brush in
+// the node so the cost grows with the splitting faces times the brushes
+pub fn estimate_bsp_cost(brush_list: &[Brush]) -> u64 {
+    let splitting_faces = brush_list
+        .iter()
+        .filter(|b| b.type_ != DETAIL_BRUSH_TYPE)
+        .map(|b| b.face.len() as u64)
+        .sum::<u64>();
+    splitting_faces * brush_list.len() as u64
+}
+
+// Estimated cost above which the exhaustive BSP takes long enough to be worth warning about
+pub const SLOW_BSP_COST: u64 = 25_000_000;
+
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
 pub fn build_bsp(
     brush_list: &[Brush],
+    config: &ConvertConfig,
     progress_report_callback: &mut dyn ProgressEventListener,
 ) -> (CSXBSPNode, Vec<PlaneF>) {
     let mut plane_list: Vec<PlaneF> = vec![];
@@ -663,6 +889,7 @@ pub fn build_bsp(
                         plane_id: face_id,
                         id: f.face_id,
                         used_plane: false,
+                        detail: b.type_ == DETAIL_BRUSH_TYPE,
                     };
                     csx_face
                 })
@@ -682,7 +909,9 @@ pub fn build_bsp(
         .collect::<Vec<_>>();
 
     let mut root = CSXBSPNode::from_brushes(csx_brushes);
-    if unsafe { BSP_CONFIG.split_method } == SplitMethod::None {
+    if plane_list.is_empty() {
+        // Nothing to split with, leave the root as an empty leaf
+    } else if config.split_method == SplitMethod::None {
         root.front = Some(Box::new(CSXBSPNode {
             back: None,
             brush_list: Vec::new(),
@@ -699,8 +928,642 @@ pub fn build_bsp(
         }));
         root.plane_index = Some(0);
     } else {
+        if config.split_method == SplitMethod::Fast && config.verbose {
+            progress_report_callback.progress(
+                0,
+                0,
+                format!(
+                    "Sampling {} splitters per node with seed {}",
+                    config.bsp_sample_count, config.bsp_seed
+                ),
+                format!(
+                    "Sampled {} splitters per node with seed {}",
+                    config.bsp_sample_count, config.bsp_seed
+                ),
+            );
+        }
         let mut used_planes: HashSet<usize> = HashSet::new();
-        root.split(&plane_list, &mut used_planes, progress_report_callback);
+        let mut capped = false;
+        root.split(
+            &plane_list,
+            &mut used_planes,
+            0,
+            &mut capped,
+            config,
+            progress_report_callback,
+        );
+        if let Some(max_depth) = config.bsp_max_depth {
+            let depth = root.height() - 1;
+            let status = if capped {
+                format!("BSP depth {}, capped at the max depth {}", depth, max_depth)
+            } else {
+                format!("BSP depth {}, within the max depth {}", depth, max_depth)
+            };
+            progress_report_callback.progress(0, 0, status.clone(), status);
+        }
+    }
+    (root, plane_list)
+}
+
+enum CachedBSPNode {
+    Leaf {
+        solid: bool,
+        // (plane_id, face id) of every face in the leaf's brush list
+        faces: Vec<(usize, i32)>,
+    },
+    Node {
+        plane_index: usize,
+        front: Option<Box<CachedBSPNode>>,
+        back: Option<Box<CachedBSPNode>>,
+    },
+}
+
+impl CachedBSPNode {
+    fn from_node(node: &CSXBSPNode) -> Self {
+        match node.plane_index {
+            Some(plane_index) => CachedBSPNode::Node {
+                plane_index,
+                front: node
+                    .front
+                    .as_ref()
+                    .map(|n| Box::new(CachedBSPNode::from_node(n))),
+                back: node
+                    .back
+                    .as_ref()
+                    .map(|n| Box::new(CachedBSPNode::from_node(n))),
+            },
+            None => CachedBSPNode::Leaf {
+                solid: node.solid,
+                faces: node
+                    .brush_list
+                    .iter()
+                    .flat_map(|b| b.faces.iter().map(|f| (f.plane_id, f.id)))
+                    .collect(),
+            },
+        }
+    }
+
+    fn to_node(&self) -> CSXBSPNode {
+        match self {
+            CachedBSPNode::Node {
+                plane_index,
+                front,
+                back,
+            } => CSXBSPNode {
+                brush_list: vec![],
+                front: front.as_ref().map(|n| Box::new(n.to_node())),
+                back: back.as_ref().map(|n| Box::new(n.to_node())),
+                plane_index: Some(*plane_index),
+                solid: false,
+            },
+            CachedBSPNode::Leaf { solid, faces } => CSXBSPNode {
+                // Leaves only need the face ids and planes for export and raycasts
+                brush_list: vec![CSXBrush {
+                    vertices: vec![],
+                    faces: faces
+                        .iter()
+                        .map(|&(plane_id, id)| CSXFace {
+                            plane_id,
+                            indices: vec![],
+                            id,
+                            used_plane: true,
+                            detail: false,
+                        })
+                        .collect(),
+                }],
+                front: None,
+                back: None,
+                plane_index: None,
+                solid: *solid,
+            },
+        }
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        match self {
+            CachedBSPNode::Leaf { solid, faces } => {
+                buf.push(0);
+                buf.push(*solid as u8);
+                buf.extend((faces.len() as u32).to_le_bytes());
+                for (plane_id, id) in faces {
+                    buf.extend((*plane_id as u32).to_le_bytes());
+                    buf.extend(id.to_le_bytes());
+                }
+            }
+            CachedBSPNode::Node {
+                plane_index,
+                front,
+                back,
+            } => {
+                buf.push(1);
+                buf.extend((*plane_index as u32).to_le_bytes());
+                for child in [front, back] {
+                    match child {
+                        Some(n) => {
+                            buf.push(1);
+                            n.write(buf);
+                        }
+                        None => buf.push(0),
+                    }
+                }
+            }
+        }
+    }
+
+    fn read(reader: &mut CacheReader) -> Option<Self> {
+        match reader.u8()? {
+            0 => {
+                let solid = reader.u8()? != 0;
+                let count = reader.u32()?;
+                let mut faces = vec![];
+                for _ in 0..count {
+                    faces.push((reader.u32()? as usize, reader.u32()? as i32));
+                }
+                Some(CachedBSPNode::Leaf { solid, faces })
+            }
+            1 => {
+                let plane_index = reader.u32()? as usize;
+                let mut children = [None, None];
+                for child in children.iter_mut() {
+                    if reader.u8()? != 0 {
+                        *child = Some(Box::new(CachedBSPNode::read(reader)?));
+                    }
+                }
+                let [front, back] = children;
+                Some(CachedBSPNode::Node {
+                    plane_index,
+                    front,
+                    back,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+struct CacheReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl CacheReader<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        let slice = self.data.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(slice)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
+
+const BSP_CACHE_MAGIC: &[u8; 8] = b"CSXBSP02";
+
+// The emit strings of each vertex of each brush of an interior
+pub type HullEmitStrings = Vec<Vec<Vec<u8>>>;
+
+// Cache of previously built BSP trees and hull emit strings, keyed by a hash of the brush geometry
+// and the configuration they depend on. Material and texgen edits don't change the keys, so
+// re-converting after them reuses the trees and hulls instead of rebuilding them.
+pub struct BSPCache {
+    entries: HashMap<u64, CachedBSPNode>,
+    hulls: HashMap<u64, HullEmitStrings>,
+    used: HashSet<u64>,
+    // Lookups of either a tree or the hulls
+    pub hits: u32,
+    pub misses: u32,
+}
+
+impl BSPCache {
+    pub fn new() -> Self {
+        BSPCache {
+            entries: HashMap::new(),
+            hulls: HashMap::new(),
+            used: HashSet::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Loads a cache written by to_bytes, an unreadable cache is treated as empty
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut cache = BSPCache::new();
+        let mut reader = CacheReader { data, pos: 0 };
+        if reader.take(BSP_CACHE_MAGIC.len()) != Some(BSP_CACHE_MAGIC.as_slice()) {
+            return cache;
+        }
+        let mut entries = HashMap::new();
+        let count = match reader.u32() {
+            Some(c) => c,
+            None => return cache,
+        };
+        for _ in 0..count {
+            let key = match reader.u64() {
+                Some(k) => k,
+                None => return cache,
+            };
+            match CachedBSPNode::read(&mut reader) {
+                Some(node) => {
+                    entries.insert(key, node);
+                }
+                None => return cache,
+            }
+        }
+        let mut hulls = HashMap::new();
+        let count = match reader.u32() {
+            Some(c) => c,
+            None => return cache,
+        };
+        for _ in 0..count {
+            let key = match reader.u64() {
+                Some(k) => k,
+                None => return cache,
+            };
+            match read_hull_emit_strings(&mut reader) {
+                Some(emit_strings) => {
+                    hulls.insert(key, emit_strings);
+                }
+                None => return cache,
+            }
+        }
+        cache.entries = entries;
+        cache.hulls = hulls;
+        cache
+    }
+
+    // Only the trees used during this run are written, stale ones are dropped
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = BSP_CACHE_MAGIC.to_vec();
+        let keys = self
+            .used
+            .iter()
+            .filter(|k| self.entries.contains_key(k))
+            .collect::<Vec<_>>();
+        buf.extend((keys.len() as u32).to_le_bytes());
+        for key in keys {
+            buf.extend(key.to_le_bytes());
+            self.entries[key].write(&mut buf);
+        }
+        let keys = self
+            .used
+            .iter()
+            .filter(|k| self.hulls.contains_key(k))
+            .collect::<Vec<_>>();
+        buf.extend((keys.len() as u32).to_le_bytes());
+        for key in keys {
+            buf.extend(key.to_le_bytes());
+            let brushes = &self.hulls[key];
+            buf.extend((brushes.len() as u32).to_le_bytes());
+            for emit_strings in brushes {
+                buf.extend((emit_strings.len() as u32).to_le_bytes());
+                for emit_string in emit_strings {
+                    buf.extend((emit_string.len() as u32).to_le_bytes());
+                    buf.extend(emit_string);
+                }
+            }
+        }
+        buf
+    }
+}
+
+fn read_hull_emit_strings(reader: &mut CacheReader) -> Option<HullEmitStrings> {
+    let mut brushes = vec![];
+    for _ in 0..reader.u32()? {
+        let mut emit_strings = vec![];
+        for _ in 0..reader.u32()? {
+            let len = reader.u32()? as usize;
+            emit_strings.push(reader.take(len)?.to_vec());
+        }
+        brushes.push(emit_strings);
+    }
+    Some(brushes)
+}
+
+// FNV-1a, so that the keys stay stable across builds unlike DefaultHasher
+struct GeometryHasher(u64);
+
+impl GeometryHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_f32(&mut self, v: f32) {
+        self.write(&v.to_bits().to_le_bytes());
+    }
+
+    fn write_brushes(&mut self, brush_list: &[Brush]) {
+        for b in brush_list {
+            self.write(&[(b.type_ == DETAIL_BRUSH_TYPE) as u8]);
+            self.write(&(b.vertices.vertex.len() as u32).to_le_bytes());
+            for v in b.vertices.vertex.iter() {
+                self.write_f32(v.pos.x);
+                self.write_f32(v.pos.y);
+                self.write_f32(v.pos.z);
+            }
+            self.write(&(b.face.len() as u32).to_le_bytes());
+            for f in b.face.iter() {
+                self.write(&f.face_id.to_le_bytes());
+                self.write_f32(f.plane.normal.x);
+                self.write_f32(f.plane.normal.y);
+                self.write_f32(f.plane.normal.z);
+                self.write_f32(f.plane.distance);
+                self.write(&(f.indices.indices.len() as u32).to_le_bytes());
+                for i in f.indices.indices.iter() {
+                    self.write(&i.to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+// The brushes have already lost the faces point_epsilon found degenerate, and the BSP doesn't
+// use the epsilon otherwise, so it's left out of the key
+fn geometry_hash(brush_list: &[Brush], config: &ConvertConfig) -> u64 {
+    let mut hasher = GeometryHasher(0xcbf29ce484222325);
+    hasher.write(b"bsp");
+    hasher.write(&[config.split_method as u8]);
+    hasher.write_f32(config.plane_epsilon);
+    // Only the sampling BSP depends on the seed, the other trees can be reused across seeds
+    if config.split_method == SplitMethod::Fast {
+        hasher.write(&(config.bsp_sample_count as u64).to_le_bytes());
+        hasher.write(&config.bsp_seed.to_le_bytes());
+    }
+    if let Some(max_depth) = config.bsp_max_depth {
+        hasher.write(&max_depth.to_le_bytes());
     }
+    hasher.write_brushes(brush_list);
+    hasher.0
+}
+
+// The emit strings only depend on the windings and on which faces the plane_epsilon merges into
+// the same plane
+fn hull_hash(brush_list: &[Brush], config: &ConvertConfig) -> u64 {
+    let mut hasher = GeometryHasher(0xcbf29ce484222325);
+    hasher.write(b"hulls");
+    hasher.write_f32(config.plane_epsilon);
+    hasher.write_brushes(brush_list);
+    hasher.0
+}
+
+pub fn build_bsp_cached(
+    brush_list: &[Brush],
+    config: &ConvertConfig,
+    progress_report_callback: &mut dyn ProgressEventListener,
+    cache: &mut BSPCache,
+) -> (CSXBSPNode, Vec<PlaneF>) {
+    let key = geometry_hash(brush_list, config);
+    cache.used.insert(key);
+    if let Some(cached) = cache.entries.get(&key) {
+        cache.hits += 1;
+        progress_report_callback.progress(
+            0,
+            0,
+            "Reusing cached BSP".to_string(),
+            "Reused cached BSP".to_string(),
+        );
+        let plane_list = brush_list
+            .iter()
+            .flat_map(|b| b.face.iter().map(|f| f.plane.clone()))
+            .collect::<Vec<_>>();
+        return (cached.to_node(), plane_list);
+    }
+    cache.misses += 1;
+    let (root, plane_list) = build_bsp(brush_list, config, progress_report_callback);
+    cache.entries.insert(key, CachedBSPNode::from_node(&root));
     (root, plane_list)
 }
+
+// Builds the hull emit strings of the brushes with build, unless the cache already has them
+pub fn hull_emit_strings_cached(
+    brush_list: &[Brush],
+    config: &ConvertConfig,
+    progress_report_callback: &mut dyn ProgressEventListener,
+    cache: &mut BSPCache,
+    build: impl FnOnce() -> HullEmitStrings,
+) -> HullEmitStrings {
+    let key = hull_hash(brush_list, config);
+    cache.used.insert(key);
+    if let Some(cached) = cache.hulls.get(&key) {
+        cache.hits += 1;
+        progress_report_callback.progress(
+            0,
+            0,
+            "Reusing cached hulls".to_string(),
+            "Reused cached hulls".to_string(),
+        );
+        return cached.clone();
+    }
+    cache.misses += 1;
+    let emit_strings = build();
+    cache.hulls.insert(key, emit_strings.clone());
+    emit_strings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::NoopListener;
+    use crate::builder::RecordingListener;
+    use crate::convert_csx_to_dif_cached;
+    use crate::convert_csx_to_dif_with_config;
+    use dif::dif::Dif;
+    use dif::io::EngineVersion;
+
+    const SIMPLE: &str = include_str!("../snapshots/simple.csx");
+
+    fn convert(csx: &str, cache: &mut BSPCache) -> Vec<Vec<u8>> {
+        let config = ConvertConfig {
+            mb_only: false,
+            bake_lighting: false,
+            ..ConvertConfig::DEFAULT
+        };
+        let (bufs, ..) = convert_csx_to_dif_cached(
+            csx.to_string(),
+            EngineVersion::MBG,
+            0,
+            &config,
+            &mut NoopListener,
+            cache,
+        )
+        .unwrap();
+        bufs
+    }
+
+    #[test]
+    fn flipped_coplanar_faces_get_the_coplanar_bonus() {
+        let plane = |normal: Point3F, distance: f32| PlaneF { normal, distance };
+        // The neighbour's face is the flipped splitter, off by the float error of the plane math
+        let plane_list = vec![
+            plane(Point3F::new(1.0, 0.0, 0.0), -8.0),
+            plane(Point3F::new(-1.0, 0.0, 0.000002), 8.000002),
+        ];
+        let config = ConvertConfig::DEFAULT;
+        assert!(planes_coincide(
+            &plane(Point3F::new(1.0, 0.0, -0.000002), -8.000002),
+            &plane_list[0],
+            &config
+        ));
+        assert!(!planes_coincide(
+            &plane(Point3F::new(1.0, 0.0, 0.0), -8.1),
+            &plane_list[0],
+            &config
+        ));
+
+        let neighbour = CSXBrush {
+            vertices: vec![],
+            faces: vec![CSXFace {
+                plane_id: 1,
+                indices: vec![],
+                id: 0,
+                used_plane: false,
+                detail: false,
+            }],
+        };
+        let considered_planes = Mutex::new(RefCell::new(HashSet::new()));
+        assert_eq!(
+            neighbour.calculate_split_rating(0, &plane_list, &considered_planes, &config),
+            (1, 0, 0, 1, 0)
+        );
+    }
+
+    #[test]
+    fn none_split_without_planes_is_an_empty_leaf() {
+        let config = ConvertConfig {
+            split_method: SplitMethod::None,
+            ..ConvertConfig::DEFAULT
+        };
+        let (root, plane_list) = build_bsp(&[], &config, &mut NoopListener);
+        assert!(plane_list.is_empty());
+        assert!(root.plane_index.is_none() && root.front.is_none() && root.back.is_none());
+    }
+
+    #[test]
+    fn none_split_converts_a_scene_without_brushes() {
+        let far = Point3F::new(1000.0, 1000.0, 1000.0);
+        let config = ConvertConfig {
+            split_method: SplitMethod::None,
+            clip_bounds: Some(BoxF {
+                min: far,
+                max: far + Point3F::new(1.0, 1.0, 1.0),
+            }),
+            ..ConvertConfig::DEFAULT
+        };
+        let result = convert_csx_to_dif_with_config(
+            SIMPLE.to_string(),
+            EngineVersion::MBG,
+            0,
+            &config,
+            &mut NoopListener,
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn detail_brushes_shrink_the_bsp() {
+        let bsp_nodes = |csx: &str| {
+            let bufs = convert(csx, &mut BSPCache::new());
+            let (dif, _) = Dif::from_bytes(&bufs[0]).unwrap();
+            dif.interiors[0].bsp_nodes.len()
+        };
+        // Mark every brush but the first as detail
+        let detail = SIMPLE
+            .replace(
+                "<Brush id=\"1\" owner=\"0\" type=\"0\"",
+                "<Brush id=\"1\" owner=\"0\" type=\"1\"",
+            )
+            .replace(
+                "<Brush id=\"2\" owner=\"0\" type=\"0\"",
+                "<Brush id=\"2\" owner=\"0\" type=\"1\"",
+            );
+        assert_ne!(detail, SIMPLE);
+        assert!(bsp_nodes(&detail) < bsp_nodes(SIMPLE));
+    }
+
+    #[test]
+    fn sampling_seed_is_only_reported_when_verbose() {
+        let sampling_notes = |verbose| {
+            let config = ConvertConfig {
+                split_method: SplitMethod::Fast,
+                verbose,
+                ..ConvertConfig::DEFAULT
+            };
+            let mut listener = RecordingListener::new();
+            convert_csx_to_dif_with_config(
+                SIMPLE.to_string(),
+                EngineVersion::MBG,
+                0,
+                &config,
+                &mut listener,
+            )
+            .unwrap();
+            listener
+                .events
+                .iter()
+                .filter(|(.., status, _)| status.starts_with("Sampling"))
+                .count()
+        };
+        assert_eq!(sampling_notes(false), 0);
+        assert!(sampling_notes(true) > 0);
+    }
+
+    #[test]
+    fn material_edits_reuse_the_cache() {
+        let mut cache = BSPCache::new();
+        convert(SIMPLE, &mut cache);
+        assert_eq!((cache.hits, cache.misses), (0, 2));
+
+        let retextured = SIMPLE.replace("material=\"grass\"", "material=\"stone\"");
+        let cached = convert(&retextured, &mut cache);
+        assert_eq!((cache.hits, cache.misses), (2, 2));
+        assert_eq!(cached, convert(&retextured, &mut BSPCache::new()));
+    }
+
+    #[test]
+    fn geometry_edits_miss_the_cache() {
+        let mut cache = BSPCache::new();
+        convert(SIMPLE, &mut cache);
+        let moved = SIMPLE.replace("<Vertex pos=\"8 8 1\" />", "<Vertex pos=\"8 8 2\" />");
+        convert(&moved, &mut cache);
+        assert_eq!((cache.hits, cache.misses), (0, 4));
+    }
+
+    #[test]
+    fn cache_round_trips_through_bytes() {
+        let mut cache = BSPCache::new();
+        let bufs = convert(SIMPLE, &mut cache);
+        let mut cache = BSPCache::from_bytes(&cache.to_bytes());
+        assert_eq!(convert(SIMPLE, &mut cache), bufs);
+        assert_eq!((cache.hits, cache.misses), (2, 0));
+    }
+
+    #[test]
+    fn corrupt_cache_is_ignored() {
+        let mut cache = BSPCache::new();
+        convert(SIMPLE, &mut cache);
+        let bytes = cache.to_bytes();
+        for corrupt in [
+            &bytes[..bytes.len() / 2],
+            &bytes[..BSP_CACHE_MAGIC.len()],
+            b"CSXBSP01 and then some".as_slice(),
+            &[0xff; 64],
+            &[],
+        ] {
+            let mut cache = BSPCache::from_bytes(corrupt);
+            assert!(cache.entries.is_empty() && cache.hulls.is_empty());
+            convert(SIMPLE, &mut cache);
+            assert_eq!((cache.hits, cache.misses), (0, 2));
+        }
+    }
+}
@@ -1,11 +1,20 @@
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::io::Write;
 
-use crate::bsp::build_bsp;
+use crate::bsp::build_bsp_cached;
+use crate::bsp::estimate_bsp_cost;
+use crate::bsp::hull_emit_strings_cached;
+use crate::bsp::BSPCache;
 use crate::bsp::CSXBSPNode;
+use crate::bsp::ConvertConfig;
+use crate::bsp::SplitMethod;
+use crate::bsp::SLOW_BSP_COST;
+use crate::csx::get_winding_normal;
 use crate::csx::Brush;
+use crate::csx::ConversionError;
 use crate::csx::Face;
 use crate::csx::TexGen;
 use crate::csx::Vertex;
@@ -17,21 +26,57 @@ use cgmath::InnerSpace;
 use cgmath::Transform;
 use cgmath::Vector3;
 use dif::interior::*;
+use dif::io::EngineVersion;
 use dif::types::*;
+use dif::vehicle_collision;
+use dif::vehicle_collision::VehicleCollision;
 use image::codecs::png::PngEncoder;
 use image::ImageBuffer;
 use image::ImageEncoder;
 use image::Rgb;
+use rayon::prelude::*;
 use rectangle_pack::contains_smallest_box;
 use rectangle_pack::pack_rects;
 use rectangle_pack::volume_heuristic;
 use rectangle_pack::GroupedRectsToPlace;
 use rectangle_pack::RectToInsert;
 use rectangle_pack::TargetBin;
+use serde::Serialize;
 use std::hash::Hash;
 
 pub trait ProgressEventListener {
     fn progress(&mut self, current: u32, total: u32, status: String, finish_status: String);
+
+    // Polled between the stages of the conversion, returning true stops it with
+    // ConversionError::Cancelled
+    fn cancelled(&self) -> bool {
+        false
+    }
+}
+
+// Ignores all the progress events
+pub struct NoopListener;
+
+impl ProgressEventListener for NoopListener {
+    fn progress(&mut self, _: u32, _: u32, _: String, _: String) {}
+}
+
+// Keeps every progress event as (current, total, status, finish_status), in the order received
+#[derive(Default)]
+pub struct RecordingListener {
+    pub events: Vec<(u32, u32, String, String)>,
+}
+
+impl RecordingListener {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ProgressEventListener for RecordingListener {
+    fn progress(&mut self, current: u32, total: u32, status: String, finish_status: String) {
+        self.events.push((current, total, status, finish_status));
+    }
 }
 
 pub struct BSPReport {
@@ -39,59 +84,113 @@ pub struct BSPReport {
     pub hit: i32,
     pub total: usize,
     pub hit_area_percentage: f32,
+    // Whether the surface area coverage reaches the coverage threshold
+    pub passed: bool,
+}
+
+// The plane the 16x16 grid of coordbins is laid out in, the hulls of each bin are the ones that
+// overlap its column along the remaining axis. Stored as the coord_bin_mode of the interior.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CoordBinMode {
+    XY = 0,
+    XZ = 1,
+    YZ = 2,
+}
+
+impl CoordBinMode {
+    // The stock engines only look up the coordbins in the xy plane, whatever mode the interior
+    // says, so that's the only mode they can collide with
+    pub fn for_engine(engine: &EngineVersion) -> Self {
+        match engine {
+            EngineVersion::MBG
+            | EngineVersion::TGE
+            | EngineVersion::TGEA
+            | EngineVersion::T3D
+            | EngineVersion::Unknown => CoordBinMode::XY,
+        }
+    }
+
+    fn axes(self) -> (usize, usize) {
+        match self {
+            CoordBinMode::XY => (0, 1),
+            CoordBinMode::XZ => (0, 2),
+            CoordBinMode::YZ => (1, 2),
+        }
+    }
 }
 
 pub struct DIFBuilder {
     brushes: Vec<Brush>,
+    patches: Vec<Brush>,
     interior: Interior,
     face_to_surface: HashMap<i32, SurfaceIndex>,
     face_to_plane: HashMap<i32, PlaneIndex>,
     plane_map: HashMap<OrdPlaneF, PlaneIndex>,
-    point_map: HashMap<OrdPoint, PointIndex>,
-    normal_map: HashMap<OrdPoint, NormalIndex>,
+    point_map: PointMap<PointIndex>,
+    normal_map: PointMap<NormalIndex>,
     texgen_map: HashMap<OrdTexGen, TexGenIndex>,
     emit_string_map: HashMap<Vec<u8>, EmitStringIndex>,
-    mb_only: bool,
     bsp_report: BSPReport,
     ambient_color: Point3F,
     emergency_ambient_color: Point3F,
     lumel_scale: u32,
     geometry_scale: u32,
     lights: Vec<Light>,
+    config: ConvertConfig,
+    bake_lightmaps: bool,
+    collision: bool,
 }
 
-pub static mut POINT_EPSILON: f32 = 1e-6;
-pub static mut PLANE_EPSILON: f32 = 1e-5;
-
 impl DIFBuilder {
-    pub fn new(mb_only: bool) -> DIFBuilder {
+    pub fn new(config: &ConvertConfig) -> DIFBuilder {
         return DIFBuilder {
             brushes: vec![],
+            patches: vec![],
             interior: empty_interior(),
             face_to_surface: HashMap::new(),
             face_to_plane: HashMap::new(),
             plane_map: HashMap::new(),
-            point_map: HashMap::new(),
-            normal_map: HashMap::new(),
+            point_map: PointMap::new(),
+            normal_map: PointMap::new(),
             texgen_map: HashMap::new(),
             emit_string_map: HashMap::new(),
-            mb_only: mb_only,
             bsp_report: BSPReport {
                 balance_factor: 0,
                 hit: 0,
                 total: 0,
                 hit_area_percentage: 0.0,
+                passed: true,
             },
             ambient_color: Point3F::new(0.0, 0.0, 0.0),
             emergency_ambient_color: Point3F::new(0.0, 0.0, 0.0),
             lumel_scale: 8,
             geometry_scale: 32,
             lights: vec![],
+            config: config.clone(),
+            bake_lightmaps: true,
+            collision: true,
         };
     }
 
     pub fn add_brush(&mut self, brush: &Brush) {
-        self.brushes.push(brush.clone());
+        let mut brush = brush.clone();
+        drop_duplicate_faces(&mut brush, &self.config);
+        if is_patch_brush(&brush) {
+            self.patches.push(brush);
+        } else {
+            self.brushes.push(brush);
+        }
+    }
+
+    pub fn brush_count(&self) -> usize {
+        self.brushes.len()
+    }
+
+    // Whether building the BSP of the added brushes is estimated to take long
+    pub fn is_bsp_slow(&self) -> bool {
+        self.collision
+            && self.config.split_method == SplitMethod::Exhaustive
+            && estimate_bsp_cost(&self.brushes) > SLOW_BSP_COST
     }
 
     pub fn set_ambient(&mut self, ambient: Point3F, emergency_ambient: Point3F) {
@@ -103,6 +202,11 @@ impl DIFBuilder {
         self.lumel_scale = scale;
     }
 
+    pub fn set_detail_level(&mut self, detail_level: u32, min_pixels: u32) {
+        self.interior.detail_level = detail_level;
+        self.interior.min_pixels = min_pixels;
+    }
+
     pub fn set_geometry_scale(&mut self, scale: u32) {
         self.geometry_scale = scale;
     }
@@ -111,13 +215,67 @@ impl DIFBuilder {
         self.lights = lights;
     }
 
+    // Override the global split method for this interior's BSP, movers and other sub-objects
+    // rarely need the exhaustive split
+    pub fn set_split_method(&mut self, split_method: SplitMethod) {
+        self.config.split_method = split_method;
+    }
+
+    // Without baking, every surface shares a single lightmap of the ambient color
+    pub fn set_bake_lightmaps(&mut self, bake: bool) {
+        self.bake_lightmaps = bake;
+    }
+
+    // Without collision the interior is render only, the surfaces are exported but there are no
+    // convex hulls, BSP or coordbins to collide with
+    pub fn set_collision(&mut self, collision: bool) {
+        self.collision = collision;
+    }
+
+    fn is_surface_unlit(&self, surface_index: usize) -> bool {
+        let material = &self.interior.material_names
+            [*self.interior.surfaces[surface_index].texture_index.inner() as usize];
+        self.config
+            .unlit_materials
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(material))
+    }
+
     pub fn build(
+        self,
+        progress_report_callback: &mut dyn ProgressEventListener,
+    ) -> Result<(Interior, BSPReport), ConversionError> {
+        self.build_with_cache(progress_report_callback, &mut BSPCache::new())
+    }
+
+    // Same as build, but reuses the BSP tree from the cache if the brush geometry is unchanged
+    #[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+    pub fn build_with_cache(
         mut self,
         progress_report_callback: &mut dyn ProgressEventListener,
-    ) -> (Interior, BSPReport) {
-        self.interior.bounding_box = get_bounding_box(&self.brushes);
-        self.interior.bounding_sphere = get_bounding_sphere(&self.brushes);
-        self.export_brushes(progress_report_callback);
+        bsp_cache: &mut BSPCache,
+    ) -> Result<(Interior, BSPReport), ConversionError> {
+        if progress_report_callback.cancelled() {
+            return Err(ConversionError::Cancelled);
+        }
+        if self.config.weld_tolerance > 0.0 {
+            self.weld_vertices();
+        }
+        let all_brushes = self
+            .brushes
+            .iter()
+            .chain(self.patches.iter())
+            .collect::<Vec<_>>();
+        self.interior.bounding_box = get_bounding_box_not_owned(&all_brushes);
+        self.interior.bounding_sphere = get_bounding_sphere(&all_brushes);
+        self.export_brushes(progress_report_callback, bsp_cache);
+        if progress_report_callback.cancelled() {
+            return Err(ConversionError::Cancelled);
+        }
+        compact_materials(&mut self.interior);
+        if self.config.sort_materials {
+            sort_materials(&mut self.interior);
+        }
         self.interior.zones.push(Zone {
             portal_start: PortalIndex::new(0),
             portal_count: 0,
@@ -128,7 +286,7 @@ impl DIFBuilder {
             flags: 0,
         });
         self.export_coord_bins();
-        if self.mb_only {
+        if self.config.mb_only {
             self.interior
                 .poly_list_plane_indices
                 .push(PlaneIndex::from(0));
@@ -155,16 +313,250 @@ impl DIFBuilder {
                 a: 255,
             };
             self.process_hull_poly_lists(); // Hull poly lists
-            self.compute_lightmaps(); // lightmaps
+            if progress_report_callback.cancelled() {
+                return Err(ConversionError::Cancelled);
+            }
+            self.compute_lightmaps()?; // lightmaps
+        }
+        if self.config.compute_point_visibility {
+            self.compute_point_visibilities();
         }
         // self.calculate_bsp_coverage();
-        let balance_factor_save = self.bsp_report.balance_factor;
-        self.bsp_report = self.interior.calculate_bsp_raycast_coverage();
-        self.bsp_report.balance_factor = balance_factor_save;
-        (self.interior, self.bsp_report)
+        if progress_report_callback.cancelled() {
+            return Err(ConversionError::Cancelled);
+        }
+        // There's no BSP to raycast without collision
+        if self.collision {
+            let balance_factor_save = self.bsp_report.balance_factor;
+            self.bsp_report = self.interior.calculate_bsp_raycast_coverage();
+            self.bsp_report.balance_factor = balance_factor_save;
+        }
+        // The engine sizes the animated light state of the interior by this, it has to match the
+        // state data that was emitted
+        self.interior.num_light_state_entries = self.interior.state_datas.len() as u32;
+        Ok((self.interior, self.bsp_report))
+    }
+
+    // Builds the brushes into the vehicle collision of a DIF, the hulls vehicles collide with in
+    // place of the interior's. It has no rendered surfaces, every face becomes a null surface.
+    pub fn build_vehicle_collision(
+        mut self,
+        progress_report_callback: &mut dyn ProgressEventListener,
+    ) -> Result<VehicleCollision, ConversionError> {
+        // The poly lists are only exported outside of the MB only mode, and the surfaces don't
+        // need lightmaps
+        self.config.mb_only = false;
+        self.config.bake_lighting = false;
+        self.collision = true;
+        self.patches.clear();
+        let brushes = self.brushes.clone();
+        let (interior, _) = self.build(progress_report_callback)?;
+
+        // The hulls are exported in the order of the brushes, with a surface and hull plane for
+        // every face. The engine tells the null surfaces apart by the high bit of their index.
+        let mut null_surfaces = vec![];
+        let mut windings = vec![];
+        let mut hull_surface_indices = vec![];
+        for (hull, b) in interior.convex_hulls.iter().zip(brushes.iter()) {
+            let hull_start = *hull.hull_start.inner() as usize;
+            let plane_start = *hull.plane_start.inner() as usize;
+            for (i, f) in b.face.iter().enumerate() {
+                hull_surface_indices.push(0x80000000 | null_surfaces.len() as u32);
+                null_surfaces.push(vehicle_collision::NullSurface {
+                    winding_start: windings.len() as u32,
+                    plane_index: *interior.hull_plane_indices[plane_start + i].inner(),
+                    surface_flags: 0,
+                    winding_count: f.indices.indices.len() as u32,
+                });
+                windings.extend(
+                    f.indices
+                        .indices
+                        .iter()
+                        .map(|&p| *interior.hull_indices[hull_start + p as usize].inner()),
+                );
+            }
+        }
+        Ok(VehicleCollision {
+            version: 0,
+            convex_hulls: interior
+                .convex_hulls
+                .iter()
+                .map(|hull| vehicle_collision::ConvexHull {
+                    hull_start: *hull.hull_start.inner(),
+                    hull_count: hull.hull_count,
+                    min_x: hull.min_x,
+                    max_x: hull.max_x,
+                    min_y: hull.min_y,
+                    max_y: hull.max_y,
+                    min_z: hull.min_z,
+                    max_z: hull.max_z,
+                    surface_start: *hull.surface_start.inner(),
+                    surface_count: hull.surface_count,
+                    plane_start: *hull.plane_start.inner(),
+                    poly_list_plane_start: *hull.poly_list_plane_start.inner(),
+                    poly_list_point_start: *hull.poly_list_point_start.inner(),
+                    poly_list_string_start: *hull.poly_list_string_start.inner(),
+                })
+                .collect(),
+            convex_hull_emit_string_characters: interior.convex_hull_emit_string_characters,
+            hull_indices: interior.hull_indices.iter().map(|i| *i.inner()).collect(),
+            hull_plane_indices: interior
+                .hull_plane_indices
+                .iter()
+                .map(|i| *i.inner())
+                .collect(),
+            hull_emit_string_indices: interior
+                .hull_emit_string_indices
+                .iter()
+                .map(|i| *i.inner())
+                .collect(),
+            hull_surface_indices,
+            poly_list_plane_indices: interior
+                .poly_list_plane_indices
+                .iter()
+                .map(|i| *i.inner())
+                .collect(),
+            poly_list_point_indices: interior
+                .poly_list_point_indices
+                .iter()
+                .map(|i| *i.inner())
+                .collect(),
+            poly_list_string_characters: interior.poly_list_string_characters,
+            null_surfaces,
+            points: interior.points,
+            planes: interior
+                .planes
+                .iter()
+                .map(|p| PlaneF {
+                    normal: interior.normals[*p.normal_index.inner() as usize],
+                    distance: p.plane_distance,
+                })
+                .collect(),
+            windings,
+            winding_indices: vec![],
+        })
+    }
+
+    // The visibility of a point is the fraction of rays cast from it that escape the interior
+    // without hitting a surface
+    #[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+    fn compute_point_visibilities(&mut self) {
+        const RAY_COUNT: usize = 64;
+        if self.interior.bsp_nodes.is_empty() {
+            return;
+        }
+        let ray_length = self.interior.bounding_box.extent().magnitude() + 1.0;
+        // Evenly spread the directions over the sphere with a fibonacci lattice
+        let golden_angle = std::f32::consts::PI * (3.0 - 5f32.sqrt());
+        let directions = (0..RAY_COUNT)
+            .map(|i| {
+                let z = 1.0 - 2.0 * (i as f32 + 0.5) / RAY_COUNT as f32;
+                let r = (1.0 - z * z).sqrt();
+                let theta = golden_angle * i as f32;
+                Point3F::new(r * theta.cos(), r * theta.sin(), z)
+            })
+            .collect::<Vec<_>>();
+        let start_node_index = BSPIndex {
+            index: 0,
+            leaf: false,
+            solid: false,
+        };
+        let interior = &self.interior;
+        let visibilities = interior
+            .points
+            .par_iter()
+            .map(|p| {
+                let escaped = directions
+                    .iter()
+                    .filter(|&&dir| {
+                        !interior.bsp_ray_cast(
+                            &start_node_index,
+                            &u16::MAX,
+                            p + dir * 0.01,
+                            p + dir * ray_length,
+                        )
+                    })
+                    .count();
+                (escaped * 255 / RAY_COUNT) as u8
+            })
+            .collect::<Vec<_>>();
+        self.interior.point_visibilities = visibilities;
+    }
+
+    // Every vertex within the tolerance of an earlier one is moved onto it. The faces with a moved
+    // vertex get their plane refit to the welded winding, so coincident walls end up on the same
+    // plane.
+    fn weld_vertices(&mut self) {
+        let mut positions = PointMap::new();
+        for b in self.brushes.iter_mut().chain(self.patches.iter_mut()) {
+            let mut moved = vec![false; b.vertices.vertex.len()];
+            for (v, moved) in b.vertices.vertex.iter_mut().zip(moved.iter_mut()) {
+                let point = OrdPoint {
+                    x: v.pos.x,
+                    y: v.pos.y,
+                    z: v.pos.z,
+                    epsilon: self.config.weld_tolerance,
+                };
+                match positions.get(&point) {
+                    Some(pos) => {
+                        *moved = pos != v.pos;
+                        v.pos = pos;
+                    }
+                    None => positions.insert(point, v.pos),
+                }
+            }
+            for f in b.face.iter_mut() {
+                let winding = f
+                    .indices
+                    .indices
+                    .iter()
+                    .filter(|&&i| (i as usize) < moved.len())
+                    .collect::<Vec<_>>();
+                if winding.is_empty() || !winding.iter().any(|&&i| moved[i as usize]) {
+                    continue;
+                }
+                let points = winding.iter().map(|&&i| b.vertices.vertex[i as usize].pos);
+                let center = points.clone().sum::<Point3F>() / winding.len() as f32;
+                // The welded winding may have turned, a moved vertex tilts its face
+                let normal = get_winding_normal(points);
+                if normal.magnitude2() > 0.0 {
+                    let normal = normal.normalize();
+                    f.plane.normal = if normal.dot(f.plane.normal) < 0.0 {
+                        -normal
+                    } else {
+                        normal
+                    };
+                }
+                f.plane.distance = -center.dot(f.plane.normal);
+            }
+        }
     }
 
-    fn export_brushes(&mut self, progress_report_callback: &mut dyn ProgressEventListener) {
+    fn export_brushes(
+        &mut self,
+        progress_report_callback: &mut dyn ProgressEventListener,
+        bsp_cache: &mut BSPCache,
+    ) {
+        // The brushes are checked and their emit strings built on all the threads, but the points,
+        // planes and surfaces go through the dedup maps one brush at a time so their indices stay
+        // the same whatever the threads do
+        let point_epsilon = self.config.point_epsilon;
+        let degenerate_faces = self
+            .brushes
+            .par_iter_mut()
+            .map(|brush| drop_degenerate_faces(brush, point_epsilon))
+            .collect::<Vec<_>>();
+        for (brush, face_ids) in self.brushes.iter().zip(degenerate_faces) {
+            for face_id in face_ids {
+                progress_report_callback.progress(
+                    0,
+                    0,
+                    format!("Skipping degenerate face {} of brush {}", face_id, brush.id),
+                    format!("Skipped degenerate face {} of brush {}", face_id, brush.id),
+                );
+            }
+        }
+        let mut hull_poly_planes = vec![];
         for i in 0..self.brushes.len() {
             progress_report_callback.progress(
                 (i + 1) as u32,
@@ -172,11 +564,54 @@ impl DIFBuilder {
                 "Exporting convex hulls".to_string(),
                 "Exported convex hulls".to_string(),
             );
-            self.export_convex_hull(i);
+            hull_poly_planes.push(self.export_convex_hull(i));
+        }
+        if !self.config.mb_only {
+            let emit_strings = hull_emit_strings_cached(
+                &self.brushes,
+                &self.config,
+                progress_report_callback,
+                bsp_cache,
+                || {
+                    self.brushes
+                        .par_iter()
+                        .zip(hull_poly_planes.par_iter())
+                        .map(|(brush, poly_planes)| hull_emit_strings(brush, poly_planes))
+                        .collect::<Vec<_>>()
+                },
+            );
+            for emit_string in emit_strings.into_iter().flatten() {
+                let emit_string_index = self.export_emit_string(emit_string);
+                self.interior
+                    .hull_emit_string_indices
+                    .push(emit_string_index as _);
+            }
+        }
+        for i in 0..self.patches.len() {
+            self.export_patch(i);
+        }
+        // The hulls export the points and surfaces, so drop only the hulls themselves
+        if !self.collision {
+            self.interior.convex_hulls.clear();
+            self.interior.hull_indices.clear();
+            self.interior.hull_surface_indices.clear();
+            self.interior.hull_plane_indices.clear();
+            self.interior.poly_list_plane_indices.clear();
+            self.interior.poly_list_point_indices.clear();
+            return;
         }
-        let (bsp_root, plane_remap) = build_bsp(&self.brushes, progress_report_callback);
+        let (bsp_root, plane_remap) = build_bsp_cached(
+            &self.brushes,
+            &self.config,
+            progress_report_callback,
+            bsp_cache,
+        );
         self.bsp_report.balance_factor = bsp_root.balance_factor();
-        self.export_bsp_node(&bsp_root, &plane_remap);
+        let root_index = self.export_bsp_node(&bsp_root, &plane_remap);
+        debug_assert!(
+            self.bsp_export_matches(&bsp_root, &root_index, &plane_remap),
+            "The exported BSP doesn't match the planes and children of the built BSP"
+        );
         // self.calculate_bsp_raycast_root_coverage(&bsp_root, &plane_remap);
     }
 
@@ -226,6 +661,17 @@ impl DIFBuilder {
                 return leaf_index;
             }
         } else {
+            // A node referring to a plane we don't have can't be exported, treat it as empty space
+            let node_plane = match plane_remap.get(node.plane_index.unwrap() as usize) {
+                Some(p) => p,
+                None => {
+                    return BSPIndex {
+                        leaf: true,
+                        solid: false,
+                        index: 0,
+                    }
+                }
+            };
             let node_index = self.interior.bsp_nodes.len();
             let bsp_node = BSPNode {
                 front_index: BSPIndex {
@@ -243,7 +689,6 @@ impl DIFBuilder {
 
             self.interior.bsp_nodes.push(bsp_node);
 
-            let node_plane = &plane_remap[node.plane_index.unwrap() as usize];
             let plane_index = self.export_plane(node_plane);
             let plane_flipped = *plane_index.inner() & 0x8000 != 0;
 
@@ -281,10 +726,53 @@ impl DIFBuilder {
         }
     }
 
+    // Re-derives the plane of every exported node and checks it against the plane it was exported
+    // from, the children have to be swapped exactly when the exported plane is the flipped one
+    fn bsp_export_matches(
+        &self,
+        node: &CSXBSPNode,
+        index: &BSPIndex,
+        plane_remap: &Vec<PlaneF>,
+    ) -> bool {
+        let node_plane = match node.plane_index.and_then(|i| plane_remap.get(i as usize)) {
+            Some(p) => p,
+            None => return index.leaf && (node.solid || !index.solid),
+        };
+        if index.leaf {
+            return false;
+        }
+        let bsp_node = &self.interior.bsp_nodes[index.index as usize];
+        let plane = &self.interior.planes[*bsp_node.plane_index.inner() as usize];
+        let exported_plane = PlaneF {
+            normal: self.interior.normals[*plane.normal_index.inner() as usize],
+            distance: plane.plane_distance,
+        };
+        let flipped_plane = PlaneF {
+            normal: -exported_plane.normal,
+            distance: -exported_plane.distance,
+        };
+        let (front_index, back_index) = if OrdPlaneF::from(node_plane, &self.config)
+            == OrdPlaneF::from(&exported_plane, &self.config)
+        {
+            (&bsp_node.front_index, &bsp_node.back_index)
+        } else if OrdPlaneF::from(node_plane, &self.config)
+            == OrdPlaneF::from(&flipped_plane, &self.config)
+        {
+            (&bsp_node.back_index, &bsp_node.front_index)
+        } else {
+            return false;
+        };
+        let children_match = |child: &Option<Box<CSXBSPNode>>, child_index: &BSPIndex| match child {
+            Some(n) => self.bsp_export_matches(n, child_index, plane_remap),
+            None => child_index.leaf && !child_index.solid,
+        };
+        children_match(&node.front, front_index) && children_match(&node.back, back_index)
+    }
+
     fn export_point(&mut self, point: &Vertex) -> PointIndex {
-        let ord_point = OrdPoint::from(&point.pos);
+        let ord_point = OrdPoint::from(&point.pos, &self.config);
         if let Some(p) = self.point_map.get(&ord_point) {
-            return *p;
+            return p;
         }
         let index = PointIndex::new(self.interior.points.len() as u32);
         self.interior.points.push(point.pos);
@@ -311,36 +799,56 @@ impl DIFBuilder {
         return index;
     }
 
+    #[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
     fn export_coord_bins(&mut self) {
-        // There are always 256 of these (hard-coded in engine)
-        for i in 0..256 {
+        // The engine looks the bins up in a fixed 16x16 grid over the bounding box, and the file
+        // doesn't store the bin count, it always reads 256 bins. So the resolution can't change.
+        const GRID_SIZE: usize = 16;
+        for i in 0..GRID_SIZE * GRID_SIZE {
             self.interior.coord_bins.push(CoordBin {
-                bin_start: CoordBinIndex::new(i),
+                bin_start: CoordBinIndex::new(i as _),
                 bin_count: 1,
             });
         }
-        // Split coordbins into 16x16 equal rect prisms in the xy plane
+        // The file format still needs all the bins, so just leave them empty
+        if !self.config.generate_coordbins {
+            self.interior.coord_bins.iter_mut().for_each(|bin| {
+                bin.bin_start = CoordBinIndex::new(0);
+                bin.bin_count = 0;
+            });
+            return;
+        }
+        // Split coordbins into GRID_SIZE x GRID_SIZE equal rect prisms in the plane of the coord bin
+        // mode
         // Probably a more efficient way to do this but this will work
-        for i in 0..16 {
-            let min_x = self.interior.bounding_box.min.x
-                + (i as f32 * self.interior.bounding_box.extent().x / 16f32);
-            let max_x = self.interior.bounding_box.min.x
-                + ((i + 1) as f32 * self.interior.bounding_box.extent().x / 16f32);
-            for j in 0..16 {
-                let min_y = self.interior.bounding_box.min.y
-                    + (j as f32 * self.interior.bounding_box.extent().y / 16f32);
-                let max_y = self.interior.bounding_box.min.y
-                    + ((j + 1) as f32 * self.interior.bounding_box.extent().y / 16f32);
-
-                let bin_index = (i * 16) + j;
+        let coord_bin_mode = self.config.coord_bin_mode.unwrap_or(CoordBinMode::XY);
+        self.interior.coord_bin_mode = coord_bin_mode as u32;
+        let (u, v) = coord_bin_mode.axes();
+        let hull_range = |hull: &ConvexHull, axis: usize| match axis {
+            0 => (hull.min_x, hull.max_x),
+            1 => (hull.min_y, hull.max_y),
+            _ => (hull.min_z, hull.max_z),
+        };
+        let bounds_min = self.interior.bounding_box.min;
+        let extent = self.interior.bounding_box.extent();
+        for i in 0..GRID_SIZE {
+            let min_u = bounds_min[u] + (i as f32 * extent[u] / GRID_SIZE as f32);
+            let max_u = bounds_min[u] + ((i + 1) as f32 * extent[u] / GRID_SIZE as f32);
+            for j in 0..GRID_SIZE {
+                let min_v = bounds_min[v] + (j as f32 * extent[v] / GRID_SIZE as f32);
+                let max_v = bounds_min[v] + ((j + 1) as f32 * extent[v] / GRID_SIZE as f32);
+
+                let bin_index = (i * GRID_SIZE) + j;
                 let mut bin_count = 0;
-                self.interior.coord_bins[bin_index as usize].bin_start =
+                self.interior.coord_bins[bin_index].bin_start =
                     CoordBinIndex::new(self.interior.coord_bin_indices.len() as _);
                 for (k, hull) in self.interior.convex_hulls.iter().enumerate() {
-                    if !(min_x > hull.max_x
-                        || max_x < hull.min_x
-                        || min_y > hull.max_y
-                        || max_y < hull.min_y)
+                    let (hull_min_u, hull_max_u) = hull_range(hull, u);
+                    let (hull_min_v, hull_max_v) = hull_range(hull, v);
+                    if !(min_u > hull_max_u
+                        || max_u < hull_min_u
+                        || min_v > hull_max_v
+                        || max_v < hull_min_v)
                     {
                         self.interior
                             .coord_bin_indices
@@ -349,7 +857,7 @@ impl DIFBuilder {
                     }
                 }
 
-                self.interior.coord_bins[bin_index as usize].bin_count = bin_count as _;
+                self.interior.coord_bins[bin_index].bin_count = bin_count as _;
             }
         }
     }
@@ -367,7 +875,7 @@ impl DIFBuilder {
 
     fn export_plane(&mut self, plane: &PlaneF) -> PlaneIndex {
         assert!(self.interior.planes.len() < 0x10000);
-        let pord = OrdPlaneF::from(&plane);
+        let pord = OrdPlaneF::from(&plane, &self.config);
 
         if self.plane_map.contains_key(&pord) {
             let pval = self.plane_map.get(&pord).unwrap();
@@ -378,7 +886,7 @@ impl DIFBuilder {
         pinvplane.normal *= -1.0;
         pinvplane.distance *= -1.0;
 
-        let pord = OrdPlaneF::from(&pinvplane);
+        let pord = OrdPlaneF::from(&pinvplane, &self.config);
 
         if self.plane_map.contains_key(&pord) {
             let pval = self.plane_map.get(&pord).unwrap();
@@ -389,14 +897,14 @@ impl DIFBuilder {
 
         let index = PlaneIndex::new(self.interior.planes.len() as _);
 
-        let normal_ord = OrdPoint::from(&plane.normal);
+        let normal_ord = OrdPoint::from(&plane.normal, &self.config);
 
         let normal_map_idx = self.normal_map.get(&normal_ord);
 
         match normal_map_idx {
             Some(nidx) => {
                 self.interior.planes.push(Plane {
-                    normal_index: *nidx,
+                    normal_index: nidx,
                     plane_distance: plane.distance,
                 });
             }
@@ -404,7 +912,7 @@ impl DIFBuilder {
                 let normal_index = NormalIndex::new(self.interior.normals.len() as _);
                 self.normal_map.insert(normal_ord, normal_index);
                 self.interior.normals.push(plane.normal);
-                if !self.mb_only {
+                if !self.config.mb_only {
                     self.interior.normal2s.push(plane.normal);
                 }
 
@@ -415,7 +923,7 @@ impl DIFBuilder {
             }
         }
 
-        let pord = OrdPlaneF::from(&plane);
+        let pord = OrdPlaneF::from(&plane, &self.config);
 
         self.plane_map.insert(pord, index);
 
@@ -434,35 +942,46 @@ impl DIFBuilder {
         let pflipped = plane_index.inner() & 0x8000 > 0;
         self.face_to_plane.insert(face.face_id, plane_index);
 
-        let tex_gen_index = self.export_tex_gen(&face.texgens);
-        let winding_index = WindingIndexIndex::new(self.interior.indices.len() as _);
-        let winding_length = face.indices.indices.len();
-        for i in 0..winding_length {
-            if i >= 2 {
-                if i % 2 == 0 {
-                    self.interior.indices.push(
-                        hull_points
-                            [face.indices.indices[winding_length - 1 - (i - 2) / 2] as usize],
-                    );
-                } else {
-                    self.interior
-                        .indices
-                        .push(hull_points[face.indices.indices[(i + 1) / 2] as usize]);
-                }
-            } else {
-                self.interior
-                    .indices
-                    .push(hull_points[face.indices.indices[i] as usize]);
+        let tex_gen_index = match self
+            .config
+            .texgen_overrides
+            .iter()
+            .find(|(m, _, _)| m.eq_ignore_ascii_case(&face.material))
+        {
+            Some((_, plane_x, plane_y)) => {
+                let mut texgens = face.texgens.clone();
+                texgens.plane_x = plane_x.clone();
+                texgens.plane_y = plane_y.clone();
+                self.export_tex_gen(&texgens)
             }
+            None => self.export_tex_gen(&face.texgens),
+        };
+        // The interiors are split before this can happen, see the winding index limit in csx.rs
+        let winding_index = WindingIndexIndex::new(
+            u32::try_from(self.interior.indices.len()).expect("Too many winding indices"),
+        );
+        let winding_length = face.indices.indices.len();
+
+        // Start the winding from a convex corner so the first triangles don't fold over or
+        // collapse, collinear corners are left out of the fan
+        let winding_points = face
+            .indices
+            .indices
+            .iter()
+            .map(|&i| self.interior.points[*hull_points[i as usize].inner() as usize])
+            .collect::<Vec<_>>();
+        let (winding, fan_mask) = get_fan_winding(
+            &get_winding_corners(&winding_points),
+            self.config.full_fan_mask,
+        );
+        for corner in winding {
+            self.interior
+                .indices
+                .push(hull_points[face.indices.indices[corner] as usize]);
         }
 
         let material_index = self.export_texture(face.material.clone());
 
-        let mut fan_mask = 0b0;
-        for i in 0..winding_length {
-            fan_mask |= 1 << i;
-        }
-
         let surface = Surface {
             winding_start: winding_index,
             winding_count: winding_length as _,
@@ -471,7 +990,7 @@ impl DIFBuilder {
             texture_index: material_index,
             tex_gen_index: tex_gen_index,
             surface_flags: SurfaceFlags::OUTSIDE_VISIBLE,
-            fan_mask: fan_mask as _,
+            fan_mask,
             light_map: SurfaceLightMap {
                 final_word: 0, // stEnc, lmapLogScaleX, lmapLogScaleY
                 tex_gen_x_distance: 0.0,
@@ -500,20 +1019,27 @@ impl DIFBuilder {
         index
     }
 
-    fn export_convex_hull(&mut self, brush_index: usize) -> usize {
-        let b = self.brushes[brush_index].clone();
-        struct HullPoly {
-            pub points: Vec<usize>,
-            pub plane_index: usize,
-        }
-        #[derive(Hash, PartialEq, Eq)]
-        struct EmitEdge {
-            pub first: usize,
-            pub last: usize,
-        }
-
-        let index = self.interior.convex_hulls.len();
+    // Patches are only rendered, they have no volume for a hull to collide with or to be solid in
+    // the BSP, so only their points and surfaces are exported
+    fn export_patch(&mut self, patch_index: usize) {
+        let b = self.patches[patch_index].clone();
+        let patch_points = b
+            .vertices
+            .vertex
+            .iter()
+            .map(|v| self.export_point(v))
+            .collect::<Vec<_>>();
+        b.face.iter().for_each(|f| {
+            self.export_surface(f, &patch_points);
+        });
+    }
 
+    #[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+    // Exports the points, planes and surfaces of the brush's hull, and returns the plane index of
+    // each of its faces for the emit strings
+    #[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+    fn export_convex_hull(&mut self, brush_index: usize) -> Vec<usize> {
+        let b = self.brushes[brush_index].clone();
         let hull_count: usize = b.vertices.vertex.len();
         assert!(hull_count < 0x10000);
         let bounding_box =
@@ -553,7 +1079,7 @@ impl DIFBuilder {
         self.interior
             .hull_indices
             .append(&mut hull_exported_points.clone());
-        if !self.mb_only {
+        if !self.config.mb_only {
             self.interior
                 .poly_list_point_indices
                 .append(&mut hull_exported_points.clone());
@@ -565,7 +1091,7 @@ impl DIFBuilder {
             .iter()
             .map(|f| self.export_plane(&f.plane))
             .collect::<Vec<_>>();
-        if !self.mb_only {
+        if !self.config.mb_only {
             self.interior
                 .poly_list_plane_indices
                 .append(&mut hull_plane_indices.clone());
@@ -586,119 +1112,17 @@ impl DIFBuilder {
             .hull_surface_indices
             .append(&mut hull_surface_indices);
 
-        // Hull polys
-        let mut hull_polys = vec![];
-        b.face.iter().for_each(|face| {
-            let mut points = vec![];
-            for i in 0..face.indices.indices.len() {
-                points.push(face.indices.indices[i] as usize);
-            }
-            hull_polys.push(HullPoly {
-                points: points.into_iter().map(|p| p).collect::<Vec<_>>(),
-                plane_index: *self.face_to_plane[&face.face_id].inner() as usize,
-            });
-        });
-
-        // Ok, now we have to construct an emit string for each vertex.  This should be fairly
-        //  straightforward, the procedure is:
-        // for each point:
-        //   - find all polys that contain that point
-        //   - find all points in those polys
-        //   - find all edges  in those polys
-        //   - enter the string
-        //  The tricky bit is that we have to set up the emit indices to be relative to the
-        //   hullindices.
-        for (i, _) in b.vertices.vertex.into_iter().enumerate() {
-            let mut emit_poly_indices = vec![];
-            if !self.mb_only {
-                // Collect emitted polys for this point
-                for (j, poly) in hull_polys.iter().enumerate() {
-                    if poly.points.contains(&i) {
-                        emit_poly_indices.push(j);
-                    }
-                }
-                // We also have to emit any polys that share the plane, but not necessarily the
-                //  support point
-                let mut new_indices = vec![];
-                for (j, poly) in hull_polys.iter().enumerate() {
-                    for &emit_poly in emit_poly_indices.iter() {
-                        if emit_poly == j {
-                            continue;
-                        }
-
-                        if hull_polys[emit_poly].plane_index == poly.plane_index {
-                            if emit_poly_indices.contains(&j) {
-                                continue;
-                            }
-                            new_indices.push(j);
-                        }
-                    }
-                }
-                emit_poly_indices.extend(new_indices);
-
-                assert_ne!(emit_poly_indices.len(), 0);
-
-                // Then generate all points and edges these polys contain
-                let emit_points: Vec<usize> = Vec::from_iter(
-                    emit_poly_indices
-                        .iter()
-                        .flat_map(|&poly| hull_polys[poly].points.clone())
-                        .collect::<HashSet<_>>()
-                        .into_iter(),
-                );
-                let emit_edges: Vec<EmitEdge> = Vec::from_iter(
-                    emit_poly_indices
-                        .iter()
-                        .flat_map(|&poly| {
-                            windows2_wrap(&hull_polys[poly].points).into_iter().map(
-                                |(&first, &second)| EmitEdge {
-                                    first: first.min(second),
-                                    last: first.max(second),
-                                },
-                            )
-                        })
-                        .collect::<HashSet<_>>()
-                        .into_iter(),
-                );
-
-                let mut emit_string: Vec<u8> = vec![];
-                emit_string.push(emit_points.len() as _);
-                for &point in &emit_points {
-                    assert!(point < 0x100);
-                    emit_string.push(point as _);
-                }
-                emit_string.push(emit_edges.len() as _);
-                for edge in emit_edges {
-                    assert!(edge.first < 0x100);
-                    assert!(edge.last < 0x100);
-                    emit_string.push(edge.first as _);
-                    emit_string.push(edge.last as _);
-                }
-                emit_string.push(emit_poly_indices.len() as _);
-                for poly_index in emit_poly_indices {
-                    assert!(hull_polys[poly_index].points.len() < 0x100);
-                    assert!(poly_index < 0x100);
-                    emit_string.push(hull_polys[poly_index].points.len() as _);
-                    emit_string.push(poly_index as _);
-                    for point in hull_polys[poly_index].points.iter() {
-                        if let Some(point_index) = emit_points.iter().position(|pt| pt == point) {
-                            assert!(point_index < 0x100);
-                            emit_string.push(point_index as _);
-                        }
-                    }
-                }
-
-                let emit_string_index = self.export_emit_string(emit_string);
-                self.interior
-                    .hull_emit_string_indices
-                    .push(emit_string_index as _);
-            }
-        }
+        let poly_planes = b
+            .face
+            .iter()
+            .map(|face| *self.face_to_plane[&face.face_id].inner() as usize)
+            .collect::<Vec<_>>();
 
         self.interior.convex_hulls.push(hull);
-        index
+        poly_planes
     }
 
+    #[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
     fn process_hull_poly_lists(&mut self) {
         self.interior.poly_list_plane_indices.clear();
         self.interior.poly_list_point_indices.clear();
@@ -1160,18 +1584,59 @@ impl DIFBuilder {
         );
     }
 
-    fn compute_lightmaps(&mut self) {
+    #[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+    fn compute_lightmaps(&mut self) -> Result<(), ConversionError> {
+        if !self.bake_lightmaps {
+            // The surfaces already point to lightmap 0, and to lightmap 1 in the alarm state
+            self.interior.light_maps.push(LightMap {
+                light_map: empty_lightmap(
+                    self.config.lightmap_size,
+                    self.ambient_color.x as u8,
+                    self.ambient_color.y as u8,
+                    self.ambient_color.z as u8,
+                ),
+                light_dir_map: self.flat_dir_map(),
+                keep_light_map: 0,
+            });
+            if self.emergency_ambient_color != self.ambient_color {
+                self.interior.has_alarm_state = 1;
+                self.interior
+                    .alarm_lmap_indices
+                    .iter_mut()
+                    .for_each(|i| *i = LMapIndex::new(1));
+                self.interior.light_maps.push(LightMap {
+                    light_map: empty_lightmap(
+                        self.config.lightmap_size,
+                        self.emergency_ambient_color.x as u8,
+                        self.emergency_ambient_color.y as u8,
+                        self.emergency_ambient_color.z as u8,
+                    ),
+                    light_dir_map: self.flat_dir_map(),
+                    keep_light_map: 0,
+                });
+            }
+            return Ok(());
+        }
         let mut rects_to_place: GroupedRectsToPlace<usize, ()> = GroupedRectsToPlace::new();
         let mut lmaps_needed = 1;
-        let mut area_remaining = (256 * 256) as i32;
+        let atlas_area = (self.config.lightmap_size * self.config.lightmap_size) as i32;
+        let mut area_remaining = atlas_area;
 
         let mut lmap_surfaces = vec![];
 
+        let unlit_surfaces = (0..self.interior.surfaces.len())
+            .map(|surf_idx| self.is_surface_unlit(surf_idx))
+            .collect::<Vec<_>>();
+
         for surf_idx in 0..self.interior.surfaces.len() {
-            let (lmap_area, sc, tc) = self.fill_in_lightmap_info(surf_idx, &mut rects_to_place);
+            let (lmap_area, sc, tc) = if unlit_surfaces[surf_idx] {
+                (0, Point3F::new(0.0, 0.0, 0.0), Point3F::new(0.0, 0.0, 0.0))
+            } else {
+                self.fill_in_lightmap_info(surf_idx, &mut rects_to_place)?
+            };
             if area_remaining - lmap_area < 0 {
                 lmaps_needed += 1;
-                area_remaining = (256 * 256) as i32;
+                area_remaining = atlas_area;
             }
 
             let mut first_normal = self.interior.normals[*self.interior.planes
@@ -1222,88 +1687,176 @@ impl DIFBuilder {
                 normal: first_normal,
                 tri_points: points,
                 lightmap_index: 0,
+                gutter: self.config.lightmap_gutter as usize,
+                lumel_scale: self.lumel_scale,
             });
         }
-        let mut target_bins = BTreeMap::new();
-        for i in 0..lmaps_needed {
-            target_bins.insert(i, TargetBin::new(256, 256, 255));
-        }
+        // Pack the lmaps, the area estimate doesn't account for the gaps between the rects so add
+        // lightmaps until they fit. More lightmaps than surfaces means a rect can never fit.
+        let rect_placements = loop {
+            let mut target_bins = BTreeMap::new();
+            for i in 0..lmaps_needed {
+                target_bins.insert(
+                    i,
+                    TargetBin::new(self.config.lightmap_size, self.config.lightmap_size, 255),
+                );
+            }
+            match pack_rects(
+                &rects_to_place,
+                &mut target_bins,
+                &volume_heuristic,
+                &contains_smallest_box,
+            ) {
+                Ok(placements) => break placements,
+                Err(_) if (lmaps_needed as usize) <= self.interior.surfaces.len() => {
+                    lmaps_needed += 1;
+                }
+                Err(e) => panic!("Could not pack the lightmaps: {:?}", e),
+            }
+        };
 
-        // Pack the lmaps
-        let rect_placements = pack_rects(
-            &rects_to_place,
-            &mut target_bins,
-            &volume_heuristic,
-            &contains_smallest_box,
-        )
-        .unwrap();
+        // The unlit surfaces all point to a single lightmap after the packed ones
+        let unlit_lmap_index = lmaps_needed;
 
         // Then pack
         for surf_idx in 0..self.interior.surfaces.len() {
+            if unlit_surfaces[surf_idx] {
+                self.interior.normal_lmap_indices[surf_idx] = LMapIndex::new(unlit_lmap_index);
+                lmap_surfaces[surf_idx].lightmap_index = unlit_lmap_index as usize;
+                continue;
+            }
             let (lmap_index, packed_loc) =
                 rect_placements.packed_locations().get(&surf_idx).unwrap();
             self.interior.normal_lmap_indices[surf_idx] = LMapIndex::new(*lmap_index);
 
-            self.interior.surfaces[surf_idx].map_size_x = packed_loc.width();
-            self.interior.surfaces[surf_idx].map_size_y = packed_loc.height();
-            self.interior.surfaces[surf_idx].map_offset_x = packed_loc.x();
-            self.interior.surfaces[surf_idx].map_offset_y = packed_loc.y();
+            // The packed rects include the gutter on every side, the lightmap sits inside it
+            let gutter = self.config.lightmap_gutter;
+            let (offset_x, offset_y) = (packed_loc.x() + gutter, packed_loc.y() + gutter);
+            let (width, height) = (
+                packed_loc.width() - 2 * gutter,
+                packed_loc.height() - 2 * gutter,
+            );
+            self.interior.surfaces[surf_idx].map_size_x = width;
+            self.interior.surfaces[surf_idx].map_size_y = height;
+            self.interior.surfaces[surf_idx].map_offset_x = offset_x;
+            self.interior.surfaces[surf_idx].map_offset_y = offset_y;
             self.interior.surfaces[surf_idx]
                 .light_map
-                .tex_gen_x_distance += packed_loc.x() as f32 / 256.0;
+                .tex_gen_x_distance += offset_x as f32 / self.config.lightmap_size as f32;
             self.interior.surfaces[surf_idx]
                 .light_map
-                .tex_gen_y_distance += packed_loc.y() as f32 / 256.0;
+                .tex_gen_y_distance += offset_y as f32 / self.config.lightmap_size as f32;
             lmap_surfaces[surf_idx].dx = self.interior.surfaces[surf_idx]
                 .light_map
                 .tex_gen_x_distance;
             lmap_surfaces[surf_idx].dy = self.interior.surfaces[surf_idx]
                 .light_map
                 .tex_gen_y_distance;
-            lmap_surfaces[surf_idx].width = packed_loc.width() as usize;
-            lmap_surfaces[surf_idx].height = packed_loc.height() as usize;
-            lmap_surfaces[surf_idx].offset_x = packed_loc.x() as usize;
-            lmap_surfaces[surf_idx].offset_y = packed_loc.y() as usize;
+            lmap_surfaces[surf_idx].width = width as usize;
+            lmap_surfaces[surf_idx].height = height as usize;
+            lmap_surfaces[surf_idx].offset_x = offset_x as usize;
+            lmap_surfaces[surf_idx].offset_y = offset_y as usize;
             lmap_surfaces[surf_idx].lightmap_index = *lmap_index as usize;
         }
 
-        // Now actually compute the lightmaps
-        // Add the lightmaps now
-        for _ in 0..lmaps_needed {
-            // Add the blank lightmap so we don't crash
-
-            // let lmap_data = lightmap::LightMap::new(
-            //     &self.interior,
-            //     &lmap_surfaces,
-            //     &self.lights,
-            //     256,
-            //     i as usize,
-            //     self.lumel_scale,
-            // );
+        // The light bounced off the lit surfaces, the alarm lightmaps get the same bounced light
+        let bounce_patches = if self.config.bake_lighting && self.config.gi_bounces > 0 {
+            lightmap::bounce_patches(&self.interior, &lmap_surfaces, &self.lights, &self.config)?
+        } else {
+            vec![]
+        };
 
-            // self.interior.light_maps.push(LightMap {
-            //     light_map: filled_lightmap(&lmap_data.pixels),
-            //     light_dir_map: None,
-            //     keep_light_map: 0,
-            // });
+        // Now actually compute the lightmaps, the alarm ones are lit by the same lights on top of
+        // the emergency ambient color
+        let compute_lightmap = |i: u32, ambient: Point3F| {
+            let (light_map, light_dir_map) = if self.config.bake_lighting {
+                let lmap_data = lightmap::LightMap::new(
+                    &self.interior,
+                    &lmap_surfaces,
+                    &self.lights,
+                    i as usize,
+                    ambient,
+                    &bounce_patches,
+                    &self.config,
+                )?;
+                (
+                    filled_lightmap(self.config.lightmap_size, &lmap_data.pixels),
+                    lmap_data
+                        .dir_pixels
+                        .map(|pixels| filled_lightmap(self.config.lightmap_size, &pixels)),
+                )
+            } else {
+                (
+                    empty_lightmap(
+                        self.config.lightmap_size,
+                        ambient.x as u8,
+                        ambient.y as u8,
+                        ambient.z as u8,
+                    ),
+                    self.flat_dir_map(),
+                )
+            };
+            Ok(LightMap {
+                light_map,
+                light_dir_map,
+                keep_light_map: 0,
+            })
+        };
+        let mut normal_lightmaps = (0..lmaps_needed)
+            .into_par_iter()
+            .map(|i| compute_lightmap(i, self.ambient_color))
+            .collect::<Result<Vec<_>, ConversionError>>()?;
+        let has_alarm_state = self.emergency_ambient_color != self.ambient_color;
+        let mut alarm_lightmaps = if has_alarm_state {
+            (0..lmaps_needed)
+                .into_par_iter()
+                .map(|i| compute_lightmap(i, self.emergency_ambient_color))
+                .collect::<Result<Vec<_>, ConversionError>>()?
+        } else {
+            vec![]
+        };
 
+        self.interior.light_maps.append(&mut normal_lightmaps);
+        let has_unlit_surfaces = unlit_surfaces.iter().any(|unlit| *unlit);
+        if has_unlit_surfaces {
             self.interior.light_maps.push(LightMap {
-                light_map: empty_lightmap(
-                    self.ambient_color.x as u8,
-                    self.ambient_color.y as u8,
-                    self.ambient_color.z as u8,
-                ),
-                light_dir_map: None,
+                light_map: empty_lightmap(self.config.lightmap_size, 255, 255, 255),
+                light_dir_map: self.flat_dir_map(),
                 keep_light_map: 0,
             });
         }
-    }
+
+        // The alarm lightmaps share the layout of the normal ones and come right after them, the
+        // unlit surfaces keep using the shared lightmap
+        if has_alarm_state {
+            self.interior.has_alarm_state = 1;
+            let alarm_lmap_start = self.interior.light_maps.len() as u32;
+            for surf_idx in 0..self.interior.surfaces.len() {
+                self.interior.alarm_lmap_indices[surf_idx] = if unlit_surfaces[surf_idx] {
+                    LMapIndex::new(unlit_lmap_index)
+                } else {
+                    LMapIndex::new(
+                        self.interior.normal_lmap_indices[surf_idx].into_inner() + alarm_lmap_start,
+                    )
+                };
+            }
+            self.interior.light_maps.append(&mut alarm_lightmaps);
+        }
+        Ok(())
+    }
+
+    // The lightmaps that aren't baked get a dir map of the light coming from straight above
+    fn flat_dir_map(&self) -> Option<PNG> {
+        self.config
+            .generate_dir_maps
+            .then(|| empty_lightmap(self.config.lightmap_size, 128, 128, 255))
+    }
 
     fn fill_in_lightmap_info(
         &mut self,
         surface_index: usize,
         rects_to_place: &mut GroupedRectsToPlace<usize, ()>,
-    ) -> (i32, Point3F, Point3F) {
+    ) -> Result<(i32, Point3F, Point3F), ConversionError> {
         let axises = vec![
             Point3F::new(1.0, 0.0, 0.0),
             Point3F::new(0.0, 1.0, 0.0),
@@ -1350,16 +1903,6 @@ impl DIFBuilder {
             tc = 1;
         }
 
-        let st_enc = match (sc, tc) {
-            (0, 1) => 0,
-            (0, 2) => 1,
-            (1, 0) => 2,
-            (1, 2) => 3,
-            (2, 0) => 4,
-            (2, 1) => 5,
-            _ => panic!("Invalid axis combination"),
-        };
-
         let mut coords = vec![];
         let surface = &mut self.interior.surfaces[surface_index];
         for i in 0..surface.winding_count {
@@ -1445,19 +1988,40 @@ impl DIFBuilder {
             }
         }
 
-        let lmap_dim_x = (desired_end[0] - desired_start[0] + 0.5) as u32;
-        let lmap_dim_y = (desired_end[1] - desired_start[1] + 0.5) as u32;
+        // Tiny or degenerate surfaces still get a texel so they can be packed
+        let mut lmap_dim_x = ((desired_end[0] - desired_start[0] + 0.5) as u32).max(1);
+        let mut lmap_dim_y = ((desired_end[1] - desired_start[1] + 0.5) as u32).max(1);
+
+        // Lay the tall lightmaps on their side by swapping the s and t axes, rects that all have
+        // the same orientation pack tighter
+        let (sc, tc) = if self.config.rotate_lightmaps && lmap_dim_y > lmap_dim_x {
+            desired_start.swap(0, 1);
+            std::mem::swap(&mut lmap_dim_x, &mut lmap_dim_y);
+            (tc, sc)
+        } else {
+            (sc, tc)
+        };
+
+        let st_enc = match (sc, tc) {
+            (0, 1) => 0,
+            (0, 2) => 1,
+            (1, 0) => 2,
+            (1, 2) => 3,
+            (2, 0) => 4,
+            (2, 1) => 5,
+            _ => panic!("Invalid axis combination"),
+        };
 
         //desired_start[0] *= self.lumel_scale as f32;
         //desired_start[1] *= self.lumel_scale as f32;
         //desired_end[0] *= self.lumel_scale as f32;
         // desired_end[1] *= self.lumel_scale as f32;
 
-        surface.light_map.tex_gen_x_distance = -desired_start[0] / (256.0);
-        surface.light_map.tex_gen_y_distance = -desired_start[1] / (256.0);
+        surface.light_map.tex_gen_x_distance = -desired_start[0] / self.config.lightmap_size as f32;
+        surface.light_map.tex_gen_y_distance = -desired_start[1] / self.config.lightmap_size as f32;
 
-        let sc_scale = 1.0 / (256.0 * self.lumel_scale as f32);
-        let tc_scale = 1.0 / (256.0 * self.lumel_scale as f32);
+        let sc_scale = 1.0 / (self.config.lightmap_size as f32 * self.lumel_scale as f32);
+        let tc_scale = 1.0 / (self.config.lightmap_size as f32 * self.lumel_scale as f32);
 
         let inv_scale_x = ((1.0 / sc_scale) + 0.5) as u32;
         let inv_scale_y = ((1.0 / tc_scale) + 0.5) as u32;
@@ -1469,18 +2033,79 @@ impl DIFBuilder {
             | (log_scale_y & 0b111111) as u16;
         //  stEnc | logScaleX | logScaleY
 
+        let rect_dim_x = lmap_dim_x + 2 * self.config.lightmap_gutter;
+        let rect_dim_y = lmap_dim_y + 2 * self.config.lightmap_gutter;
+        // Adding atlases can't help a rect that doesn't fit in an empty one
+        if rect_dim_x > self.config.lightmap_size || rect_dim_y > self.config.lightmap_size {
+            let (brush, face) = self
+                .brushes
+                .iter()
+                .chain(self.patches.iter())
+                .flat_map(|b| b.face.iter().map(move |f| (b, f)))
+                .find(|(_, f)| {
+                    self.face_to_surface
+                        .get(&f.face_id)
+                        .is_some_and(|index| *index.inner() as usize == surface_index)
+                })
+                .map_or((-1, -1), |(b, f)| (b.id, f.id));
+            return Err(ConversionError::LightmapTooLarge {
+                brush,
+                face,
+                width: rect_dim_x,
+                height: rect_dim_y,
+                lightmap_size: self.config.lightmap_size,
+            });
+        }
         rects_to_place.push_rect(
             surface_index,
             None,
-            RectToInsert::new(lmap_dim_x, lmap_dim_y, 255),
+            RectToInsert::new(rect_dim_x, rect_dim_y, 255),
         );
 
-        (
-            lmap_dim_x as i32 * lmap_dim_y as i32,
+        Ok((
+            rect_dim_x as i32 * rect_dim_y as i32,
             axises[sc] * sc_scale,
             axises[tc] * tc_scale,
-        )
+        ))
+    }
+}
+
+// A brush that doesn't enclose a volume, like the single face patches used as decals. Every edge
+// of a closed brush is shared by exactly two of its faces.
+fn is_patch_brush(brush: &Brush) -> bool {
+    // Without the windings of every face there's no telling if the brush is closed
+    if brush.face.iter().any(|f| f.indices.indices.len() < 3) {
+        return false;
+    }
+    let mut edge_faces: HashMap<(i32, i32), usize> = HashMap::new();
+    for f in brush.face.iter() {
+        for (&a, &b) in windows2_wrap(&f.indices.indices) {
+            *edge_faces.entry((a.min(b), a.max(b))).or_default() += 1;
+        }
+    }
+    edge_faces.values().any(|&count| count != 2)
+}
+
+// Bad boolean ops can leave a brush with several faces on the same plane, which would give the hull
+// redundant surfaces and polys. Keep one face per plane, preferring the ones with a valid winding.
+fn drop_duplicate_faces(brush: &mut Brush, config: &ConvertConfig) {
+    let has_valid_winding = |f: &Face| f.indices.indices.iter().collect::<HashSet<_>>().len() >= 3;
+    let mut kept: Vec<Face> = vec![];
+    for face in brush.face.drain(..) {
+        let plane = OrdPlaneF::from(&face.plane, config);
+        match kept
+            .iter_mut()
+            .find(|k| OrdPlaneF::from(&k.plane, config) == plane)
+        {
+            Some(k) => {
+                if !has_valid_winding(k) && has_valid_winding(&face) {
+                    *k = face;
+                }
+            }
+            None => kept.push(face),
+        }
     }
+    brush.face = kept;
 }
 
 pub fn windows2_wrap<T>(input: &Vec<T>) -> Vec<(&T, &T)>
@@ -1504,6 +2129,71 @@ pub fn get_bounding_box(brushes: &[Brush]) -> BoxF {
     )
 }
 
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum WindingCorner {
+    Convex,
+    Reflex,
+    Collinear,
+}
+
+fn get_winding_corners(points: &[Point3F]) -> Vec<WindingCorner> {
+    // The corners are classified relative to the winding's own orientation
+    let normal = get_winding_normal(points.iter().copied());
+    (0..points.len())
+        .map(|i| {
+            let prev = points[(i + points.len() - 1) % points.len()];
+            let next = points[(i + 1) % points.len()];
+            let e1 = points[i] - prev;
+            let e2 = next - points[i];
+            let cross = e1.cross(e2);
+            if cross.magnitude() <= 1e-4 * e1.magnitude() * e2.magnitude() {
+                WindingCorner::Collinear
+            } else if cross.dot(normal) > 0.0 {
+                WindingCorner::Convex
+            } else {
+                WindingCorner::Reflex
+            }
+        })
+        .collect()
+}
+
+// The order the corners of the winding are exported in, a strip starting from the first convex
+// corner, along with the fan mask of the corners that make up the triangles
+fn get_fan_winding(corners: &[WindingCorner], full_fan_mask: bool) -> (Vec<usize>, u32) {
+    let winding_length = corners.len();
+    let apex = corners
+        .iter()
+        .position(|&c| c == WindingCorner::Convex)
+        .unwrap_or(0);
+
+    let mut winding = vec![];
+    let mut fan_mask: u32 = 0b0;
+    for i in 0..winding_length {
+        let winding_pos = if i >= 2 {
+            if i % 2 == 0 {
+                winding_length - 1 - (i - 2) / 2
+            } else {
+                (i + 1) / 2
+            }
+        } else {
+            i
+        };
+        let corner = (winding_pos + apex) % winding_length;
+        winding.push(corner);
+        if i < 32 && (full_fan_mask || corners[corner] != WindingCorner::Collinear) {
+            fan_mask |= 1 << i;
+        }
+    }
+    // Not enough corners left for a triangle, just use all of them
+    if fan_mask.count_ones() < 3 {
+        fan_mask = 0b0;
+        for i in 0..winding_length.min(32) {
+            fan_mask |= 1 << i;
+        }
+    }
+    (winding, fan_mask)
+}
+
 pub fn get_bounding_box_not_owned(brushes: &[&Brush]) -> BoxF {
     BoxF::from_vertices(
         &brushes
@@ -1514,8 +2204,8 @@ pub fn get_bounding_box_not_owned(brushes: &[&Brush]) -> BoxF {
     )
 }
 
-fn get_bounding_sphere(brushes: &[Brush]) -> SphereF {
-    let b = get_bounding_box(brushes);
+fn get_bounding_sphere(brushes: &[&Brush]) -> SphereF {
+    let b = get_bounding_box_not_owned(brushes);
 
     SphereF {
         origin: b.center(),
@@ -1614,27 +2304,27 @@ fn empty_interior() -> Interior {
     }
 }
 
-fn empty_lightmap(r: u8, g: u8, b: u8) -> PNG {
-    let mut img = ImageBuffer::new(256, 256);
+fn empty_lightmap(size: u32, r: u8, g: u8, b: u8) -> PNG {
+    let mut img = ImageBuffer::new(size, size);
     for (_, _, pixel) in img.enumerate_pixels_mut() {
         *pixel = image::Rgb([r, g, b]);
     }
     let mut v = Vec::new();
     let png = PngEncoder::new(v.by_ref());
     let _ = png
-        .write_image(&img, 256, 256, image::ExtendedColorType::Rgb8)
+        .write_image(&img, size, size, image::ExtendedColorType::Rgb8)
         .unwrap();
 
     PNG { data: v }
 }
 
-fn _filled_lightmap(data: &[u8]) -> PNG {
-    let mut img = ImageBuffer::<Rgb<u8>, Vec<u8>>::new(256, 256);
+fn filled_lightmap(size: u32, data: &[u8]) -> PNG {
+    let mut img = ImageBuffer::<Rgb<u8>, Vec<u8>>::new(size, size);
     img.copy_from_slice(data);
     let mut v = Vec::new();
     let png = PngEncoder::new(v.by_ref());
     let _ = png
-        .write_image(&img, 256, 256, image::ExtendedColorType::Rgb8)
+        .write_image(&img, size, size, image::ExtendedColorType::Rgb8)
         .unwrap();
 
     PNG { data: v }
@@ -1675,41 +2365,97 @@ impl PlaneGrouping {
     }
 }
 
+// The points carry the epsilon of the conversion they're from, to compare without a global
 #[derive(Clone, PartialOrd)]
 pub struct OrdPoint {
     pub x: f32,
     pub y: f32,
     pub z: f32,
+    pub epsilon: f32,
 }
 
 impl OrdPoint {
-    pub fn from(p: &Point3F) -> Self {
+    pub fn from(p: &Point3F, config: &ConvertConfig) -> Self {
         OrdPoint {
             x: p.x,
             y: p.y,
             z: p.z,
+            epsilon: config.point_epsilon,
         }
     }
 }
 
 impl PartialEq for OrdPoint {
     fn eq(&self, other: &Self) -> bool {
-        self.x.abs_diff_eq(&other.x, unsafe { POINT_EPSILON })
-            && self.y.abs_diff_eq(&other.y, unsafe { POINT_EPSILON })
-            && self.z.abs_diff_eq(&other.z, unsafe { POINT_EPSILON })
+        self.x.abs_diff_eq(&other.x, self.epsilon)
+            && self.y.abs_diff_eq(&other.y, self.epsilon)
+            && self.z.abs_diff_eq(&other.z, self.epsilon)
     }
 }
 
 impl Eq for OrdPoint {}
 
-impl Hash for OrdPoint {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        let x = (self.x.floor() as u32 >> 5) & 0xf;
-        let y = (self.y.floor() as u32 >> 5) & 0xf;
-        let z = (self.z.floor() as u32 >> 5) & 0xf;
+impl OrdPoint {
+    // The cells are an epsilon wide, so the points equal to this one are at most a cell away
+    pub fn cell(&self) -> [i64; 3] {
+        let size = self.epsilon.max(f32::EPSILON) as f64;
+        [
+            (self.x as f64 / size).floor() as i64,
+            (self.y as f64 / size).floor() as i64,
+            (self.z as f64 / size).floor() as i64,
+        ]
+    }
+}
 
-        let hash_val = (x << 8) | (y << 4) | z;
-        hash_val.hash(state);
+// Dedups the points by their epsilon. Two equal points can straddle a cell boundary, so a lookup
+// searches the cell of the point first and then the cells around it.
+pub struct PointMap<T> {
+    cells: HashMap<[i64; 3], Vec<(OrdPoint, T)>>,
+    len: usize,
+}
+
+impl<T: Copy> PointMap<T> {
+    pub fn new() -> Self {
+        PointMap {
+            cells: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    pub fn get(&self, point: &OrdPoint) -> Option<T> {
+        let [x, y, z] = point.cell();
+        let own = std::iter::once([x, y, z]);
+        let neighbours = (-1..=1)
+            .flat_map(|dx| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| [dx, dy, dz])))
+            .filter(|d| *d != [0, 0, 0])
+            .map(|[dx, dy, dz]| [x + dx, y + dy, z + dz]);
+        own.chain(neighbours)
+            .filter_map(|cell| self.cells.get(&cell))
+            .flat_map(|entries| entries.iter())
+            .find(|(p, _)| p == point)
+            .map(|(_, value)| *value)
+    }
+
+    pub fn insert(&mut self, point: OrdPoint, value: T) {
+        self.cells
+            .entry(point.cell())
+            .or_default()
+            .push((point, value));
+        self.len += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<T: Copy> Default for PointMap<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -1719,15 +2465,17 @@ pub struct OrdPlaneF {
     pub y: f32,
     pub z: f32,
     pub d: f32,
+    pub epsilon: f32,
 }
 
 impl OrdPlaneF {
-    pub fn from(v: &PlaneF) -> Self {
+    pub fn from(v: &PlaneF, config: &ConvertConfig) -> Self {
         OrdPlaneF {
             x: v.normal.x,
             y: v.normal.y,
             z: v.normal.z,
             d: v.distance,
+            epsilon: config.plane_epsilon,
         }
     }
 }
@@ -1735,7 +2483,7 @@ impl OrdPlaneF {
 impl PartialEq for OrdPlaneF {
     fn eq(&self, other: &Self) -> bool {
         self.x * other.x + self.y * other.y + self.z * other.z > 0.999
-            && self.d.abs_diff_eq(&other.d, unsafe { PLANE_EPSILON })
+            && self.d.abs_diff_eq(&other.d, self.epsilon)
     }
 }
 
@@ -1837,105 +2585,804 @@ impl Hash for OrdTexGen {
     }
 }
 
-pub trait RaycastCalc {
-    fn bsp_ray_cast(
-        &self,
-        node: &BSPIndex,
-        plane_index: &u16,
-        start: Point3F,
-        end: Point3F,
-    ) -> bool;
-
-    fn calculate_bsp_raycast_coverage(&mut self) -> BSPReport;
+pub struct InteriorMetrics {
+    pub surface_area: f32,
+    pub volume: f32,
+    pub brush_count: usize,
 }
 
-impl RaycastCalc for Interior {
-    fn calculate_bsp_raycast_coverage(&mut self) -> BSPReport {
-        let mut hit = 0;
-        let mut total_surface_area = 0.0;
-        let mut hit_surface_area = 0.0;
-        self.surfaces.iter().enumerate().for_each(|(_, s)| {
-            let points = &self.indices[(*s.winding_start.inner() as usize)
-                ..((*s.winding_start.inner() + s.winding_count) as usize)]
+pub fn interior_metrics(interior: &Interior) -> InteriorMetrics {
+    let surface_area = interior
+        .surfaces
+        .iter()
+        .map(|s| winding_area(&surface_winding(interior, s)))
+        .sum();
+
+    // Each brush is exported as a convex hull, so sum up the volumes of the tetrahedrons formed
+    // by the hull surfaces and the center of the hull
+    let volume = interior
+        .convex_hulls
+        .iter()
+        .map(|hull| {
+            let hull_start = *hull.hull_start.inner() as usize;
+            let hull_points = interior.hull_indices
+                [hull_start..(hull_start + hull.hull_count as usize)]
                 .iter()
-                .map(|i| self.points[*i.inner() as usize])
+                .map(|i| interior.points[*i.inner() as usize])
                 .collect::<Vec<_>>();
-            let mut avg_point: Point3F = points.iter().sum();
-            avg_point /= s.winding_count as f32;
+            if hull_points.is_empty() {
+                return 0.0;
+            }
+            let center: Point3F = hull_points.iter().sum::<Point3F>() / hull_points.len() as f32;
+
+            let surface_start = *hull.surface_start.inner() as usize;
+            interior.hull_surface_indices
+                [surface_start..(surface_start + hull.surface_count as usize)]
+                .iter()
+                .map(|surface_index| match surface_index {
+                    PossiblyNullSurfaceIndex::NonNull(index) => {
+                        let points =
+                            surface_winding(interior, &interior.surfaces[*index.inner() as usize]);
+                        (2..points.len())
+                            .map(|i| {
+                                (points[i - 2] - center)
+                                    .dot((points[i - 1] - center).cross(points[i] - center))
+                                    .abs()
+                                    / 6.0
+                            })
+                            .sum::<f32>()
+                    }
+                    PossiblyNullSurfaceIndex::Null(_) => 0.0,
+                })
+                .sum::<f32>()
+        })
+        .sum();
+
+    InteriorMetrics {
+        surface_area,
+        volume,
+        brush_count: interior.convex_hulls.len(),
+    }
+}
 
-            let mut surface_area = 0.0;
-            for i in 0..points.len() {
-                surface_area += (points[i] - avg_point)
-                    .cross(points[(i + 1) % points.len()] - avg_point)
-                    .magnitude()
-                    / 2.0;
+// The centroid of the interior points, the principal axes of their spread from the longest to the
+// shortest, and the half extents of the box around the centroid along those axes
+pub fn interior_oriented_bounds(interior: &Interior) -> (Point3F, [Point3F; 3], Point3F) {
+    let mut axes = [Point3F::unit_x(), Point3F::unit_y(), Point3F::unit_z()];
+    if interior.points.is_empty() {
+        return (
+            Point3F::new(0.0, 0.0, 0.0),
+            axes,
+            Point3F::new(0.0, 0.0, 0.0),
+        );
+    }
+    // Accumulate in f64, the covariance of far away interiors loses too much precision otherwise
+    let point_count = interior.points.len() as f64;
+    let centroid = interior
+        .points
+        .iter()
+        .map(|p| p.map(|c| c as f64))
+        .sum::<Vector3<f64>>()
+        / point_count;
+    let mut covariance = [[0.0; 3]; 3];
+    for p in interior.points.iter() {
+        let d = p.map(|c| c as f64) - centroid;
+        for i in 0..3 {
+            for j in 0..3 {
+                covariance[i][j] += d[i] * d[j] / point_count;
             }
-            total_surface_area += surface_area;
+        }
+    }
+    let (eigenvalues, eigenvectors) = symmetric_eigen(covariance);
+    let mut order = [0, 1, 2];
+    order.sort_by(|&a, &b| eigenvalues[b].total_cmp(&eigenvalues[a]));
+    for (axis, &k) in axes.iter_mut().zip(order.iter()) {
+        *axis = Point3F::new(
+            eigenvectors[0][k] as f32,
+            eigenvectors[1][k] as f32,
+            eigenvectors[2][k] as f32,
+        )
+        .normalize();
+    }
 
-            let plane_index = *s.plane_index.inner() & 0x7FFF;
-            let norm =
-                self.normals[*self.planes[plane_index as usize].normal_index.inner() as usize];
+    let centroid = centroid.map(|c| c as f32);
+    let mut extents = Point3F::new(0.0, 0.0, 0.0);
+    for p in interior.points.iter() {
+        for i in 0..3 {
+            extents[i] = extents[i].max((p - centroid).dot(axes[i]).abs());
+        }
+    }
+    (centroid, axes, extents)
+}
 
-            let start = avg_point
-                + (norm
-                    * match s.plane_flipped {
-                        true => -1.0,
-                        false => 1.0,
-                    })
-                    * 0.1;
-            let end = avg_point
-                - (norm
-                    * match s.plane_flipped {
-                        true => -1.0,
-                        false => 1.0,
-                    })
-                    * 0.1;
-            let pidx = u16::MAX;
-            let start_node_index = BSPIndex {
-                index: 0,
-                leaf: false,
-                solid: false,
+// Jacobi eigenvalue iteration for a symmetric 3x3 matrix, returns the eigenvalues and the matrix
+// with the matching eigenvectors as its columns
+fn symmetric_eigen(mut a: [[f64; 3]; 3]) -> ([f64; 3], [[f64; 3]; 3]) {
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    for _ in 0..50 {
+        // Rotate away the largest off diagonal element
+        let (p, q) = [(0, 1), (0, 2), (1, 2)]
+            .into_iter()
+            .max_by(|&(i1, j1), &(i2, j2)| a[i1][j1].abs().total_cmp(&a[i2][j2].abs()))
+            .unwrap();
+        if a[p][q].abs() < 1e-12 {
+            break;
+        }
+        let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+        let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+        let c = 1.0 / (t * t + 1.0).sqrt();
+        let s = t * c;
+        for k in 0..3 {
+            let (akp, akq) = (a[k][p], a[k][q]);
+            a[k][p] = c * akp - s * akq;
+            a[k][q] = s * akp + c * akq;
+        }
+        for k in 0..3 {
+            let (apk, aqk) = (a[p][k], a[q][k]);
+            a[p][k] = c * apk - s * aqk;
+            a[q][k] = s * apk + c * aqk;
+        }
+        for k in 0..3 {
+            let (vkp, vkq) = (v[k][p], v[k][q]);
+            v[k][p] = c * vkp - s * vkq;
+            v[k][q] = s * vkp + c * vkq;
+        }
+    }
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
+
+// The surfaces using each material, for retexturing tools that edit the surfaces per material
+pub fn material_surface_map(interior: &Interior) -> HashMap<String, Vec<SurfaceIndex>> {
+    let mut map: HashMap<String, Vec<SurfaceIndex>> = HashMap::new();
+    interior.surfaces.iter().enumerate().for_each(|(i, s)| {
+        if let Some(material) = interior
+            .material_names
+            .get(*s.texture_index.inner() as usize)
+        {
+            map.entry(material.clone())
+                .or_default()
+                .push(SurfaceIndex::new(i as _));
+        }
+    });
+    map
+}
+
+// Removes the material names that no surface uses and renumbers the texture indices of the
+// surfaces to match, keeping the order of the used ones. Running it again changes nothing.
+pub fn compact_materials(interior: &mut Interior) {
+    let mut used = vec![false; interior.material_names.len()];
+    interior
+        .surfaces
+        .iter()
+        .for_each(|s| used[*s.texture_index.inner() as usize] = true);
+    let mut remap = vec![0; interior.material_names.len()];
+    let mut material_names = vec![];
+    for (i, name) in std::mem::take(&mut interior.material_names)
+        .into_iter()
+        .enumerate()
+    {
+        if used[i] {
+            remap[i] = material_names.len();
+            material_names.push(name);
+        }
+    }
+    interior.material_names = material_names;
+    interior.surfaces.iter_mut().for_each(|s| {
+        s.texture_index = TextureIndex::new(remap[*s.texture_index.inner() as usize] as _);
+    });
+}
+
+// Sorts the material names lexicographically and renumbers the texture indices of the surfaces to
+// match, so the material order doesn't depend on the order the surfaces were exported in
+pub fn sort_materials(interior: &mut Interior) {
+    let mut order = (0..interior.material_names.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| interior.material_names[a].cmp(&interior.material_names[b]));
+    let mut remap = vec![0; interior.material_names.len()];
+    order
+        .iter()
+        .enumerate()
+        .for_each(|(i, &old)| remap[old] = i);
+    interior.material_names = order
+        .iter()
+        .map(|&old| interior.material_names[old].clone())
+        .collect();
+    interior.surfaces.iter_mut().for_each(|s| {
+        s.texture_index = TextureIndex::new(remap[*s.texture_index.inner() as usize] as _);
+    });
+}
+
+// Stacks the lightmap atlases of the interior vertically into a single PNG, for renderers that
+// want them as one texture array. Also returns the row each atlas starts at.
+pub fn stack_lightmaps(interior: &Interior) -> (Vec<u8>, Vec<u32>) {
+    let atlases = interior
+        .light_maps
+        .iter()
+        .map(|lmap| {
+            image::load_from_memory(&lmap.light_map.data)
+                .unwrap()
+                .to_rgb8()
+        })
+        .collect::<Vec<_>>();
+    let width = atlases.iter().map(|a| a.width()).max().unwrap_or(0);
+    let height = atlases.iter().map(|a| a.height()).sum::<u32>();
+
+    let mut img = ImageBuffer::<Rgb<u8>, Vec<u8>>::new(width, height);
+    let mut row_offsets = vec![];
+    let mut row = 0;
+    for atlas in atlases.iter() {
+        row_offsets.push(row);
+        for (x, y, pixel) in atlas.enumerate_pixels() {
+            img.put_pixel(x, row + y, *pixel);
+        }
+        row += atlas.height();
+    }
+
+    let mut v = Vec::new();
+    let png = PngEncoder::new(v.by_ref());
+    let _ = png
+        .write_image(&img, width, height, image::ExtendedColorType::Rgb8)
+        .unwrap();
+
+    (v, row_offsets)
+}
+
+// Lists every point of the interior with its visibility, 0 for fully occluded up to 255 for fully
+// visible, as CSV for plotting the point visibility bake
+pub fn point_visibility_csv(interior: &Interior) -> String {
+    let mut csv = "x,y,z,visibility\n".to_string();
+    interior
+        .points
+        .iter()
+        .zip(interior.point_visibilities.iter())
+        .for_each(|(p, vis)| csv.push_str(&format!("{},{},{},{}\n", p.x, p.y, p.z, vis)));
+    csv
+}
+
+// Writes the surfaces of the interior as an OBJ and its MTL, grouped by material. The engine draws
+// a surface as a triangle strip over its whole winding, so the strip is written out triangle by
+// triangle, each turned to face the way of the surface's plane like the engine culls it.
+pub fn export_obj(interior: &Interior, mtl_file: &str) -> (String, String) {
+    let mut obj = format!("mtllib {}\n", mtl_file);
+    interior
+        .points
+        .iter()
+        .for_each(|p| obj.push_str(&format!("v {} {} {}\n", p.x, p.y, p.z)));
+    let mut material = None;
+    for s in interior.surfaces.iter() {
+        let texture_index = *s.texture_index.inner() as usize;
+        if material != Some(texture_index) {
+            material = Some(texture_index);
+            obj.push_str(&format!(
+                "usemtl {}\n",
+                interior.material_names[texture_index]
+            ));
+        }
+        let plane = &interior.planes[(*s.plane_index.inner() & 0x7FFF) as usize];
+        let mut normal = interior.normals[*plane.normal_index.inner() as usize];
+        if s.plane_flipped {
+            normal = -normal;
+        }
+        let indices = &interior.indices[(*s.winding_start.inner() as usize)
+            ..((*s.winding_start.inner() + s.winding_count) as usize)];
+        for i in 2..indices.len() {
+            let mut triangle = [indices[i - 2], indices[i - 1], indices[i]].map(|i| *i.inner());
+            let [a, b, c] = triangle.map(|i| interior.points[i as usize]);
+            let triangle_normal = (b - a).cross(c - a);
+            // The strips repeat points for the collinear corners
+            if triangle_normal.magnitude2() == 0.0 {
+                continue;
+            }
+            if triangle_normal.dot(normal) < 0.0 {
+                triangle.swap(1, 2);
+            }
+            obj.push_str(&format!(
+                "f {} {} {}\n",
+                triangle[0] + 1,
+                triangle[1] + 1,
+                triangle[2] + 1
+            ));
+        }
+    }
+    let mtl = interior
+        .material_names
+        .iter()
+        .map(|name| format!("newmtl {}\nKd 0.8 0.8 0.8\n", name))
+        .collect::<String>();
+    (obj, mtl)
+}
+
+// The lightmap layout of a surface, for baking its lighting in another tool. The rect is the x, y,
+// width and height of the surface's lightmap in its atlas in texels, the uvs are those of the
+// winding points, from 0 to 1 across the atlas.
+#[derive(Serialize)]
+pub struct SurfaceLightmapUVs {
+    pub surface: usize,
+    pub lightmap: u32,
+    pub atlas_size: [u32; 2],
+    pub rect: [u32; 4],
+    pub positions: Vec<[f32; 3]>,
+    pub uvs: Vec<[f32; 2]>,
+}
+
+// Computes the lightmap UVs of the surfaces the way the engine does, from the axes and scales
+// packed in the final word and the texgen distances of the lightmap
+pub fn export_lightmap_uvs(interior: &Interior) -> Vec<SurfaceLightmapUVs> {
+    let atlas_sizes = interior
+        .light_maps
+        .iter()
+        .map(|lmap| {
+            image::load_from_memory(&lmap.light_map.data)
+                .map(|img| [img.width(), img.height()])
+                .unwrap_or([0, 0])
+        })
+        .collect::<Vec<_>>();
+    interior
+        .surfaces
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            let final_word = s.light_map.final_word;
+            let (sc, tc) = match final_word >> 13 {
+                0 => (0, 1),
+                1 => (0, 2),
+                2 => (1, 0),
+                3 => (1, 2),
+                4 => (2, 0),
+                _ => (2, 1),
             };
+            let scale_x = 2.0f32.powi(-(((final_word >> 6) & 0b111111) as i32));
+            let scale_y = 2.0f32.powi(-((final_word & 0b111111) as i32));
+            let lightmap = interior
+                .normal_lmap_indices
+                .get(i)
+                .map_or(0, |l| *l.inner());
+            let points = interior.indices[*s.winding_start.inner() as usize
+                ..(*s.winding_start.inner() + s.winding_count) as usize]
+                .iter()
+                .map(|p| interior.points[*p.inner() as usize])
+                .collect::<Vec<_>>();
+            SurfaceLightmapUVs {
+                surface: i,
+                lightmap,
+                atlas_size: atlas_sizes
+                    .get(lightmap as usize)
+                    .copied()
+                    .unwrap_or([0, 0]),
+                rect: [s.map_offset_x, s.map_offset_y, s.map_size_x, s.map_size_y],
+                positions: points.iter().map(|p| [p.x, p.y, p.z]).collect(),
+                uvs: points
+                    .iter()
+                    .map(|p| {
+                        [
+                            p[sc] * scale_x + s.light_map.tex_gen_x_distance,
+                            p[tc] * scale_y + s.light_map.tex_gen_y_distance,
+                        ]
+                    })
+                    .collect(),
+            }
+        })
+        .collect()
+}
 
-            if self.bsp_ray_cast(&start_node_index, &pidx, start, end) {
-                hit += 1;
-                hit_surface_area += surface_area;
-            } else {
-                // println!("Miss: surface {} plane {}", i, plane_index);
-                // self.bsp_ray_cast(&start_node_index, &pidx, start, end);
+// The collision of an interior as plain convex hulls, for physics engines that don't read DIFs
+#[derive(Serialize)]
+pub struct CollisionData {
+    pub points: Vec<[f32; 3]>,
+    pub hulls: Vec<CollisionHull>,
+}
+
+// The planes are (normal, distance) facing out of the hull, the points index into the points of
+// the CollisionData
+#[derive(Serialize)]
+pub struct CollisionHull {
+    pub planes: Vec<[f32; 4]>,
+    pub point_indices: Vec<u32>,
+}
+
+pub fn export_collision_data(interior: &Interior) -> CollisionData {
+    // The high bit of a plane index means the plane is flipped
+    let plane_equation = |plane_index: u16| {
+        let plane = &interior.planes[(plane_index & 0x7FFF) as usize];
+        let mut normal = interior.normals[*plane.normal_index.inner() as usize];
+        let mut distance = plane.plane_distance;
+        if plane_index & 0x8000 != 0 {
+            normal = -normal;
+            distance = -distance;
+        }
+        [normal.x, normal.y, normal.z, distance]
+    };
+
+    let hulls = interior
+        .convex_hulls
+        .iter()
+        .map(|hull| {
+            let hull_start = *hull.hull_start.inner() as usize;
+            let point_indices = interior.hull_indices
+                [hull_start..(hull_start + hull.hull_count as usize)]
+                .iter()
+                .map(|i| *i.inner() as u32)
+                .collect::<Vec<_>>();
+
+            // The hull planes are only exported for non MB DIFs, otherwise use the planes of the
+            // hull surfaces
+            let plane_start = *hull.plane_start.inner() as usize;
+            let surface_start = *hull.surface_start.inner() as usize;
+            let planes =
+                if plane_start + hull.surface_count as usize <= interior.hull_plane_indices.len() {
+                    interior.hull_plane_indices
+                        [plane_start..(plane_start + hull.surface_count as usize)]
+                        .iter()
+                        .map(|i| plane_equation(*i.inner()))
+                        .collect::<Vec<_>>()
+                } else {
+                    interior.hull_surface_indices
+                        [surface_start..(surface_start + hull.surface_count as usize)]
+                        .iter()
+                        .filter_map(|surface_index| match surface_index {
+                            PossiblyNullSurfaceIndex::NonNull(index) => Some(plane_equation(
+                                *interior.surfaces[*index.inner() as usize]
+                                    .plane_index
+                                    .inner(),
+                            )),
+                            PossiblyNullSurfaceIndex::Null(_) => None,
+                        })
+                        .collect::<Vec<_>>()
+                };
+
+            CollisionHull {
+                planes,
+                point_indices,
             }
-        });
-        BSPReport {
-            hit,
-            balance_factor: 0,
-            total: self.surfaces.len(),
-            hit_area_percentage: (hit_surface_area / total_surface_area) * 100.0,
+        })
+        .collect::<Vec<_>>();
+
+    CollisionData {
+        points: interior.points.iter().map(|p| [p.x, p.y, p.z]).collect(),
+        hulls,
+    }
+}
+
+fn surface_winding(interior: &Interior, s: &Surface) -> Vec<Point3F> {
+    interior.indices[(*s.winding_start.inner() as usize)
+        ..((*s.winding_start.inner() + s.winding_count) as usize)]
+        .iter()
+        .map(|i| interior.points[*i.inner() as usize])
+        .collect::<Vec<_>>()
+}
+
+// A winding needs three points that aren't in a line to have an area along its plane
+fn is_degenerate_winding(points: &[Point3F], normal: Point3F, epsilon: f32) -> bool {
+    if points.len() < 3 {
+        return true;
+    }
+    let area = (2..points.len())
+        .map(|i| (points[i - 1] - points[0]).cross(points[i] - points[0]))
+        .sum::<Point3F>()
+        .dot(normal)
+        .abs()
+        / 2.0;
+    area.is_nan() || area <= epsilon
+}
+
+// A face without area has no normal to raycast along or light, and its plane is already bounded by
+// the other faces of the brush. It's dropped from the brush so the BSP and the lightmaps don't see
+// it either. Returns the ids of the dropped faces.
+fn drop_degenerate_faces(brush: &mut Brush, point_epsilon: f32) -> Vec<i32> {
+    let vertices = &brush.vertices.vertex;
+    let mut dropped = vec![];
+    brush.face.retain(|f| {
+        let winding = f
+            .indices
+            .indices
+            .iter()
+            .filter_map(|&i| vertices.get(i as usize))
+            .map(|v| v.pos)
+            .collect::<Vec<_>>();
+        if !is_degenerate_winding(&winding, f.plane.normal, point_epsilon) {
+            return true;
+        }
+        dropped.push(f.id);
+        false
+    });
+    dropped
+}
+
+// The emit string of each point of the brush's hull, `poly_planes` holds the exported plane index
+// of each of its faces
+fn hull_emit_strings(brush: &Brush, poly_planes: &[usize]) -> Vec<Vec<u8>> {
+    struct HullPoly {
+        pub points: Vec<usize>,
+        pub plane_index: usize,
+    }
+    #[derive(Hash, PartialEq, Eq, PartialOrd, Ord)]
+    struct EmitEdge {
+        pub first: usize,
+        pub last: usize,
+    }
+
+    let hull_polys = brush
+        .face
+        .iter()
+        .zip(poly_planes)
+        .map(|(face, &plane_index)| HullPoly {
+            points: face.indices.indices.iter().map(|&p| p as usize).collect(),
+            plane_index,
+        })
+        .collect::<Vec<_>>();
+
+    // Ok, now we have to construct an emit string for each vertex.  This should be fairly
+    //  straightforward, the procedure is:
+    // for each point:
+    //   - find all polys that contain that point
+    //   - find all points in those polys
+    //   - find all edges  in those polys
+    //   - enter the string
+    //  The tricky bit is that we have to set up the emit indices to be relative to the
+    //   hullindices.
+    let mut emit_strings = vec![];
+    for i in 0..brush.vertices.vertex.len() {
+        let mut emit_poly_indices = vec![];
+        // Collect emitted polys for this point
+        for (j, poly) in hull_polys.iter().enumerate() {
+            if poly.points.contains(&i) {
+                emit_poly_indices.push(j);
+            }
+        }
+        // We also have to emit any polys that share the plane, but not necessarily the
+        //  support point
+        let mut new_indices = vec![];
+        for (j, poly) in hull_polys.iter().enumerate() {
+            for &emit_poly in emit_poly_indices.iter() {
+                if emit_poly == j {
+                    continue;
+                }
+
+                if hull_polys[emit_poly].plane_index == poly.plane_index {
+                    if emit_poly_indices.contains(&j) {
+                        continue;
+                    }
+                    new_indices.push(j);
+                }
+            }
+        }
+        emit_poly_indices.extend(new_indices);
+
+        assert_ne!(emit_poly_indices.len(), 0);
+
+        // Then generate all points and edges these polys contain, sorted so the emit strings
+        // are the same every time
+        let emit_points: Vec<usize> = Vec::from_iter(
+            emit_poly_indices
+                .iter()
+                .flat_map(|&poly| hull_polys[poly].points.clone())
+                .collect::<BTreeSet<_>>()
+                .into_iter(),
+        );
+        let emit_edges: Vec<EmitEdge> = Vec::from_iter(
+            emit_poly_indices
+                .iter()
+                .flat_map(|&poly| {
+                    windows2_wrap(&hull_polys[poly].points)
+                        .into_iter()
+                        .map(|(&first, &second)| EmitEdge {
+                            first: first.min(second),
+                            last: first.max(second),
+                        })
+                })
+                .collect::<BTreeSet<_>>()
+                .into_iter(),
+        );
+
+        let mut emit_string: Vec<u8> = vec![];
+        emit_string.push(emit_points.len() as _);
+        for &point in &emit_points {
+            assert!(point < 0x100);
+            emit_string.push(point as _);
         }
+        emit_string.push(emit_edges.len() as _);
+        for edge in emit_edges {
+            assert!(edge.first < 0x100);
+            assert!(edge.last < 0x100);
+            emit_string.push(edge.first as _);
+            emit_string.push(edge.last as _);
+        }
+        emit_string.push(emit_poly_indices.len() as _);
+        for poly_index in emit_poly_indices {
+            assert!(hull_polys[poly_index].points.len() < 0x100);
+            assert!(poly_index < 0x100);
+            emit_string.push(hull_polys[poly_index].points.len() as _);
+            emit_string.push(poly_index as _);
+            for point in hull_polys[poly_index].points.iter() {
+                if let Some(point_index) = emit_points.iter().position(|pt| pt == point) {
+                    assert!(point_index < 0x100);
+                    emit_string.push(point_index as _);
+                }
+            }
+        }
+        emit_strings.push(emit_string);
     }
+    emit_strings
+}
+
+// The windings are stored as triangle strips
+fn winding_area(points: &[Point3F]) -> f32 {
+    (2..points.len())
+        .map(|i| {
+            (points[i - 1] - points[i - 2])
+                .cross(points[i] - points[i - 2])
+                .magnitude()
+                / 2.0
+        })
+        .sum()
+}
 
+pub trait RaycastCalc {
     fn bsp_ray_cast(
         &self,
         node: &BSPIndex,
         plane_index: &u16,
         start: Point3F,
         end: Point3F,
-    ) -> bool {
-        if !node.leaf {
-            use std::cmp::Ordering;
+    ) -> bool;
+
+    fn calculate_bsp_raycast_coverage(&mut self) -> BSPReport;
+
+    fn bsp_point_in_solid(&self, point: Point3F) -> bool;
+}
+
+impl RaycastCalc for Interior {
+    // Walks the BSP down to the leaf containing the point, points on a plane go to the front
+    fn bsp_point_in_solid(&self, point: Point3F) -> bool {
+        if self.bsp_nodes.is_empty() {
+            return false;
+        }
+        let root = BSPIndex {
+            index: 0,
+            leaf: false,
+            solid: false,
+        };
+        let mut node = &root;
+        while !node.leaf {
             let node_value = &self.bsp_nodes[node.index as usize];
             let node_plane_index = *node_value.plane_index.inner();
-            let plane_flipped = node_plane_index & 0x8000 > 0;
             let plane_value = &self.planes[(node_plane_index & 0x7FFF) as usize];
-            let mut plane_norm = self.normals[*plane_value.normal_index.inner() as usize];
-            if plane_flipped {
-                plane_norm = -plane_norm;
-            }
-            let mut plane_d = plane_value.plane_distance;
-            if plane_flipped {
-                plane_d = -plane_d;
+            let mut side = self.normals[*plane_value.normal_index.inner() as usize].dot(point)
+                + plane_value.plane_distance;
+            if node_plane_index & 0x8000 > 0 {
+                side = -side;
             }
+            node = if side >= 0.0 {
+                &node_value.front_index
+            } else {
+                &node_value.back_index
+            };
+        }
+        node.solid
+    }
 
-            let s_side_value = plane_norm.dot(start) + plane_d;
+    #[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+    fn calculate_bsp_raycast_coverage(&mut self) -> BSPReport {
+        let mut hit = 0;
+        let mut total_surface_area = 0.0;
+        let mut hit_surface_area = 0.0;
+        // Only the hull surfaces have collision, the patch surfaces are render only
+        let hull_surfaces = self
+            .hull_surface_indices
+            .iter()
+            .filter_map(|i| match i {
+                PossiblyNullSurfaceIndex::NonNull(index) => Some(*index.inner() as usize),
+                PossiblyNullSurfaceIndex::Null(_) => None,
+            })
+            .collect::<HashSet<_>>();
+        // The rays are cast in parallel, but the areas are summed in surface order so the report
+        // doesn't depend on how the surfaces were split between the threads
+        let interior = &*self;
+        let results = interior
+            .surfaces
+            .par_iter()
+            .enumerate()
+            .filter(|(i, _)| hull_surfaces.contains(i))
+            .map(|(_, s)| {
+                let points = surface_winding(interior, s);
+                let mut avg_point: Point3F = points.iter().sum();
+                avg_point /= s.winding_count as f32;
+
+                let surface_area = winding_area(&points);
+
+                let plane_index = *s.plane_index.inner() & 0x7FFF;
+                let norm = interior.normals
+                    [*interior.planes[plane_index as usize].normal_index.inner() as usize];
+
+                let start = avg_point
+                    + (norm
+                        * match s.plane_flipped {
+                            true => -1.0,
+                            false => 1.0,
+                        })
+                        * 0.1;
+                let end = avg_point
+                    - (norm
+                        * match s.plane_flipped {
+                            true => -1.0,
+                            false => 1.0,
+                        })
+                        * 0.1;
+                let pidx = u16::MAX;
+                let start_node_index = BSPIndex {
+                    index: 0,
+                    leaf: false,
+                    solid: false,
+                };
+
+                (
+                    surface_area,
+                    interior.bsp_ray_cast(&start_node_index, &pidx, start, end),
+                )
+            })
+            .collect::<Vec<_>>();
+        for (surface_area, surface_hit) in results {
+            total_surface_area += surface_area;
+            if surface_hit {
+                hit += 1;
+                hit_surface_area += surface_area;
+            }
+        }
+        BSPReport {
+            hit,
+            balance_factor: 0,
+            total: hull_surfaces.len(),
+            hit_area_percentage: (hit_surface_area / total_surface_area) * 100.0,
+            passed: true,
+        }
+    }
+
+    // Walks the BSP with a stack instead of recursing so deep trees can't overflow the call stack.
+    // The second half of a split segment is pushed first, so the nodes are visited in the same
+    // order as a recursive walk and the first hit ends the walk.
+    fn bsp_ray_cast(
+        &self,
+        node: &BSPIndex,
+        plane_index: &u16,
+        start: Point3F,
+        end: Point3F,
+    ) -> bool {
+        use std::cmp::Ordering;
+        let mut stack = vec![(node, *plane_index, start, end)];
+        while let Some((node, plane_index, start, end)) = stack.pop() {
+            if node.leaf {
+                if !node.solid {
+                    continue;
+                }
+                let leaf = &self.bsp_solid_leaves[node.index as usize];
+                let surfaces = &self.solid_leaf_surfaces[(*leaf.surface_index.inner() as usize)
+                    ..((*leaf.surface_index.inner() + leaf.surface_count as u32) as usize)];
+                let found = surfaces.iter().any(|s| match s {
+                    PossiblyNullSurfaceIndex::NonNull(s_index) => {
+                        let surf = &self.surfaces[*s_index.inner() as usize];
+                        *surf.plane_index.inner() & 0x7FFF == plane_index & 0x7FFF
+                    }
+                    _ => false,
+                });
+                if found {
+                    return true;
+                }
+                continue;
+            }
+
+            let node_value = &self.bsp_nodes[node.index as usize];
+            let node_plane_index = *node_value.plane_index.inner();
+            let plane_flipped = node_plane_index & 0x8000 > 0;
+            let plane_value = &self.planes[(node_plane_index & 0x7FFF) as usize];
+            let mut plane_norm = self.normals[*plane_value.normal_index.inner() as usize];
+            if plane_flipped {
+                plane_norm = -plane_norm;
+            }
+            let mut plane_d = plane_value.plane_distance;
+            if plane_flipped {
+                plane_d = -plane_d;
+            }
+
+            let s_side_value = plane_norm.dot(start) + plane_d;
             let e_side_value = plane_norm.dot(end) + plane_d;
             let s_side = s_side_value.total_cmp(&0.0);
             let e_side = e_side_value.total_cmp(&0.0);
@@ -1944,74 +3391,1194 @@ impl RaycastCalc for Interior {
                 (Ordering::Greater, Ordering::Greater)
                 | (Ordering::Greater, Ordering::Equal)
                 | (Ordering::Equal, Ordering::Greater) => {
-                    self.bsp_ray_cast(&node_value.front_index, &plane_index, start, end)
+                    stack.push((&node_value.front_index, plane_index, start, end));
                 }
                 (Ordering::Greater, Ordering::Less) => {
                     let intersect_t =
                         (-plane_d - start.dot(plane_norm)) / (end - start).dot(plane_norm);
                     let ip = start + (end - start) * intersect_t;
-                    if self.bsp_ray_cast(&node_value.front_index, &plane_index, start, ip) {
-                        return true;
-                    }
-                    self.bsp_ray_cast(
-                        &node_value.back_index,
-                        node_value.plane_index.inner(),
-                        ip,
-                        end,
-                    )
+                    stack.push((&node_value.back_index, node_plane_index, ip, end));
+                    stack.push((&node_value.front_index, plane_index, start, ip));
                 }
                 (Ordering::Less, Ordering::Greater) => {
                     let intersect_t =
                         (-plane_d - start.dot(plane_norm)) / (end - start).dot(plane_norm);
                     let ip = start + (end - start) * intersect_t;
-                    if self.bsp_ray_cast(&node_value.back_index, &plane_index, start, ip) {
-                        return true;
-                    }
-                    self.bsp_ray_cast(
-                        &node_value.front_index,
-                        node_value.plane_index.inner(),
-                        ip,
-                        end,
-                    )
+                    stack.push((&node_value.front_index, node_plane_index, ip, end));
+                    stack.push((&node_value.back_index, plane_index, start, ip));
                 }
                 (Ordering::Less, Ordering::Less)
                 | (Ordering::Less, Ordering::Equal)
                 | (Ordering::Equal, Ordering::Less) => {
-                    self.bsp_ray_cast(&node_value.back_index, &plane_index, start, end)
+                    stack.push((&node_value.back_index, plane_index, start, end));
                 }
                 (Ordering::Equal, Ordering::Equal) => {
-                    if self.bsp_ray_cast(&node_value.front_index, &plane_index, start, end) {
-                        return true;
-                    }
-                    if self.bsp_ray_cast(&node_value.back_index, &plane_index, start, end) {
-                        return true;
-                    }
-                    false
+                    stack.push((&node_value.back_index, plane_index, start, end));
+                    stack.push((&node_value.front_index, plane_index, start, end));
                 }
             }
-        } else if node.solid {
-            let leaf = &self.bsp_solid_leaves[node.index as usize];
-            let surfaces = &self.solid_leaf_surfaces[(*leaf.surface_index.inner() as usize)
-                ..((*leaf.surface_index.inner() + leaf.surface_count as u32) as usize)];
-            let mut found = 0;
-            surfaces.iter().for_each(|s| {
-                match s {
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csx::{preprocess_csx, try_parse_scene};
+
+    const WELDED: &str = include_str!("../snapshots/welded.csx");
+    const MATERIALS: &str = include_str!("../snapshots/materials.csx");
+    const SIMPLE: &str = include_str!("../snapshots/simple.csx");
+    const CLIPPED: &str = include_str!("../snapshots/clipped.csx");
+    const HARD_SHADOWS: &str = include_str!("../snapshots/hard_shadows.csx");
+
+    // Builds the first detail level of a scene into a single interior
+    fn build_interior(csx: &str, config: &ConvertConfig) -> Interior {
+        let mut scene = try_parse_scene(csx.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let mut builder = DIFBuilder::new(config);
+        for b in scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush
+            .iter()
+        {
+            builder.add_brush(b);
+        }
+        builder.build(&mut NoopListener).unwrap().0
+    }
+
+    // The point indices of the surfaces whose plane sits at x = 4
+    fn surfaces_at_x4(interior: &Interior) -> Vec<(&Surface, BTreeSet<u32>)> {
+        interior
+            .surfaces
+            .iter()
+            .filter(|s| {
+                let plane = &interior.planes[(*s.plane_index.inner() & 0x7fff) as usize];
+                let normal = interior.normals[*plane.normal_index.inner() as usize];
+                normal.x.abs() > 0.99 && (plane.plane_distance.abs() - 4.0).abs() < 1e-2
+            })
+            .map(|s| {
+                let start = *s.winding_start.inner() as usize;
+                let points = interior.indices[start..start + s.winding_count as usize]
+                    .iter()
+                    .map(|i| *i.inner())
+                    .collect::<BTreeSet<_>>();
+                (s, points)
+            })
+            .collect()
+    }
+
+    // Builds the interior on a single thread, so nothing can depend on the order the rayon
+    // workers finish in
+    fn build_interior_sequential(csx: &str, config: &ConvertConfig) -> Interior {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(|| build_interior(csx, config))
+    }
+
+    #[test]
+    fn sorted_materials_are_the_same_in_parallel_and_sequentially() {
+        let config = ConvertConfig {
+            sort_materials: true,
+            ..ConvertConfig::DEFAULT
+        };
+        let parallel = build_interior(MATERIALS, &config);
+        let sequential = build_interior_sequential(MATERIALS, &config);
+        assert_eq!(parallel.material_names, vec!["brick", "grass", "stone"]);
+        assert_eq!(parallel.material_names, sequential.material_names);
+        let texture_indices = |interior: &Interior| {
+            interior
+                .surfaces
+                .iter()
+                .map(|s| *s.texture_index.inner())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(texture_indices(&parallel), texture_indices(&sequential));
+    }
+
+    // The recursive ray cast bsp_ray_cast replaced, kept as the reference for its results
+    fn recursive_ray_cast(
+        interior: &Interior,
+        node: &BSPIndex,
+        plane_index: u16,
+        start: Point3F,
+        end: Point3F,
+    ) -> bool {
+        use std::cmp::Ordering;
+        if node.leaf {
+            if !node.solid {
+                return false;
+            }
+            let leaf = &interior.bsp_solid_leaves[node.index as usize];
+            let start_index = *leaf.surface_index.inner() as usize;
+            return interior.solid_leaf_surfaces
+                [start_index..start_index + leaf.surface_count as usize]
+                .iter()
+                .any(|s| match s {
                     PossiblyNullSurfaceIndex::NonNull(s_index) => {
-                        let surf = &self.surfaces[*s_index.inner() as usize];
-                        let surf_plane_index = *surf.plane_index.inner();
-                        if surf_plane_index & 0x7FFF == *plane_index & 0x7FFF {
-                            found += 1;
-                        }
+                        let surf = &interior.surfaces[*s_index.inner() as usize];
+                        *surf.plane_index.inner() & 0x7FFF == plane_index & 0x7FFF
                     }
-                    _ => {}
-                };
-            });
-            if found == 0 {
-                return false;
+                    _ => false,
+                });
+        }
+        let node_value = &interior.bsp_nodes[node.index as usize];
+        let node_plane_index = *node_value.plane_index.inner();
+        let plane_value = &interior.planes[(node_plane_index & 0x7FFF) as usize];
+        let mut plane_norm = interior.normals[*plane_value.normal_index.inner() as usize];
+        let mut plane_d = plane_value.plane_distance;
+        if node_plane_index & 0x8000 > 0 {
+            plane_norm = -plane_norm;
+            plane_d = -plane_d;
+        }
+        let s_side = (plane_norm.dot(start) + plane_d).total_cmp(&0.0);
+        let e_side = (plane_norm.dot(end) + plane_d).total_cmp(&0.0);
+        let (front, back) = (&node_value.front_index, &node_value.back_index);
+        let intersect = || {
+            let t = (-plane_d - start.dot(plane_norm)) / (end - start).dot(plane_norm);
+            start + (end - start) * t
+        };
+        match (s_side, e_side) {
+            (Ordering::Greater, Ordering::Greater)
+            | (Ordering::Greater, Ordering::Equal)
+            | (Ordering::Equal, Ordering::Greater) => {
+                recursive_ray_cast(interior, front, plane_index, start, end)
+            }
+            (Ordering::Greater, Ordering::Less) => {
+                let ip = intersect();
+                recursive_ray_cast(interior, front, plane_index, start, ip)
+                    || recursive_ray_cast(interior, back, node_plane_index, ip, end)
+            }
+            (Ordering::Less, Ordering::Greater) => {
+                let ip = intersect();
+                recursive_ray_cast(interior, back, plane_index, start, ip)
+                    || recursive_ray_cast(interior, front, node_plane_index, ip, end)
+            }
+            (Ordering::Less, Ordering::Less)
+            | (Ordering::Less, Ordering::Equal)
+            | (Ordering::Equal, Ordering::Less) => {
+                recursive_ray_cast(interior, back, plane_index, start, end)
+            }
+            (Ordering::Equal, Ordering::Equal) => {
+                recursive_ray_cast(interior, front, plane_index, start, end)
+                    || recursive_ray_cast(interior, back, plane_index, start, end)
             }
-            return true;
-        } else {
-            return false;
         }
     }
+
+    #[test]
+    fn iterative_ray_cast_matches_the_recursive_one() {
+        use rand::{Rng, SeedableRng};
+        let interior = build_interior(SIMPLE, &ConvertConfig::DEFAULT);
+        let root = BSPIndex {
+            index: 0,
+            leaf: false,
+            solid: false,
+        };
+        let bounds = &interior.bounding_box;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut random_point = || {
+            Point3F::new(
+                rng.gen_range(bounds.min.x - 2.0..bounds.max.x + 2.0),
+                rng.gen_range(bounds.min.y - 2.0..bounds.max.y + 2.0),
+                rng.gen_range(bounds.min.z - 2.0..bounds.max.z + 2.0),
+            )
+        };
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let (start, end) = (random_point(), random_point());
+            let hit = interior.bsp_ray_cast(&root, &u16::MAX, start, end);
+            assert_eq!(
+                hit,
+                recursive_ray_cast(&interior, &root, u16::MAX, start, end),
+                "ray from {:?} to {:?}",
+                start,
+                end
+            );
+            hits += hit as usize;
+        }
+        // Both the hits and the misses are compared
+        assert!(hits > 0 && hits < 2000);
+    }
+
+    #[test]
+    fn coverage_is_the_same_on_one_thread_and_in_parallel() {
+        let mut interior = build_interior(CLIPPED, &ConvertConfig::DEFAULT);
+        let parallel = interior.calculate_bsp_raycast_coverage();
+        let sequential = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(|| interior.calculate_bsp_raycast_coverage());
+        assert!(parallel.total > 0);
+        assert_eq!(parallel.hit, sequential.hit);
+        assert_eq!(parallel.total, sequential.total);
+        assert_eq!(
+            parallel.hit_area_percentage.to_bits(),
+            sequential.hit_area_percentage.to_bits()
+        );
+    }
+
+    #[test]
+    fn lightmaps_pack_into_larger_atlases() {
+        // Two boxes of 150 lumels a side, only one of their faces fits a 256 atlas but nine fit
+        // a 512 one
+        let boxes = WELDED.replace(
+            "transform=\"1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1\"",
+            "transform=\"300 0 0 0 0 300 0 0 0 0 300 0 0 0 0 1\"",
+        );
+        let lightmaps = |lightmap_size| {
+            let config = ConvertConfig {
+                mb_only: false,
+                bake_lighting: false,
+                lightmap_size,
+                ..ConvertConfig::DEFAULT
+            };
+            build_interior(&boxes, &config)
+        };
+        let small = lightmaps(256);
+        let large = lightmaps(512);
+        assert_eq!(small.light_maps.len(), 12);
+        assert_eq!(large.light_maps.len(), 2);
+        for light_map in large.light_maps.iter() {
+            let image = image::load_from_memory(&light_map.light_map.data).unwrap();
+            assert_eq!((image.width(), image.height()), (512, 512));
+        }
+        assert!(large
+            .surfaces
+            .iter()
+            .all(|s| s.map_offset_x + s.map_size_x <= 512 && s.map_offset_y + s.map_size_y <= 512));
+        assert!(large.surfaces.iter().any(|s| s.map_offset_x >= 256));
+    }
+
+    #[test]
+    fn disabled_coord_bins_are_empty_and_read_back() {
+        let config = ConvertConfig {
+            generate_coordbins: false,
+            ..ConvertConfig::DEFAULT
+        };
+        let (bufs, ..) = crate::convert_csx_to_dif_with_config(
+            SIMPLE.to_string(),
+            EngineVersion::MBG,
+            0,
+            &config,
+            &mut NoopListener,
+        )
+        .unwrap();
+        let (dif, _) = dif::dif::Dif::from_bytes(&bufs[0]).unwrap();
+        let interior = &dif.interiors[0];
+        assert_eq!(interior.coord_bins.len(), 256);
+        assert!(interior.coord_bins.iter().all(|bin| bin.bin_count == 0));
+        assert!(interior.coord_bin_indices.is_empty());
+        assert!(!interior.convex_hulls.is_empty());
+    }
+
+    #[test]
+    fn unit_cube_metrics() {
+        // The first box of the welded scene on its own, scaled down from 4 units to 1
+        let second_brush = WELDED.find("<Brush id=\"1\"").unwrap();
+        let end = WELDED.rfind("</Brush>").unwrap() + "</Brush>".len();
+        let cube = format!("{}{}", &WELDED[..second_brush], &WELDED[end..]).replace(
+            "transform=\"1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1\"",
+            "transform=\"0.25 0 0 0 0 0.25 0 0 0 0 0.25 0 0 0 0 1\"",
+        );
+        let metrics = interior_metrics(&build_interior(&cube, &ConvertConfig::DEFAULT));
+        assert_eq!(metrics.brush_count, 1);
+        assert!((metrics.surface_area - 6.0).abs() < 1e-4);
+        assert!((metrics.volume - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn occluded_points_are_less_visible() {
+        let config = ConvertConfig {
+            compute_point_visibility: true,
+            ..ConvertConfig::DEFAULT
+        };
+        let interior = build_interior(HARD_SHADOWS, &config);
+        let visibility = |x, y, z| {
+            let point = Point3F::new(x, y, z);
+            let index = interior
+                .points
+                .iter()
+                .position(|p| p.abs_diff_eq(&point, 1e-4))
+                .unwrap();
+            interior.point_visibilities[index]
+        };
+        // The corner of the floor sees most of the sky, the bottom corner of the floating box
+        // has the floor right under it
+        let exposed = visibility(0.0, 0.0, 1.0);
+        let enclosed = visibility(6.0, 6.0, 4.0);
+        assert!(enclosed < exposed, "{} < {}", enclosed, exposed);
+    }
+
+    #[test]
+    fn welded_walls_share_points_and_plane() {
+        let config = ConvertConfig {
+            weld_tolerance: 1e-3,
+            ..ConvertConfig::DEFAULT
+        };
+        let interior = build_interior(WELDED, &config);
+        let walls = surfaces_at_x4(&interior);
+        assert_eq!(walls.len(), 2);
+        let ((a, a_points), (b, b_points)) = (&walls[0], &walls[1]);
+        assert_eq!(a_points, b_points);
+        assert_eq!(
+            *a.plane_index.inner() & 0x7fff,
+            *b.plane_index.inner() & 0x7fff
+        );
+        assert_ne!(a.plane_flipped, b.plane_flipped);
+        assert_eq!(interior.points.len(), 12);
+
+        // Without the weld the walls stay a hair apart
+        let interior = build_interior(WELDED, &ConvertConfig::DEFAULT);
+        let walls = surfaces_at_x4(&interior);
+        assert_eq!(walls.len(), 2);
+        assert!(walls[0].1.is_disjoint(&walls[1].1));
+    }
+
+    #[test]
+    fn winding_corners_are_classified_against_the_winding() {
+        // An L shape in the XY plane with a collinear corner along its bottom edge
+        let points = [
+            Point3F::new(0.0, 0.0, 0.0),
+            Point3F::new(1.0, 0.0, 0.0),
+            Point3F::new(2.0, 0.0, 0.0),
+            Point3F::new(2.0, 1.0, 0.0),
+            Point3F::new(1.0, 1.0, 0.0),
+            Point3F::new(1.0, 2.0, 0.0),
+            Point3F::new(0.0, 2.0, 0.0),
+        ];
+        let corners = get_winding_corners(&points);
+        assert_eq!(
+            corners,
+            vec![
+                WindingCorner::Convex,
+                WindingCorner::Collinear,
+                WindingCorner::Convex,
+                WindingCorner::Convex,
+                WindingCorner::Reflex,
+                WindingCorner::Convex,
+                WindingCorner::Convex,
+            ]
+        );
+        // Reversing the winding flips its normal, so the corners keep their kinds
+        let reversed = points.iter().rev().copied().collect::<Vec<_>>();
+        let mut reversed_corners = get_winding_corners(&reversed);
+        reversed_corners.reverse();
+        assert_eq!(reversed_corners, corners);
+    }
+
+    #[test]
+    fn fan_skips_a_collinear_first_corner() {
+        // A square with a collinear corner halfway along its bottom edge, first in the winding
+        let points = [
+            Point3F::new(1.0, 0.0, 0.0),
+            Point3F::new(2.0, 0.0, 0.0),
+            Point3F::new(2.0, 2.0, 0.0),
+            Point3F::new(0.0, 2.0, 0.0),
+            Point3F::new(0.0, 0.0, 0.0),
+        ];
+        let corners = get_winding_corners(&points);
+        let (winding, fan_mask) = get_fan_winding(&corners, false);
+        assert_ne!(winding[0], 0);
+        let fan = winding
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| fan_mask & (1 << i) != 0)
+            .map(|(_, &corner)| points[corner])
+            .collect::<Vec<_>>();
+        assert_eq!(fan.len(), 4);
+        // The triangles of the strip cover the square without folding over or overlapping
+        let areas = fan
+            .windows(3)
+            .map(|t| (t[1] - t[0]).cross(t[2] - t[0]).magnitude() / 2.0)
+            .collect::<Vec<_>>();
+        assert!(areas.iter().all(|&area| area > 0.0));
+        assert_eq!(areas.iter().sum::<f32>(), 4.0);
+
+        let (_, full_fan_mask) = get_fan_winding(&corners, true);
+        assert_eq!(full_fan_mask, 0b11111);
+    }
+
+    #[test]
+    fn alarm_lightmaps_follow_the_normal_ones() {
+        let mut scene = try_parse_scene(WELDED.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let mut builder = DIFBuilder::new(&ConvertConfig {
+            mb_only: false,
+            ..ConvertConfig::DEFAULT
+        });
+        for b in scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush
+            .iter()
+        {
+            builder.add_brush(b);
+        }
+        builder.set_ambient(Point3F::new(20.0, 20.0, 20.0), Point3F::new(80.0, 0.0, 0.0));
+        let interior = builder.build(&mut NoopListener).unwrap().0;
+
+        assert_eq!(interior.has_alarm_state, 1);
+        let surfaces = interior.surfaces.len();
+        assert_eq!(interior.alarm_lmap_indices.len(), surfaces);
+        // The alarm atlases come after the normal ones with the same layout, but lit red
+        let lightmaps = interior.light_maps.len() as u32;
+        assert_eq!(lightmaps % 2, 0);
+        for i in 0..surfaces {
+            let normal = interior.normal_lmap_indices[i].into_inner();
+            let alarm = interior.alarm_lmap_indices[i].into_inner();
+            assert!(alarm < lightmaps);
+            assert_ne!(alarm, normal);
+            assert_eq!(alarm, normal + lightmaps / 2);
+        }
+        let pixel = |i: u32| {
+            let png = &interior.light_maps[i as usize].light_map;
+            let image = image::load_from_memory(&png.data).unwrap().to_rgb8();
+            let (x, y) = (
+                interior.surfaces[0].map_offset_x as u32,
+                interior.surfaces[0].map_offset_y as u32,
+            );
+            *image.get_pixel(x, y)
+        };
+        let normal = pixel(interior.normal_lmap_indices[0].into_inner());
+        let alarm = pixel(interior.alarm_lmap_indices[0].into_inner());
+        assert!(alarm[0] > normal[0]);
+        assert!(alarm[1] < normal[1]);
+    }
+
+    #[test]
+    fn unlit_materials_share_a_fully_bright_lightmap() {
+        let interior = build_interior(
+            MATERIALS,
+            &ConvertConfig {
+                mb_only: false,
+                unlit_materials: vec!["Grass".to_owned()],
+                ..ConvertConfig::DEFAULT
+            },
+        );
+        let shared = interior.light_maps.len() as u32 - 1;
+        let is_grass = |i: usize| {
+            interior.material_names[*interior.surfaces[i].texture_index.inner() as usize] == "grass"
+        };
+        let mut unlit = 0;
+        for i in 0..interior.surfaces.len() {
+            let lmap_index = interior.normal_lmap_indices[i].into_inner();
+            if is_grass(i) {
+                // Not packed, every unlit surface samples the same lightmap
+                assert_eq!(lmap_index, shared);
+                unlit += 1;
+            } else {
+                assert!(lmap_index < shared);
+            }
+        }
+        assert!(unlit > 0);
+        let image = image::load_from_memory(&interior.light_maps[shared as usize].light_map.data)
+            .unwrap()
+            .to_rgb8();
+        assert!(image.pixels().all(|p| p.0 == [255, 255, 255]));
+    }
+
+    #[test]
+    fn tall_lightmaps_are_laid_on_their_side() {
+        // Two pillars ten times taller than they're wide
+        let pillars = WELDED.replace(
+            "transform=\"1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1\"",
+            "transform=\"8 0 0 0 0 8 0 0 0 0 80 0 0 0 0 1\"",
+        );
+        let lightmaps = |rotate_lightmaps| {
+            let config = ConvertConfig {
+                mb_only: false,
+                bake_lighting: false,
+                rotate_lightmaps,
+                ..ConvertConfig::DEFAULT
+            };
+            build_interior(&pillars, &config)
+        };
+        let upright = lightmaps(false);
+        let rotated = lightmaps(true);
+        assert!(upright.surfaces.iter().any(|s| s.map_size_y > s.map_size_x));
+        assert!(rotated
+            .surfaces
+            .iter()
+            .all(|s| s.map_size_x >= s.map_size_y));
+
+        // The s and t axes of the turned surfaces are swapped, their points still land inside
+        // their rects
+        let axes = |final_word: u16| match final_word >> 13 {
+            0 => (0, 1),
+            1 => (0, 2),
+            2 => (1, 0),
+            3 => (1, 2),
+            4 => (2, 0),
+            5 => (2, 1),
+            _ => unreachable!(),
+        };
+        let mut turned = 0;
+        for (before, after) in upright.surfaces.iter().zip(rotated.surfaces.iter()) {
+            let (s, t) = axes(after.light_map.final_word);
+            if before.map_size_y > before.map_size_x {
+                assert_eq!(axes(before.light_map.final_word), (t, s));
+                assert_eq!(
+                    (after.map_size_x, after.map_size_y),
+                    (before.map_size_y, before.map_size_x)
+                );
+                turned += 1;
+            }
+            let scale = 1 << ((after.light_map.final_word >> 6) & 0b111111);
+            for k in after.winding_start.into_inner()
+                ..after.winding_start.into_inner() + after.winding_count
+            {
+                let point = rotated.points[rotated.indices[k as usize].into_inner() as usize];
+                let u = (point[s] / scale as f32 + after.light_map.tex_gen_x_distance) * 256.0;
+                let v = (point[t] / scale as f32 + after.light_map.tex_gen_y_distance) * 256.0;
+                assert!(u >= after.map_offset_x as f32 - 1e-3);
+                assert!(u <= (after.map_offset_x + after.map_size_x) as f32 + 1e-3);
+                assert!(v >= after.map_offset_y as f32 - 1e-3);
+                assert!(v <= (after.map_offset_y + after.map_size_y) as f32 + 1e-3);
+            }
+        }
+        assert!(turned > 0);
+    }
+
+    #[test]
+    fn lightmap_rects_are_padded_with_their_edges() {
+        let gutter = 2;
+        let config = ConvertConfig {
+            mb_only: false,
+            lightmap_gutter: gutter,
+            lightmap_blur: crate::lightmap::BlurKind::None,
+            ..ConvertConfig::DEFAULT
+        };
+        let mut scene = try_parse_scene(HARD_SHADOWS.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let mut builder = DIFBuilder::new(&config);
+        for b in scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush
+            .iter()
+        {
+            builder.add_brush(b);
+        }
+        builder.set_lights(vec![Light::Omni {
+            position: Point3F::new(8.0, 8.0, 12.0),
+            color: ColorI {
+                r: 255,
+                g: 255,
+                b: 255,
+                a: 255,
+            },
+            falloff1: 1.0,
+            falloff2: 60.0,
+        }]);
+        let interior = builder.build(&mut NoopListener).unwrap().0;
+
+        // The rects grown by their gutter never overlap
+        let padded = |i: usize| {
+            let s = &interior.surfaces[i];
+            (
+                interior.normal_lmap_indices[i].into_inner(),
+                s.map_offset_x as u32 - gutter,
+                s.map_offset_y as u32 - gutter,
+                s.map_offset_x as u32 + s.map_size_x as u32 + gutter,
+                s.map_offset_y as u32 + s.map_size_y as u32 + gutter,
+            )
+        };
+        for i in 0..interior.surfaces.len() {
+            for j in i + 1..interior.surfaces.len() {
+                let (a, b) = (padded(i), padded(j));
+                assert!(
+                    a.0 != b.0 || a.3 <= b.1 || b.3 <= a.1 || a.4 <= b.2 || b.4 <= a.2,
+                    "surfaces {} and {} overlap",
+                    i,
+                    j
+                );
+            }
+        }
+
+        // The gutter pixels repeat the closest pixel of the surface
+        let images = interior
+            .light_maps
+            .iter()
+            .map(|l| {
+                image::load_from_memory(&l.light_map.data)
+                    .unwrap()
+                    .to_rgb8()
+            })
+            .collect::<Vec<_>>();
+        for i in 0..interior.surfaces.len() {
+            let (lmap, start_x, start_y, end_x, end_y) = padded(i);
+            let s = &interior.surfaces[i];
+            let image = &images[lmap as usize];
+            for y in start_y..end_y {
+                for x in start_x..end_x {
+                    let edge_x = x.clamp(
+                        s.map_offset_x as u32,
+                        (s.map_offset_x + s.map_size_x) as u32 - 1,
+                    );
+                    let edge_y = y.clamp(
+                        s.map_offset_y as u32,
+                        (s.map_offset_y + s.map_size_y) as u32 - 1,
+                    );
+                    assert_eq!(image.get_pixel(x, y), image.get_pixel(edge_x, edge_y));
+                }
+            }
+        }
+        assert!(images
+            .iter()
+            .any(|image| image.pixels().any(|p| p.0 != [0, 0, 0])));
+    }
+
+    #[test]
+    fn lightmaps_that_overflow_the_estimate_get_more_atlases() {
+        // Faces of 150 lumels a side, by area two fit an atlas but only one does by size
+        let boxes = WELDED.replace(
+            "transform=\"1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1\"",
+            "transform=\"300 0 0 0 0 300 0 0 0 0 300 0 0 0 0 1\"",
+        );
+        let config = ConvertConfig {
+            mb_only: false,
+            bake_lighting: false,
+            ..ConvertConfig::DEFAULT
+        };
+        let interior = build_interior(&boxes, &config);
+        let area = interior
+            .surfaces
+            .iter()
+            .map(|s| (s.map_size_x as u32 + 2) * (s.map_size_y as u32 + 2))
+            .sum::<u32>();
+        assert!(area.div_ceil(256 * 256) < interior.light_maps.len() as u32);
+        assert_eq!(interior.light_maps.len(), 12);
+        for (i, s) in interior.surfaces.iter().enumerate() {
+            assert!((interior.normal_lmap_indices[i].into_inner() as usize) < 12);
+            assert!(s.map_offset_x as u32 + s.map_size_x as u32 <= 256);
+            assert!(s.map_offset_y as u32 + s.map_size_y as u32 <= 256);
+        }
+    }
+
+    #[test]
+    fn lightmaps_larger_than_an_atlas_are_an_error() {
+        let boxes = WELDED.replace(
+            "transform=\"1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1\"",
+            "transform=\"600 0 0 0 0 600 0 0 0 0 600 0 0 0 0 1\"",
+        );
+        let config = ConvertConfig {
+            mb_only: false,
+            bake_lighting: false,
+            ..ConvertConfig::DEFAULT
+        };
+        let mut scene = try_parse_scene(boxes.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let mut builder = DIFBuilder::new(&config);
+        for b in scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush
+            .iter()
+        {
+            builder.add_brush(b);
+        }
+        match builder.build(&mut NoopListener) {
+            Err(ConversionError::LightmapTooLarge {
+                brush,
+                width,
+                height,
+                lightmap_size,
+                ..
+            }) => {
+                assert_eq!(brush, 0);
+                assert!(width > 256 && height > 256);
+                assert_eq!(lightmap_size, 256);
+            }
+            _ => panic!("expected a LightmapTooLarge error"),
+        }
+    }
+
+    #[test]
+    fn material_surface_map_follows_the_brushes() {
+        // The grass box spans x 0 to 4 and the stone box x 4 to 8
+        let interior = build_interior(WELDED, &ConvertConfig::DEFAULT);
+        let map = material_surface_map(&interior);
+        assert_eq!(
+            map.keys().cloned().collect::<BTreeSet<_>>(),
+            BTreeSet::from(["grass".to_string(), "stone".to_string()])
+        );
+        let surface_xs = |material: &str| {
+            map[material]
+                .iter()
+                .flat_map(|&i| {
+                    let s = &interior.surfaces[i.into_inner() as usize];
+                    (s.winding_start.into_inner()..s.winding_start.into_inner() + s.winding_count)
+                        .map(|k| {
+                            interior.points[interior.indices[k as usize].into_inner() as usize].x
+                        })
+                })
+                .collect::<Vec<_>>()
+        };
+        assert!(surface_xs("grass").iter().all(|&x| x <= 4.0));
+        assert!(surface_xs("stone").iter().all(|&x| x >= 4.0));
+        let mut all = map
+            .values()
+            .flatten()
+            .map(|i| i.into_inner() as usize)
+            .collect::<Vec<_>>();
+        all.sort();
+        assert_eq!(all, (0..interior.surfaces.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn exported_bsp_nodes_keep_their_planes_and_children() {
+        // A thin floor with a box standing on one corner of it
+        let mut scene = try_parse_scene(HARD_SHADOWS.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let config = ConvertConfig::DEFAULT;
+        let mut builder = DIFBuilder::new(&config);
+        for b in scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush
+            .iter()
+        {
+            builder.add_brush(b);
+        }
+        let brushes = builder.brushes.clone();
+        let mut cache = BSPCache::new();
+        let (interior, _) = builder
+            .build_with_cache(&mut NoopListener, &mut cache)
+            .unwrap();
+        // The cached tree is the one the interior was exported from
+        let (bsp_root, plane_remap) =
+            build_bsp_cached(&brushes, &config, &mut NoopListener, &mut cache);
+        assert_eq!(cache.hits, 1);
+
+        let mut checker = DIFBuilder::new(&config);
+        checker.interior = interior;
+        let root_index = BSPIndex {
+            index: 0,
+            leaf: false,
+            solid: false,
+        };
+        assert!(checker.bsp_export_matches(&bsp_root, &root_index, &plane_remap));
+        assert!(checker.interior.bsp_nodes.len() > 1);
+
+        // Swapping the children of the root makes the exported tree inside out
+        let root = &mut checker.interior.bsp_nodes[0];
+        std::mem::swap(&mut root.front_index, &mut root.back_index);
+        assert!(!checker.bsp_export_matches(&bsp_root, &root_index, &plane_remap));
+    }
+
+    #[test]
+    fn sub_texel_surfaces_pack_and_bake() {
+        // Flattened to a hundredth of a unit, the sides are far thinner than a lumel
+        let slabs = WELDED.replace(
+            "transform=\"1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1\"",
+            "transform=\"1 0 0 0 0 1 0 0 0 0 0.01 0 0 0 0 1\"",
+        );
+        let config = ConvertConfig {
+            mb_only: false,
+            ..ConvertConfig::DEFAULT
+        };
+        let interior = build_interior(&slabs, &config);
+        assert!(!interior.light_maps.is_empty());
+        // The sides only get the texels the rects are padded with
+        assert!(interior
+            .surfaces
+            .iter()
+            .any(|s| s.map_size_x.min(s.map_size_y) == 2));
+        assert!(interior
+            .surfaces
+            .iter()
+            .all(|s| s.map_size_x >= 1 && s.map_size_y >= 1));
+    }
+
+    #[test]
+    fn recording_listener_keeps_the_stages_in_order() {
+        let mut scene = try_parse_scene(SIMPLE.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let mut builder = DIFBuilder::new(&ConvertConfig::DEFAULT);
+        for b in scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush
+            .iter()
+        {
+            builder.add_brush(b);
+        }
+        let mut listener = RecordingListener::new();
+        builder.build(&mut listener).unwrap();
+        let mut stages = listener
+            .events
+            .iter()
+            .map(|(_, _, status, _)| status.as_str())
+            .collect::<Vec<_>>();
+        stages.dedup();
+        assert_eq!(stages, vec!["Exporting convex hulls", "Building BSP"]);
+        // Every stage counts up to its total, one event at a time
+        let hulls = listener
+            .events
+            .iter()
+            .filter(|e| e.2 == "Exporting convex hulls")
+            .map(|e| (e.0, e.1))
+            .collect::<Vec<_>>();
+        assert_eq!(hulls, vec![(1, 3), (2, 3), (3, 3)]);
+        assert_eq!(
+            listener.events.last().unwrap(),
+            &(18, 18, "Building BSP".to_string(), "Built BSP".to_string())
+        );
+    }
+
+    #[test]
+    fn collision_hulls_of_boxes_have_six_outward_planes() {
+        for mb_only in [true, false] {
+            let config = ConvertConfig {
+                mb_only,
+                bake_lighting: false,
+                ..ConvertConfig::DEFAULT
+            };
+            let collision = export_collision_data(&build_interior(WELDED, &config));
+            assert_eq!(collision.hulls.len(), 2);
+            for hull in collision.hulls.iter() {
+                assert_eq!(hull.planes.len(), 6);
+                let mut normals = hull
+                    .planes
+                    .iter()
+                    .map(|p| {
+                        [
+                            p[0].round() as i32,
+                            p[1].round() as i32,
+                            p[2].round() as i32,
+                        ]
+                    })
+                    .collect::<Vec<_>>();
+                normals.sort();
+                assert_eq!(
+                    normals,
+                    vec![
+                        [-1, 0, 0],
+                        [0, -1, 0],
+                        [0, 0, -1],
+                        [0, 0, 1],
+                        [0, 1, 0],
+                        [1, 0, 0]
+                    ]
+                );
+                // The points of the hull are all on or behind its planes
+                for &i in hull.point_indices.iter() {
+                    let p = collision.points[i as usize];
+                    for plane in hull.planes.iter() {
+                        assert!(
+                            plane[0] * p[0] + plane[1] * p[1] + plane[2] * p[2] + plane[3] < 1e-3
+                        );
+                    }
+                }
+            }
+            let json = serde_json::to_value(&collision).unwrap();
+            assert_eq!(json["hulls"][0]["planes"].as_array().unwrap().len(), 6);
+        }
+    }
+
+    #[test]
+    fn vehicle_collision_has_a_null_surface_for_every_face() {
+        let mut scene = try_parse_scene(WELDED.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let mut builder = DIFBuilder::new(&ConvertConfig::DEFAULT);
+        for b in scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush
+            .iter()
+        {
+            builder.add_brush(b);
+        }
+        let vehicle = builder.build_vehicle_collision(&mut NoopListener).unwrap();
+        assert_eq!(vehicle.convex_hulls.len(), 2);
+        assert_eq!(vehicle.null_surfaces.len(), 12);
+        // The hull surfaces all point at their null surface, tagged with the high bit
+        assert_eq!(
+            vehicle.hull_surface_indices,
+            (0..12).map(|i| 0x80000000 | i).collect::<Vec<_>>()
+        );
+        assert_eq!(vehicle.windings.len(), 48);
+        for (i, surface) in vehicle.null_surfaces.iter().enumerate() {
+            assert_eq!(surface.winding_start, i as u32 * 4);
+            assert_eq!(surface.winding_count, 4);
+            // The winding is made of points of its hull, on the plane of the face
+            let hull = &vehicle.convex_hulls[i / 6];
+            let hull_points = &vehicle.hull_indices
+                [hull.hull_start as usize..(hull.hull_start + hull.hull_count as u32) as usize];
+            let plane = &vehicle.planes[(surface.plane_index & 0x7fff) as usize];
+            let start = surface.winding_start as usize;
+            for &point in vehicle.windings[start..start + 4].iter() {
+                assert!(hull_points.contains(&point));
+                let p = vehicle.points[point as usize];
+                assert!((plane.normal.dot(p) + plane.distance).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn duplicate_faces_of_a_brush_are_dropped() {
+        // Two more copies of the top of the grass box, the first without a valid winding
+        let duplicates = concat!(
+            "<Face id=\"20\" plane=\"0 0 1 -4\" material=\"grass\" texgens=\"1 0 0 0 0 1 0 0 0 1 1\" texDiv=\"128 128\">\n",
+            "<Indices indices=\"4 5 4 5\" />\n",
+            "</Face>\n",
+            "<Face id=\"21\" plane=\"0 0 1 -4\" material=\"grass\" texgens=\"1 0 0 0 0 1 0 0 0 1 1\" texDiv=\"128 128\">\n",
+            "<Indices indices=\"4 5 6 7\" />\n",
+            "</Face>\n",
+            "<Face id=\"0\"",
+        );
+        let csx = WELDED.replacen("<Face id=\"0\"", duplicates, 1);
+        let scene = try_parse_scene(csx.as_bytes()).unwrap();
+        let mut brush = scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush[0]
+            .clone();
+        assert_eq!(brush.face.len(), 8);
+        drop_duplicate_faces(&mut brush, &ConvertConfig::DEFAULT);
+        assert_eq!(
+            brush.face.iter().map(|f| f.id).collect::<Vec<_>>(),
+            vec![21, 1, 2, 3, 4, 5]
+        );
+
+        let interior = build_interior(&csx, &ConvertConfig::DEFAULT);
+        assert_eq!(interior.surfaces.len(), 12);
+    }
+
+    #[test]
+    fn overridden_materials_share_the_forced_texgen() {
+        let plane_x = PlaneF {
+            normal: Point3F::new(0.5, 0.0, 0.0),
+            distance: 0.25,
+        };
+        let plane_y = PlaneF {
+            normal: Point3F::new(0.0, 0.0, 0.5),
+            distance: 0.0,
+        };
+        let config = ConvertConfig {
+            texgen_overrides: vec![("Stone".to_string(), plane_x.clone(), plane_y.clone())],
+            ..ConvertConfig::DEFAULT
+        };
+        let interior = build_interior(MATERIALS, &config);
+        let tex_gens = |material: &str| {
+            interior
+                .surfaces
+                .iter()
+                .filter(|s| interior.material_names[*s.texture_index.inner() as usize] == material)
+                .map(|s| *s.tex_gen_index.inner())
+                .collect::<BTreeSet<_>>()
+        };
+        let stone = tex_gens("stone");
+        assert_eq!(stone.len(), 1);
+        let eq = &interior.tex_gen_eqs[*stone.first().unwrap() as usize];
+        assert_eq!(
+            (eq.plane_x.normal, eq.plane_x.distance),
+            (plane_x.normal, plane_x.distance)
+        );
+        assert_eq!(
+            (eq.plane_y.normal, eq.plane_y.distance),
+            (plane_y.normal, plane_y.distance)
+        );
+        // The other materials keep the texgens of their faces
+        assert!(tex_gens("grass").len() > 1);
+        assert!(tex_gens("grass").is_disjoint(&stone));
+    }
+
+    #[test]
+    fn compacting_drops_the_materials_no_surface_uses() {
+        let mut interior = build_interior(MATERIALS, &ConvertConfig::DEFAULT);
+        let surface_materials = |interior: &Interior| {
+            interior
+                .surfaces
+                .iter()
+                .map(|s| interior.material_names[*s.texture_index.inner() as usize].clone())
+                .collect::<Vec<_>>()
+        };
+        // Exclude every grass surface, the way dropped or collision only faces would be
+        interior
+            .surfaces
+            .retain(|s| interior.material_names[*s.texture_index.inner() as usize] != "grass");
+        let before = surface_materials(&interior);
+        assert_eq!(before.len(), 12);
+
+        compact_materials(&mut interior);
+        let mut expected = before.clone();
+        expected.dedup();
+        assert_eq!(interior.material_names, expected);
+        assert!(!interior.material_names.contains(&"grass".to_string()));
+        assert_eq!(surface_materials(&interior), before);
+
+        let compacted = interior.material_names.clone();
+        compact_materials(&mut interior);
+        assert_eq!(interior.material_names, compacted);
+        assert_eq!(surface_materials(&interior), before);
+    }
+
+    #[test]
+    fn patch_brushes_are_render_only() {
+        // A single face floating over the grass box
+        let patch = concat!(
+            "<Brush id=\"2\" owner=\"0\" type=\"0\" transform=\"1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1\">\n",
+            "<Vertices>\n",
+            "<Vertex pos=\"0 0 10\" />\n",
+            "<Vertex pos=\"4 0 10\" />\n",
+            "<Vertex pos=\"4 4 10\" />\n",
+            "<Vertex pos=\"0 4 10\" />\n",
+            "</Vertices>\n",
+            "<Face id=\"20\" plane=\"0 0 1 -10\" material=\"decal\" texgens=\"1 0 0 0 0 1 0 0 0 1 1\" texDiv=\"128 128\">\n",
+            "<Indices indices=\"0 1 2 3\" />\n",
+            "</Face>\n",
+            "</Brush>\n",
+            "</Brushes>",
+        );
+        let csx = WELDED.replacen("</Brushes>", patch, 1);
+        let scene = try_parse_scene(csx.as_bytes()).unwrap();
+        let brushes = &scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush;
+        assert!(!is_patch_brush(&brushes[0]));
+        assert!(is_patch_brush(&brushes[2]));
+
+        let interior = build_interior(&csx, &ConvertConfig::DEFAULT);
+        assert_eq!(interior.surfaces.len(), 13);
+        let decal = interior
+            .surfaces
+            .iter()
+            .position(|s| interior.material_names[*s.texture_index.inner() as usize] == "decal")
+            .unwrap() as u32;
+        assert_eq!(interior.convex_hulls.len(), 2);
+        assert_eq!(interior.bounding_box.max.z, 10.0);
+        // Neither a hull nor a solid leaf has the patch surface
+        let is_decal = |i: &PossiblyNullSurfaceIndex| matches!(i, PossiblyNullSurfaceIndex::NonNull(s) if *s.inner() as u32 == decal);
+        assert!(!interior.hull_surface_indices.iter().any(is_decal));
+        assert!(!interior.solid_leaf_surfaces.iter().any(is_decal));
+        assert!(!interior.solid_leaf_surfaces.is_empty());
+    }
+
+    #[test]
+    fn coord_bins_are_laid_out_in_the_plane_of_their_mode() {
+        // The floor is at z 0 to 1 and the box over its middle at z 4 to 5
+        let interior = |coord_bin_mode| {
+            let config = ConvertConfig {
+                coord_bin_mode,
+                ..ConvertConfig::DEFAULT
+            };
+            build_interior(HARD_SHADOWS, &config)
+        };
+        let hulls = |interior: &Interior, i: usize, j: usize| {
+            let bin = &interior.coord_bins[i * 16 + j];
+            let start = *bin.bin_start.inner() as usize;
+            interior.coord_bin_indices[start..start + bin.bin_count as usize]
+                .iter()
+                .map(|h| *h.inner())
+                .collect::<Vec<_>>()
+        };
+
+        let xy = interior(None);
+        assert_eq!(xy.coord_bin_mode, 0);
+        assert_eq!(hulls(&xy, 0, 0), vec![0]);
+        assert_eq!(hulls(&xy, 8, 8), vec![0, 1]);
+
+        // Along z the floor and the box fall into separate bins
+        let xz = interior(Some(CoordBinMode::XZ));
+        assert_eq!(xz.coord_bin_mode, 1);
+        assert_eq!(hulls(&xz, 0, 0), vec![0]);
+        assert_eq!(hulls(&xz, 8, 15), vec![1]);
+        assert!(hulls(&xz, 0, 15).is_empty());
+
+        for engine in [
+            EngineVersion::MBG,
+            EngineVersion::TGE,
+            EngineVersion::TGEA,
+            EngineVersion::T3D,
+        ] {
+            assert_eq!(CoordBinMode::for_engine(&engine), CoordBinMode::XY);
+        }
+    }
+
+    #[test]
+    fn a_bounce_lights_the_floor_behind_the_wall() {
+        // The wall between the light and the far side of the floor shadows it, only the light
+        // bouncing off the ceiling reaches it
+        let mut scene =
+            try_parse_scene(include_str!("../snapshots/bounce.csx").as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let floor_behind_wall = |gi_bounces| {
+            let mut builder = DIFBuilder::new(&ConvertConfig {
+                mb_only: false,
+                gi_bounces,
+                lightmap_blur: crate::lightmap::BlurKind::None,
+                ..ConvertConfig::DEFAULT
+            });
+            for b in scene.detail_levels.detail_level[0]
+                .interior_map
+                .brushes
+                .brush
+                .iter()
+            {
+                builder.add_brush(b);
+            }
+            builder.set_lumel_scale(1);
+            builder.set_lights(vec![Light::Omni {
+                position: Point3F::new(5.0, 8.0, 2.0),
+                color: ColorI {
+                    r: 255,
+                    g: 255,
+                    b: 255,
+                    a: 255,
+                },
+                falloff1: 1.0,
+                falloff2: 30.0,
+            }]);
+            let interior = builder.build(&mut NoopListener).unwrap().0;
+
+            // The lumel of the top of the floor at x 16, y 8
+            let (i, surface) = interior
+                .surfaces
+                .iter()
+                .enumerate()
+                .find(|(_, s)| {
+                    let plane = &interior.planes[(*s.plane_index.inner() & 0x7fff) as usize];
+                    let normal = interior.normals[*plane.normal_index.inner() as usize];
+                    normal.z.abs() > 0.99 && plane.plane_distance.abs() < 1e-3
+                })
+                .unwrap();
+            let axes = match surface.light_map.final_word >> 13 {
+                0 => (0, 1),
+                1 => (0, 2),
+                2 => (1, 0),
+                3 => (1, 2),
+                4 => (2, 0),
+                _ => (2, 1),
+            };
+            let scale = (1 << ((surface.light_map.final_word >> 6) & 0b111111)) as f32;
+            let point = Point3F::new(16.0, 8.0, 0.0);
+            let u = (point[axes.0] / scale + surface.light_map.tex_gen_x_distance) * 256.0;
+            let v = (point[axes.1] / scale + surface.light_map.tex_gen_y_distance) * 256.0;
+            assert!(
+                (surface.map_offset_x..surface.map_offset_x + surface.map_size_x)
+                    .contains(&(u as u32))
+            );
+            assert!(
+                (surface.map_offset_y..surface.map_offset_y + surface.map_size_y)
+                    .contains(&(v as u32))
+            );
+            let png = &interior.light_maps[interior.normal_lmap_indices[i].into_inner() as usize];
+            let image = image::load_from_memory(&png.light_map.data)
+                .unwrap()
+                .to_rgb8();
+            image.get_pixel(u as u32, v as u32)[0]
+        };
+        let direct = floor_behind_wall(0);
+        let bounced = floor_behind_wall(1);
+        assert_eq!(direct, 0);
+        assert!(bounced > direct);
+    }
 }
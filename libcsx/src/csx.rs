@@ -1,24 +1,27 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use cgmath::{
-    EuclideanSpace, InnerSpace, Matrix, Matrix3, Matrix4, Point3, Rad, Rotation3, Transform,
-    Vector3,
+    EuclideanSpace, InnerSpace, Matrix, Matrix3, Matrix4, Point3, Rad, Rotation3, SquareMatrix,
+    Transform, Vector3,
 };
 use dif::interior_path_follower::{InteriorPathFollower, WayPoint};
 use dif::trigger::{Polyhedron, PolyhedronEdge, Trigger};
-use dif::types::{Dictionary, QuatF};
+use dif::types::{Dictionary, DifError, QuatF};
 use dif::{
     dif::Dif,
     game_entity::GameEntity,
     interior::Interior,
     io::{Version, Writable},
-    types::{MatrixF, PlaneF, Point3F},
+    types::{BoxF, MatrixF, PlaneF, Point3F},
 };
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
+use crate::bsp::BSPCache;
+use crate::bsp::ConvertConfig;
 use crate::builder::{
-    get_bounding_box, get_bounding_box_not_owned, BSPReport, DIFBuilder, ProgressEventListener,
+    get_bounding_box, get_bounding_box_not_owned, BSPReport, DIFBuilder, NoopListener,
+    ProgressEventListener, RaycastCalc,
 };
 use crate::light::{self, Light};
 
@@ -70,6 +73,9 @@ pub struct InteriorMap {
 
     pub entities: Entities,
     pub brushes: Brushes,
+    // Brushes that are only placed through BrushInstances
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brush_definitions: Option<Brushes>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -111,7 +117,31 @@ pub struct EntityProperties {
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Brushes {
+    #[serde(default)]
     pub brush: Vec<Brush>,
+    #[serde(default)]
+    pub brush_instance: Vec<BrushInstance>,
+}
+
+// A copy of another brush, either from the BrushDefinitions or the Brushes, placed with its own
+// transform on top of the referenced brush's transform
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct BrushInstance {
+    #[serde(rename = "@id")]
+    pub id: i32,
+    #[serde(rename = "@brush")]
+    pub brush: i32,
+    #[serde(rename = "@owner", default)]
+    pub owner: Option<i32>,
+    #[serde(rename = "@type", default)]
+    pub type_: Option<i32>,
+    #[serde(
+        rename = "@transform",
+        serialize_with = "serialize_matrix",
+        deserialize_with = "deserialize_matrix"
+    )]
+    pub transform: MatrixF,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -130,6 +160,7 @@ pub struct Brush {
     )]
     pub transform: MatrixF,
     pub vertices: Vertices,
+    #[serde(default)]
     pub face: Vec<Face>,
 }
 
@@ -158,7 +189,8 @@ pub struct Face {
     #[serde(
         rename = "@plane",
         serialize_with = "serialize_plane",
-        deserialize_with = "deserialize_plane"
+        deserialize_with = "deserialize_plane",
+        default = "missing_plane"
     )]
     pub plane: PlaneF,
     #[serde(rename = "@material")]
@@ -176,7 +208,7 @@ pub struct Face {
     )]
     pub tex_div: Vec<i32>,
     pub indices: Indices,
-    #[serde(skip_deserializing)]
+    #[serde(skip)]
     pub face_id: i32,
 }
 
@@ -199,17 +231,38 @@ pub struct TexGen {
     pub scale: [f32; 2],
 }
 
+// Parses a whitespace separated list of numbers, any run of spaces, tabs or newlines separates
+// them. Errors if there are less than min_count of them, naming the attribute value that failed.
+fn parse_numbers<T, E>(s: &str, min_count: usize) -> Result<Vec<T>, E>
+where
+    T: std::str::FromStr,
+    E: serde::de::Error,
+{
+    let numbers = s
+        .split_whitespace()
+        .map(|v| {
+            v.parse::<T>()
+                .map_err(|_| E::custom(format!("invalid number {} in \"{}\"", v, s.trim())))
+        })
+        .collect::<Result<Vec<T>, E>>()?;
+    if numbers.len() < min_count {
+        return Err(E::custom(format!(
+            "expected {} numbers in \"{}\", got {}",
+            min_count,
+            s.trim(),
+            numbers.len()
+        )));
+    }
+    Ok(numbers)
+}
+
 fn deserialize_point<'de, D>(deserializer: D) -> Result<Point3F, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
     match String::deserialize(deserializer) {
         Ok(s) => {
-            let coords = s
-                .trim()
-                .split(' ')
-                .map(|v| v.parse::<f32>().unwrap())
-                .collect::<Vec<f32>>();
+            let coords = parse_numbers::<f32, D::Error>(&s, 3)?;
             Ok(Point3F::new(coords[0], coords[1], coords[2]))
         }
         Err(e) => Err(e),
@@ -233,11 +286,7 @@ where
             if s.len() == 0 {
                 return Ok(None);
             }
-            let coords = s
-                .trim()
-                .split(' ')
-                .map(|v| v.parse::<f32>().unwrap())
-                .collect::<Vec<f32>>();
+            let coords = parse_numbers::<f32, D::Error>(&s, 3)?;
             Ok(Some(Point3F::new(coords[0], coords[1], coords[2])))
         }
         Err(e) => Err(e),
@@ -257,17 +306,21 @@ where
     }
 }
 
+// Faces without a plane get one computed from their winding in preprocess_csx
+fn missing_plane() -> PlaneF {
+    PlaneF {
+        normal: Point3F::new(0.0, 0.0, 0.0),
+        distance: 0.0,
+    }
+}
+
 fn deserialize_plane<'de, D>(deserializer: D) -> Result<PlaneF, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
     match String::deserialize(deserializer) {
         Ok(s) => {
-            let coords = s
-                .trim()
-                .split(' ')
-                .map(|v| v.parse::<f32>().unwrap())
-                .collect::<Vec<f32>>();
+            let coords = parse_numbers::<f32, D::Error>(&s, 4)?;
             Ok(PlaneF {
                 normal: Point3F::new(coords[0], coords[1], coords[2]),
                 distance: coords[3],
@@ -293,11 +346,7 @@ where
     D: serde::Deserializer<'de>,
 {
     match String::deserialize(deserializer) {
-        Ok(s) => Ok(s
-            .trim()
-            .split(' ')
-            .map(|v| v.parse::<i32>().unwrap())
-            .collect()),
+        Ok(s) => parse_numbers::<i32, D::Error>(&s, 0),
         Err(e) => Err(e),
     }
 }
@@ -322,11 +371,7 @@ where
 {
     match String::deserialize(deserializer) {
         Ok(s) => {
-            let coords = s
-                .trim()
-                .split(' ')
-                .map(|v| v.parse::<f32>().unwrap())
-                .collect::<Vec<f32>>();
+            let coords = parse_numbers::<f32, D::Error>(&s, 11)?;
             Ok(TexGen {
                 plane_x: {
                     PlaneF {
@@ -375,11 +420,7 @@ where
 {
     match String::deserialize(deserializer) {
         Ok(s) => {
-            let coords = s
-                .trim()
-                .split(' ')
-                .map(|v| v.parse::<f32>().unwrap())
-                .collect::<Vec<f32>>();
+            let coords = parse_numbers::<f32, D::Error>(&s, 16)?;
             Ok(MatrixF::new(
                 coords[0], coords[4], coords[8], coords[12], coords[1], coords[5], coords[9],
                 coords[13], coords[2], coords[6], coords[10], coords[14], coords[3], coords[7],
@@ -425,7 +466,7 @@ where
     match raw {
         Ok(s) => Ok(s
             .iter()
-            .map(|(k, v)| (k.strip_prefix('@').unwrap().to_owned(), v.clone()))
+            .map(|(k, v)| (k.strip_prefix('@').unwrap_or(k).to_owned(), v.clone()))
             .collect::<HashMap<String, String>>()),
         Err(e) => Err(e),
     }
@@ -435,25 +476,232 @@ fn serialize_propertymap<S>(v: &HashMap<String, String>, serializer: S) -> Resul
 where
     S: serde::Serializer,
 {
-    let format_str = format!(
-        "{{{}}}",
-        v.iter()
-            .map(|(k, v)| format!("@{}={}", k, v))
-            .collect::<Vec<String>>()
-            .join(",")
-    );
-    format_str.serialize(serializer)
+    // Written back as the attributes of the Properties element, sorted so the output is stable
+    v.iter()
+        .map(|(k, v)| (format!("@{}", k), v))
+        .collect::<BTreeMap<_, _>>()
+        .serialize(serializer)
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Xml(quick_xml::DeError),
+    InvalidTransform { brush: i32 },
+    InvalidFaceIndex { brush: i32, face: i32 },
+    // A detail level whose lightScale is 0, its lumels would have no size
+    InvalidLightScale { detail_level: usize },
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Xml(e) => write!(f, "Invalid CSX: {}", e),
+            ParseError::InvalidTransform { brush } => {
+                write!(f, "Brush {} has a non invertible transform", brush)
+            }
+            ParseError::InvalidFaceIndex { brush, face } => {
+                write!(
+                    f,
+                    "Face {} of brush {} has an out of range vertex index",
+                    face, brush
+                )
+            }
+            ParseError::InvalidLightScale { detail_level } => {
+                write!(f, "Detail level {} has a lightScale of 0", detail_level)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Problems that stop the conversion, they name the brush, face or entity they come from
+#[derive(Debug)]
+pub enum ConversionError {
+    Parse(ParseError),
+    NoScenes,
+    MissingOrigin {
+        entity: i32,
+    },
+    LightmapTooLarge {
+        brush: i32,
+        face: i32,
+        width: u32,
+        height: u32,
+        lightmap_size: u32,
+    },
+    // A light entity whose color isn't three numbers
+    BadColor {
+        entity: i32,
+        value: String,
+    },
+    // An entity named like a light that isn't one of the known light types
+    UnknownLight {
+        entity: i32,
+        classname: String,
+    },
+    // The lightmap of the surface isn't projected along a single axis
+    BadLightmapTexgens {
+        surface: usize,
+    },
+    Write(DifError),
+    // The progress listener asked to stop the conversion
+    Cancelled,
+}
+
+impl std::fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConversionError::Parse(e) => write!(f, "{}", e),
+            ConversionError::NoScenes => write!(f, "There are no scenes to convert"),
+            ConversionError::MissingOrigin { entity } => {
+                write!(f, "Entity {} has no origin", entity)
+            }
+            ConversionError::LightmapTooLarge {
+                brush,
+                face,
+                width,
+                height,
+                lightmap_size,
+            } => write!(
+                f,
+                "The lightmap of face {} of brush {} needs {}x{} lumels, more than the {}x{} lightmap, use a larger lightScale",
+                face, brush, width, height, lightmap_size, lightmap_size
+            ),
+            ConversionError::BadColor { entity, value } => {
+                write!(f, "Entity {} has the color \"{}\", expected three numbers", entity, value)
+            }
+            ConversionError::UnknownLight { entity, classname } => {
+                write!(f, "Entity {} has the unknown light type {}", entity, classname)
+            }
+            ConversionError::BadLightmapTexgens { surface } => {
+                write!(f, "The lightmap of surface {} isn't aligned to an axis", surface)
+            }
+            ConversionError::Write(e) => write!(f, "Could not write the DIF: {}", e),
+            ConversionError::Cancelled => write!(f, "The conversion was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+impl From<ParseError> for ConversionError {
+    fn from(e: ParseError) -> Self {
+        ConversionError::Parse(e)
+    }
+}
+
+// Parses and validates a scene so that it can be preprocessed and converted, never panics
+pub fn try_parse_scene(buf: &[u8]) -> Result<ConstructorScene, ParseError> {
+    let mut des = quick_xml::de::Deserializer::from_reader(buf);
+    let cscene = ConstructorScene::deserialize(&mut des).map_err(ParseError::Xml)?;
+
+    for (i, d) in cscene.detail_levels.detail_level.iter().enumerate() {
+        if d.interior_map.light_scale == 0 {
+            return Err(ParseError::InvalidLightScale { detail_level: i });
+        }
+        let brushes = d.interior_map.brushes.brush.iter().chain(
+            d.interior_map
+                .brush_definitions
+                .iter()
+                .flat_map(|defs| defs.brush.iter()),
+        );
+        for b in brushes {
+            if b.transform.inverse_transform().is_none() {
+                return Err(ParseError::InvalidTransform { brush: b.id });
+            }
+            for f in b.face.iter() {
+                if f.indices
+                    .indices
+                    .iter()
+                    .any(|&i| i < 0 || i as usize >= b.vertices.vertex.len())
+                {
+                    return Err(ParseError::InvalidFaceIndex {
+                        brush: b.id,
+                        face: f.id,
+                    });
+                }
+            }
+        }
+        for instance in d.interior_map.brushes.brush_instance.iter() {
+            if instance.transform.inverse_transform().is_none() {
+                return Err(ParseError::InvalidTransform { brush: instance.id });
+            }
+        }
+    }
+    Ok(cscene)
+}
+
+// The axis the normal is within angle degrees of, if any
+pub fn snap_normal_to_axis(normal: Point3F, angle: f32) -> Option<Point3F> {
+    let axis = (0..3)
+        .max_by(|&a, &b| normal[a].abs().total_cmp(&normal[b].abs()))
+        .unwrap();
+    let cos = normal[axis].abs() / normal.magnitude();
+    if cos >= angle.to_radians().cos() {
+        let mut axial_normal = Point3F::new(0.0, 0.0, 0.0);
+        axial_normal[axis] = normal[axis].signum();
+        Some(axial_normal)
+    } else {
+        None
+    }
 }
 
-pub fn preprocess_csx(cscene: &mut ConstructorScene) {
+// Returns the number of faces whose winding opposes their plane normal, those get reversed if
+// fix_windings is set. A vertex_snap greater than 0 snaps the vertices to a grid of that size, and
+// an axis_snap_angle greater than 0 snaps the plane normals within that many degrees of an axis.
+pub fn preprocess_csx(
+    cscene: &mut ConstructorScene,
+    fix_windings: bool,
+    vertex_snap: f32,
+    axis_snap_angle: f32,
+) -> usize {
+    // Expand the brush instances into copies of the brushes they reference
+    cscene.detail_levels.detail_level.iter_mut().for_each(|d| {
+        let instances = std::mem::take(&mut d.interior_map.brushes.brush_instance);
+        for instance in instances {
+            let referenced = d
+                .interior_map
+                .brush_definitions
+                .iter()
+                .flat_map(|defs| defs.brush.iter())
+                .chain(d.interior_map.brushes.brush.iter())
+                .find(|b| b.id == instance.brush)
+                .cloned();
+            if let Some(mut b) = referenced {
+                b.id = instance.id;
+                b.owner = instance.owner.unwrap_or(b.owner);
+                b.type_ = instance.type_.unwrap_or(b.type_);
+                b.transform = instance.transform * b.transform;
+                d.interior_map.brushes.brush.push(b);
+            }
+        }
+    });
+
     let mut cur_face_id = 0;
+    let mut reversed_faces = 0;
     cscene.detail_levels.detail_level.iter_mut().for_each(|d| {
         d.interior_map.brushes.brush.iter_mut().for_each(|b| {
+            // Fill in the planes of the faces that only have a winding
+            if b.face.iter().any(|f| f.plane.normal.magnitude2() == 0.0) {
+                let planes = compute_brush_planes(b);
+                b.face.iter_mut().zip(planes).for_each(|(f, plane)| {
+                    if f.plane.normal.magnitude2() == 0.0 {
+                        f.plane = plane;
+                    }
+                });
+            }
+            // A mirroring transform turns the windings inside out, the planes are still transformed
+            // to face outwards so only the windings need to be flipped back
+            let mirrored = b.transform.determinant() < 0.0;
             b.vertices.vertex.iter_mut().for_each(|v| {
                 v.pos = b
                     .transform
                     .transform_point(Point3::from_vec(v.pos))
                     .to_vec();
+                if vertex_snap > 0.0 {
+                    v.pos = v.pos.map(|c| (c / vertex_snap).round() * vertex_snap);
+                }
             });
             b.face.iter_mut().for_each(|f| {
                 let mut o = (f.plane.normal * -f.plane.distance).extend(1.0);
@@ -466,6 +714,60 @@ pub fn preprocess_csx(cscene: &mut ConstructorScene) {
                 f.plane.distance = d;
                 f.face_id = cur_face_id;
                 cur_face_id += 1;
+                if mirrored {
+                    f.indices.indices.reverse();
+                }
+
+                let winding_normal = get_winding_normal(
+                    f.indices
+                        .indices
+                        .iter()
+                        .map(|&i| b.vertices.vertex[i as usize].pos),
+                );
+
+                // The snapped vertices may not lie on the plane anymore, so fit the plane to them
+                if vertex_snap > 0.0 && winding_normal.magnitude2() > 0.0 {
+                    let mut snapped_normal = winding_normal.normalize();
+                    if snapped_normal.dot(f.plane.normal) < 0.0 {
+                        snapped_normal = -snapped_normal;
+                    }
+                    let center = f
+                        .indices
+                        .indices
+                        .iter()
+                        .map(|&i| b.vertices.vertex[i as usize].pos)
+                        .sum::<Point3F>()
+                        / f.indices.indices.len() as f32;
+                    f.plane.normal = snapped_normal;
+                    f.plane.distance = -center.dot(snapped_normal);
+                }
+
+                // Snap the normals that are nearly axis aligned to the axis, so they get the axial
+                // bonus when picking the BSP splitters and dedupe with the truly axial planes
+                if axis_snap_angle > 0.0 {
+                    if let Some(axial_normal) = snap_normal_to_axis(f.plane.normal, axis_snap_angle)
+                    {
+                        let center = if f.indices.indices.is_empty() {
+                            f.plane.normal * -f.plane.distance
+                        } else {
+                            f.indices
+                                .indices
+                                .iter()
+                                .map(|&i| b.vertices.vertex[i as usize].pos)
+                                .sum::<Point3F>()
+                                / f.indices.indices.len() as f32
+                        };
+                        f.plane.normal = axial_normal;
+                        f.plane.distance = -center.dot(axial_normal);
+                    }
+                }
+
+                if winding_normal.dot(f.plane.normal) < 0.0 {
+                    reversed_faces += 1;
+                    if fix_windings {
+                        f.indices.indices.reverse();
+                    }
+                }
             });
         });
     });
@@ -515,8 +817,443 @@ pub fn preprocess_csx(cscene: &mut ConstructorScene) {
                     b.transform,
                 );
             });
+            // Everything is in absolute coords now
+            b.transform = MatrixF::identity();
+        });
+    });
+
+    reversed_faces
+}
+
+// Counts the entities of every detail level of the scene by their classname
+pub fn scene_entity_summary(cscene: &ConstructorScene) -> HashMap<String, usize> {
+    let mut summary = HashMap::new();
+    cscene
+        .detail_levels
+        .detail_level
+        .iter()
+        .flat_map(|d| d.interior_map.entities.entity.iter())
+        .for_each(|e| *summary.entry(e.classname.clone()).or_insert(0) += 1);
+    summary
+}
+
+// The four numbers of a rotation property, None if there aren't exactly four
+fn parse_four_floats(s: &str) -> Option<[f32; 4]> {
+    let components = s
+        .split_whitespace()
+        .map(|c| c.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    components.try_into().ok()
+}
+
+// Parses a quaternion x y z w, anything that isn't four numbers of nonzero length is no rotation
+pub fn parse_quat(s: &str) -> QuatF {
+    let identity = QuatF::from_axis_angle(Vector3::new(1.0, 0.0, 0.0), Rad(0.0));
+    match parse_four_floats(s) {
+        Some([x, y, z, w]) => {
+            let quat = QuatF::new(w, x, y, z);
+            if quat.magnitude2() < 1e-12 {
+                identity
+            } else {
+                quat.normalize()
+            }
+        }
+        None => identity,
+    }
+}
+
+// Parses an axis and an angle in degrees like the rotation of Torque objects, anything that isn't
+// four numbers with a nonzero axis is no rotation
+pub fn parse_axis_angle(s: &str) -> QuatF {
+    let identity = QuatF::from_axis_angle(Vector3::new(1.0, 0.0, 0.0), Rad(0.0));
+    match parse_four_floats(s) {
+        Some([x, y, z, angle]) if Vector3::new(x, y, z).magnitude2() >= 1e-12 => {
+            QuatF::from_axis_angle(Vector3::new(x, y, z).normalize(), cgmath::Deg(angle))
+        }
+        _ => identity,
+    }
+}
+
+// The player start and camera entities of every detail level of the scene. The orientation comes
+// from the angles property, or just the yaw from the angle property.
+pub fn scene_spawn_points(cscene: &ConstructorScene) -> Vec<SpawnPoint> {
+    cscene
+        .detail_levels
+        .detail_level
+        .iter()
+        .flat_map(|d| d.interior_map.entities.entity.iter())
+        .filter_map(|e| {
+            let kind = if PLAYER_START_CLASSNAMES.contains(&e.classname.as_str()) {
+                SpawnKind::PlayerStart
+            } else if CAMERA_CLASSNAMES.contains(&e.classname.as_str()) {
+                SpawnKind::Camera
+            } else {
+                return None;
+            };
+            let angles = match (e.properties.get("angles"), e.properties.get("angle")) {
+                (Some(angles), _) => {
+                    let components = angles
+                        .split_whitespace()
+                        .map(|c| c.parse::<f32>().unwrap_or(0.0))
+                        .collect::<Vec<_>>();
+                    Point3F::new(
+                        components.first().copied().unwrap_or(0.0),
+                        components.get(1).copied().unwrap_or(0.0),
+                        components.get(2).copied().unwrap_or(0.0),
+                    )
+                }
+                (None, Some(angle)) => Point3F::new(0.0, angle.parse::<f32>().unwrap_or(0.0), 0.0),
+                (None, None) => Point3F::new(0.0, 0.0, 0.0),
+            };
+            Some(SpawnPoint {
+                kind,
+                classname: e.classname.clone(),
+                position: e.origin.unwrap_or(Point3F::new(0.0, 0.0, 0.0)),
+                angles,
+            })
+        })
+        .collect()
+}
+
+// The materials of the scene that aren't among the available ones, along with the first face that
+// uses each of them. Material names are compared case insensitively like Torque does.
+pub fn find_missing_materials(
+    cscene: &ConstructorScene,
+    available_materials: &[String],
+) -> Vec<ConversionWarning> {
+    let mut missing: Vec<ConversionWarning> = vec![];
+    cscene
+        .detail_levels
+        .detail_level
+        .iter()
+        .flat_map(|d| d.interior_map.brushes.brush.iter())
+        .flat_map(|b| b.face.iter().map(move |f| (b, f)))
+        .for_each(|(b, f)| {
+            let is_available = available_materials
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(&f.material));
+            let is_reported = missing.iter().any(|w| match w {
+                ConversionWarning::MissingMaterial { material, .. } => {
+                    material.eq_ignore_ascii_case(&f.material)
+                }
+                _ => false,
+            });
+            if !is_available && !is_reported {
+                missing.push(ConversionWarning::MissingMaterial {
+                    material: f.material.clone(),
+                    brush: b.id,
+                    face: f.id,
+                });
+            }
+        });
+    missing
+}
+
+// Dumps the entities of every detail level and the moving platforms as JSON. A moving platform is
+// made of the brushes it owns and the path_nodes and triggers that follow its entity, the same way
+// they are grouped during the conversion.
+pub fn export_scene_json(cscene: &ConstructorScene) -> String {
+    let detail_levels = cscene
+        .detail_levels
+        .detail_level
+        .iter()
+        .map(|d| {
+            let entities = d
+                .interior_map
+                .entities
+                .entity
+                .iter()
+                .map(|e| {
+                    serde_json::json!({
+                        "id": e.id,
+                        "classname": e.classname,
+                        "gametype": e.gametype,
+                        "origin": e.origin.map(|o| [o.x, o.y, o.z]),
+                        "properties": e.properties,
+                    })
+                })
+                .collect::<Vec<_>>();
+            serde_json::json!({ "entities": entities })
+        })
+        .collect::<Vec<_>>();
+
+    let mut moving_platforms: Vec<(i32, Vec<i32>, Vec<i32>)> = vec![];
+    cscene
+        .detail_levels
+        .detail_level
+        .iter()
+        .flat_map(|d| d.interior_map.entities.entity.iter())
+        .for_each(|e| {
+            if e.classname == "Door_Elevator" {
+                moving_platforms.push((e.id, vec![], vec![]));
+            } else if let Some((_, path_nodes, triggers)) = moving_platforms.last_mut() {
+                if e.classname == "path_node" {
+                    path_nodes.push(e.id);
+                } else if e.classname == "trigger" {
+                    triggers.push(e.id);
+                }
+            }
+        });
+    let moving_platforms = moving_platforms
+        .into_iter()
+        .map(|(id, path_nodes, triggers)| {
+            let brushes = cscene
+                .detail_levels
+                .detail_level
+                .iter()
+                .flat_map(|d| d.interior_map.brushes.brush.iter())
+                .filter(|b| b.owner == id && b.type_ == 999)
+                .map(|b| b.id)
+                .collect::<Vec<_>>();
+            serde_json::json!({
+                "entity": id,
+                "brushes": brushes,
+                "path_nodes": path_nodes,
+                "triggers": triggers,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::to_string_pretty(&serde_json::json!({
+        "detail_levels": detail_levels,
+        "moving_platforms": moving_platforms,
+    }))
+    .unwrap()
+}
+
+// Writes the scene back out as CSX XML, to see what preprocess_csx did to it. The brushes of a
+// preprocessed scene are already in absolute coords and get identity transforms.
+pub fn export_scene_csx(cscene: &ConstructorScene) -> Result<String, quick_xml::DeError> {
+    let mut xml = String::new();
+    let mut ser = quick_xml::se::Serializer::with_root(&mut xml, Some("ConstructorScene"))?;
+    ser.indent(' ', 2);
+    cscene.serialize(ser)?;
+    Ok(xml)
+}
+
+// The .map UVs are in pixels, the size of the textures isn't known so they're assumed to be this big
+const MAP_TEXTURE_SIZE: f32 = 256.0;
+
+// Writes the brushes of a preprocessed scene as a Valve 220 .map, to look at them in an idTech
+// editor. Each face is written as three points on its plane, wound so the plane faces out of the
+// brush like idTech expects, with the texgen axes as the UV axes.
+pub fn export_brushes_to_map(cscene: &ConstructorScene) -> String {
+    let mut map = String::new();
+    map.push_str("// entity 0\n{\n\"classname\" \"worldspawn\"\n\"mapversion\" \"220\"\n");
+    cscene
+        .detail_levels
+        .detail_level
+        .iter()
+        .flat_map(|d| d.interior_map.brushes.brush.iter())
+        .for_each(|b| {
+            map.push_str(&format!("// brush {}\n{{\n", b.id));
+            for f in b.face.iter() {
+                let normal = f.plane.normal;
+                if !normal.x.is_finite() || normal.magnitude2() == 0.0 {
+                    continue;
+                }
+                // Start from the middle of the winding so the points are near the brush
+                let winding = f
+                    .indices
+                    .indices
+                    .iter()
+                    .filter_map(|&i| b.vertices.vertex.get(i as usize))
+                    .map(|v| v.pos)
+                    .collect::<Vec<_>>();
+                let center = if winding.is_empty() {
+                    normal * -f.plane.distance
+                } else {
+                    let center = winding.iter().sum::<Point3F>() / winding.len() as f32;
+                    center - normal * (center.dot(normal) + f.plane.distance)
+                };
+                let helper = if normal.x.abs() < 0.9 {
+                    Point3F::unit_x()
+                } else {
+                    Point3F::unit_y()
+                };
+                let tangent = normal.cross(helper).normalize();
+                let bitangent = normal.cross(tangent);
+                // idTech takes the normal as (p0 - p1) x (p2 - p1)
+                let points = [center + tangent, center, center + bitangent];
+                let points = points
+                    .iter()
+                    .map(|p| format!("( {} {} {} )", p.x, p.y, p.z))
+                    .join(" ");
+                let axes = [&f.texgens.plane_x, &f.texgens.plane_y].map(|plane| {
+                    let length = plane.normal.magnitude();
+                    let axis = if length > 0.0 {
+                        plane.normal / length
+                    } else {
+                        plane.normal
+                    };
+                    let scale = if length > 0.0 {
+                        1.0 / (length * MAP_TEXTURE_SIZE)
+                    } else {
+                        1.0
+                    };
+                    (axis, plane.distance * MAP_TEXTURE_SIZE, scale)
+                });
+                let material = f.material.split_whitespace().join("_");
+                map.push_str(&format!(
+                    "{} {} [ {} {} {} {} ] [ {} {} {} {} ] 0 {} {}\n",
+                    points,
+                    material,
+                    axes[0].0.x,
+                    axes[0].0.y,
+                    axes[0].0.z,
+                    axes[0].1,
+                    axes[1].0.x,
+                    axes[1].0.y,
+                    axes[1].0.z,
+                    axes[1].1,
+                    axes[0].2,
+                    axes[1].2
+                ));
+            }
+            map.push_str("}\n");
+        });
+    map.push_str("}\n");
+    map
+}
+
+// Drops the brushes of a preprocessed scene that lie entirely outside the bounds, to convert only
+// a region of a large map. The brushes straddling the bounds are kept whole. Returns how many
+// brushes were dropped.
+pub fn clip_scene_to_bounds(cscene: &mut ConstructorScene, bounds: &BoxF) -> usize {
+    let mut dropped = 0;
+    cscene.detail_levels.detail_level.iter_mut().for_each(|d| {
+        let count = d.interior_map.brushes.brush.len();
+        d.interior_map.brushes.brush.retain(|b| {
+            let brush_bounds =
+                BoxF::from_vertices(&b.vertices.vertex.iter().map(|v| &v.pos).collect::<Vec<_>>());
+            brush_bounds.max.x >= bounds.min.x
+                && brush_bounds.max.y >= bounds.min.y
+                && brush_bounds.max.z >= bounds.min.z
+                && brush_bounds.min.x <= bounds.max.x
+                && brush_bounds.min.y <= bounds.max.y
+                && brush_bounds.min.z <= bounds.max.z
         });
+        dropped += count - d.interior_map.brushes.brush.len();
     });
+    dropped
+}
+
+// Merges preprocessed scenes into the first one, detail level by detail level. The ids of the
+// brushes, entities and faces of every following scene are offset past the ones already merged,
+// with the brush owners offset along with the entities so the moving platforms and triggers keep
+// their brushes. The interior properties (scale, ambient) of the first scene are kept.
+pub fn merge_scenes(scenes: Vec<ConstructorScene>) -> Option<ConstructorScene> {
+    let mut scenes = scenes.into_iter();
+    let mut merged = scenes.next()?;
+    for mut scene in scenes {
+        let merged_brushes = merged
+            .detail_levels
+            .detail_level
+            .iter()
+            .flat_map(|d| d.interior_map.brushes.brush.iter());
+        let brush_offset = merged_brushes.clone().map(|b| b.id).max().unwrap_or(0) + 1;
+        let face_offset = merged_brushes
+            .clone()
+            .flat_map(|b| b.face.iter().map(|f| f.face_id))
+            .max()
+            .unwrap_or(-1)
+            + 1;
+        let entity_offset = merged
+            .detail_levels
+            .detail_level
+            .iter()
+            .flat_map(|d| d.interior_map.entities.entity.iter().map(|e| e.id))
+            .chain(merged_brushes.map(|b| b.owner))
+            .max()
+            .unwrap_or(0)
+            + 1;
+
+        for (i, mut d) in scene.detail_levels.detail_level.drain(..).enumerate() {
+            d.interior_map.brushes.brush.iter_mut().for_each(|b| {
+                b.id += brush_offset;
+                if b.owner != 0 {
+                    b.owner += entity_offset;
+                }
+                b.face.iter_mut().for_each(|f| f.face_id += face_offset);
+            });
+            d.interior_map
+                .entities
+                .entity
+                .iter_mut()
+                .for_each(|e| e.id += entity_offset);
+            match merged.detail_levels.detail_level.get_mut(i) {
+                Some(merged_d) => {
+                    merged_d
+                        .interior_map
+                        .brushes
+                        .brush
+                        .append(&mut d.interior_map.brushes.brush);
+                    merged_d
+                        .interior_map
+                        .entities
+                        .entity
+                        .append(&mut d.interior_map.entities.entity);
+                }
+                None => merged.detail_levels.detail_level.push(d),
+            }
+        }
+    }
+    Some(merged)
+}
+
+// Computes the plane of every face of the brush from its winding, in the brush's local space.
+// The normals point away from the center of the brush regardless of the winding order.
+pub fn compute_brush_planes(brush: &Brush) -> Vec<PlaneF> {
+    let brush_center = if brush.vertices.vertex.is_empty() {
+        Point3F::new(0.0, 0.0, 0.0)
+    } else {
+        brush.vertices.vertex.iter().map(|v| v.pos).sum::<Point3F>()
+            / brush.vertices.vertex.len() as f32
+    };
+    brush
+        .face
+        .iter()
+        .map(|f| {
+            let winding_normal = get_winding_normal(
+                f.indices
+                    .indices
+                    .iter()
+                    .map(|&i| brush.vertices.vertex[i as usize].pos),
+            );
+            if winding_normal.magnitude2() == 0.0 {
+                return missing_plane();
+            }
+            let face_center = f
+                .indices
+                .indices
+                .iter()
+                .map(|&i| brush.vertices.vertex[i as usize].pos)
+                .sum::<Point3F>()
+                / f.indices.indices.len() as f32;
+            let mut normal = winding_normal.normalize();
+            if normal.dot(face_center - brush_center) < 0.0 {
+                normal = -normal;
+            }
+            PlaneF {
+                normal,
+                distance: -face_center.dot(normal),
+            }
+        })
+        .collect()
+}
+
+// Newell's method so that concave or slightly non planar faces still give a sane normal
+pub(crate) fn get_winding_normal(points: impl Iterator<Item = Point3F> + Clone) -> Vector3<f32> {
+    let next_points = points.clone().cycle().skip(1);
+    points
+        .zip(next_points)
+        .fold(Vector3::new(0.0, 0.0, 0.0), |mut normal, (cur, next)| {
+            normal.x += (cur.y - next.y) * (cur.z + next.z);
+            normal.y += (cur.z - next.z) * (cur.x + next.x);
+            normal.z += (cur.x - next.x) * (cur.y + next.y);
+            normal
+        })
 }
 
 fn transform_plane(
@@ -571,12 +1308,232 @@ impl MPGroup<'_, '_> {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    FilteredByType,
+    Degenerate,
+    Duplicate,
+    TooComplex,
+}
+
+pub struct SkippedBrush {
+    pub detail_level: usize,
+    pub id: i32,
+    pub reason: SkipReason,
+}
+
+// Problems with the scene that don't stop the conversion but likely give a broken DIF
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConversionWarning {
+    // Faces whose winding opposes their plane normal, only reported when they weren't fixed
+    ReversedWindings {
+        count: usize,
+    },
+    // A light placed inside a brush, only checked in strict mode
+    LightInSolid {
+        light: String,
+        classname: String,
+    },
+    // An interior whose exhaustive BSP is estimated to take long to build
+    SlowBSP {
+        brush_count: usize,
+    },
+    // A material that isn't in the materials manifest, with one of the faces using it
+    MissingMaterial {
+        material: String,
+        brush: i32,
+        face: i32,
+    },
+    // A trigger field whose value the engine can't use, the default is exported instead
+    InvalidTriggerProperty {
+        trigger: i32,
+        property: String,
+        value: String,
+        default: String,
+    },
+    // A trigger property that isn't a field of the Trigger class, only checked in strict mode
+    UnknownTriggerProperty {
+        trigger: i32,
+        property: String,
+    },
+    // An interior whose BSP raycasts hit less of its surface area than the coverage threshold
+    LowCoverage {
+        interior: usize,
+        hit_area_percentage: f32,
+        threshold: f32,
+    },
+}
+
+impl std::fmt::Display for ConversionWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConversionWarning::ReversedWindings { count } => {
+                write!(f, "found {} faces with reversed winding", count)
+            }
+            ConversionWarning::LightInSolid { light, classname } => {
+                write!(f, "light {} ({}) is inside solid geometry", light, classname)
+            }
+            ConversionWarning::SlowBSP { brush_count } => write!(
+                f,
+                "building the BSP of {} brushes may take a while, try the sampling BSP or no BSP if it is too slow",
+                brush_count
+            ),
+            ConversionWarning::MissingMaterial {
+                material,
+                brush,
+                face,
+            } => write!(
+                f,
+                "material {} is not in the materials manifest, it is used by face {} of brush {}",
+                material, face, brush
+            ),
+            ConversionWarning::InvalidTriggerProperty {
+                trigger,
+                property,
+                value,
+                default,
+            } => write!(
+                f,
+                "trigger {} has an invalid {} of \"{}\", using {} instead",
+                trigger, property, value, default
+            ),
+            ConversionWarning::UnknownTriggerProperty { trigger, property } => write!(
+                f,
+                "trigger {} has the property {}, which isn't a trigger field and is only seen by the scripts as a dynamic field",
+                trigger, property
+            ),
+            ConversionWarning::LowCoverage {
+                interior,
+                hit_area_percentage,
+                threshold,
+            } => write!(
+                f,
+                "the BSP of interior {} only covers {}% of its surface area, below the {}% threshold",
+                interior, hit_area_percentage, threshold
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpawnKind {
+    PlayerStart,
+    Camera,
+}
+
+// Where the map wants the player or the camera to start, the angles are pitch, yaw and roll in
+// degrees
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpawnPoint {
+    pub kind: SpawnKind,
+    pub classname: String,
+    pub position: Point3F,
+    pub angles: Point3F,
+}
+
+const PLAYER_START_CLASSNAMES: [&str; 2] = ["info_player_start", "info_player_deathmatch"];
+const CAMERA_CLASSNAMES: [&str; 2] = ["observer", "info_camera"];
+
+// The DIFs, the BSP reports of every interior, the brushes that were skipped, the warnings and the
+// spawn points
+pub type ConversionResult = (
+    Vec<Vec<u8>>,
+    Vec<BSPReport>,
+    Vec<SkippedBrush>,
+    Vec<ConversionWarning>,
+    Vec<SpawnPoint>,
+);
+
+// Records the warning and shows it to the user right away
+pub fn report_warning(
+    warnings: &mut Vec<ConversionWarning>,
+    progress_fn: &mut dyn ProgressEventListener,
+    warning: ConversionWarning,
+) {
+    progress_fn.progress(0, 0, format!("Warning: {}", warning), "".to_string());
+    warnings.push(warning);
+}
+
+// Max number of faces that can go in a single interior
+const MAX_INTERIOR_FACES: usize = 16383;
+
+// Constructor's brush type for detail brushes, their faces don't split the BSP so they can't be
+// raycast against, but they still get surfaces and convex hulls for rendering and collision
+pub const DETAIL_BRUSH_TYPE: i32 = 1;
+
+// Material of the brushes that only make up the vehicle collision of the DIF, they aren't part of
+// the interior
+pub const VEHICLE_COLLISION_MATERIAL: &str = "VEHICLE_COLLISION";
+
+pub fn is_vehicle_collision_brush(b: &Brush) -> bool {
+    !b.face.is_empty()
+        && b.face
+            .iter()
+            .all(|f| f.material.eq_ignore_ascii_case(VEHICLE_COLLISION_MATERIAL))
+}
+
+// Upper bound of the winding indices the brush adds to an interior, every face gets its own
+// winding
+fn brush_winding_indices(b: &Brush) -> usize {
+    b.face.iter().map(|f| f.indices.indices.len()).sum()
+}
+
+// Finds the brushes of the detail level that can't be exported, keyed by their index
+fn find_skipped_brushes(d: &DetailLevel, max_winding_indices: usize) -> HashMap<usize, SkipReason> {
+    let mut skipped = HashMap::new();
+    let mut seen_brushes = HashSet::new();
+    for (i, b) in d.interior_map.brushes.brush.iter().enumerate() {
+        if b.face.is_empty() || b.vertices.vertex.is_empty() {
+            skipped.insert(i, SkipReason::Degenerate);
+            continue;
+        }
+        if b.face.len() > MAX_INTERIOR_FACES || brush_winding_indices(b) > max_winding_indices {
+            skipped.insert(i, SkipReason::TooComplex);
+            continue;
+        }
+        // Exact copies of a brush only add z-fighting surfaces
+        let brush_key = (
+            b.owner,
+            b.type_,
+            b.vertices
+                .vertex
+                .iter()
+                .flat_map(|v| [v.pos.x.to_bits(), v.pos.y.to_bits(), v.pos.z.to_bits()])
+                .collect::<Vec<_>>(),
+            b.face
+                .iter()
+                .flat_map(|f| {
+                    [
+                        f.plane.normal.x.to_bits(),
+                        f.plane.normal.y.to_bits(),
+                        f.plane.normal.z.to_bits(),
+                        f.plane.distance.to_bits(),
+                    ]
+                })
+                .collect::<Vec<_>>(),
+        );
+        if !seen_brushes.insert(brush_key) {
+            skipped.insert(i, SkipReason::Duplicate);
+        }
+    }
+    skipped
+}
+
 pub fn convert_csx(
     cscene: &ConstructorScene,
     version: Version,
-    mb_only: bool,
+    config: &ConvertConfig,
     progress_fn: &mut dyn ProgressEventListener,
-) -> (Vec<Vec<u8>>, Vec<BSPReport>) {
+    bsp_cache: &mut BSPCache,
+) -> Result<ConversionResult, ConversionError> {
+    let mut warnings = vec![];
+    let skipped_brushes = cscene
+        .detail_levels
+        .detail_level
+        .iter()
+        .map(|d| find_skipped_brushes(d, config.max_winding_indices))
+        .collect::<Vec<_>>();
+
     // Collect the light entities
     let lights = cscene
         .detail_levels
@@ -589,7 +1546,25 @@ pub fn convert_csx(
                 .iter()
                 .filter(|e| e.classname.starts_with("light_"))
         })
-        .map(|light_ent| Light::new(light_ent))
+        .map(Light::new)
+        .collect::<Result<Vec<_>, _>>()?;
+    // The lightmaps only hold the light that never changes, the animated lights would need light
+    // states which aren't exported
+    let animated_lights = lights.iter().filter(|light| light.is_animated()).count();
+    if animated_lights > 0 {
+        progress_fn.progress(
+            0,
+            0,
+            format!(
+                "Left {} animated lights out of the lightmaps",
+                animated_lights
+            ),
+            "".to_string(),
+        );
+    }
+    let lights = lights
+        .into_iter()
+        .filter(|light| !light.is_animated())
         .collect::<Vec<_>>();
 
     let mut detail_levels = cscene
@@ -611,45 +1586,65 @@ pub fn convert_csx(
                 .iter()
                 .map(|b| b.face.len())
                 .sum();
-            let total_splits = (face_count / 16383) + 1;
+            let winding_index_count: usize = d
+                .interior_map
+                .brushes
+                .brush
+                .iter()
+                .map(|b| brush_winding_indices(b))
+                .sum();
+            let total_splits = (face_count / MAX_INTERIOR_FACES)
+                .max(winding_index_count / config.max_winding_indices)
+                + 1;
 
             let mut split_interiors = vec![];
-            let mut cur_builder = DIFBuilder::new(mb_only);
+            let mut cur_builder = DIFBuilder::new(config);
             let mut cur_face_count = 0;
-            cur_builder.set_ambient(
-                d.interior_map.ambient_color.clone(),
-                d.interior_map.ambient_color_emerg.clone(),
-            );
-            cur_builder.set_lumel_scale(d.interior_map.light_scale);
-            cur_builder.set_geometry_scale(d.interior_map.brush_scale);
-            cur_builder.set_lights(lights.clone());
-            for b in d
+            let mut cur_winding_index_count = 0;
+            configure_builder(&mut cur_builder, d, i, &lights);
+            let collision = config
+                .collision_detail_level
+                .map_or(true, |level| level == i);
+            cur_builder.set_collision(collision);
+            for (_, b) in d
                 .interior_map
                 .brushes
                 .brush
                 .iter()
-                .filter(|b| (b.type_ != 999 && b.type_ != 4) || b.owner == 0)
+                .enumerate()
+                .filter(|(j, _)| !skipped_brushes[i].contains_key(j))
+                .filter(|(_, b)| (b.type_ != 999 && b.type_ != 4) || b.owner == 0)
+                .filter(|(_, b)| !is_vehicle_collision_brush(b))
             {
                 let face_count = b.face.len();
-                if cur_face_count + face_count > 16383 {
+                let winding_index_count = brush_winding_indices(b);
+                if cur_face_count + face_count > MAX_INTERIOR_FACES
+                    || cur_winding_index_count + winding_index_count > config.max_winding_indices
+                {
                     progress_fn.progress(
                         (split_interiors.len() + 1) as u32,
                         total_splits as _,
                         "Exporting interior".to_string(),
                         "Exported interiors".to_string(),
                     );
-                    split_interiors.push(cur_builder.build(progress_fn));
-                    cur_builder = DIFBuilder::new(mb_only);
-                    cur_builder.set_ambient(
-                        d.interior_map.ambient_color.clone(),
-                        d.interior_map.ambient_color_emerg.clone(),
-                    );
-                    cur_builder.set_lumel_scale(d.interior_map.light_scale);
-                    cur_builder.set_geometry_scale(d.interior_map.brush_scale);
-                    cur_builder.set_lights(lights.clone());
+                    if cur_builder.is_bsp_slow() {
+                        report_warning(
+                            &mut warnings,
+                            progress_fn,
+                            ConversionWarning::SlowBSP {
+                                brush_count: cur_builder.brush_count(),
+                            },
+                        );
+                    }
+                    split_interiors.push(cur_builder.build_with_cache(progress_fn, bsp_cache)?);
+                    cur_builder = DIFBuilder::new(config);
+                    configure_builder(&mut cur_builder, d, i, &lights);
+                    cur_builder.set_collision(collision);
                     cur_face_count = 0;
+                    cur_winding_index_count = 0;
                 }
                 cur_face_count += face_count;
+                cur_winding_index_count += winding_index_count;
                 cur_builder.add_brush(b);
             }
             progress_fn.progress(
@@ -658,10 +1653,56 @@ pub fn convert_csx(
                 "Exporting interior".to_string(),
                 "Exported interiors".to_string(),
             );
-            split_interiors.push(cur_builder.build(progress_fn));
-            split_interiors
+            if cur_builder.is_bsp_slow() {
+                report_warning(
+                    &mut warnings,
+                    progress_fn,
+                    ConversionWarning::SlowBSP {
+                        brush_count: cur_builder.brush_count(),
+                    },
+                );
+            }
+            split_interiors.push(cur_builder.build_with_cache(progress_fn, bsp_cache)?);
+            Ok(split_interiors)
         })
-        .collect::<Vec<_>>();
+        .collect::<Result<Vec<_>, ConversionError>>()?;
+
+    // Lights inside solid geometry have all their shadow rays blocked right away
+    if config.strict {
+        cscene
+            .detail_levels
+            .detail_level
+            .iter()
+            .zip(detail_levels.iter())
+            .for_each(|(d, split_interiors)| {
+                d.interior_map
+                    .entities
+                    .entity
+                    .iter()
+                    .filter(|e| e.classname.starts_with("light_"))
+                    .for_each(|e| {
+                        let position = e.origin.unwrap_or(Point3F::new(0.0, 0.0, 0.0));
+                        if split_interiors
+                            .iter()
+                            .any(|(itr, _)| itr.bsp_point_in_solid(position))
+                        {
+                            let name = e
+                                .properties
+                                .get("name")
+                                .cloned()
+                                .unwrap_or_else(|| e.id.to_string());
+                            report_warning(
+                                &mut warnings,
+                                progress_fn,
+                                ConversionWarning::LightInSolid {
+                                    light: name,
+                                    classname: e.classname.clone(),
+                                },
+                            );
+                        }
+                    });
+            });
+    }
 
     let mut reports = vec![];
 
@@ -676,17 +1717,50 @@ pub fn convert_csx(
             .collect_vec(),
     );
 
+    // The vehicle collision comes from the collision detail level only
+    let vehicle_detail_index = config.collision_detail_level.unwrap_or(0);
+    if let Some(d) = cscene.detail_levels.detail_level.get(vehicle_detail_index) {
+        let vehicle_brushes = d
+            .interior_map
+            .brushes
+            .brush
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| !skipped_brushes[vehicle_detail_index].contains_key(j))
+            .filter(|(_, b)| (b.type_ != 999 && b.type_ != 4) || b.owner == 0)
+            .filter(|(_, b)| is_vehicle_collision_brush(b))
+            .map(|(_, b)| b)
+            .collect::<Vec<_>>();
+        if !vehicle_brushes.is_empty() {
+            progress_fn.progress(
+                0,
+                0,
+                "Exporting vehicle collision".to_string(),
+                "Exported vehicle collision".to_string(),
+            );
+            let mut builder = DIFBuilder::new(config);
+            for b in vehicle_brushes {
+                builder.add_brush(b);
+            }
+            dif.vehicle_collision = Some(builder.build_vehicle_collision(progress_fn)?);
+        }
+    }
+
     // Do the MPs
     dif.sub_objects = cscene
         .detail_levels
         .detail_level
         .iter()
-        .flat_map(|d| {
+        .enumerate()
+        .flat_map(|(detail_index, d)| {
             let group_query = d
                 .interior_map
                 .brushes
                 .brush
                 .iter()
+                .enumerate()
+                .filter(|(j, _)| !skipped_brushes[detail_index].contains_key(j))
+                .map(|(_, b)| b)
                 .filter(|b| b.owner != 0 && b.type_ == 999)
                 .group_by(|b| b.owner);
             let groups: Vec<_> = group_query.into_iter().collect();
@@ -696,14 +1770,12 @@ pub fn convert_csx(
                 .sorted_by(|(a, _), (b, _)| a.cmp(b))
                 .enumerate()
                 .map(|(i, (_, g))| {
-                    let mut builder = DIFBuilder::new(mb_only);
-                    builder.set_ambient(
-                        d.interior_map.ambient_color.clone(),
-                        d.interior_map.ambient_color_emerg.clone(),
-                    );
-                    builder.set_lumel_scale(d.interior_map.light_scale);
-                    builder.set_geometry_scale(d.interior_map.brush_scale);
-                    builder.set_lights(lights.clone());
+                    let mut builder = DIFBuilder::new(config);
+                    configure_builder(&mut builder, d, detail_index, &lights);
+                    if let Some(split_method) = config.subobject_split_method {
+                        builder.set_split_method(split_method);
+                    }
+                    builder.set_bake_lightmaps(config.subobject_lightmaps);
                     g.for_each(|b| {
                         builder.add_brush(b);
                     });
@@ -713,13 +1785,24 @@ pub fn convert_csx(
                         "Exporting subobject".to_string(),
                         "Exported subobjects".to_string(),
                     );
-                    let (itr, report) = builder.build(progress_fn);
+                    if builder.is_bsp_slow() {
+                        report_warning(
+                            &mut warnings,
+                            progress_fn,
+                            ConversionWarning::SlowBSP {
+                                brush_count: builder.brush_count(),
+                            },
+                        );
+                    }
+                    let (itr, report) = builder.build_with_cache(progress_fn, bsp_cache)?;
                     reports.push(report);
-                    itr
+                    Ok(itr)
                 })
                 .collect::<Vec<_>>()
         })
-        .collect::<Vec<_>>();
+        .collect::<Result<Vec<_>, ConversionError>>()?;
+
+    let mut exported_trigger_owners = HashSet::new();
 
     // path_nodes for MPs, they come after the MP entity
     let path_node_ents = cscene
@@ -734,6 +1817,12 @@ pub fn convert_csx(
             })
         })
         .collect::<Vec<_>>();
+    if let Some(e) = path_node_ents
+        .iter()
+        .find(|e| e.classname == "path_node" && e.origin.is_none())
+    {
+        return Err(ConversionError::MissingOrigin { entity: e.id });
+    }
     if path_node_ents.len() > 0
         && path_node_ents
             .iter()
@@ -788,27 +1877,35 @@ pub fn convert_csx(
                         .triggers
                         .iter()
                         .map(|t| {
+                            exported_trigger_owners.insert(t.id);
                             let trigger_brushes = cscene
                                 .detail_levels
                                 .detail_level
                                 .iter()
-                                .flat_map(|d| {
+                                .enumerate()
+                                .flat_map(|(detail_index, d)| {
                                     d.interior_map
                                         .brushes
                                         .brush
                                         .iter()
+                                        .enumerate()
+                                        .filter(|(j, _)| {
+                                            !skipped_brushes[detail_index].contains_key(j)
+                                        })
+                                        .map(|(_, b)| b)
                                         .filter(|b| b.owner == t.id)
                                         .collect::<Vec<_>>()
                                 })
                                 .collect::<Vec<_>>();
-                            // Just take the last one - there should only be one
-                            let trigger_bbox =
-                                get_bounding_box_not_owned(trigger_brushes.as_slice());
-
                             let mut tprops = t.properties.clone();
                             if tprops.contains_key("datablock") {
                                 tprops.remove("datablock").unwrap();
                             }
+                            let (tprops, trigger_warnings) =
+                                normalize_trigger_properties(t.id, tprops, config.strict);
+                            for warning in trigger_warnings {
+                                report_warning(&mut warnings, progress_fn, warning);
+                            }
 
                             let len = exported_triggers.len();
                             exported_triggers.push(build_trigger(
@@ -817,8 +1914,7 @@ pub fn convert_csx(
                                     .unwrap_or(&"DefaultTrigger".to_string())
                                     .to_string(),
                                 tprops,
-                                &trigger_bbox.min,
-                                &trigger_bbox.extent(),
+                                trigger_brushes.as_slice(),
                             ));
                             len as u32
                         })
@@ -845,6 +1941,7 @@ pub fn convert_csx(
                                 .parse::<u32>()
                                 .unwrap_or(0),
 
+                            // The origins of the path_nodes were checked above
                             position: v.origin.unwrap(),
                             smoothing_type: v
                                 .properties
@@ -853,7 +1950,14 @@ pub fn convert_csx(
                                 .parse::<u32>()
                                 .unwrap_or(0),
 
-                            rotation: QuatF::from_axis_angle(Vector3::new(1.0, 0.0, 0.0), Rad(0.0)),
+                            // The quaternion property is a quaternion, the rotation property
+                            // an axis and an angle
+                            rotation: match v.properties.get("quaternion") {
+                                Some(quat) => parse_quat(quat),
+                                None => parse_axis_angle(
+                                    v.properties.get("rotation").map_or("", |r| r.as_str()),
+                                ),
+                            },
                         })
                         .collect::<Vec<_>>(),
                 }
@@ -863,6 +1967,40 @@ pub fn convert_csx(
         dif.triggers = exported_triggers;
     }
 
+    // Trigger brushes only get exported as part of the triggers of the MPs
+    let skipped_brushes = cscene
+        .detail_levels
+        .detail_level
+        .iter()
+        .enumerate()
+        .flat_map(|(detail_index, d)| {
+            let skipped = &skipped_brushes[detail_index];
+            let exported_trigger_owners = &exported_trigger_owners;
+            d.interior_map
+                .brushes
+                .brush
+                .iter()
+                .enumerate()
+                .filter_map(move |(j, b)| {
+                    let reason = match skipped.get(&j) {
+                        Some(reason) => *reason,
+                        None if b.type_ == 4
+                            && b.owner != 0
+                            && !exported_trigger_owners.contains(&b.owner) =>
+                        {
+                            SkipReason::FilteredByType
+                        }
+                        None => return None,
+                    };
+                    Some(SkippedBrush {
+                        detail_level: detail_index,
+                        id: b.id,
+                        reason,
+                    })
+                })
+        })
+        .collect::<Vec<_>>();
+
     // progress_fn.progress(0, 0, "Exporting entities".to_string(), "Exported entities");
     //  Do the entities
     dif.game_entities = cscene
@@ -900,25 +2038,70 @@ pub fn convert_csx(
         })
         .collect::<Vec<_>>();
 
-    // The split interiors
+    // The split interiors, either as extra interiors of the main DIF or as separate DIFs
     let split_interiors = detail_levels.remove(0);
-    let mut split_difs = split_interiors
-        .into_iter()
-        .map(|(i, _)| dif_with_interiors(vec![i]))
-        .collect::<Vec<_>>();
+    let mut split_difs = if config.merge_interiors {
+        dif.interiors
+            .extend(split_interiors.into_iter().map(|(i, _)| i));
+        vec![]
+    } else {
+        split_interiors
+            .into_iter()
+            .map(|(i, _)| dif_with_interiors(vec![i]))
+            .collect::<Vec<_>>()
+    };
 
     split_difs.insert(0, dif);
 
     let dif_data = split_difs
         .into_iter()
-        .map(|d| {
+        .map(|mut d| {
+            d.interiors
+                .iter_mut()
+                .chain(d.sub_objects.iter_mut())
+                .for_each(|itr| prepare_alarm_lmap_indices(itr, &version));
             let mut buf = vec![];
-            d.write(&mut buf, &version).unwrap();
-            buf
+            d.write(&mut buf, &version)
+                .map_err(ConversionError::Write)?;
+            Ok(buf)
         })
-        .collect::<Vec<_>>();
+        .collect::<Result<Vec<_>, ConversionError>>()?;
+
+    Ok((
+        dif_data,
+        reports,
+        skipped_brushes,
+        warnings,
+        scene_spawn_points(cscene),
+    ))
+}
 
-    (dif_data, reports)
+// Apply the per detail level settings so that every LOD carries its own ambient and lumel scale
+fn configure_builder(builder: &mut DIFBuilder, d: &DetailLevel, index: usize, lights: &Vec<Light>) {
+    builder.set_ambient(
+        d.interior_map.ambient_color.clone(),
+        d.interior_map.ambient_color_emerg.clone(),
+    );
+    builder.set_lumel_scale(d.interior_map.light_scale);
+    builder.set_geometry_scale(d.interior_map.brush_scale);
+    builder.set_lights(lights.clone());
+
+    // The worldspawn entity holds the detail number and min pixels of this LOD
+    let worldspawn = d
+        .interior_map
+        .entities
+        .entity
+        .iter()
+        .find(|e| e.classname == "worldspawn");
+    let detail_number = worldspawn
+        .and_then(|e| e.properties.get("detail_number"))
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(index as u32);
+    let min_pixels = worldspawn
+        .and_then(|e| e.properties.get("min_pixels"))
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(250);
+    builder.set_detail_level(detail_number, min_pixels);
 }
 
 pub fn dif_with_interiors(interiors: Vec<Interior>) -> Dif {
@@ -934,128 +2117,1493 @@ pub fn dif_with_interiors(interiors: Vec<Interior>) -> Dif {
     }
 }
 
-fn build_trigger(
-    datablock: String,
+// The surfaces get the 0xffffffff "no alarm lightmap" index when exported. The versions before 13
+// store the indices as u8, so it's written as the 0xff they expect, but the TGEA/T3D loaders of
+// the u32 indices of version 13 and up don't accept it, so drop the array when there's no alarm
+// state. With an alarm state every surface has a real index already.
+pub fn prepare_alarm_lmap_indices(interior: &mut Interior, version: &Version) {
+    if interior.has_alarm_state == 0 && version.interior >= 13 {
+        interior.alarm_lmap_indices.clear();
+    }
+}
+
+// Builds an interior made of a single box brush filling the bounds, for distant LODs and occluders
+pub fn build_proxy_interior(
+    bounds: BoxF,
+    material: &str,
+    config: &ConvertConfig,
+) -> Result<Interior, ConversionError> {
+    let (min, max) = (bounds.min, bounds.max);
+    let vertex = |x: f32, y: f32, z: f32| Vertex {
+        pos: Point3F::new(x, y, z),
+    };
+    let vertices = vec![
+        vertex(min.x, min.y, min.z),
+        vertex(max.x, min.y, min.z),
+        vertex(max.x, max.y, min.z),
+        vertex(min.x, max.y, min.z),
+        vertex(min.x, min.y, max.z),
+        vertex(max.x, min.y, max.z),
+        vertex(max.x, max.y, max.z),
+        vertex(min.x, max.y, max.z),
+    ];
+    // The outward normal, the texgen axes and the counter-clockwise winding seen from outside of
+    // each side
+    let sides = [
+        (
+            Point3F::unit_z(),
+            Point3F::unit_x(),
+            Point3F::unit_y(),
+            [4, 5, 6, 7],
+        ),
+        (
+            -Point3F::unit_z(),
+            Point3F::unit_x(),
+            Point3F::unit_y(),
+            [3, 2, 1, 0],
+        ),
+        (
+            Point3F::unit_x(),
+            Point3F::unit_y(),
+            Point3F::unit_z(),
+            [1, 2, 6, 5],
+        ),
+        (
+            -Point3F::unit_x(),
+            Point3F::unit_y(),
+            Point3F::unit_z(),
+            [0, 4, 7, 3],
+        ),
+        (
+            Point3F::unit_y(),
+            Point3F::unit_x(),
+            Point3F::unit_z(),
+            [2, 3, 7, 6],
+        ),
+        (
+            -Point3F::unit_y(),
+            Point3F::unit_x(),
+            Point3F::unit_z(),
+            [0, 1, 5, 4],
+        ),
+    ];
+    // Same texel density as a Constructor face with the default brush scale and texture scale
+    let texgen_scale = 32.0 / 128.0;
+    let face = sides
+        .iter()
+        .enumerate()
+        .map(|(i, (normal, axis_u, axis_v, indices))| Face {
+            id: i as i32,
+            plane: PlaneF {
+                normal: *normal,
+                distance: -vertices[indices[0] as usize].pos.dot(*normal),
+            },
+            material: material.to_string(),
+            texgens: TexGen {
+                plane_x: PlaneF {
+                    normal: axis_u * texgen_scale,
+                    distance: 0.0,
+                },
+                plane_y: PlaneF {
+                    normal: axis_v * texgen_scale,
+                    distance: 0.0,
+                },
+                rot: 0.0,
+                scale: [1.0, 1.0],
+            },
+            tex_div: vec![128, 128],
+            indices: Indices {
+                indices: indices.to_vec(),
+            },
+            face_id: i as i32,
+        })
+        .collect::<Vec<_>>();
+    let brush = Brush {
+        id: 0,
+        owner: 0,
+        type_: 0,
+        transform: MatrixF::identity(),
+        vertices: Vertices { vertex: vertices },
+        face,
+    };
+    let mut builder = DIFBuilder::new(&ConvertConfig {
+        mb_only: true,
+        ..config.clone()
+    });
+    builder.add_brush(&brush);
+    let (interior, _) = builder.build(&mut NoopListener)?;
+    Ok(interior)
+}
+
+// The script fields of the Torque Trigger class
+const TRIGGER_COMMAND_FIELDS: [&str; 3] = ["enterCommand", "leaveCommand", "tickCommand"];
+const TRIGGER_TICK_PERIOD_FIELD: &str = "tickPeriodMS";
+const DEFAULT_TRIGGER_TICK_PERIOD_MS: u32 = 100;
+
+// Gives the trigger fields the case the engine uses and makes sure the tick period is a whole
+// number of milliseconds. The commands are kept as they are, the other properties become dynamic
+// fields which are only reported in strict mode.
+fn normalize_trigger_properties(
+    trigger: i32,
     properties: Dictionary,
-    pos: &Point3F,
-    size: &Point3F,
-) -> Trigger {
+    strict: bool,
+) -> (Dictionary, Vec<ConversionWarning>) {
+    let mut warnings = vec![];
+    let mut normalized = Dictionary::new();
+    for (key, value) in properties.into_iter().sorted() {
+        if let Some(field) = TRIGGER_COMMAND_FIELDS
+            .iter()
+            .find(|field| field.eq_ignore_ascii_case(&key))
+        {
+            normalized.insert(field.to_string(), value);
+        } else if TRIGGER_TICK_PERIOD_FIELD.eq_ignore_ascii_case(&key) {
+            let tick_period = value.trim().parse::<u32>().ok().or_else(|| {
+                value
+                    .trim()
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|ms| ms.is_finite() && *ms >= 0.0)
+                    .map(|ms| ms.round() as u32)
+            });
+            if tick_period.is_none() {
+                warnings.push(ConversionWarning::InvalidTriggerProperty {
+                    trigger,
+                    property: TRIGGER_TICK_PERIOD_FIELD.to_string(),
+                    value: value.clone(),
+                    default: DEFAULT_TRIGGER_TICK_PERIOD_MS.to_string(),
+                });
+            }
+            normalized.insert(
+                TRIGGER_TICK_PERIOD_FIELD.to_string(),
+                tick_period
+                    .unwrap_or(DEFAULT_TRIGGER_TICK_PERIOD_MS)
+                    .to_string(),
+            );
+        } else {
+            if strict {
+                warnings.push(ConversionWarning::UnknownTriggerProperty {
+                    trigger,
+                    property: key.clone(),
+                });
+            }
+            normalized.insert(key, value);
+        }
+    }
+    (normalized, warnings)
+}
+
+fn build_trigger(datablock: String, properties: Dictionary, brushes: &[&Brush]) -> Trigger {
+    // There should only be one brush, use its actual shape if possible, otherwise fall back to the
+    // bounding box of the trigger brushes
+    let polyhedron = match brushes {
+        [brush] => build_brush_polyhedron(brush),
+        _ => None,
+    }
+    .unwrap_or_else(|| {
+        let bbox = get_bounding_box_not_owned(brushes);
+        build_box_polyhedron(&bbox.min, &bbox.extent())
+    });
     Trigger {
         name: "MustChange".to_string(),
         datablock: datablock,
         offset: Point3F::new(0.0, 0.0, 0.0),
         properties: properties,
-        polyhedron: Polyhedron {
-            point_list: vec![
-                Point3F::new(pos.x, pos.y, pos.z + size.z),
-                Point3F::new(pos.x, pos.y + size.y, pos.z + size.z),
-                Point3F::new(pos.x + size.x, pos.y + size.y, pos.z + size.z),
-                Point3F::new(pos.x + size.x, pos.y, pos.z + size.z),
-                Point3F::new(pos.x, pos.y, pos.z),
-                Point3F::new(pos.x, pos.y + size.y, pos.z),
-                Point3F::new(pos.x + size.x, pos.y + size.y, pos.z),
-                Point3F::new(pos.x + size.x, pos.y, pos.z),
-            ],
-            plane_list: vec![
-                PlaneF {
-                    normal: Point3F::new(-1.0, 0.0, 0.0),
-                    distance: pos.x,
-                },
-                PlaneF {
-                    normal: Point3F::new(0.0, 1.0, 0.0),
-                    distance: pos.y + size.y,
-                },
-                PlaneF {
-                    normal: Point3F::new(1.0, 0.0, 0.0),
-                    distance: pos.x + size.x,
-                },
-                PlaneF {
-                    normal: Point3F::new(0.0, -1.0, 0.0),
-                    distance: pos.y,
-                },
-                PlaneF {
-                    normal: Point3F::new(0.0, 0.0, 1.0),
-                    distance: pos.z + size.z,
-                },
-                PlaneF {
-                    normal: Point3F::new(0.0, 0.0, -1.0),
-                    distance: pos.z,
-                },
-            ],
-            edge_list: vec![
-                PolyhedronEdge {
-                    face0: 0,
-                    face1: 4,
-                    vertex0: 0,
-                    vertex1: 1,
-                },
-                PolyhedronEdge {
-                    face0: 5,
-                    face1: 0,
-                    vertex0: 4,
-                    vertex1: 5,
-                },
-                PolyhedronEdge {
-                    face0: 3,
-                    face1: 0,
-                    vertex0: 0,
-                    vertex1: 4,
-                },
-                PolyhedronEdge {
-                    face0: 1,
-                    face1: 4,
-                    vertex0: 1,
-                    vertex1: 2,
-                },
-                PolyhedronEdge {
-                    face0: 5,
-                    face1: 6,
-                    vertex0: 5,
-                    vertex1: 1,
-                },
-                PolyhedronEdge {
-                    face0: 0,
-                    face1: 1,
-                    vertex0: 1,
-                    vertex1: 5,
-                },
-                PolyhedronEdge {
-                    face0: 2,
-                    face1: 4,
-                    vertex0: 2,
-                    vertex1: 3,
-                },
-                PolyhedronEdge {
-                    face0: 5,
-                    face1: 2,
-                    vertex0: 6,
-                    vertex1: 7,
-                },
-                PolyhedronEdge {
-                    face0: 1,
-                    face1: 2,
-                    vertex0: 2,
-                    vertex1: 6,
-                },
-                PolyhedronEdge {
-                    face0: 3,
-                    face1: 4,
-                    vertex0: 3,
-                    vertex1: 0,
-                },
-                PolyhedronEdge {
-                    face0: 5,
-                    face1: 3,
-                    vertex0: 7,
-                    vertex1: 4,
+        polyhedron: polyhedron,
+    }
+}
+
+// Returns None if the brush is open or not convex
+fn build_brush_polyhedron(brush: &Brush) -> Option<Polyhedron> {
+    // Max distance a point can be in front of a plane and still count as convex
+    const CONVEX_EPSILON: f32 = 1e-3;
+
+    let point_list = brush
+        .vertices
+        .vertex
+        .iter()
+        .map(|v| v.pos)
+        .collect::<Vec<_>>();
+    let plane_list = brush
+        .face
+        .iter()
+        .map(|f| f.plane.clone())
+        .collect::<Vec<_>>();
+
+    // Each edge of a closed brush is shared by exactly two faces, the first being the one that
+    // winds it from vertex0 to vertex1
+    let mut edge_faces: BTreeMap<(i32, i32), Vec<usize>> = BTreeMap::new();
+    for (i, f) in brush.face.iter().enumerate() {
+        let indices = &f.indices.indices;
+        for j in 0..indices.len() {
+            let (v0, v1) = (indices[j], indices[(j + 1) % indices.len()]);
+            if v0 == v1 {
+                continue;
+            }
+            if v0 < 0 || v1 < 0 || v0.max(v1) as usize >= point_list.len() {
+                return None;
+            }
+            let faces = edge_faces.entry((v0.min(v1), v0.max(v1))).or_default();
+            if v0 < v1 {
+                faces.insert(0, i);
+            } else {
+                faces.push(i);
+            }
+        }
+    }
+    if edge_faces.is_empty() || edge_faces.values().any(|faces| faces.len() != 2) {
+        return None;
+    }
+
+    if point_list.iter().any(|p| {
+        plane_list
+            .iter()
+            .any(|plane| plane.normal.dot(*p) + plane.distance > CONVEX_EPSILON)
+    }) {
+        return None;
+    }
+
+    Some(Polyhedron {
+        point_list,
+        plane_list,
+        edge_list: edge_faces
+            .into_iter()
+            .map(|((v0, v1), faces)| PolyhedronEdge {
+                face0: faces[0] as u32,
+                face1: faces[1] as u32,
+                vertex0: v0 as u32,
+                vertex1: v1 as u32,
+            })
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn build_box_polyhedron(pos: &Point3F, size: &Point3F) -> Polyhedron {
+    Polyhedron {
+        point_list: vec![
+            Point3F::new(pos.x, pos.y, pos.z + size.z),
+            Point3F::new(pos.x, pos.y + size.y, pos.z + size.z),
+            Point3F::new(pos.x + size.x, pos.y + size.y, pos.z + size.z),
+            Point3F::new(pos.x + size.x, pos.y, pos.z + size.z),
+            Point3F::new(pos.x, pos.y, pos.z),
+            Point3F::new(pos.x, pos.y + size.y, pos.z),
+            Point3F::new(pos.x + size.x, pos.y + size.y, pos.z),
+            Point3F::new(pos.x + size.x, pos.y, pos.z),
+        ],
+        plane_list: vec![
+            PlaneF {
+                normal: Point3F::new(-1.0, 0.0, 0.0),
+                distance: pos.x,
+            },
+            PlaneF {
+                normal: Point3F::new(0.0, 1.0, 0.0),
+                distance: pos.y + size.y,
+            },
+            PlaneF {
+                normal: Point3F::new(1.0, 0.0, 0.0),
+                distance: pos.x + size.x,
+            },
+            PlaneF {
+                normal: Point3F::new(0.0, -1.0, 0.0),
+                distance: pos.y,
+            },
+            PlaneF {
+                normal: Point3F::new(0.0, 0.0, 1.0),
+                distance: pos.z + size.z,
+            },
+            PlaneF {
+                normal: Point3F::new(0.0, 0.0, -1.0),
+                distance: pos.z,
+            },
+        ],
+        edge_list: vec![
+            PolyhedronEdge {
+                face0: 0,
+                face1: 4,
+                vertex0: 0,
+                vertex1: 1,
+            },
+            PolyhedronEdge {
+                face0: 5,
+                face1: 0,
+                vertex0: 4,
+                vertex1: 5,
+            },
+            PolyhedronEdge {
+                face0: 3,
+                face1: 0,
+                vertex0: 0,
+                vertex1: 4,
+            },
+            PolyhedronEdge {
+                face0: 1,
+                face1: 4,
+                vertex0: 1,
+                vertex1: 2,
+            },
+            PolyhedronEdge {
+                face0: 5,
+                face1: 6,
+                vertex0: 5,
+                vertex1: 1,
+            },
+            PolyhedronEdge {
+                face0: 0,
+                face1: 1,
+                vertex0: 1,
+                vertex1: 5,
+            },
+            PolyhedronEdge {
+                face0: 2,
+                face1: 4,
+                vertex0: 2,
+                vertex1: 3,
+            },
+            PolyhedronEdge {
+                face0: 5,
+                face1: 2,
+                vertex0: 6,
+                vertex1: 7,
+            },
+            PolyhedronEdge {
+                face0: 1,
+                face1: 2,
+                vertex0: 2,
+                vertex1: 6,
+            },
+            PolyhedronEdge {
+                face0: 3,
+                face1: 4,
+                vertex0: 3,
+                vertex1: 0,
+            },
+            PolyhedronEdge {
+                face0: 5,
+                face1: 3,
+                vertex0: 7,
+                vertex1: 4,
+            },
+            PolyhedronEdge {
+                face0: 2,
+                face1: 3,
+                vertex0: 3,
+                vertex1: 7,
+            },
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::SplitMethod;
+    use crate::builder::RecordingListener;
+    use dif::io::EngineVersion;
+
+    const ANIMATED: &str = include_str!("../snapshots/animated.csx");
+    const CLIPPED: &str = include_str!("../snapshots/clipped.csx");
+    const SIMPLE: &str = include_str!("../snapshots/simple.csx");
+    const SLIVER: &str = include_str!("../snapshots/sliver.csx");
+    const TRIGGER: &str = include_str!("../snapshots/trigger.csx");
+    const WAYPOINTS: &str = include_str!("../snapshots/waypoints.csx");
+    const WELDED: &str = include_str!("../snapshots/welded.csx");
+
+    // Adds a copy of the first detail level of the scene after it, changed by edit
+    fn with_second_lod(csx: &str, edit: impl Fn(&str) -> String) -> String {
+        let start = csx.find("<DetailLevel>").unwrap();
+        let end = csx.find("</DetailLevel>").unwrap() + "</DetailLevel>".len();
+        let lod = &csx[start..end];
+        format!("{}\n{}{}", &csx[..end], edit(lod), &csx[end..])
+    }
+
+    #[test]
+    fn snap_normal_to_axis_snaps_within_the_angle() {
+        let normal = Point3F::new(0.02, -0.01, -1.0).normalize();
+        assert_eq!(
+            snap_normal_to_axis(normal, 5.0),
+            Some(Point3F::new(0.0, 0.0, -1.0))
+        );
+        assert_eq!(snap_normal_to_axis(normal, 1.0), None);
+        let diagonal = Point3F::new(1.0, 1.0, 0.0).normalize();
+        assert_eq!(snap_normal_to_axis(diagonal, 30.0), None);
+    }
+
+    #[test]
+    fn snap_normal_to_axis_does_not_panic_on_nan() {
+        let normal = Point3F::new(f32::NAN, 0.0, 1.0);
+        snap_normal_to_axis(normal, 10.0);
+    }
+
+    #[test]
+    fn find_skipped_brushes_finds_duplicate_and_degenerate_brushes() {
+        let mut scene = try_parse_scene(SIMPLE.as_bytes()).unwrap();
+        let d = &mut scene.detail_levels.detail_level[0];
+        let brushes = &mut d.interior_map.brushes.brush;
+        let count = brushes.len();
+        let mut duplicate = brushes[0].clone();
+        duplicate.id = 100;
+        brushes.push(duplicate);
+        let mut degenerate = brushes[0].clone();
+        degenerate.id = 101;
+        degenerate.face.clear();
+        brushes.push(degenerate);
+
+        let skipped = find_skipped_brushes(d, usize::MAX);
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(skipped.get(&count), Some(&SkipReason::Duplicate));
+        assert_eq!(skipped.get(&(count + 1)), Some(&SkipReason::Degenerate));
+        assert_eq!(
+            find_skipped_brushes(d, 3).get(&0),
+            Some(&SkipReason::TooComplex)
+        );
+    }
+
+    #[test]
+    fn every_skipped_brush_is_reported_with_its_reason() {
+        let mut scene = try_parse_scene(SIMPLE.as_bytes()).unwrap();
+        let brushes = &mut scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush;
+        let with_id = |id| {
+            let mut b = brushes[0].clone();
+            b.id = id;
+            b
+        };
+        let duplicate = with_id(100);
+        let mut degenerate = with_id(101);
+        degenerate.face.clear();
+        // Every face twice is more winding indices than allowed
+        let mut complex = with_id(102);
+        complex.face.extend(brushes[0].face.clone());
+        // A trigger brush whose owner isn't an exported trigger
+        let mut filtered = with_id(103);
+        filtered.type_ = 4;
+        filtered.owner = 55;
+        brushes.extend([duplicate, degenerate, complex, filtered]);
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+
+        let config = ConvertConfig {
+            max_winding_indices: 30,
+            ..ConvertConfig::DEFAULT
+        };
+        let version = Version {
+            engine: EngineVersion::MBG,
+            dif: 44,
+            interior: 0,
+            material_list: 1,
+            vehicle_collision: 0,
+            force_field: 0,
+        };
+        let (bufs, _, skipped, ..) = convert_csx(
+            &scene,
+            version,
+            &config,
+            &mut NoopListener,
+            &mut BSPCache::new(),
+        )
+        .unwrap();
+        assert!(!bufs.is_empty());
+        let mut reported = skipped
+            .iter()
+            .map(|s| (s.detail_level, s.id, s.reason))
+            .collect::<Vec<_>>();
+        reported.sort_by_key(|(_, id, _)| *id);
+        assert_eq!(
+            reported,
+            vec![
+                (0, 100, SkipReason::Duplicate),
+                (0, 101, SkipReason::Degenerate),
+                (0, 102, SkipReason::TooComplex),
+                (0, 103, SkipReason::FilteredByType),
+            ]
+        );
+    }
+
+    #[test]
+    fn exported_scenes_are_not_transformed_again() {
+        let csx = SIMPLE.replacen(
+            "transform=\"1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1\"",
+            "transform=\"1 0 0 5 0 1 0 -3 0 0 1 2 0 0 0 1\"",
+            1,
+        );
+        let mut scene = try_parse_scene(csx.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let mut exported = try_parse_scene(export_scene_csx(&scene).unwrap().as_bytes()).unwrap();
+        preprocess_csx(&mut exported, false, 0.0, 0.0);
+
+        let positions = |scene: &ConstructorScene| {
+            scene.detail_levels.detail_level[0]
+                .interior_map
+                .brushes
+                .brush
+                .iter()
+                .flat_map(|b| b.vertices.vertex.iter().map(|v| v.pos))
+                .collect::<Vec<_>>()
+        };
+        let (original, reexported) = (positions(&scene), positions(&exported));
+        assert_eq!(original.len(), reexported.len());
+        for (a, b) in original.iter().zip(reexported.iter()) {
+            assert!((a - b).magnitude() < 1e-4, "{:?} moved to {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn reversed_windings_are_counted_and_fixed() {
+        let csx = SIMPLE.replacen("indices=\"4 5 6 7\"", "indices=\"7 6 5 4\"", 1);
+        for fix_windings in [false, true] {
+            let mut scene = try_parse_scene(csx.as_bytes()).unwrap();
+            assert_eq!(preprocess_csx(&mut scene, fix_windings, 0.0, 0.0), 1);
+            let brush = &scene.detail_levels.detail_level[0]
+                .interior_map
+                .brushes
+                .brush[0];
+            let face = &brush.face[0];
+            let winding_normal = get_winding_normal(
+                face.indices
+                    .indices
+                    .iter()
+                    .map(|&i| brush.vertices.vertex[i as usize].pos),
+            );
+            assert_eq!(winding_normal.dot(face.plane.normal) > 0.0, fix_windings);
+        }
+        let mut scene = try_parse_scene(SIMPLE.as_bytes()).unwrap();
+        assert_eq!(preprocess_csx(&mut scene, true, 0.0, 0.0), 0);
+    }
+
+    #[test]
+    fn vertex_snap_moves_vertices_and_planes_to_the_grid() {
+        let csx = SIMPLE.replacen(
+            "transform=\"1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1\"",
+            "transform=\"1 0 0 0.04 0 1 0 0.03 0 0 1 -0.05 0 0 0 1\"",
+            1,
+        );
+        let mut scene = try_parse_scene(csx.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.125, 0.0);
+        for brush in scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush
+            .iter()
+        {
+            for v in brush.vertices.vertex.iter() {
+                let on_grid = v.pos.map(|c| (c / 0.125).fract() == 0.0);
+                assert!(on_grid.x && on_grid.y && on_grid.z, "{:?}", v.pos);
+            }
+            for face in brush.face.iter() {
+                for &i in face.indices.indices.iter() {
+                    let pos = brush.vertices.vertex[i as usize].pos;
+                    assert!((face.plane.normal.dot(pos) + face.plane.distance).abs() < 1e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_scenes_are_errors() {
+        // The parser forgives a cut off closing tag, so only the cuts before it have to fail
+        let closing_tag = SIMPLE.rfind("</ConstructorScene>").unwrap();
+        let bytes = SIMPLE.as_bytes();
+        for len in 0..bytes.len() {
+            let result = try_parse_scene(&bytes[..len]);
+            assert!(
+                len >= closing_tag || result.is_err(),
+                "parsed {} bytes",
+                len
+            );
+        }
+        assert!(try_parse_scene(bytes).is_ok());
+    }
+
+    #[test]
+    fn mirrored_brushes_face_outwards() {
+        let csx = SIMPLE.replacen(
+            "transform=\"1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1\"",
+            "transform=\"-1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1\"",
+            1,
+        );
+        let mut scene = try_parse_scene(csx.as_bytes()).unwrap();
+        assert_eq!(preprocess_csx(&mut scene, false, 0.0, 0.0), 0);
+        let brush = &scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush[0];
+        let centroid = brush.vertices.vertex.iter().map(|v| v.pos).sum::<Point3F>()
+            / brush.vertices.vertex.len() as f32;
+        assert!(centroid.x < 0.0);
+        for face in brush.face.iter() {
+            let points = face
+                .indices
+                .indices
+                .iter()
+                .map(|&i| brush.vertices.vertex[i as usize].pos);
+            let center = points.clone().sum::<Point3F>() / face.indices.indices.len() as f32;
+            assert!(face.plane.normal.dot(center - centroid) > 0.0);
+            assert!(get_winding_normal(points).dot(face.plane.normal) > 0.0);
+        }
+    }
+
+    #[test]
+    fn rotations_are_read_by_their_property() {
+        let quarter_turn = QuatF::from_axis_angle(Vector3::unit_z(), cgmath::Deg(90.0));
+        assert!((parse_axis_angle("0 0 1 90") - quarter_turn).magnitude() < 1e-5);
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        let quat = parse_quat(&format!("0 0 {} {}", half, half));
+        assert!((quat - quarter_turn).magnitude() < 1e-5);
+        // A unit axis with an angle of 1 degree is still an axis and an angle
+        let degree = QuatF::from_axis_angle(Vector3::unit_z(), cgmath::Deg(1.0));
+        assert!((parse_axis_angle("0 0 1 1") - degree).magnitude() < 1e-5);
+
+        let identity = QuatF::from_axis_angle(Vector3::unit_x(), Rad(0.0));
+        assert_eq!(parse_axis_angle(""), identity);
+        assert_eq!(parse_axis_angle("0 0 0 90"), identity);
+        assert_eq!(parse_quat("0 0 0 0"), identity);
+        assert_eq!(parse_quat("1 2 3"), identity);
+    }
+
+    #[test]
+    fn way_points_turn_by_the_rotation_of_their_path_node() {
+        let config = ConvertConfig {
+            bake_lighting: false,
+            ..ConvertConfig::DEFAULT
+        };
+        let (bufs, ..) = crate::convert_csx_to_dif_with_config(
+            WAYPOINTS.to_string(),
+            EngineVersion::MBG,
+            0,
+            &config,
+            &mut NoopListener,
+        )
+        .unwrap();
+        let dif = Dif::from_bytes(&bufs[0]).unwrap().0;
+        let way_points = &dif.interior_path_followers[0].way_points;
+        assert_eq!(way_points.len(), 2);
+        // The first path node has no rotation, the second one is a quarter turn around z
+        let identity = QuatF::from_axis_angle(Vector3::unit_x(), Rad(0.0));
+        assert!((way_points[0].rotation - identity).magnitude() < 1e-5);
+        let quarter_turn = QuatF::from_axis_angle(Vector3::unit_z(), cgmath::Deg(90.0));
+        assert!((way_points[1].rotation - quarter_turn).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn try_parse_scene_rejects_a_zero_light_scale() {
+        assert!(try_parse_scene(SIMPLE.as_bytes()).is_ok());
+        let csx = SIMPLE.replacen("lightScale=\"32\"", "lightScale=\"0\"", 1);
+        assert!(matches!(
+            try_parse_scene(csx.as_bytes()),
+            Err(ParseError::InvalidLightScale { detail_level: 0 })
+        ));
+    }
+
+    #[test]
+    fn zero_area_faces_are_dropped() {
+        let config = ConvertConfig {
+            mb_only: false,
+            ..ConvertConfig::DEFAULT
+        };
+        let mut listener = RecordingListener::new();
+        let (_, reports, ..) = crate::convert_csx_to_dif_with_config(
+            SLIVER.to_string(),
+            EngineVersion::MBG,
+            0,
+            &config,
+            &mut listener,
+        )
+        .unwrap();
+        assert!(listener
+            .events
+            .iter()
+            .any(|(.., status)| status == "Skipped degenerate face 6 of brush 0"));
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].total, 6);
+        assert!(reports[0].hit_area_percentage.is_finite());
+    }
+
+    #[test]
+    fn merged_scenes_keep_their_detail_levels() {
+        let two_lods = with_second_lod(SIMPLE, |lod| lod.to_string());
+        let scenes = [two_lods.as_str(), TRIGGER]
+            .iter()
+            .map(|csx| try_parse_scene(csx.as_bytes()).unwrap())
+            .collect::<Vec<_>>();
+        let merged = merge_scenes(scenes).unwrap();
+        let counts = merged
+            .detail_levels
+            .detail_level
+            .iter()
+            .map(|d| {
+                (
+                    d.interior_map.brushes.brush.len(),
+                    d.interior_map.entities.entity.len(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![(6, 6), (3, 1)]);
+
+        // The brush ids stay unique and the door keeps its brush
+        let d = &merged.detail_levels.detail_level[0];
+        let ids = d
+            .interior_map
+            .brushes
+            .brush
+            .iter()
+            .map(|b| b.id)
+            .collect::<HashSet<_>>();
+        assert_eq!(ids.len(), 6);
+        let door = d
+            .interior_map
+            .entities
+            .entity
+            .iter()
+            .find(|e| e.classname == "Door_Elevator")
+            .unwrap();
+        assert!(d
+            .interior_map
+            .brushes
+            .brush
+            .iter()
+            .any(|b| b.type_ == 999 && b.owner == door.id));
+    }
+
+    #[test]
+    fn every_lod_keeps_its_ambient_color() {
+        let csx = with_second_lod(SIMPLE, |lod| {
+            lod.replace(
+                "ambientColor=\"0 0 0\" ambientColorEmerg=\"0 0 0\"",
+                "ambientColor=\"40 50 60\" ambientColorEmerg=\"70 0 0\"",
+            )
+        });
+        assert!(csx.contains("ambientColor=\"40 50 60\""));
+        let config = ConvertConfig {
+            mb_only: false,
+            bake_lighting: false,
+            ..ConvertConfig::DEFAULT
+        };
+        let (bufs, ..) = crate::convert_csx_to_dif_with_config(
+            csx,
+            EngineVersion::MBG,
+            0,
+            &config,
+            &mut NoopListener,
+        )
+        .unwrap();
+        let (dif, _) = Dif::from_bytes(&bufs[0]).unwrap();
+        let ambient = dif
+            .interiors
+            .iter()
+            .map(|itr| {
+                let (base, alarm) = (&itr.base_ambient_color, &itr.alarm_ambient_color);
+                ([base.r, base.g, base.b], [alarm.r, alarm.g, alarm.b])
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ambient,
+            vec![([0, 0, 0], [0, 0, 0]), ([40, 50, 60], [70, 0, 0])]
+        );
+    }
+
+    #[test]
+    fn hexagonal_prism_trigger_keeps_its_faces() {
+        use cgmath::AbsDiffEq;
+        let scene = try_parse_scene(SIMPLE.as_bytes()).unwrap();
+        let mut brush = scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush[0]
+            .clone();
+        // A prism 2 units tall around a hexagon with corners 2 units from its center
+        let corner = |i: usize| {
+            let angle = i as f32 * std::f32::consts::PI / 3.0;
+            (2.0 * angle.cos(), 2.0 * angle.sin())
+        };
+        brush.vertices.vertex = (0..12)
+            .map(|i| {
+                let (x, y) = corner(i % 6);
+                Vertex {
+                    pos: Point3F::new(x, y, if i < 6 { 0.0 } else { 2.0 }),
+                }
+            })
+            .collect();
+        let mut windings = vec![(0..6).rev().collect::<Vec<i32>>(), (6..12).collect()];
+        windings.extend((0..6).map(|i| vec![i, (i + 1) % 6, (i + 1) % 6 + 6, i + 6]));
+        let template = brush.face[0].clone();
+        brush.face = windings
+            .into_iter()
+            .enumerate()
+            .map(|(i, indices)| {
+                let mut face = template.clone();
+                face.id = i as i32;
+                face.indices.indices = indices;
+                face
+            })
+            .collect();
+        let planes = compute_brush_planes(&brush);
+        brush
+            .face
+            .iter_mut()
+            .zip(planes)
+            .for_each(|(f, plane)| f.plane = plane);
+
+        let polyhedron = build_brush_polyhedron(&brush).unwrap();
+        assert_eq!(polyhedron.point_list.len(), 12);
+        assert_eq!(polyhedron.edge_list.len(), 18);
+        assert_eq!(polyhedron.plane_list.len(), 8);
+        let apothem = 2.0 * (std::f32::consts::PI / 6.0).cos();
+        for (i, plane) in polyhedron.plane_list.iter().enumerate() {
+            match i {
+                0 => assert!(plane
+                    .normal
+                    .abs_diff_eq(&Point3F::new(0.0, 0.0, -1.0), 1e-5)),
+                1 => assert!(plane.normal.abs_diff_eq(&Point3F::new(0.0, 0.0, 1.0), 1e-5)),
+                _ => {
+                    // The sides face out of the middle of their edge of the hexagon
+                    let angle =
+                        (i - 2) as f32 * std::f32::consts::PI / 3.0 + std::f32::consts::PI / 6.0;
+                    let normal = Point3F::new(angle.cos(), angle.sin(), 0.0);
+                    assert!(plane.normal.abs_diff_eq(&normal, 1e-5));
+                    assert!((plane.distance + apothem).abs() < 1e-5);
+                }
+            }
+        }
+        // Every edge joins two different faces
+        assert!(polyhedron.edge_list.iter().all(|e| e.face0 != e.face1));
+    }
+
+    #[test]
+    fn brush_instances_are_placed_copies_of_their_definition() {
+        // The first box of the welded scene becomes a definition, placed once moved along x and
+        // once turned 90 degrees around z and moved along y
+        let start = WELDED.find("<Brush id=\"0\"").unwrap();
+        let second = WELDED.find("<Brush id=\"1\"").unwrap();
+        let end = WELDED.rfind("</Brush>").unwrap() + "</Brush>".len();
+        let instances = concat!(
+            "<BrushInstance id=\"10\" brush=\"0\" ",
+            "transform=\"1 0 0 10 0 1 0 0 0 0 1 0 0 0 0 1\" />\n",
+            "<BrushInstance id=\"11\" brush=\"0\" ",
+            "transform=\"0 -1 0 0 1 0 0 20 0 0 1 0 0 0 0 1\" />\n",
+        );
+        let csx = format!(
+            "{}{}{}\n<BrushDefinitions>\n{}\n</BrushDefinitions>{}",
+            &WELDED[..start],
+            instances,
+            &WELDED[end..WELDED.find("</Brushes>").unwrap() + "</Brushes>".len()],
+            &WELDED[start..second],
+            &WELDED[WELDED.find("</Brushes>").unwrap() + "</Brushes>".len()..],
+        );
+        let mut scene = try_parse_scene(csx.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let brushes = &scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush;
+        assert_eq!(
+            brushes.iter().map(|b| b.id).collect::<Vec<_>>(),
+            vec![10, 11]
+        );
+
+        let bounds = |b: &Brush| {
+            let points = b.vertices.vertex.iter().map(|v| v.pos).collect::<Vec<_>>();
+            BoxF::from_vertices(&points.iter().collect::<Vec<_>>())
+        };
+        let moved = bounds(&brushes[0]);
+        assert_eq!(moved.min, Point3F::new(10.0, 0.0, 0.0));
+        assert_eq!(moved.max, Point3F::new(14.0, 4.0, 4.0));
+        use cgmath::AbsDiffEq;
+        let turned = bounds(&brushes[1]);
+        assert!(turned.min.abs_diff_eq(&Point3F::new(-4.0, 20.0, 0.0), 1e-5));
+        assert!(turned.max.abs_diff_eq(&Point3F::new(0.0, 24.0, 4.0), 1e-5));
+        // The copies share the faces of the definition, with the planes following the transform
+        for b in brushes.iter() {
+            assert_eq!(b.face.len(), 6);
+            let center = bounds(b).center();
+            for f in b.face.iter() {
+                let face_center = f
+                    .indices
+                    .indices
+                    .iter()
+                    .map(|&i| b.vertices.vertex[i as usize].pos)
+                    .sum::<Point3F>()
+                    / f.indices.indices.len() as f32;
+                assert!((f.plane.normal.dot(face_center) + f.plane.distance).abs() < 1e-4);
+                assert!(f.plane.normal.dot(face_center - center) > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn numbers_may_be_separated_by_any_whitespace() {
+        // Every list of numbers gets double spaces and tabs between its values and around them
+        let loose = SIMPLE
+            .split('"')
+            .enumerate()
+            .map(|(i, part)| {
+                let is_number_list = i % 2 == 1
+                    && part.contains(' ')
+                    && part.split(' ').all(|v| v.parse::<f32>().is_ok());
+                match is_number_list {
+                    true => format!(" {}\t", part.replace(' ', "  \t")),
+                    false => part.to_owned(),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\"");
+        assert!(loose.contains("pos=\" 0  \t0  \t0\t\""));
+        let exported = |csx: &str| export_scene_csx(&try_parse_scene(csx.as_bytes()).unwrap());
+        assert_eq!(exported(&loose).unwrap(), exported(SIMPLE).unwrap());
+    }
+
+    #[test]
+    fn trigger_commands_survive_into_the_dif() {
+        let csx = TRIGGER.replace(
+            "<Properties datablock=\"TriggerGotoTarget\" />",
+            "<Properties datablock=\"TriggerGotoTarget\" \
+             entercommand=\"echo(&quot;in&quot;); $count = 1;\" tickPeriodMS=\"250.4\" />",
+        );
+        assert!(csx.contains("entercommand"));
+        let config = ConvertConfig {
+            strict: true,
+            ..ConvertConfig::DEFAULT
+        };
+        let (bufs, _, _, warnings, _) = crate::convert_csx_to_dif_with_config(
+            csx,
+            EngineVersion::MBG,
+            0,
+            &config,
+            &mut NoopListener,
+        )
+        .unwrap();
+        assert!(!warnings
+            .iter()
+            .any(|w| matches!(w, ConversionWarning::UnknownTriggerProperty { .. })));
+        let (dif, _) = Dif::from_bytes(&bufs[0]).unwrap();
+        let trigger = &dif.triggers[0];
+        assert_eq!(trigger.datablock, "TriggerGotoTarget");
+        assert_eq!(
+            trigger.properties.get("enterCommand").map(String::as_str),
+            Some("echo(\"in\"); $count = 1;")
+        );
+        assert_eq!(
+            trigger.properties.get("tickPeriodMS").map(String::as_str),
+            Some("250")
+        );
+        assert!(!trigger.properties.contains_key("entercommand"));
+    }
+
+    #[test]
+    fn sub_objects_use_their_own_split_method() {
+        let bsp_nodes = |split_method, subobject_split_method| {
+            let config = ConvertConfig {
+                split_method,
+                subobject_split_method,
+                ..ConvertConfig::DEFAULT
+            };
+            let (bufs, ..) = crate::convert_csx_to_dif_with_config(
+                TRIGGER.to_string(),
+                EngineVersion::MBG,
+                0,
+                &config,
+                &mut NoopListener,
+            )
+            .unwrap();
+            let (dif, _) = Dif::from_bytes(&bufs[0]).unwrap();
+            (
+                dif.interiors[0].bsp_nodes.len(),
+                dif.sub_objects[0].bsp_nodes.len(),
+            )
+        };
+        let exhaustive = bsp_nodes(SplitMethod::Exhaustive, None);
+        let unsplit = bsp_nodes(SplitMethod::None, None);
+        assert_ne!(exhaustive.1, unsplit.1);
+        // The main interior keeps the global split method, the sub-object gets its own
+        let mixed = bsp_nodes(SplitMethod::Exhaustive, Some(SplitMethod::None));
+        assert_eq!(mixed, (exhaustive.0, unsplit.1));
+    }
+
+    #[test]
+    fn zeroed_planes_are_computed_from_the_windings() {
+        let mut scene = try_parse_scene(SIMPLE.as_bytes()).unwrap();
+        let brushes = &mut scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush;
+        let expected = brushes
+            .iter()
+            .map(|b| b.face.iter().map(|f| f.plane.clone()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        for b in brushes.iter_mut() {
+            b.face.iter_mut().for_each(|f| f.plane = missing_plane());
+        }
+        // The normals face out of the brush whichever way the winding goes
+        brushes[0].face[0].indices.indices.reverse();
+
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let brushes = &scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush;
+        for (b, planes) in brushes.iter().zip(expected.iter()) {
+            for (f, plane) in b.face.iter().zip(planes.iter()) {
+                assert!((f.plane.normal - plane.normal).magnitude() < 1e-5);
+                assert!((f.plane.distance - plane.distance).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn lights_inside_brushes_are_reported_in_strict_mode() {
+        let lights = concat!(
+            "<Entity id=\"5\" classname=\"light_omni\" gametype=\"Torque\" origin=\"2 2 2\">\n",
+            "<Properties name=\"buried\" color=\"255 255 255\" falloff1=\"1\" falloff2=\"20\" />\n",
+            "</Entity>\n",
+            "<Entity id=\"6\" classname=\"light_omni\" gametype=\"Torque\" origin=\"2 2 8\">\n",
+            "<Properties color=\"255 255 255\" falloff1=\"1\" falloff2=\"20\" />\n",
+            "</Entity>\n",
+            "</Entities>",
+        );
+        let csx = WELDED.replacen("</Entities>", lights, 1);
+        let warnings = |strict| {
+            let config = ConvertConfig {
+                strict,
+                ..ConvertConfig::DEFAULT
+            };
+            crate::convert_csx_to_dif_with_config(
+                csx.clone(),
+                EngineVersion::MBG,
+                0,
+                &config,
+                &mut NoopListener,
+            )
+            .unwrap()
+            .3
+            .into_iter()
+            .filter(|w| matches!(w, ConversionWarning::LightInSolid { .. }))
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            warnings(true),
+            vec![ConversionWarning::LightInSolid {
+                light: "buried".to_string(),
+                classname: "light_omni".to_string(),
+            }]
+        );
+        assert!(warnings(false).is_empty());
+    }
+
+    #[test]
+    fn interiors_are_split_before_the_winding_index_limit() {
+        // Three boxes of 24 winding indices each
+        let interiors = |max_winding_indices| {
+            let config = ConvertConfig {
+                max_winding_indices,
+                ..ConvertConfig::DEFAULT
+            };
+            let (bufs, ..) = crate::convert_csx_to_dif_with_config(
+                SIMPLE.to_string(),
+                EngineVersion::MBG,
+                0,
+                &config,
+                &mut NoopListener,
+            )
+            .unwrap();
+            // The split interiors go to DIFs of their own
+            bufs.iter()
+                .flat_map(|buf| Dif::from_bytes(buf).unwrap().0.interiors)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(interiors(usize::MAX).len(), 1);
+        let split = interiors(50);
+        assert_eq!(split.len(), 2);
+        assert_eq!(split.iter().map(|i| i.surfaces.len()).sum::<usize>(), 18);
+        for interior in split.iter() {
+            assert!(interior.indices.len() <= 50);
+            for s in interior.surfaces.iter() {
+                let end = s.winding_start.into_inner() as usize + s.winding_count as usize;
+                assert!(end <= interior.indices.len());
+            }
+        }
+    }
+
+    #[test]
+    fn entity_summary_counts_every_classname() {
+        let light = |id| {
+            format!(
+                "<Entity id=\"{}\" classname=\"light_point\" gametype=\"Torque\" origin=\"4 4 6\">\n\
+                 <Properties color=\"255 255 255\" falloff1=\"1\" falloff2=\"20\" />\n\
+                 </Entity>\n",
+                id
+            )
+        };
+        let csx = TRIGGER.replacen(
+            "</Entities>",
+            &format!("{}{}</Entities>", light(20), light(21)),
+            1,
+        );
+        let scene = try_parse_scene(csx.as_bytes()).unwrap();
+        let summary = scene_entity_summary(&scene);
+        assert_eq!(
+            summary.into_iter().collect::<BTreeMap<_, _>>(),
+            BTreeMap::from([
+                ("Door_Elevator".to_string(), 1),
+                ("light_point".to_string(), 2),
+                ("path_node".to_string(), 2),
+                ("trigger".to_string(), 1),
+                ("worldspawn".to_string(), 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn player_starts_and_cameras_are_reported_as_spawn_points() {
+        let spawns = concat!(
+            "<Entity id=\"5\" classname=\"info_player_start\" gametype=\"Torque\" origin=\"2 3 9\">\n",
+            "<Properties angle=\"90\" />\n",
+            "</Entity>\n",
+            "<Entity id=\"6\" classname=\"observer\" gametype=\"Torque\" origin=\"-4 0 12\">\n",
+            "<Properties angles=\"30 45 0\" />\n",
+            "</Entity>\n",
+            "</Entities>",
+        );
+        let csx = WELDED.replacen("</Entities>", spawns, 1);
+        let (bufs, _, _, _, spawn_points) = crate::convert_csx_to_dif_with_config(
+            csx,
+            EngineVersion::MBG,
+            0,
+            &ConvertConfig::DEFAULT,
+            &mut NoopListener,
+        )
+        .unwrap();
+        assert!(!bufs.is_empty());
+        assert_eq!(
+            spawn_points,
+            vec![
+                SpawnPoint {
+                    kind: SpawnKind::PlayerStart,
+                    classname: "info_player_start".to_string(),
+                    position: Point3F::new(2.0, 3.0, 9.0),
+                    angles: Point3F::new(0.0, 90.0, 0.0),
                 },
-                PolyhedronEdge {
-                    face0: 2,
-                    face1: 3,
-                    vertex0: 3,
-                    vertex1: 7,
+                SpawnPoint {
+                    kind: SpawnKind::Camera,
+                    classname: "observer".to_string(),
+                    position: Point3F::new(-4.0, 0.0, 12.0),
+                    angles: Point3F::new(30.0, 45.0, 0.0),
                 },
-            ],
-        },
+            ]
+        );
+    }
+
+    #[test]
+    fn trigger_planes_are_the_faces_of_the_rotated_brush() {
+        let config = ConvertConfig {
+            bake_lighting: false,
+            ..ConvertConfig::DEFAULT
+        };
+        let (bufs, ..) = crate::convert_csx_to_dif_with_config(
+            TRIGGER.to_string(),
+            EngineVersion::MBG,
+            0,
+            &config,
+            &mut NoopListener,
+        )
+        .unwrap();
+        let dif = Dif::from_bytes(&bufs[0]).unwrap().0;
+        let polyhedron = &dif.triggers[0].polyhedron;
+        assert_eq!(polyhedron.point_list.len(), 8);
+        assert_eq!(polyhedron.edge_list.len(), 12);
+
+        // The trigger brush is turned 45 degrees around z by its transform
+        let scene = try_parse_scene(TRIGGER.as_bytes()).unwrap();
+        let brush = &scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush
+            .iter()
+            .find(|b| b.owner == 10)
+            .unwrap();
+        let turn = Matrix3::from_angle_z(cgmath::Deg(45.0));
+        assert_eq!(polyhedron.plane_list.len(), brush.face.len());
+        for (plane, face) in polyhedron.plane_list.iter().zip(brush.face.iter()) {
+            assert!((plane.normal - turn * face.plane.normal).magnitude() < 1e-5);
+            assert!((plane.distance - face.plane.distance).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn scene_json_has_the_entities_and_moving_platforms() {
+        let mut scene = try_parse_scene(TRIGGER.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let json: serde_json::Value = serde_json::from_str(&export_scene_json(&scene)).unwrap();
+        let entities = json["detail_levels"][0]["entities"].as_array().unwrap();
+        assert_eq!(entities.len(), 5);
+        assert_eq!(entities[2]["classname"], "path_node");
+        assert_eq!(entities[2]["origin"], serde_json::json!([11.0, 11.0, 0.0]));
+        assert_eq!(
+            json["moving_platforms"],
+            serde_json::json!([{
+                "entity": 7,
+                "brushes": [3],
+                "path_nodes": [8, 9],
+                "triggers": [10],
+            }])
+        );
+    }
+
+    #[test]
+    fn brushes_outside_the_clip_bounds_are_dropped() {
+        let brush_ids = |scene: &ConstructorScene| {
+            scene.detail_levels.detail_level[0]
+                .interior_map
+                .brushes
+                .brush
+                .iter()
+                .map(|b| b.id)
+                .collect::<Vec<_>>()
+        };
+        // The boxes of the scene are 4 units wide, every 10 units along x
+        let mut scene = try_parse_scene(CLIPPED.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let bounds = BoxF {
+            min: Point3F::new(-1.0, -1.0, -1.0),
+            max: Point3F::new(12.0, 5.0, 5.0),
+        };
+        // The second box straddles the bounds and is kept whole
+        assert_eq!(clip_scene_to_bounds(&mut scene, &bounds), 4);
+        assert_eq!(brush_ids(&scene), vec![0, 1]);
+        let first_lod = &scene.detail_levels.detail_level[0].interior_map.brushes;
+        assert_eq!(first_lod.brush[1].vertices.vertex.len(), 8);
+
+        // A box only touching the bounds is kept
+        let mut scene = try_parse_scene(CLIPPED.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let bounds = BoxF {
+            min: Point3F::new(20.0, 0.0, 0.0),
+            max: Point3F::new(20.0, 4.0, 4.0),
+        };
+        assert_eq!(clip_scene_to_bounds(&mut scene, &bounds), 5);
+        assert_eq!(brush_ids(&scene), vec![2]);
+
+        // The count is summed over the detail levels
+        let two_lods = with_second_lod(CLIPPED, |lod| lod.to_string());
+        let mut scene = try_parse_scene(two_lods.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        assert_eq!(clip_scene_to_bounds(&mut scene, &bounds), 10);
+        assert!(scene.detail_levels.detail_level.iter().all(|d| d
+            .interior_map
+            .brushes
+            .brush
+            .len()
+            == 1));
+    }
+
+    #[test]
+    fn proxy_interior_is_a_box_of_the_bounds() {
+        let bounds = BoxF {
+            min: Point3F::new(-2.0, 1.0, 0.0),
+            max: Point3F::new(6.0, 4.0, 3.0),
+        };
+        let interior =
+            build_proxy_interior(bounds.clone(), "brick", &ConvertConfig::DEFAULT).unwrap();
+        assert_eq!(interior.surfaces.len(), 6);
+        assert_eq!(interior.points.len(), 8);
+        assert_eq!(interior.bounding_box.min, bounds.min);
+        assert_eq!(interior.bounding_box.max, bounds.max);
+        assert_eq!(interior.material_names, vec!["brick"]);
+        // Every side faces out of the box
+        let center = Point3F::new(2.0, 2.5, 1.5);
+        for surface in interior.surfaces.iter() {
+            let plane = &interior.planes[(*surface.plane_index.inner() & 0x7fff) as usize];
+            let mut normal = interior.normals[*plane.normal_index.inner() as usize];
+            if *surface.plane_index.inner() & 0x8000 != 0 {
+                normal = -normal;
+            }
+            let start = *surface.winding_start.inner() as usize;
+            let point = interior.points[*interior.indices[start].inner() as usize];
+            assert!((point - center).dot(normal) > 0.0);
+        }
+    }
+
+    #[test]
+    fn alarm_lightmap_indices_are_only_dropped_for_new_versions_without_alarm() {
+        let interior = |csx: &str, engine, interior_version, mb_only| {
+            let config = ConvertConfig {
+                mb_only,
+                bake_lighting: false,
+                // The TGEA lightmaps come with a dir map
+                generate_dir_maps: true,
+                ..ConvertConfig::DEFAULT
+            };
+            let (bufs, ..) = crate::convert_csx_to_dif_with_config(
+                csx.to_string(),
+                engine,
+                interior_version,
+                &config,
+                &mut NoopListener,
+            )
+            .unwrap();
+            Dif::from_bytes(&bufs[0]).unwrap().0.interiors.remove(0)
+        };
+        // The old versions keep the 0xff "no alarm lightmap" index of every surface
+        let old = interior(WELDED, EngineVersion::MBG, 0, true);
+        assert_eq!(old.has_alarm_state, 0);
+        assert_eq!(old.alarm_lmap_indices.len(), old.surfaces.len());
+        assert!(old.alarm_lmap_indices.iter().all(|i| *i.inner() == 0xff));
+
+        let new = interior(WELDED, EngineVersion::TGEA, 13, true);
+        assert_eq!(new.has_alarm_state, 0);
+        assert!(new.alarm_lmap_indices.is_empty());
+
+        // The emergency ambient gives the interior an alarm state, its indices are real ones
+        let alarm = interior(
+            include_str!("../snapshots/hard_shadows.csx"),
+            EngineVersion::TGEA,
+            13,
+            false,
+        );
+        assert_ne!(alarm.has_alarm_state, 0);
+        assert_eq!(alarm.alarm_lmap_indices.len(), alarm.surfaces.len());
+        assert!(alarm
+            .alarm_lmap_indices
+            .iter()
+            .all(|i| (*i.inner() as usize) < alarm.light_maps.len()));
+    }
+
+    #[test]
+    fn animated_lights_are_left_out_of_the_lightmaps() {
+        let config = ConvertConfig {
+            mb_only: false,
+            ..ConvertConfig::DEFAULT
+        };
+        let dif = |csx: String, listener: &mut RecordingListener| {
+            let (bufs, ..) = crate::convert_csx_to_dif_with_config(
+                csx,
+                EngineVersion::MBG,
+                0,
+                &config,
+                listener,
+            )
+            .unwrap();
+            Dif::from_bytes(&bufs[0]).unwrap().0
+        };
+        let mut listener = RecordingListener::new();
+        let animated = dif(ANIMATED.to_string(), &mut listener);
+        assert!(listener
+            .events
+            .iter()
+            .any(|(_, _, status, _)| status == "Left 2 animated lights out of the lightmaps"));
+        for interior in animated.interiors.iter() {
+            assert_eq!(
+                interior.num_light_state_entries as usize,
+                interior.state_datas.len()
+            );
+        }
+
+        // The lightmaps are the same as with only the omni light
+        let start = ANIMATED.find("<Entity id=\"5\"").unwrap();
+        let end = ANIMATED.find("<Entity id=\"7\"").unwrap();
+        let static_only = format!("{}{}", &ANIMATED[..start], &ANIMATED[end..]);
+        let mut listener = RecordingListener::new();
+        let omni = dif(static_only, &mut listener);
+        assert!(!listener
+            .events
+            .iter()
+            .any(|(_, _, status, _)| status.contains("animated lights")));
+        let light_maps = |dif: &Dif| {
+            dif.interiors[0]
+                .light_maps
+                .iter()
+                .map(|l| l.light_map.data.clone())
+                .collect::<Vec<_>>()
+        };
+        assert!(!light_maps(&animated).is_empty());
+        assert_eq!(light_maps(&animated), light_maps(&omni));
+    }
+
+    #[test]
+    fn sub_objects_can_have_ambient_only_lightmaps() {
+        // A light over the main floor and the platform
+        let light = concat!(
+            "<Entity id=\"11\" classname=\"light_omni\" gametype=\"Torque\" origin=\"8 8 6\">\n",
+            "<Properties color=\"255 255 255\" falloff1=\"1\" falloff2=\"30\" />\n",
+            "</Entity>\n",
+            "</Entities>",
+        );
+        let csx = TRIGGER.replacen("</Entities>", light, 1);
+        let dif = |subobject_lightmaps| {
+            let config = ConvertConfig {
+                mb_only: false,
+                subobject_lightmaps,
+                ..ConvertConfig::DEFAULT
+            };
+            let (bufs, ..) = crate::convert_csx_to_dif_with_config(
+                csx.clone(),
+                EngineVersion::MBG,
+                0,
+                &config,
+                &mut NoopListener,
+            )
+            .unwrap();
+            Dif::from_bytes(&bufs[0]).unwrap().0
+        };
+        let colors = |interior: &Interior, i: usize| {
+            image::load_from_memory(&interior.light_maps[i].light_map.data)
+                .unwrap()
+                .to_rgb8()
+                .pixels()
+                .map(|p| p.0)
+                .collect::<HashSet<_>>()
+        };
+        let baked = dif(true);
+        let ambient = dif(false);
+        assert!(colors(&baked.sub_objects[0], 0).len() > 1);
+        // The main interior is baked either way
+        assert!(colors(&ambient.interiors[0], 0).len() > 1);
+
+        // One lightmap of the 30 30 30 ambient, and one of the black emergency ambient for the
+        // alarm state
+        let sub_object = &ambient.sub_objects[0];
+        assert_eq!(sub_object.light_maps.len(), 2);
+        assert_eq!(sub_object.has_alarm_state, 1);
+        assert_eq!(colors(sub_object, 0), HashSet::from([[30, 30, 30]]));
+        assert_eq!(colors(sub_object, 1), HashSet::from([[0, 0, 0]]));
+        assert!(sub_object
+            .normal_lmap_indices
+            .iter()
+            .all(|i| *i.inner() == 0));
+        assert!(sub_object
+            .alarm_lmap_indices
+            .iter()
+            .all(|i| *i.inner() == 1));
+    }
+
+    #[test]
+    fn only_the_collision_detail_level_has_collision() {
+        let csx = with_second_lod(SIMPLE, |lod| lod.to_string());
+        // The hull and solid leaf counts of both detail levels
+        let collision = |collision_detail_level| {
+            let config = ConvertConfig {
+                collision_detail_level,
+                ..ConvertConfig::DEFAULT
+            };
+            let (bufs, ..) = crate::convert_csx_to_dif_with_config(
+                csx.clone(),
+                EngineVersion::MBG,
+                0,
+                &config,
+                &mut NoopListener,
+            )
+            .unwrap();
+            let (dif, _) = Dif::from_bytes(&bufs[0]).unwrap();
+            assert_eq!(dif.interiors.len(), 2);
+            dif.interiors
+                .iter()
+                .map(|itr| {
+                    assert!(!itr.surfaces.is_empty());
+                    (itr.convex_hulls.len(), itr.bsp_solid_leaves.len())
+                })
+                .collect::<Vec<_>>()
+        };
+        let both = collision(None);
+        assert!(both.iter().all(|&(hulls, leaves)| hulls == 3 && leaves > 0));
+        assert_eq!(collision(Some(0)), vec![both[0], (0, 0)]);
+        assert_eq!(collision(Some(1)), vec![(0, 0), both[1]]);
     }
 }
@@ -3,23 +3,43 @@ pub mod builder;
 pub mod csx;
 pub mod light;
 pub mod lightmap;
-use std::io::Cursor;
-
-use bsp::BSP_CONFIG;
-use builder::{BSPReport, ProgressEventListener};
-use builder::{PLANE_EPSILON, POINT_EPSILON};
+use bsp::BSPCache;
+use bsp::ConvertConfig;
+use builder::BSPReport;
+use builder::CoordBinMode;
+use builder::ProgressEventListener;
+use csx::ConversionError;
+use csx::ConversionResult;
+use csx::ConversionWarning;
 use dif::io::EngineVersion;
 use dif::io::Version;
-use quick_xml::de::Deserializer;
-use serde::Deserialize;
+use dif::io::Writable;
+use std::ops::RangeInclusive;
 
 use crate::bsp::SplitMethod;
 
+use crate::builder::get_bounding_box_not_owned;
+use crate::csx::build_proxy_interior;
+use crate::csx::clip_scene_to_bounds;
 use crate::csx::convert_csx;
+use crate::csx::dif_with_interiors;
+use crate::csx::find_missing_materials;
+use crate::csx::merge_scenes;
+use crate::csx::prepare_alarm_lmap_indices;
 use crate::csx::preprocess_csx;
+use crate::csx::report_warning;
+use crate::csx::try_parse_scene;
 
-static mut MB_ONLY: bool = true;
+static mut CONVERT_CONFIG: ConvertConfig = ConvertConfig::DEFAULT;
 
+/// Sets the split method and epsilons convert_csx_to_dif converts with, along with the MB only
+/// option, the other options keep their defaults. Only kept for the callers that predate
+/// ConvertConfig, convert_csx_to_dif_with_config takes the whole config instead.
+///
+/// # Safety
+///
+/// The options are global, so this must not be called while another thread is converting with
+/// convert_csx_to_dif.
 pub unsafe fn set_convert_configuration(
     mb_only: bool,
     point_epsilon: f32,
@@ -27,27 +47,228 @@ pub unsafe fn set_convert_configuration(
     split_method: SplitMethod,
 ) {
     unsafe {
-        BSP_CONFIG.epsilon = plane_epsilon;
-        BSP_CONFIG.split_method = split_method;
-        POINT_EPSILON = point_epsilon;
-        PLANE_EPSILON = plane_epsilon;
-        MB_ONLY = mb_only;
+        CONVERT_CONFIG = ConvertConfig {
+            mb_only,
+            split_method,
+            point_epsilon,
+            plane_epsilon,
+            ..ConvertConfig::DEFAULT
+        };
     }
 }
 
+// The engines the DIFs can be exported for, along with the interior versions each of them reads.
+// MBG and TGE only read the version 0 surface layout, the later versions are TGEA/T3D only.
+pub fn supported_versions() -> Vec<(EngineVersion, RangeInclusive<u32>)> {
+    vec![
+        (EngineVersion::MBG, 0..=0),
+        (EngineVersion::TGE, 0..=0),
+        (EngineVersion::TGEA, 0..=13),
+        (EngineVersion::T3D, 0..=13),
+    ]
+}
+
+// Converts with the options of set_convert_configuration
 pub fn convert_csx_to_dif(
     csxbuf: String,
     engine_ver: EngineVersion,
     interior_version: u32,
     progress_fn: &mut dyn ProgressEventListener,
-) -> (Vec<Vec<u8>>, Vec<BSPReport>) {
-    let cur = Cursor::new(csxbuf);
-    let reader = std::io::BufReader::new(cur);
-    let mut des = Deserializer::from_reader(reader);
-    let mut cscene = csx::ConstructorScene::deserialize(&mut des).unwrap();
+) -> Result<ConversionResult, ConversionError> {
+    let config = unsafe { (*std::ptr::addr_of!(CONVERT_CONFIG)).clone() };
+    convert_csx_to_dif_with_config(csxbuf, engine_ver, interior_version, &config, progress_fn)
+}
+
+pub fn convert_csx_to_dif_with_config(
+    csxbuf: String,
+    engine_ver: EngineVersion,
+    interior_version: u32,
+    config: &ConvertConfig,
+    progress_fn: &mut dyn ProgressEventListener,
+) -> Result<ConversionResult, ConversionError> {
+    convert_csx_to_dif_cached(
+        csxbuf,
+        engine_ver,
+        interior_version,
+        config,
+        progress_fn,
+        &mut BSPCache::new(),
+    )
+}
+
+// Converts reusing the BSP trees in bsp_cache for interiors whose geometry didn't change, the
+// cache is updated with the trees built during this conversion.
+pub fn convert_csx_to_dif_cached(
+    csxbuf: String,
+    engine_ver: EngineVersion,
+    interior_version: u32,
+    config: &ConvertConfig,
+    progress_fn: &mut dyn ProgressEventListener,
+    bsp_cache: &mut BSPCache,
+) -> Result<ConversionResult, ConversionError> {
+    convert_combined_csx_to_dif_cached(
+        vec![csxbuf],
+        engine_ver,
+        interior_version,
+        config,
+        progress_fn,
+        bsp_cache,
+    )
+}
+
+// Converts several scenes into a single DIF, the brushes and entities of every detail level are
+// combined with those of the same detail level of the other scenes.
+pub fn convert_combined_csx_to_dif_cached(
+    csxbufs: Vec<String>,
+    engine_ver: EngineVersion,
+    interior_version: u32,
+    config: &ConvertConfig,
+    progress_fn: &mut dyn ProgressEventListener,
+    bsp_cache: &mut BSPCache,
+) -> Result<ConversionResult, ConversionError> {
+    let mut cscenes = csxbufs
+        .iter()
+        .map(|csxbuf| try_parse_scene(csxbuf.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()?;
 
     // Transform the vertices and planes to absolute coords, also assign unique ids to face
-    preprocess_csx(&mut cscene);
+    let reversed_faces: usize = cscenes
+        .iter_mut()
+        .map(|cscene| {
+            preprocess_csx(
+                cscene,
+                config.fix_windings,
+                config.vertex_snap,
+                config.axis_snap_angle,
+            )
+        })
+        .sum();
+    let mut cscene = merge_scenes(cscenes).ok_or(ConversionError::NoScenes)?;
+    if let Some(bounds) = &config.clip_bounds {
+        let dropped = clip_scene_to_bounds(&mut cscene, bounds);
+        progress_fn.progress(
+            0,
+            0,
+            format!("Dropped {} brushes outside the clip bounds", dropped),
+            "".to_string(),
+        );
+    }
+    let mut warnings = vec![];
+    if reversed_faces > 0 {
+        if config.fix_windings {
+            progress_fn.progress(
+                0,
+                0,
+                format!("Fixed {} faces with reversed winding", reversed_faces),
+                "".to_string(),
+            );
+        } else {
+            report_warning(
+                &mut warnings,
+                progress_fn,
+                ConversionWarning::ReversedWindings {
+                    count: reversed_faces,
+                },
+            );
+        }
+    }
+    if let Some(available_materials) = &config.materials_manifest {
+        for warning in find_missing_materials(&cscene, available_materials) {
+            report_warning(&mut warnings, progress_fn, warning);
+        }
+    }
+    let version = Version {
+        engine: engine_ver,
+        dif: 44,
+        interior: interior_version,
+        material_list: 1,
+        vehicle_collision: 0,
+        force_field: 0,
+    };
+    // MBG and TGE don't read the dir maps
+    let config = ConvertConfig {
+        generate_dir_maps: config.generate_dir_maps && !version.is_tge(),
+        coord_bin_mode: Some(
+            config
+                .coord_bin_mode
+                .unwrap_or_else(|| CoordBinMode::for_engine(&version.engine)),
+        ),
+        ..config.clone()
+    };
+    let (mut bufs, mut reports, skipped_brushes, conversion_warnings, spawn_points) =
+        convert_csx(&cscene, version, &config, progress_fn, bsp_cache)?;
+    warnings.extend(conversion_warnings);
+    check_coverage(
+        &mut reports,
+        config.coverage_threshold,
+        &mut warnings,
+        progress_fn,
+    );
+    let has_missing_materials = warnings
+        .iter()
+        .any(|w| matches!(w, ConversionWarning::MissingMaterial { .. }));
+    if (config.deny_warnings && !warnings.is_empty()) || (config.strict && has_missing_materials) {
+        bufs.clear();
+    }
+    Ok((bufs, reports, skipped_brushes, warnings, spawn_points))
+}
+
+// Fails the reports whose BSP covers less of the surface area than the threshold and warns about
+// them. Interiors without surfaces have nothing to cover, so they always pass.
+fn check_coverage(
+    reports: &mut [BSPReport],
+    threshold: f32,
+    warnings: &mut Vec<ConversionWarning>,
+    progress_fn: &mut dyn ProgressEventListener,
+) {
+    for (i, r) in reports.iter_mut().enumerate() {
+        r.passed = r.total == 0 || r.hit_area_percentage >= threshold;
+        if !r.passed {
+            report_warning(
+                warnings,
+                progress_fn,
+                ConversionWarning::LowCoverage {
+                    interior: i,
+                    hit_area_percentage: r.hit_area_percentage,
+                    threshold,
+                },
+            );
+        }
+    }
+}
+
+// Converts the scene into a DIF with a single box interior covering the bounds of the first detail
+// level, textured with the given material
+pub fn convert_csx_to_proxy_dif(
+    csxbuf: String,
+    engine_ver: EngineVersion,
+    interior_version: u32,
+    material: &str,
+    config: &ConvertConfig,
+) -> Result<Vec<u8>, ConversionError> {
+    let mut cscene = try_parse_scene(csxbuf.as_bytes())?;
+    preprocess_csx(
+        &mut cscene,
+        config.fix_windings,
+        config.vertex_snap,
+        config.axis_snap_angle,
+    );
+    // Same brushes as the main interior, the moving platform and trigger brushes aren't part of it
+    let brushes = cscene
+        .detail_levels
+        .detail_level
+        .first()
+        .map(|d| {
+            d.interior_map
+                .brushes
+                .brush
+                .iter()
+                .filter(|b| (b.type_ != 999 && b.type_ != 4) || b.owner == 0)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let mut interior =
+        build_proxy_interior(get_bounding_box_not_owned(&brushes), material, config)?;
     let version = Version {
         engine: engine_ver,
         dif: 44,
@@ -56,6 +277,129 @@ pub fn convert_csx_to_dif(
         vehicle_collision: 0,
         force_field: 0,
     };
-    let buf = convert_csx(&cscene, version, unsafe { MB_ONLY }, progress_fn);
-    buf
+    prepare_alarm_lmap_indices(&mut interior, &version);
+    let mut buf = vec![];
+    dif_with_interiors(vec![interior])
+        .write(&mut buf, &version)
+        .map_err(ConversionError::Write)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{NoopListener, RecordingListener};
+
+    fn report(hit_area_percentage: f32, total: usize) -> BSPReport {
+        BSPReport {
+            balance_factor: 0,
+            hit: 0,
+            total,
+            hit_area_percentage,
+            passed: true,
+        }
+    }
+
+    #[test]
+    fn low_coverage_fails_the_report_with_a_warning() {
+        let mut reports = [report(100.0, 6), report(50.0, 6), report(0.0, 0)];
+        let mut warnings = vec![];
+        let mut listener = RecordingListener::new();
+        check_coverage(&mut reports, 95.0, &mut warnings, &mut listener);
+        assert!(reports[0].passed);
+        assert!(!reports[1].passed);
+        // Nothing to cover
+        assert!(reports[2].passed);
+        assert_eq!(
+            warnings,
+            vec![ConversionWarning::LowCoverage {
+                interior: 1,
+                hit_area_percentage: 50.0,
+                threshold: 95.0,
+            }]
+        );
+        assert_eq!(listener.events.len(), 1);
+        assert!(listener.events[0]
+            .2
+            .starts_with("Warning: the BSP of interior 1"));
+    }
+
+    #[test]
+    fn combined_scenes_keep_the_rooms_and_entities_of_both() {
+        // The welded room moved away from the trigger scene so their brushes don't touch
+        let room = include_str!("../snapshots/welded.csx").replace(
+            "transform=\"1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1\"",
+            "transform=\"1 0 0 100 0 1 0 0 0 0 1 0 0 0 0 1\"",
+        );
+        let trigger = include_str!("../snapshots/trigger.csx").to_string();
+        let convert = |csxbufs: Vec<String>| {
+            let (bufs, ..) = convert_combined_csx_to_dif_cached(
+                csxbufs,
+                EngineVersion::MBG,
+                0,
+                &ConvertConfig::DEFAULT,
+                &mut NoopListener,
+                &mut BSPCache::new(),
+            )
+            .unwrap();
+            assert_eq!(bufs.len(), 1);
+            dif::dif::Dif::from_bytes(&bufs[0]).unwrap().0
+        };
+        let first = convert(vec![room.clone()]);
+        let second = convert(vec![trigger.clone()]);
+        let combined = convert(vec![room, trigger]);
+
+        assert_eq!(combined.interiors.len(), 1);
+        assert_eq!(
+            combined.interiors[0].surfaces.len(),
+            first.interiors[0].surfaces.len() + second.interiors[0].surfaces.len()
+        );
+        let bounds = &combined.interiors[0].bounding_box;
+        assert_eq!(bounds.min.x, 0.0);
+        assert_eq!(bounds.max.x, 108.0);
+        // The shared grass material is only listed once
+        assert_eq!(
+            combined.interiors[0].material_names,
+            vec!["grass".to_string(), "stone".to_string()]
+        );
+        assert_eq!(
+            combined.triggers.len(),
+            first.triggers.len() + second.triggers.len()
+        );
+        assert_eq!(
+            combined.sub_objects.len(),
+            first.sub_objects.len() + second.sub_objects.len()
+        );
+        assert_eq!(
+            combined.interior_path_followers.len(),
+            first.interior_path_followers.len() + second.interior_path_followers.len()
+        );
+        assert!(!combined.triggers.is_empty() && !combined.interior_path_followers.is_empty());
+    }
+
+    #[test]
+    fn every_supported_version_converts() {
+        let versions = supported_versions();
+        assert_eq!(
+            versions,
+            vec![
+                (EngineVersion::MBG, 0..=0),
+                (EngineVersion::TGE, 0..=0),
+                (EngineVersion::TGEA, 0..=13),
+                (EngineVersion::T3D, 0..=13),
+            ]
+        );
+        // The newest interior version of every engine
+        for (engine, interior_versions) in versions {
+            let (bufs, ..) = convert_csx_to_dif_with_config(
+                include_str!("../snapshots/welded.csx").to_string(),
+                engine,
+                *interior_versions.end(),
+                &ConvertConfig::DEFAULT,
+                &mut NoopListener,
+            )
+            .unwrap();
+            assert!(!bufs[0].is_empty());
+        }
+    }
 }
@@ -1,7 +1,8 @@
-use cgmath::MetricSpace;
+use cgmath::{InnerSpace, MetricSpace};
 use dif::types::{ColorI, Point3F};
 
 use crate::csx;
+use crate::csx::ConversionError;
 
 #[derive(Copy, Clone)]
 pub enum Light {
@@ -100,18 +101,88 @@ pub enum Light {
     },
 }
 
-fn make_color(v: Vec<u8>) -> ColorI {
-    ColorI {
+// Colors are usually written as 0-255 integers, but some exporters write 0-1 floats like
+// "1.0 0.5 0.0" instead, those get scaled up to 0-255. None if there aren't three components or
+// they aren't numbers.
+fn make_color(color: &str) -> Option<ColorI> {
+    let components = color.split_whitespace().collect::<Vec<_>>();
+    if components.len() < 3 {
+        return None;
+    }
+    let v = if components.iter().any(|c| c.contains('.')) {
+        let floats = components
+            .iter()
+            .map(|c| c.parse::<f32>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let scale = if floats.iter().all(|f| *f <= 1.0) {
+            255.0
+        } else {
+            1.0
+        };
+        floats
+            .iter()
+            .map(|f| (f * scale).round().clamp(0.0, 255.0) as u8)
+            .collect::<Vec<_>>()
+    } else {
+        components
+            .iter()
+            .map(|c| c.parse::<u8>().ok())
+            .collect::<Option<Vec<_>>>()?
+    };
+    Some(ColorI {
         r: v[0],
         g: v[1],
         b: v[2],
         a: 255,
+    })
+}
+
+// The color in the property of the light entity, or the default if it doesn't have it
+fn entity_color(ent: &csx::Entity, key: &str, default: &str) -> Result<ColorI, ConversionError> {
+    let value = ent.properties.get(key).map_or(default, |v| v.as_str());
+    make_color(value).ok_or_else(|| ConversionError::BadColor {
+        entity: ent.id,
+        value: value.to_string(),
+    })
+}
+
+// How the point and omni lights fade from their inner radius out to their outer radius
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum AttenuationModel {
+    // Straight line from full brightness at the inner radius to nothing at the outer radius
+    #[default]
+    Linear,
+    // Falls off with the square of the distance like real lights, windowed to reach nothing at the
+    // outer radius
+    InverseSquare,
+    // Eases out of the inner radius and into the outer radius
+    Smoothstep,
+}
+
+impl AttenuationModel {
+    // The brightness at the distance from the light, the distance must be between the radii
+    pub fn falloff(&self, len: f32, inner: f32, outer: f32) -> f32 {
+        let t = if outer > inner {
+            ((len - inner) / (outer - inner)).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        match self {
+            AttenuationModel::Linear => 1.0 - t,
+            AttenuationModel::InverseSquare => {
+                // The lights without an inner radius are at full brightness within a unit of them
+                let reference = inner.max(1.0);
+                let falloff = reference / len.max(reference);
+                falloff * falloff * (1.0 - t * t) * (1.0 - t * t)
+            }
+            AttenuationModel::Smoothstep => 1.0 - t * t * (3.0 - 2.0 * t),
+        }
     }
 }
 
 impl Light {
-    pub fn new(ent: &csx::Entity) -> Self {
-        match ent.classname.as_str() {
+    pub fn new(ent: &csx::Entity) -> Result<Self, ConversionError> {
+        Ok(match ent.classname.as_str() {
             "light_point" => Light::Point {
                 position: ent
                     .origin
@@ -121,15 +192,7 @@ impl Light {
                         z: 0.0,
                     })
                     .clone(),
-                color: make_color(
-                    ent.properties
-                        .get("color")
-                        .unwrap_or(&"255 255 255".to_string())
-                        .trim()
-                        .split(' ')
-                        .map(|v| v.parse::<u8>().unwrap())
-                        .collect::<Vec<u8>>(),
-                ),
+                color: entity_color(ent, "color", "255 255 255")?,
                 intensity: ent
                     .properties
                     .get("intensity")
@@ -158,15 +221,7 @@ impl Light {
                         z: 0.0,
                     })
                     .clone(),
-                color: make_color(
-                    ent.properties
-                        .get("color")
-                        .unwrap_or(&"255 255 255".to_string())
-                        .trim()
-                        .split(' ')
-                        .map(|v| v.parse::<u8>().unwrap())
-                        .collect::<Vec<u8>>(),
-                ),
+                color: entity_color(ent, "color", "255 255 255")?,
                 intensity: ent
                     .properties
                     .get("intensity")
@@ -300,51 +355,11 @@ impl Light {
                     })
                     .clone(),
                 color: [
-                    make_color(
-                        ent.properties
-                            .get("color1")
-                            .unwrap_or(&"255 255 255".to_string())
-                            .trim()
-                            .split(' ')
-                            .map(|v| v.parse::<u8>().unwrap())
-                            .collect::<Vec<u8>>(),
-                    ),
-                    make_color(
-                        ent.properties
-                            .get("color2")
-                            .unwrap_or(&"0 0 0".to_string())
-                            .trim()
-                            .split(' ')
-                            .map(|v| v.parse::<u8>().unwrap())
-                            .collect::<Vec<u8>>(),
-                    ),
-                    make_color(
-                        ent.properties
-                            .get("color3")
-                            .unwrap_or(&"0 0 0".to_string())
-                            .trim()
-                            .split(' ')
-                            .map(|v| v.parse::<u8>().unwrap())
-                            .collect::<Vec<u8>>(),
-                    ),
-                    make_color(
-                        ent.properties
-                            .get("color4")
-                            .unwrap_or(&"0 0 0".to_string())
-                            .trim()
-                            .split(' ')
-                            .map(|v| v.parse::<u8>().unwrap())
-                            .collect::<Vec<u8>>(),
-                    ),
-                    make_color(
-                        ent.properties
-                            .get("color5")
-                            .unwrap_or(&"0 0 0".to_string())
-                            .trim()
-                            .split(' ')
-                            .map(|v| v.parse::<u8>().unwrap())
-                            .collect::<Vec<u8>>(),
-                    ),
+                    entity_color(ent, "color1", "255 255 255")?,
+                    entity_color(ent, "color2", "0 0 0")?,
+                    entity_color(ent, "color3", "0 0 0")?,
+                    entity_color(ent, "color4", "0 0 0")?,
+                    entity_color(ent, "color5", "0 0 0")?,
                 ],
                 speed: ent
                     .properties
@@ -380,15 +395,7 @@ impl Light {
                         z: 0.0,
                     })
                     .clone(),
-                color: make_color(
-                    ent.properties
-                        .get("color")
-                        .unwrap_or(&"255 255 255".to_string())
-                        .trim()
-                        .split(' ')
-                        .map(|v| v.parse::<u8>().unwrap())
-                        .collect::<Vec<u8>>(),
-                ),
+                color: entity_color(ent, "color", "255 255 255")?,
                 falloff1: ent
                     .properties
                     .get("falloff1")
@@ -412,24 +419,8 @@ impl Light {
                     })
                     .clone(),
                 color: [
-                    make_color(
-                        ent.properties
-                            .get("color1")
-                            .unwrap_or(&"255 255 255".to_string())
-                            .trim()
-                            .split(' ')
-                            .map(|v| v.parse::<u8>().unwrap())
-                            .collect::<Vec<u8>>(),
-                    ),
-                    make_color(
-                        ent.properties
-                            .get("color2")
-                            .unwrap_or(&"0 0 0".to_string())
-                            .trim()
-                            .split(' ')
-                            .map(|v| v.parse::<u8>().unwrap())
-                            .collect::<Vec<u8>>(),
-                    ),
+                    entity_color(ent, "color1", "255 255 255")?,
+                    entity_color(ent, "color2", "0 0 0")?,
                 ],
                 speed: ent
                     .properties
@@ -466,24 +457,8 @@ impl Light {
                     })
                     .clone(),
                 color: [
-                    make_color(
-                        ent.properties
-                            .get("color1")
-                            .unwrap_or(&"255 255 255".to_string())
-                            .trim()
-                            .split(' ')
-                            .map(|v| v.parse::<u8>().unwrap())
-                            .collect::<Vec<u8>>(),
-                    ),
-                    make_color(
-                        ent.properties
-                            .get("color2")
-                            .unwrap_or(&"0 0 0".to_string())
-                            .trim()
-                            .split(' ')
-                            .map(|v| v.parse::<u8>().unwrap())
-                            .collect::<Vec<u8>>(),
-                    ),
+                    entity_color(ent, "color1", "255 255 255")?,
+                    entity_color(ent, "color2", "0 0 0")?,
                 ],
                 falloff1: ent
                     .properties
@@ -537,15 +512,7 @@ impl Light {
                         z: 0.0,
                     })
                     .clone(),
-                color: make_color(
-                    ent.properties
-                        .get("color")
-                        .unwrap_or(&"255 255 255".to_string())
-                        .trim()
-                        .split(' ')
-                        .map(|v| v.parse::<u8>().unwrap())
-                        .collect::<Vec<u8>>(),
-                ),
+                color: entity_color(ent, "color", "255 255 255")?,
                 falloff1: ent
                     .properties
                     .get("falloff1")
@@ -593,15 +560,7 @@ impl Light {
                         z: 0.0,
                     })
                     .clone(),
-                color: make_color(
-                    ent.properties
-                        .get("color")
-                        .unwrap_or(&"255 255 255".to_string())
-                        .trim()
-                        .split(' ')
-                        .map(|v| v.parse::<u8>().unwrap())
-                        .collect::<Vec<u8>>(),
-                ),
+                color: entity_color(ent, "color", "255 255 255")?,
                 falloff1: ent
                     .properties
                     .get("falloff1")
@@ -637,24 +596,8 @@ impl Light {
                     })
                     .clone(),
                 color: [
-                    make_color(
-                        ent.properties
-                            .get("color1")
-                            .unwrap_or(&"255 255 255".to_string())
-                            .trim()
-                            .split(' ')
-                            .map(|v| v.parse::<u8>().unwrap())
-                            .collect::<Vec<u8>>(),
-                    ),
-                    make_color(
-                        ent.properties
-                            .get("color2")
-                            .unwrap_or(&"0 0 0".to_string())
-                            .trim()
-                            .split(' ')
-                            .map(|v| v.parse::<u8>().unwrap())
-                            .collect::<Vec<u8>>(),
-                    ),
+                    entity_color(ent, "color1", "255 255 255")?,
+                    entity_color(ent, "color2", "0 0 0")?,
                 ],
                 speed: ent
                     .properties
@@ -682,11 +625,16 @@ impl Light {
                     .unwrap_or(3),
             },
 
-            _ => panic!("Invalid light type: {}", ent.classname),
-        }
+            _ => {
+                return Err(ConversionError::UnknownLight {
+                    entity: ent.id,
+                    classname: ent.classname.clone(),
+                })
+            }
+        })
     }
 
-    pub fn calculate_intensity(&self, pt: &Point3F) -> f32 {
+    pub fn calculate_intensity(&self, pt: &Point3F, model: AttenuationModel) -> f32 {
         match self {
             Light::Point {
                 position,
@@ -699,13 +647,7 @@ impl Light {
                 if len > *falloff_outer || len < *falloff_inner {
                     return 0.0;
                 }
-                let intensity = if (len > *falloff_inner) {
-                    1.0 - ((len - *falloff_inner) / (*falloff_outer - *falloff_inner))
-                } else {
-                    1.0
-                };
-
-                intensity
+                model.falloff(len, *falloff_inner, *falloff_outer)
             }
             Light::Omni {
                 position,
@@ -717,39 +659,356 @@ impl Light {
                 if len > *falloff2 || len < *falloff1 {
                     return 0.0;
                 }
-                let intensity = if (len > *falloff1) {
-                    1.0 - ((len - *falloff1) / (*falloff2 - *falloff1))
+                model.falloff(len, *falloff1, *falloff2)
+            }
+            Light::SpotLight {
+                position,
+                falloff_inner,
+                falloff_outer,
+                heading,
+                pitch,
+                angle_inner,
+                angle_outer,
+                ..
+            } => {
+                let to_point = pt - position;
+                let len = to_point.magnitude();
+                if len > *falloff_outer {
+                    return 0.0;
+                }
+                let distance_intensity = if len > *falloff_inner {
+                    1.0 - ((len - *falloff_inner) / (*falloff_outer - *falloff_inner))
                 } else {
                     1.0
                 };
+                if len == 0.0 {
+                    return distance_intensity;
+                }
+
+                // The heading turns the spotlight about +Z starting from +Y and the pitch tilts it
+                // up from the horizon, both in degrees like the rotations of the Constructor
+                let (heading, pitch) = (heading.to_radians(), pitch.to_radians());
+                let direction = Point3F::new(
+                    -heading.sin() * pitch.cos(),
+                    heading.cos() * pitch.cos(),
+                    pitch.sin(),
+                );
+                let cos_angle = to_point.dot(direction) / len;
+                if cos_angle <= 0.0 {
+                    return 0.0;
+                }
+                // The cone angles are the full angles, full intensity inside the inner cone and
+                // smoothly fading out to nothing at the outer cone
+                let cos_inner = (angle_inner / 2.0).to_radians().cos();
+                let cos_outer = (angle_outer / 2.0).to_radians().cos();
+                let angle_intensity = if cos_angle >= cos_inner {
+                    1.0
+                } else if cos_angle <= cos_outer {
+                    0.0
+                } else {
+                    let t = (cos_angle - cos_outer) / (cos_inner - cos_outer);
+                    t * t * (3.0 - 2.0 * t)
+                };
 
-                intensity
+                distance_intensity * angle_intensity
+            }
+            // The rest only have a position and radii, so they light all around them like the omni
+            // lights
+            _ => {
+                let len = self.get_position().distance(*pt);
+                let (inner, outer) = self.get_radii();
+                if len > outer || len < inner {
+                    return 0.0;
+                }
+                model.falloff(len, inner, outer)
             }
-            _ => panic!("Not implemented!"),
         }
     }
 
+    // The animated lights start out in their first color, the emitters have none of their own and
+    // are white
     pub fn get_base_color(&self) -> Point3F {
-        match self {
-            Light::Point { color, .. } => Point3F {
-                x: color.r as f32 / 255.0,
-                y: color.g as f32 / 255.0,
-                z: color.b as f32 / 255.0,
-            },
-            Light::Omni { color, .. } => Point3F {
-                x: color.r as f32 / 255.0,
-                y: color.g as f32 / 255.0,
-                z: color.b as f32 / 255.0,
+        let color = match self {
+            Light::Point { color, .. } => *color,
+            Light::SpotLight { color, .. } => *color,
+            Light::Omni { color, .. } => *color,
+            Light::Runway { color, .. } => *color,
+            Light::Spot { color, .. } => *color,
+            Light::Flicker { color, .. } => color[0],
+            Light::Pulse { color, .. } => color[0],
+            Light::Pulse2 { color, .. } => color[0],
+            Light::Strobe { color, .. } => color[0],
+            Light::EmitterPoint { .. } | Light::EmitterSpot { .. } => ColorI {
+                r: 255,
+                g: 255,
+                b: 255,
+                a: 255,
             },
-            _ => panic!("Not implemented!"),
+        };
+        Point3F {
+            x: color.r as f32 / 255.0,
+            y: color.g as f32 / 255.0,
+            z: color.b as f32 / 255.0,
+        }
+    }
+
+    // The radii the light fades out between
+    fn get_radii(&self) -> (f32, f32) {
+        match self {
+            Light::Point {
+                falloff_inner,
+                falloff_outer,
+                ..
+            } => (*falloff_inner, *falloff_outer),
+            Light::SpotLight {
+                falloff_inner,
+                falloff_outer,
+                ..
+            } => (*falloff_inner, *falloff_outer),
+            Light::EmitterPoint {
+                falloff1, falloff2, ..
+            } => (*falloff1, *falloff2),
+            Light::EmitterSpot {
+                falloff1, falloff2, ..
+            } => (*falloff1, *falloff2),
+            Light::Flicker {
+                falloff1, falloff2, ..
+            } => (*falloff1, *falloff2),
+            Light::Omni {
+                falloff1, falloff2, ..
+            } => (*falloff1, *falloff2),
+            Light::Pulse {
+                falloff1, falloff2, ..
+            } => (*falloff1, *falloff2),
+            Light::Pulse2 {
+                falloff1, falloff2, ..
+            } => (*falloff1, *falloff2),
+            Light::Runway {
+                falloff1, falloff2, ..
+            } => (*falloff1, *falloff2),
+            Light::Spot {
+                falloff1, falloff2, ..
+            } => (*falloff1, *falloff2),
+            Light::Strobe {
+                falloff1, falloff2, ..
+            } => (*falloff1, *falloff2),
+        }
+    }
+
+    // The animated lights change over time, the lightmaps only hold the light that never changes
+    // so they're left out of the bake
+    pub fn is_animated(&self) -> bool {
+        matches!(
+            self,
+            Light::Flicker { .. }
+                | Light::Pulse { .. }
+                | Light::Pulse2 { .. }
+                | Light::Runway { .. }
+                | Light::Strobe { .. }
+                | Light::EmitterPoint { .. }
+                | Light::EmitterSpot { .. }
+        )
+    }
+
+    // The distance past which the light doesn't reach anymore
+    pub fn get_range(&self) -> f32 {
+        match self {
+            Light::Point { falloff_outer, .. } => *falloff_outer,
+            Light::SpotLight { falloff_outer, .. } => *falloff_outer,
+            Light::EmitterPoint { falloff2, .. } => *falloff2,
+            Light::EmitterSpot { falloff2, .. } => *falloff2,
+            Light::Flicker { falloff2, .. } => *falloff2,
+            Light::Omni { falloff2, .. } => *falloff2,
+            Light::Pulse { falloff2, .. } => *falloff2,
+            Light::Pulse2 { falloff2, .. } => *falloff2,
+            Light::Runway { falloff2, .. } => *falloff2,
+            Light::Spot { falloff2, .. } => *falloff2,
+            Light::Strobe { falloff2, .. } => *falloff2,
         }
     }
 
+    // The lights are points in the scene, the soft shadows spread them over a sphere of this
+    // radius so the shadows of the nearby lights get wider penumbrae
+    pub fn get_shadow_radius(&self) -> f32 {
+        self.get_range() * 0.05
+    }
+
     pub fn get_position(&self) -> Point3F {
         match self {
             Light::Point { position, .. } => *position,
             Light::Omni { position, .. } => *position,
-            _ => panic!("Not implemented!"),
+            Light::SpotLight { position, .. } => *position,
+            Light::EmitterPoint { position, .. } => *position,
+            Light::EmitterSpot { position, .. } => *position,
+            Light::Flicker { position, .. } => *position,
+            Light::Pulse { position, .. } => *position,
+            Light::Pulse2 { position, .. } => *position,
+            Light::Runway { position, .. } => *position,
+            Light::Spot { position, .. } => *position,
+            Light::Strobe { position, .. } => *position,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn make_color_reads_integers_and_floats() {
+        let color = make_color("255 128 0").unwrap();
+        assert_eq!((color.r, color.g, color.b, color.a), (255, 128, 0, 255));
+        let color = make_color("1.0 0.5 0.0").unwrap();
+        assert_eq!((color.r, color.g, color.b), (255, 128, 0));
+    }
+
+    #[test]
+    fn animated_lights_light_like_omni_lights() {
+        let white = ColorI {
+            r: 255,
+            g: 255,
+            b: 255,
+            a: 255,
+        };
+        let flicker = Light::Flicker {
+            position: Point3F::new(0.0, 0.0, 0.0),
+            color: [white; 5],
+            speed: 1.0,
+            falloff1: 1.0,
+            falloff2: 10.0,
+            spawnflags: 3,
+        };
+        assert!(flicker.is_animated());
+        assert_eq!(flicker.get_position(), Point3F::new(0.0, 0.0, 0.0));
+        assert_eq!(flicker.get_base_color(), Point3F::new(1.0, 1.0, 1.0));
+        let model = AttenuationModel::Linear;
+        assert_eq!(
+            flicker.calculate_intensity(&Point3F::new(0.0, 0.0, 20.0), model),
+            0.0
+        );
+        let half = flicker.calculate_intensity(&Point3F::new(0.0, 0.0, 5.5), model);
+        assert!((half - 0.5).abs() < 1e-5);
+
+        let omni = Light::Omni {
+            position: Point3F::new(0.0, 0.0, 0.0),
+            color: white,
+            falloff1: 1.0,
+            falloff2: 10.0,
+        };
+        assert!(!omni.is_animated());
+    }
+
+    #[test]
+    fn lights_do_not_reach_past_their_range() {
+        let white = ColorI {
+            r: 255,
+            g: 255,
+            b: 255,
+            a: 255,
+        };
+        let origin = Point3F::new(0.0, 0.0, 0.0);
+        let lights = [
+            Light::Point {
+                position: origin,
+                color: white,
+                intensity: 1.0,
+                falloff_inner: 2.0,
+                falloff_outer: 10.0,
+            },
+            Light::SpotLight {
+                position: origin,
+                color: white,
+                intensity: 1.0,
+                falloff_inner: 2.0,
+                falloff_outer: 10.0,
+                heading: 0.0,
+                pitch: 0.0,
+                angle_inner: 180.0,
+                angle_outer: 180.0,
+            },
+            Light::Omni {
+                position: origin,
+                color: white,
+                falloff1: 2.0,
+                falloff2: 10.0,
+            },
+        ];
+        for model in [
+            AttenuationModel::Linear,
+            AttenuationModel::InverseSquare,
+            AttenuationModel::Smoothstep,
+        ] {
+            for light in lights.iter() {
+                assert_eq!(light.get_range(), 10.0);
+                for dir in [Point3F::unit_x(), Point3F::unit_y(), -Point3F::unit_z()] {
+                    assert_eq!(light.calculate_intensity(&(dir * 10.5), model), 0.0);
+                }
+            }
         }
     }
+
+    #[test]
+    fn spotlights_fade_out_across_the_cone() {
+        let spotlight = |heading: f32, pitch: f32| Light::SpotLight {
+            position: Point3F::new(0.0, 0.0, 0.0),
+            color: ColorI {
+                r: 255,
+                g: 255,
+                b: 255,
+                a: 255,
+            },
+            intensity: 1.0,
+            falloff_inner: 2.0,
+            falloff_outer: 10.0,
+            heading,
+            pitch,
+            angle_inner: 30.0,
+            angle_outer: 60.0,
+        };
+        let model = AttenuationModel::Linear;
+        let off_axis = |degrees: f32| {
+            let angle = degrees.to_radians();
+            Point3F::new(angle.sin(), angle.cos(), 0.0)
+        };
+        let light = spotlight(0.0, 0.0);
+        assert_eq!(light.calculate_intensity(&off_axis(0.0), model), 1.0);
+        assert_eq!(light.calculate_intensity(&off_axis(10.0), model), 1.0);
+        let edge = light.calculate_intensity(&off_axis(22.5), model);
+        assert!(edge > 0.0 && edge < 1.0);
+        assert!(light.calculate_intensity(&off_axis(20.0), model) > edge);
+        assert_eq!(light.calculate_intensity(&off_axis(31.0), model), 0.0);
+        assert_eq!(light.calculate_intensity(&off_axis(180.0), model), 0.0);
+        let halfway = light.calculate_intensity(&Point3F::new(0.0, 6.0, 0.0), model);
+        assert!((halfway - 0.5).abs() < 1e-5);
+
+        let west = Point3F::new(-1.0, 0.0, 0.0);
+        assert!((spotlight(90.0, 0.0).calculate_intensity(&west, model) - 1.0).abs() < 1e-5);
+        let up = Point3F::new(0.0, 0.0, 1.0);
+        assert!((spotlight(0.0, 90.0).calculate_intensity(&up, model) - 1.0).abs() < 1e-5);
+        assert_eq!(light.calculate_intensity(&up, model), 0.0);
+    }
+
+    #[test]
+    fn unknown_light_types_are_an_error() {
+        let ent = csx::Entity {
+            id: 3,
+            classname: "light_disco".to_string(),
+            gametype: "Torque".to_string(),
+            origin: None,
+            properties: Default::default(),
+        };
+        assert!(matches!(
+            Light::new(&ent),
+            Err(ConversionError::UnknownLight { entity: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn make_color_rejects_malformed_colors() {
+        assert!(make_color("255 255").is_none());
+        assert!(make_color("").is_none());
+        assert!(make_color("red green blue").is_none());
+        assert!(make_color("256 0 0").is_none());
+        assert!(make_color("1.0 0.5 x").is_none());
+    }
 }
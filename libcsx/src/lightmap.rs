@@ -3,11 +3,16 @@
 use cgmath::{InnerSpace, Vector4};
 use dif::{
     interior::{BSPIndex, Interior, Surface},
-    types::{Point2F, Point3F},
+    types::{BoxF, Point2F, Point3F},
 };
 use rayon::prelude::*;
 
-use crate::{builder::RaycastCalc, light::Light};
+use crate::{
+    bsp::ConvertConfig,
+    builder::RaycastCalc,
+    csx::ConversionError,
+    light::{AttenuationModel, Light},
+};
 
 /// A rectangle defined by position and size.
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -80,6 +85,10 @@ pub struct LightmapSurface {
     pub normal: Point3F,
     pub tri_points: Vec<Point3F>,
     pub lightmap_index: usize,
+    /// Texels of padding around the lightmap that are filled with its edge colors.
+    pub gutter: usize,
+    /// World units covered by a lumel of the lightmap.
+    pub lumel_scale: u32,
 }
 
 #[inline]
@@ -348,20 +357,95 @@ impl Grid {
     }
 }
 
+/// Whether the lumel at `offset` along a surface row or column of `size` lumels is baked when
+/// baking at 1/`downscale` resolution. The last lumel is always baked so that the upscale never
+/// has to extrapolate.
+fn is_lumel_sample(offset: usize, size: usize, downscale: u32) -> bool {
+    offset % downscale as usize == 0 || offset + 1 == size
+}
+
+/// The baked lumels surrounding the lumel at `offset` and the interpolation factor between them.
+fn lumel_sample_span(offset: usize, size: usize, downscale: u32) -> (usize, usize, f32) {
+    let start = offset - offset % downscale as usize;
+    let end = (start + downscale as usize).min(size - 1);
+    if end == start {
+        (start, end, 0.0)
+    } else {
+        (start, end, (offset - start) as f32 / (end - start) as f32)
+    }
+}
+
+/// How far off the surfaces the shadow rays end, so the surface doesn't shadow itself.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ShadowBias {
+    /// A distance in the world units of the brush vertices.
+    World(f32),
+    /// A fraction of the size of a lumel of the surface, so the bias follows the lumel scale.
+    Lumels(f32),
+}
+
+impl ShadowBias {
+    /// The distance for a surface whose lumels are `lumel_size` world units across.
+    pub fn distance(&self, lumel_size: f32) -> f32 {
+        match *self {
+            ShadowBias::World(bias) => bias,
+            ShadowBias::Lumels(bias) => bias * lumel_size,
+        }
+    }
+}
+
+/// The filter the finished lightmaps are blurred with to hide the seams between the lumels.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BlurKind {
+    /// Keep the lumels as they were baked.
+    None,
+    /// Average the lumels within `radius` of each other.
+    Box(u32),
+    /// Weigh the lumels within `radius` by a Gaussian of `sigma` lumels, softer than the box
+    /// filter at large lumel scales.
+    Gaussian { radius: u32, sigma: f32 },
+}
+
+impl BlurKind {
+    /// The weights of the lumels from `-radius` to `radius`, the filters are separable so the
+    /// same weights go along both axes. They're left unnormalized so the box filter sums whole
+    /// numbers, and the blurred lumels are divided by the square of their total.
+    fn kernel(&self) -> Vec<f32> {
+        match *self {
+            BlurKind::None => vec![1.0],
+            BlurKind::Box(radius) => vec![1.0; 2 * radius as usize + 1],
+            BlurKind::Gaussian { radius, sigma } => {
+                let sigma = sigma.max(f32::EPSILON);
+                (-(radius as i32)..=radius as i32)
+                    .map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+                    .collect()
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct LightMap {
     pub pixels: Vec<u8>,
+    /// The dominant direction of the incoming light of each lumel in world space, encoded as a
+    /// normal map. Lumels that no light reaches get the normal of their surface.
+    pub dir_pixels: Option<Vec<u8>>,
 }
 
 impl LightMap {
+    #[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
     pub fn new(
         interior: &Interior,
         surfaces: &[LightmapSurface],
         lights: &[Light],
-        atlas_size: u32,
         lmap_index: usize,
-        lumel_scale: u32,
-    ) -> Self {
+        ambient: Point3F,
+        bounce_patches: &[BouncePatch],
+        config: &ConvertConfig,
+    ) -> Result<Self, ConversionError> {
+        let atlas_size = config.lightmap_size;
+        let downscale = config.lightmap_downscale;
+        let blur = config.lightmap_blur;
         // We have to re-generate new set of world-space vertices because UV generator
         // may add new vertices on seams.
         let scale = 1.0 / atlas_size as f32;
@@ -375,6 +459,7 @@ impl LightMap {
 
         let mut pixels: Vec<Vector4<u8>> =
             vec![Vector4::new(0, 0, 0, 0); atlas_size as usize * atlas_size as usize];
+        let mut dir_pixels = config.generate_dir_maps.then(|| pixels.clone());
 
         // Color the used pixels pink pls, for debug
         // for surf in surfaces.iter() {
@@ -398,72 +483,23 @@ impl LightMap {
                 continue;
             }
 
-            let (si, ti, axis) = if surf.sc[0] == 0.0 && surf.tc[0] == 0.0 {
-                if surf.sc[1] == 0.0 {
-                    (2, 1, 0)
-                } else {
-                    (1, 2, 0)
-                }
-            } else if surf.sc[1] == 0.0 && surf.tc[1] == 0.0 {
-                if surf.sc[0] == 0.0 {
-                    (2, 0, 1)
-                } else {
-                    (0, 2, 1)
-                }
-            } else if surf.sc[2] == 0.0 && surf.tc[2] == 0.0 {
-                if surf.sc[0] == 0.0 {
-                    (1, 0, 2)
-                } else {
-                    (0, 1, 2)
+            // Degenerate surfaces have no triangles to bake, just give them the ambient color.
+            if surf.tri_points.is_empty() {
+                for y in surf.offset_y..(surf.offset_y + surf.height) {
+                    for x in surf.offset_x..(surf.offset_x + surf.width) {
+                        pixels[y * atlas_size as usize + x] =
+                            Vector4::new(ambient.x as u8, ambient.y as u8, ambient.z as u8, 255);
+                        if let Some(dir_pixels) = dir_pixels.as_mut() {
+                            dir_pixels[y * atlas_size as usize + x] = encode_direction(surf.normal);
+                        }
+                    }
                 }
-            } else {
-                panic!("Bad texgens for lightmap!")
-            };
-
-            let plane_dist = -surf.normal.dot(surf.tri_points[0]);
-
-            let mut start = Point3F::new(0.0, 0.0, 0.0);
-            start[si] = -surf.dx * lumel_scale as f32;
-            start[ti] = -surf.dy * lumel_scale as f32;
-            start[axis] =
-                (surf.normal[si] * start[si]) + (surf.normal[ti] * start[ti]) + plane_dist;
-
-            let mut s_vec = Point3F::new(0.0, 0.0, 0.0);
-            let mut t_vec = Point3F::new(0.0, 0.0, 0.0);
-            s_vec[si] = 1.0;
-            s_vec[ti] = 0.0;
-            t_vec[ti] = 1.0;
-            t_vec[si] = 0.0;
-
-            let mut plane_normal = surf.normal.clone();
-            plane_normal[ti] = 0.0;
-            plane_normal = plane_normal.normalize();
-
-            let angle = plane_normal[axis].clamp(-1.0, 1.0).acos();
-            s_vec[axis] = if plane_normal[si] < 0.0 {
-                (-angle).tan()
-            } else {
-                angle.tan()
-            };
-
-            let mut plane_normal = surf.normal.clone();
-            plane_normal[si] = 0.0;
-            plane_normal = plane_normal.normalize();
-
-            let angle = plane_normal[axis].clamp(-1.0, 1.0).acos();
-            t_vec[axis] = if plane_normal[ti] < 0.0 {
-                (-angle).tan()
-            } else {
-                angle.tan()
-            };
-
-            s_vec *= lumel_scale as f32;
-            t_vec *= lumel_scale as f32;
-
-            let s_run = s_vec * surf.width as f32;
-
-            let mut world_position = start;
+                continue;
+            }
 
+            let lumels = SurfaceLumels::new(surf, lights, config)?;
+            let s_vec = lumels.s_vec;
+            let t_vec = lumels.t_vec;
             let s_run = s_vec * surf.width as f32;
 
             let mut world_position = surf.tri_points[0];
@@ -474,10 +510,19 @@ impl LightMap {
             let end_y = surf.offset_y + surf.height;
             for y in start_y..end_y {
                 for x in start_x..end_x {
+                    // Only every downscale-th lumel is baked, the rest get upscaled from those
+                    if !is_lumel_sample(x - start_x, surf.width, downscale)
+                        || !is_lumel_sample(y - start_y, surf.height, downscale)
+                    {
+                        world_position += s_vec;
+                        continue;
+                    }
+
                     //let uv =
                     //    Point2F::new(x as f32 * scale + half_pixel, y as f32 * scale + half_pixel);
 
-                    let mut pixel_color = Point3F::new(0.0, 0.0, 0.0);
+                    let mut pixel_color = ambient / 255.0;
+                    let mut light_dir = Point3F::new(0.0, 0.0, 0.0);
 
                     // let mut i = 0;
                     // 'outer: while i < surf.tri_points.len() {
@@ -500,29 +545,22 @@ impl LightMap {
 
                     //         if barycentric_is_inside(barycentric) {
                     //             let world_position = barycentric_to_world(barycentric, p1, p2, p3);
-                    for light in lights {
-                        let mut attenuation = light.calculate_intensity(&world_position);
-                        let light_color = light.get_base_color();
-                        // Shadows
-                        if attenuation >= 0.01 {
-                            let pidx = u16::MAX;
-                            let start_node_index = BSPIndex {
-                                index: 0,
-                                leaf: false,
-                                solid: false,
-                            };
-
-                            let light_pos = light.get_position();
-                            let dir = (light_pos - world_position).normalize();
-                            let end = world_position - dir * 0.1;
-
-                            if interior.bsp_ray_cast(&start_node_index, &pidx, light_pos, end) {
-                                attenuation = 0.0;
-                            }
-                        }
-                        pixel_color += light_color * attenuation;
-                        //     }
-                        //     break 'outer;
+                    lumels.add_direct_light(
+                        interior,
+                        world_position,
+                        &mut pixel_color,
+                        &mut light_dir,
+                    );
+                    if !bounce_patches.is_empty() {
+                        add_bounce_light(
+                            interior,
+                            bounce_patches,
+                            world_position,
+                            surf.normal,
+                            lumels.bias,
+                            &mut pixel_color,
+                            &mut light_dir,
+                        );
                     }
 
                     // Offset uv to center for conservative rasterization.
@@ -538,6 +576,14 @@ impl LightMap {
                         (pixel_color.z.clamp(0.0, 1.0) * 255.0) as u8,
                         255, // Indicates that this pixel was "filled"
                     );
+                    if let Some(dir_pixels) = dir_pixels.as_mut() {
+                        dir_pixels[y * atlas_size as usize + x] =
+                            encode_direction(if light_dir.magnitude2() > 0.0 {
+                                light_dir.normalize()
+                            } else {
+                                surf.normal
+                            });
+                    }
 
                     world_position += s_vec;
                 }
@@ -612,96 +658,897 @@ impl LightMap {
         //         }
         //     });
 
-        // Prepare light map for bilinear filtration. This step is mandatory to prevent bleeding.
-        let mut rgb_pixels: Vec<Point3F> = Vec::with_capacity((atlas_size * atlas_size) as usize);
-        for y in 0..(atlas_size as i32) {
-            for x in 0..(atlas_size as i32) {
-                let fetch = |dx: i32, dy: i32| -> Option<Point3F> {
-                    pixels
-                        .get(((y + dy) * (atlas_size as i32) + x + dx) as usize)
-                        .and_then(|p| {
-                            if p.w != 0 {
-                                Some(Point3F::new(p.x as f32, p.y as f32, p.z as f32))
-                            } else {
-                                None
-                            }
-                        })
+        let pixels = finish_pixels(pixels, surfaces, lmap_index, atlas_size, downscale, blur);
+        let dir_pixels = dir_pixels.map(|dir_pixels| {
+            finish_pixels(
+                dir_pixels, surfaces, lmap_index, atlas_size, downscale, blur,
+            )
+        });
+
+        Ok(Self { pixels, dir_pixels })
+    }
+}
+
+/// The world space layout of the lumels of a surface and the lights that can reach them.
+struct SurfaceLumels<'a> {
+    /// The axes the lightmap's X and Y map to and the axis they're projected along.
+    axes: (usize, usize, usize),
+    /// The world space step from a lumel to the next one along the lightmap's X axis.
+    s_vec: Point3F,
+    /// The world space step from a lumel to the next one along the lightmap's Y axis.
+    t_vec: Point3F,
+    lights: Vec<&'a Light>,
+    /// The normal of the surface, the shadow rays end off the surface along it.
+    normal: Point3F,
+    /// How far off the surface the rays end in world units, larger lumels need a larger bias to
+    /// keep the surface from shadowing itself.
+    bias: f32,
+    /// The directions the shadow rays are cast towards the lights from, spread evenly over the
+    /// unit sphere. A single ray is cast to the center of the light when empty.
+    shadow_offsets: Vec<Point3F>,
+    /// How the lights fade out with the distance.
+    attenuation: AttenuationModel,
+}
+
+impl<'a> SurfaceLumels<'a> {
+    fn new(
+        surf: &LightmapSurface,
+        lights: &'a [Light],
+        config: &ConvertConfig,
+    ) -> Result<Self, ConversionError> {
+        let (si, ti, axis) = if surf.sc[0] == 0.0 && surf.tc[0] == 0.0 {
+            if surf.sc[1] == 0.0 {
+                (2, 1, 0)
+            } else {
+                (1, 2, 0)
+            }
+        } else if surf.sc[1] == 0.0 && surf.tc[1] == 0.0 {
+            if surf.sc[0] == 0.0 {
+                (2, 0, 1)
+            } else {
+                (0, 2, 1)
+            }
+        } else if surf.sc[2] == 0.0 && surf.tc[2] == 0.0 {
+            if surf.sc[0] == 0.0 {
+                (1, 0, 2)
+            } else {
+                (0, 1, 2)
+            }
+        } else {
+            return Err(ConversionError::BadLightmapTexgens {
+                surface: surf.surface_index,
+            });
+        };
+
+        let mut s_vec = Point3F::new(0.0, 0.0, 0.0);
+        let mut t_vec = Point3F::new(0.0, 0.0, 0.0);
+        s_vec[si] = 1.0;
+        s_vec[ti] = 0.0;
+        t_vec[ti] = 1.0;
+        t_vec[si] = 0.0;
+
+        let mut plane_normal = surf.normal.clone();
+        plane_normal[ti] = 0.0;
+        plane_normal = plane_normal.normalize();
+
+        let angle = plane_normal[axis].clamp(-1.0, 1.0).acos();
+        s_vec[axis] = if plane_normal[si] < 0.0 {
+            (-angle).tan()
+        } else {
+            angle.tan()
+        };
+
+        let mut plane_normal = surf.normal.clone();
+        plane_normal[si] = 0.0;
+        plane_normal = plane_normal.normalize();
+
+        let angle = plane_normal[axis].clamp(-1.0, 1.0).acos();
+        t_vec[axis] = if plane_normal[ti] < 0.0 {
+            (-angle).tan()
+        } else {
+            angle.tan()
+        };
+
+        s_vec *= surf.lumel_scale as f32;
+        t_vec *= surf.lumel_scale as f32;
+
+        // Only the lights that reach the lumels of the surface can light them, so cull the rest
+        // once per surface instead of testing every light for every lumel.
+        let lumel_corners = [
+            surf.tri_points[0],
+            surf.tri_points[0] + s_vec * surf.width as f32,
+            surf.tri_points[0] + t_vec * surf.height as f32,
+            surf.tri_points[0] + s_vec * surf.width as f32 + t_vec * surf.height as f32,
+        ];
+        let surface_bounds = BoxF::from_vertices(&lumel_corners.iter().collect::<Vec<_>>());
+        let lights = lights
+            .iter()
+            .filter(|light| light_reaches(light, &surface_bounds))
+            .collect::<Vec<_>>();
+
+        let bias = config
+            .shadow_bias
+            .distance(s_vec.magnitude().max(t_vec.magnitude()));
+        let shadow_samples = config.shadow_samples.max(1);
+
+        // A Fibonacci sphere, the same offsets every time so the bakes stay reproducible
+        let shadow_offsets = if shadow_samples > 1 {
+            (0..shadow_samples)
+                .map(|i| {
+                    let z = 1.0 - (2.0 * i as f32 + 1.0) / shadow_samples as f32;
+                    let r = (1.0 - z * z).sqrt();
+                    let theta = std::f32::consts::PI * (3.0 - 5.0f32.sqrt()) * i as f32;
+                    Point3F::new(r * theta.cos(), r * theta.sin(), z)
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+
+        Ok(Self {
+            axes: (si, ti, axis),
+            s_vec,
+            t_vec,
+            lights,
+            normal: surf.normal,
+            bias,
+            shadow_offsets,
+            attenuation: config.attenuation,
+        })
+    }
+
+    /// The world position of the center of the texel at the given offset into the surface's
+    /// lightmap, found by inverting the lightmap texgen onto the surface's plane.
+    fn texel_position(
+        &self,
+        surf: &LightmapSurface,
+        atlas_size: u32,
+        x: usize,
+        y: usize,
+    ) -> Point3F {
+        let (si, ti, axis) = self.axes;
+        let mut position = Point3F::new(0.0, 0.0, 0.0);
+        position[si] =
+            (((surf.offset_x + x) as f32 + 0.5) / atlas_size as f32 - surf.dx) / surf.sc[si];
+        position[ti] =
+            (((surf.offset_y + y) as f32 + 0.5) / atlas_size as f32 - surf.dy) / surf.tc[ti];
+        position[axis] = (surf.normal.dot(surf.tri_points[0])
+            - surf.normal[si] * position[si]
+            - surf.normal[ti] * position[ti])
+            / surf.normal[axis];
+        position
+    }
+
+    /// Adds the light of the lights at the position to `color`, and the directions it comes from
+    /// weighed by its brightness to `dir`.
+    fn add_direct_light(
+        &self,
+        interior: &Interior,
+        position: Point3F,
+        color: &mut Point3F,
+        dir: &mut Point3F,
+    ) {
+        for light in self.lights.iter() {
+            let mut attenuation = light.calculate_intensity(&position, self.attenuation);
+            let light_color = light.get_base_color();
+            // Shadows
+            if attenuation >= 0.01 && !interior.bsp_nodes.is_empty() {
+                let pidx = u16::MAX;
+                let start_node_index = BSPIndex {
+                    index: 0,
+                    leaf: false,
+                    solid: false,
                 };
 
-                let src_pixel = pixels[(y * (atlas_size as i32) + x) as usize];
-                if src_pixel.w == 0 {
-                    // Check neighbour pixels marked as "filled" and use it as value.
-                    if let Some(west) = fetch(-1, 0) {
-                        rgb_pixels.push(west);
-                    } else if let Some(east) = fetch(1, 0) {
-                        rgb_pixels.push(east);
-                    } else if let Some(north) = fetch(0, -1) {
-                        rgb_pixels.push(north);
-                    } else if let Some(south) = fetch(0, 1) {
-                        rgb_pixels.push(south);
-                    } else if let Some(north_west) = fetch(-1, -1) {
-                        rgb_pixels.push(north_west);
-                    } else if let Some(north_east) = fetch(1, -1) {
-                        rgb_pixels.push(north_east);
-                    } else if let Some(south_east) = fetch(1, 1) {
-                        rgb_pixels.push(south_east);
-                    } else if let Some(south_west) = fetch(-1, 1) {
-                        rgb_pixels.push(south_west);
-                    } else {
-                        rgb_pixels.push(Point3F::new(0.0, 0.0, 0.0));
+                // End the rays off the surface along its normal rather than along the ray, so the
+                // rays grazing the surface don't end up inside it and shadow it
+                let end = position + self.normal * self.bias;
+                let light_pos = light.get_position();
+
+                if self.shadow_offsets.is_empty() {
+                    if interior.bsp_ray_cast(&start_node_index, &pidx, light_pos, end) {
+                        attenuation = 0.0;
                     }
                 } else {
-                    rgb_pixels.push(Point3F::new(
-                        src_pixel.x as f32,
-                        src_pixel.y as f32,
-                        src_pixel.z as f32,
-                    ))
+                    // Average the occlusion of the rays to the points around the light, so the
+                    // lumels that only see a part of it get a penumbra
+                    let radius = light.get_shadow_radius();
+                    let occluded = self
+                        .shadow_offsets
+                        .iter()
+                        .filter(|offset| {
+                            let sample_pos = light_pos + *offset * radius;
+                            interior.bsp_ray_cast(&start_node_index, &pidx, sample_pos, end)
+                        })
+                        .count();
+                    attenuation *= 1.0 - occluded as f32 / self.shadow_offsets.len() as f32;
                 }
             }
+            *color += light_color * attenuation;
+            // Weigh the directions by how bright each light is at the lumel
+            if attenuation > 0.0 {
+                let brightness = (light_color.x + light_color.y + light_color.z) / 3.0;
+                *dir += (light.get_position() - position).normalize() * attenuation * brightness;
+            }
         }
+    }
+}
 
-        // Blur lightmap using simplest box filter.
-        let mut bytes = Vec::with_capacity((atlas_size * atlas_size * 3) as usize);
-        for y in 0..(atlas_size as i32) {
-            for x in 0..(atlas_size as i32) {
-                if x < 1 || y < 1 || x + 1 == atlas_size as i32 || y + 1 == atlas_size as i32 {
-                    bytes.push(rgb_pixels[(y * (atlas_size as i32) + x) as usize].x as u8);
-                    bytes.push(rgb_pixels[(y * (atlas_size as i32) + x) as usize].y as u8);
-                    bytes.push(rgb_pixels[(y * (atlas_size as i32) + x) as usize].z as u8);
-                } else {
-                    let fetch = |dx: i32, dy: i32| -> Point3F {
-                        let u8_pixel =
-                            rgb_pixels[((y + dy) * (atlas_size as i32) + x + dx) as usize];
-                        Point3F::new(u8_pixel.x as f32, u8_pixel.y as f32, u8_pixel.z as f32)
+/// Whether the point on the surface's plane lies within one of its triangles.
+fn surface_contains(surf: &LightmapSurface, point: Point3F) -> bool {
+    let uv = |p: &Point3F| Point2F::new(p.dot(surf.sc), p.dot(surf.tc));
+    surf.tri_points.chunks_exact(3).any(|tri| {
+        barycentric_is_inside(get_barycentric_coords_2d(
+            uv(&point),
+            uv(&tri[0]),
+            uv(&tri[1]),
+            uv(&tri[2]),
+        ))
+    })
+}
+
+/// The width and height in lumels of the patches the lit surfaces are split into for the bounces.
+const BOUNCE_PATCH_LUMELS: usize = 4;
+
+/// The fraction of the light reaching a surface that it reflects. The surfaces' textures aren't
+/// known here, so every surface is treated as a mid grey.
+const BOUNCE_REFLECTANCE: f32 = 0.5;
+
+/// A patch of a lit surface that reflects the light reaching it onto the other surfaces, acting as
+/// an area light for the bounce pass.
+#[derive(Clone, Debug)]
+pub struct BouncePatch {
+    pub position: Point3F,
+    pub normal: Point3F,
+    pub area: f32,
+    /// How far the rays to the patch stop short of it.
+    pub bias: f32,
+    /// The light the patch reflects, summed over all the bounces.
+    pub color: Point3F,
+}
+
+/// The fraction of the light leaving the patch that reaches the point, zero if it's blocked by the
+/// interior. `bias` keeps the rays from hitting the surfaces at either end.
+fn patch_form_factor(
+    interior: &Interior,
+    patch: &BouncePatch,
+    position: Point3F,
+    normal: Point3F,
+    bias: f32,
+) -> f32 {
+    let to_patch = patch.position - position;
+    let distance2 = to_patch.magnitude2();
+    if distance2 == 0.0 {
+        return 0.0;
+    }
+    let dir = to_patch / distance2.sqrt();
+    let cos_receiver = normal.dot(dir);
+    let cos_patch = -patch.normal.dot(dir);
+    if cos_receiver <= 0.0 || cos_patch <= 0.0 {
+        return 0.0;
+    }
+    if !interior.bsp_nodes.is_empty() {
+        let start_node_index = BSPIndex {
+            index: 0,
+            leaf: false,
+            solid: false,
+        };
+        let start = patch.position + patch.normal * bias;
+        let end = position + normal * bias;
+        if interior.bsp_ray_cast(&start_node_index, &u16::MAX, start, end) {
+            return 0.0;
+        }
+    }
+    // Adding the area to the denominator keeps the nearby patches from blowing up
+    cos_receiver * cos_patch * patch.area / (std::f32::consts::PI * distance2 + patch.area)
+}
+
+/// Adds the light the patches reflect onto the position to `color`, and the directions it comes
+/// from weighed by its brightness to `dir`.
+fn add_bounce_light(
+    interior: &Interior,
+    patches: &[BouncePatch],
+    position: Point3F,
+    normal: Point3F,
+    bias: f32,
+    color: &mut Point3F,
+    dir: &mut Point3F,
+) {
+    for patch in patches.iter() {
+        let form_factor = patch_form_factor(interior, patch, position, normal, bias);
+        if form_factor > 0.0 {
+            let bounced = patch.color * form_factor;
+            *color += bounced;
+            let brightness = (bounced.x + bounced.y + bounced.z) / 3.0;
+            *dir += (patch.position - position).normalize() * brightness;
+        }
+    }
+}
+
+/// Splits the lit surfaces into patches that reflect the direct light reaching them, then bounces
+/// the light between the patches so that the lumels gathering it see `config.gi_bounces` bounces.
+pub fn bounce_patches(
+    interior: &Interior,
+    surfaces: &[LightmapSurface],
+    lights: &[Light],
+    config: &ConvertConfig,
+) -> Result<Vec<BouncePatch>, ConversionError> {
+    let atlas_size = config.lightmap_size;
+    let mut patches = surfaces
+        .par_iter()
+        .filter(|surf| !surf.tri_points.is_empty() && surf.width > 0 && surf.height > 0)
+        .map(|surf| {
+            let lumels = SurfaceLumels::new(surf, lights, config)?;
+            let lumel_area = lumels.s_vec.cross(lumels.t_vec).magnitude();
+            let mut patches = vec![];
+            for patch_y in (0..surf.height).step_by(BOUNCE_PATCH_LUMELS) {
+                for patch_x in (0..surf.width).step_by(BOUNCE_PATCH_LUMELS) {
+                    // Only the lumels on the surface itself reflect any light
+                    let mut position = Point3F::new(0.0, 0.0, 0.0);
+                    let mut color = Point3F::new(0.0, 0.0, 0.0);
+                    let mut dir = Point3F::new(0.0, 0.0, 0.0);
+                    let mut count = 0;
+                    for y in patch_y..(patch_y + BOUNCE_PATCH_LUMELS).min(surf.height) {
+                        for x in patch_x..(patch_x + BOUNCE_PATCH_LUMELS).min(surf.width) {
+                            let texel_position = lumels.texel_position(surf, atlas_size, x, y);
+                            if surface_contains(surf, texel_position) {
+                                lumels.add_direct_light(
+                                    interior,
+                                    texel_position,
+                                    &mut color,
+                                    &mut dir,
+                                );
+                                position += texel_position;
+                                count += 1;
+                            }
+                        }
+                    }
+                    if count > 0 {
+                        patches.push(BouncePatch {
+                            position: position / count as f32,
+                            normal: surf.normal,
+                            area: lumel_area * count as f32,
+                            bias: lumels.bias,
+                            color: color / count as f32 * BOUNCE_REFLECTANCE,
+                        });
+                    }
+                }
+            }
+            Ok(patches)
+        })
+        .collect::<Result<Vec<_>, ConversionError>>()?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+    // Every further bounce reflects the light the patches received in the previous one
+    let mut bounced = patches.iter().map(|patch| patch.color).collect::<Vec<_>>();
+    for _ in 1..config.gi_bounces {
+        bounced = patches
+            .par_iter()
+            .map(|receiver| {
+                let received = patches.iter().zip(bounced.iter()).fold(
+                    Point3F::new(0.0, 0.0, 0.0),
+                    |received, (patch, color)| {
+                        received
+                            + color
+                                * patch_form_factor(
+                                    interior,
+                                    patch,
+                                    receiver.position,
+                                    receiver.normal,
+                                    receiver.bias,
+                                )
+                    },
+                );
+                received * BOUNCE_REFLECTANCE
+            })
+            .collect();
+        patches
+            .iter_mut()
+            .zip(bounced.iter())
+            .for_each(|(patch, color)| patch.color += *color);
+    }
+    Ok(patches)
+}
+
+/// Maps the components of a unit direction from -1..1 to 0..255, the way normal maps store them.
+fn encode_direction(dir: Point3F) -> Vector4<u8> {
+    Vector4::new(
+        ((dir.x * 0.5 + 0.5) * 255.0).round() as u8,
+        ((dir.y * 0.5 + 0.5) * 255.0).round() as u8,
+        ((dir.z * 0.5 + 0.5) * 255.0).round() as u8,
+        255,
+    )
+}
+
+/// Turns the baked lumels of the lightmap into RGB bytes: the lumels skipped due to the downscale
+/// are interpolated, the gutters and the unused texels are filled from their neighbours and the
+/// result is blurred to hide the seams when filtering.
+fn finish_pixels(
+    mut pixels: Vec<Vector4<u8>>,
+    surfaces: &[LightmapSurface],
+    lmap_index: usize,
+    atlas_size: u32,
+    downscale: u32,
+    blur: BlurKind,
+) -> Vec<u8> {
+    // Fill in the lumels that were skipped due to the downscale
+    if downscale > 1 {
+        for surf in surfaces.iter() {
+            if surf.lightmap_index != lmap_index {
+                continue;
+            }
+            for y in 0..surf.height {
+                for x in 0..surf.width {
+                    let (x0, x1, tx) = lumel_sample_span(x, surf.width, downscale);
+                    let (y0, y1, ty) = lumel_sample_span(y, surf.height, downscale);
+                    let fetch = |sx: usize, sy: usize| -> Vector4<f32> {
+                        pixels[(surf.offset_y + sy) * atlas_size as usize + surf.offset_x + sx]
+                            .cast::<f32>()
+                            .unwrap()
                     };
+                    let top = fetch(x0, y0) * (1.0 - tx) + fetch(x1, y0) * tx;
+                    let bottom = fetch(x0, y1) * (1.0 - tx) + fetch(x1, y1) * tx;
+                    let value = top * (1.0 - ty) + bottom * ty;
+                    pixels[(surf.offset_y + y) * atlas_size as usize + surf.offset_x + x] =
+                        Vector4::new(value.x as u8, value.y as u8, value.z as u8, 255);
+                }
+            }
+        }
+    }
+
+    // Extend the edges of each lightmap into its gutter, so the filtering at the edges only
+    // samples the colors of the surface itself.
+    for surf in surfaces.iter() {
+        if surf.lightmap_index != lmap_index
+            || surf.gutter == 0
+            || surf.width == 0
+            || surf.height == 0
+        {
+            continue;
+        }
+        let start_x = surf.offset_x.saturating_sub(surf.gutter);
+        let start_y = surf.offset_y.saturating_sub(surf.gutter);
+        let end_x = (surf.offset_x + surf.width + surf.gutter).min(atlas_size as usize);
+        let end_y = (surf.offset_y + surf.height + surf.gutter).min(atlas_size as usize);
+        for y in start_y..end_y {
+            for x in start_x..end_x {
+                let edge_x = x.clamp(surf.offset_x, surf.offset_x + surf.width - 1);
+                let edge_y = y.clamp(surf.offset_y, surf.offset_y + surf.height - 1);
+                if edge_x != x || edge_y != y {
+                    pixels[y * atlas_size as usize + x] =
+                        pixels[edge_y * atlas_size as usize + edge_x];
+                }
+            }
+        }
+    }
+
+    // Prepare light map for bilinear filtration. This step is mandatory to prevent bleeding.
+    let mut rgb_pixels: Vec<Point3F> = Vec::with_capacity((atlas_size * atlas_size) as usize);
+    for y in 0..(atlas_size as i32) {
+        for x in 0..(atlas_size as i32) {
+            let fetch = |dx: i32, dy: i32| -> Option<Point3F> {
+                pixels
+                    .get(((y + dy) * (atlas_size as i32) + x + dx) as usize)
+                    .and_then(|p| {
+                        if p.w != 0 {
+                            Some(Point3F::new(p.x as f32, p.y as f32, p.z as f32))
+                        } else {
+                            None
+                        }
+                    })
+            };
 
-                    let north_west = fetch(-1, -1);
-                    let north = fetch(0, -1);
-                    let north_east = fetch(1, -1);
-                    let west = fetch(-1, 0);
-                    let center = fetch(0, 0);
-                    let east = fetch(1, 0);
-                    let south_west = fetch(-1, 1);
-                    let south = fetch(0, 1);
-                    let south_east = fetch(-1, 1);
-
-                    let sum = north_west
-                        + north
-                        + north_east
-                        + west
-                        + center
-                        + east
-                        + south_west
-                        + south
-                        + south_east;
-
-                    bytes.push((sum.x / 9.0).clamp(0.0, 255.0) as u8);
-                    bytes.push((sum.y / 9.0).clamp(0.0, 255.0) as u8);
-                    bytes.push((sum.z / 9.0).clamp(0.0, 255.0) as u8);
+            let src_pixel = pixels[(y * (atlas_size as i32) + x) as usize];
+            if src_pixel.w == 0 {
+                // Check neighbour pixels marked as "filled" and use it as value.
+                if let Some(west) = fetch(-1, 0) {
+                    rgb_pixels.push(west);
+                } else if let Some(east) = fetch(1, 0) {
+                    rgb_pixels.push(east);
+                } else if let Some(north) = fetch(0, -1) {
+                    rgb_pixels.push(north);
+                } else if let Some(south) = fetch(0, 1) {
+                    rgb_pixels.push(south);
+                } else if let Some(north_west) = fetch(-1, -1) {
+                    rgb_pixels.push(north_west);
+                } else if let Some(north_east) = fetch(1, -1) {
+                    rgb_pixels.push(north_east);
+                } else if let Some(south_east) = fetch(1, 1) {
+                    rgb_pixels.push(south_east);
+                } else if let Some(south_west) = fetch(-1, 1) {
+                    rgb_pixels.push(south_west);
+                } else {
+                    rgb_pixels.push(Point3F::new(0.0, 0.0, 0.0));
                 }
+            } else {
+                rgb_pixels.push(Point3F::new(
+                    src_pixel.x as f32,
+                    src_pixel.y as f32,
+                    src_pixel.z as f32,
+                ))
             }
         }
+    }
+
+    // Blur the lightmap one axis at a time, the texels closer to the edge of the atlas than the
+    // radius of the filter are left as they are.
+    let kernel = blur.kernel();
+    let total = kernel.iter().sum::<f32>();
+    let radius = (kernel.len() / 2) as i32;
+    let size = atlas_size as i32;
+    let is_inner = |v: i32| v >= radius && v + radius < size;
+    let filter = |source: &[Point3F], x: i32, y: i32, dx: i32, dy: i32| -> Point3F {
+        kernel
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| {
+                let offset = i as i32 - radius;
+                source[((y + offset * dy) * size + x + offset * dx) as usize] * *weight
+            })
+            .sum()
+    };
+    let rows = (0..size)
+        .flat_map(|y| (0..size).map(move |x| (x, y)))
+        .map(|(x, y)| match is_inner(x) {
+            true => filter(&rgb_pixels, x, y, 1, 0),
+            false => Point3F::new(0.0, 0.0, 0.0),
+        })
+        .collect::<Vec<_>>();
+    let blurred = (0..size)
+        .flat_map(|y| (0..size).map(move |x| (x, y)))
+        .map(|(x, y)| match radius > 0 && is_inner(x) && is_inner(y) {
+            true => filter(&rows, x, y, 0, 1) / (total * total),
+            false => rgb_pixels[(y * size + x) as usize],
+        })
+        .collect::<Vec<_>>();
+
+    let mut bytes = Vec::with_capacity((atlas_size * atlas_size * 3) as usize);
+    for pixel in blurred.iter() {
+        bytes.push(pixel.x.clamp(0.0, 255.0) as u8);
+        bytes.push(pixel.y.clamp(0.0, 255.0) as u8);
+        bytes.push(pixel.z.clamp(0.0, 255.0) as u8);
+    }
 
-        Self { pixels: bytes }
+    bytes
+}
+
+/// Whether any point of the bounds is within the range of the light
+fn light_reaches(light: &Light, bounds: &BoxF) -> bool {
+    let position = light.get_position();
+    let closest = Point3F::new(
+        position.x.clamp(bounds.min.x, bounds.max.x),
+        position.y.clamp(bounds.min.y, bounds.max.y),
+        position.z.clamp(bounds.min.z, bounds.max.z),
+    );
+    (closest - position).magnitude() <= light.get_range()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{DIFBuilder, NoopListener};
+    use crate::csx::{preprocess_csx, try_parse_scene};
+    use dif::types::ColorI;
+
+    const HARD_SHADOWS: &str = include_str!("../snapshots/hard_shadows.csx");
+    const FLAT_FLOOR: &str = include_str!("../snapshots/flat_floor.csx");
+
+    /// Builds the first detail level of a scene into a single interior without baking it.
+    fn build_interior(csx: &str) -> Interior {
+        let mut scene = try_parse_scene(csx.as_bytes()).unwrap();
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        let config = ConvertConfig {
+            bake_lighting: false,
+            ..ConvertConfig::DEFAULT
+        };
+        let mut builder = DIFBuilder::new(&config);
+        for b in scene.detail_levels.detail_level[0]
+            .interior_map
+            .brushes
+            .brush
+            .iter()
+        {
+            builder.add_brush(b);
+        }
+        builder.build(&mut NoopListener).unwrap().0
+    }
+
+    fn white_omni(position: Point3F, falloff2: f32) -> Light {
+        Light::Omni {
+            position,
+            color: ColorI {
+                r: 255,
+                g: 255,
+                b: 255,
+                a: 255,
+            },
+            falloff1: 1.0,
+            falloff2,
+        }
+    }
+
+    /// The top of the 16x16 floor of the shadow fixtures, one lumel per world unit.
+    fn floor_top() -> LightmapSurface {
+        LightmapSurface {
+            surface_index: 0,
+            sc: Point3F::new(1.0, 0.0, 0.0),
+            tc: Point3F::new(0.0, 1.0, 0.0),
+            dx: 0.0,
+            dy: 0.0,
+            offset_x: 0,
+            offset_y: 0,
+            width: 16,
+            height: 16,
+            normal: Point3F::new(0.0, 0.0, 1.0),
+            tri_points: vec![
+                Point3F::new(0.0, 0.0, 1.0),
+                Point3F::new(16.0, 0.0, 1.0),
+                Point3F::new(16.0, 16.0, 1.0),
+            ],
+            lightmap_index: 0,
+            gutter: 0,
+            lumel_scale: 1,
+        }
+    }
+
+    #[test]
+    fn soft_shadows_have_a_penumbra() {
+        let interior = build_interior(HARD_SHADOWS);
+        let lights = [white_omni(Point3F::new(8.0, 8.0, 12.0), 60.0)];
+        let surf = floor_top();
+        // The light reaching the lumels along a line from under the box out past its shadow
+        let light_along_shadow_edge = |shadow_samples| {
+            let config = ConvertConfig {
+                shadow_samples,
+                ..ConvertConfig::DEFAULT
+            };
+            let lumels = SurfaceLumels::new(&surf, &lights, &config).unwrap();
+            (0..24)
+                .map(|i| {
+                    let position = Point3F::new(8.0 + i as f32 * 0.25, 8.0, 1.0);
+                    let unshadowed = lights[0].calculate_intensity(&position, config.attenuation);
+                    let mut color = Point3F::new(0.0, 0.0, 0.0);
+                    let mut dir = Point3F::new(0.0, 0.0, 0.0);
+                    lumels.add_direct_light(&interior, position, &mut color, &mut dir);
+                    color.x / unshadowed
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let hard = light_along_shadow_edge(1);
+        assert!(hard.iter().all(|&lit| lit == 0.0 || lit == 1.0));
+        assert_eq!(hard[0], 0.0);
+        assert_eq!(hard[23], 1.0);
+
+        let soft = light_along_shadow_edge(16);
+        assert!(soft.iter().any(|&lit| lit > 0.0 && lit < 1.0));
+    }
+
+    #[test]
+    fn unfilled_texels_take_the_south_east_neighbour() {
+        // Only the south east and south west neighbours of the texel at (1, 1) are filled, so it
+        // must take the south east one rather than the south west one twice
+        let mut pixels = vec![Vector4::new(0, 0, 0, 0); 16];
+        pixels[2 * 4 + 2] = Vector4::new(255, 0, 0, 255);
+        pixels[2 * 4] = Vector4::new(0, 0, 255, 255);
+        let bytes = finish_pixels(pixels, &[], 0, 4, 1, BlurKind::None);
+        assert_eq!(&bytes[(4 + 1) * 3..(4 + 1) * 3 + 3], &[255, 0, 0]);
+    }
+
+    #[test]
+    fn world_shadow_bias_ignores_the_lumel_size() {
+        let bias = ShadowBias::World(0.1);
+        assert_eq!(bias.distance(1.0), 0.1);
+        assert_eq!(bias.distance(32.0), 0.1);
+    }
+
+    #[test]
+    fn lumel_shadow_bias_scales_with_the_lumel_size() {
+        let bias = ShadowBias::Lumels(0.5);
+        assert_eq!(bias.distance(1.0), 0.5);
+        assert_eq!(bias.distance(32.0), 16.0);
+    }
+
+    #[test]
+    fn lights_are_culled_by_their_range() {
+        let surface = BoxF {
+            min: Point3F::new(0.0, 0.0, 0.0),
+            max: Point3F::new(8.0, 8.0, 0.0),
+        };
+        let omni_at = |x, y, z| Light::Omni {
+            position: Point3F::new(x, y, z),
+            color: ColorI {
+                r: 255,
+                g: 255,
+                b: 255,
+                a: 255,
+            },
+            falloff1: 1.0,
+            falloff2: 10.0,
+        };
+        assert!(light_reaches(&omni_at(4.0, 4.0, 0.0), &surface));
+        assert!(light_reaches(&omni_at(4.0, 4.0, 9.5), &surface));
+        assert!(light_reaches(&omni_at(14.0, 4.0, 0.0), &surface));
+        assert!(!light_reaches(&omni_at(4.0, 4.0, 10.5), &surface));
+        assert!(!light_reaches(&omni_at(16.0, 16.0, 0.0), &surface));
+    }
+
+    #[test]
+    fn downscaled_lumels_are_interpolated_between_the_baked_ones() {
+        let surf = LightmapSurface {
+            width: 6,
+            height: 6,
+            ..floor_top()
+        };
+        // Every second lumel is baked, plus the last one so the edges aren't extrapolated
+        let baked = (0..6)
+            .filter(|&i| is_lumel_sample(i, 6, 2))
+            .collect::<Vec<_>>();
+        assert_eq!(baked, vec![0, 2, 4, 5]);
+
+        // Bake a gradient of 40 per lumel along x in red and along y in green
+        let mut pixels = vec![Vector4::new(0, 0, 0, 0); 8 * 8];
+        for &y in baked.iter() {
+            for &x in baked.iter() {
+                pixels[y * 8 + x] = Vector4::new(40 * x as u8, 40 * y as u8, 0, 255);
+            }
+        }
+        let bytes = finish_pixels(pixels, &[surf], 0, 8, 2, BlurKind::None);
+        for y in 0..6 {
+            for x in 0..6 {
+                let i = (y * 8 + x) * 3;
+                assert_eq!(&bytes[i..i + 3], &[40 * x as u8, 40 * y as u8, 0]);
+            }
+        }
+    }
+
+    #[test]
+    fn a_point_light_fades_out_the_same_along_both_axes() {
+        let interior = build_interior(FLAT_FLOOR);
+        let config = ConvertConfig {
+            lightmap_blur: BlurKind::None,
+            ..ConvertConfig::DEFAULT
+        };
+        let lightmap = LightMap::new(
+            &interior,
+            &[floor_top()],
+            &[white_omni(Point3F::new(4.0, 8.0, 5.0), 20.0)],
+            0,
+            Point3F::new(0.0, 0.0, 0.0),
+            &[],
+            &config,
+        )
+        .unwrap();
+        let red = |x: usize, y: usize| lightmap.pixels[(y * 256 + x) * 3];
+
+        // The lumels get darker away from the one under the light, by as much along x as along y
+        for d in 0..8 {
+            assert!(red(4 + d, 8) >= red(5 + d, 8));
+            assert!(red(4, 8 + d) >= red(4, 9 + d));
+            assert!(red(4 + d, 8).abs_diff(red(4, 8 + d)) <= 1);
+        }
+        for d in 0..4 {
+            assert!(red(4 - d, 8) >= red(3 - d, 8));
+            assert!(red(4, 8 - d) >= red(4, 7 - d));
+        }
+        assert!(red(4, 8) > red(15, 8));
+        assert!(red(4, 8) > red(4, 15));
+    }
+
+    #[test]
+    fn sloped_lumels_are_longer_along_the_slope() {
+        // A floor tilted 30 degrees around x, its lightmap is projected along z
+        let slope = 30f32.to_radians();
+        let surf = LightmapSurface {
+            normal: Point3F::new(0.0, -slope.sin(), slope.cos()),
+            lumel_scale: 2,
+            ..floor_top()
+        };
+        let lumels = SurfaceLumels::new(&surf, &[], &ConvertConfig::DEFAULT).unwrap();
+        use cgmath::AbsDiffEq;
+        assert!(lumels.s_vec.abs_diff_eq(&Point3F::new(2.0, 0.0, 0.0), 1e-3));
+        assert_eq!(lumels.t_vec.x, 0.0);
+        assert_eq!(lumels.t_vec.y, 2.0);
+        assert!((lumels.t_vec.z.abs() - 2.0 * slope.tan()).abs() < 1e-5);
+        assert!((lumels.t_vec.magnitude() - 2.0 / slope.cos()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn a_low_light_does_not_speckle_the_flat_floor() {
+        let interior = build_interior(FLAT_FLOOR);
+        let lights = [white_omni(Point3F::new(-20.0, 8.0, 8.0), 2000.0)];
+        let surf = floor_top();
+        // The lumels of the top of the floor that the light doesn't fully reach
+        let shadowed_lumels = |shadow_bias| {
+            let config = ConvertConfig {
+                shadow_bias,
+                ..ConvertConfig::DEFAULT
+            };
+            let lumels = SurfaceLumels::new(&surf, &lights, &config).unwrap();
+            (0..16)
+                .flat_map(|y| (0..16).map(move |x| Point3F::new(x as f32, y as f32, 1.0)))
+                .filter(|&position| {
+                    let unshadowed = lights[0].calculate_intensity(&position, config.attenuation);
+                    let mut color = Point3F::new(0.0, 0.0, 0.0);
+                    let mut dir = Point3F::new(0.0, 0.0, 0.0);
+                    lumels.add_direct_light(&interior, position, &mut color, &mut dir);
+                    color.x < unshadowed * 0.999
+                })
+                .count()
+        };
+        assert_eq!(shadowed_lumels(ConvertConfig::DEFAULT.shadow_bias), 0);
+        assert_eq!(shadowed_lumels(ShadowBias::Lumels(0.5)), 0);
+    }
+
+    #[test]
+    fn surfaces_without_triangles_are_filled_with_the_ambient() {
+        let interior = build_interior(FLAT_FLOOR);
+        let config = ConvertConfig {
+            lightmap_blur: BlurKind::None,
+            ..ConvertConfig::DEFAULT
+        };
+        let degenerate = LightmapSurface {
+            surface_index: 1,
+            offset_x: 20,
+            offset_y: 20,
+            width: 1,
+            height: 1,
+            tri_points: vec![],
+            ..floor_top()
+        };
+        let lightmap = LightMap::new(
+            &interior,
+            &[floor_top(), degenerate],
+            &[white_omni(Point3F::new(8.0, 8.0, 5.0), 20.0)],
+            0,
+            Point3F::new(40.0, 50.0, 60.0),
+            &[],
+            &config,
+        )
+        .unwrap();
+        let pixel = |x: usize, y: usize| &lightmap.pixels[(y * 256 + x) * 3..(y * 256 + x) * 3 + 3];
+        assert_eq!(pixel(20, 20), &[40, 50, 60]);
+        assert!(pixel(8, 8)[0] > 40);
+    }
+
+    #[test]
+    fn dir_maps_point_towards_the_light() {
+        let interior = build_interior(FLAT_FLOOR);
+        // A short ranged light over the left edge of the floor
+        let lights = [white_omni(Point3F::new(0.0, 8.0, 3.0), 6.0)];
+        let lightmap = |generate_dir_maps| {
+            let config = ConvertConfig {
+                generate_dir_maps,
+                lightmap_blur: BlurKind::None,
+                ..ConvertConfig::DEFAULT
+            };
+            LightMap::new(
+                &interior,
+                &[floor_top()],
+                &lights,
+                0,
+                Point3F::new(0.0, 0.0, 0.0),
+                &[],
+                &config,
+            )
+            .unwrap()
+        };
+        assert!(lightmap(false).dir_pixels.is_none());
+        let with_dirs = lightmap(true);
+        let dir_pixels = with_dirs.dir_pixels.as_ref().unwrap();
+        let dir = |x: usize, y: usize| &dir_pixels[(y * 256 + x) * 3..(y * 256 + x) * 3 + 3];
+
+        // Lit from up and to the left
+        for x in 2..5 {
+            let [r, g, b] = dir(x, 8).try_into().unwrap();
+            assert!(r < 128, "{:?}", dir(x, 8));
+            assert!(g.abs_diff(128) <= 8);
+            assert!(b > 128);
+        }
+        // Out of reach of the light the direction is the normal of the floor
+        assert_eq!(with_dirs.pixels[(8 * 256 + 15) * 3], 0);
+        assert_eq!(dir(15, 8), &[128, 128, 255]);
     }
 }
@@ -0,0 +1,48 @@
+// Samples an omni light's falloff with every attenuation model, and checks the inverse square curve
+// is darker than the linear one halfway between the radii while all of them start at full
+// brightness and fade out to nothing.
+use csx::light::AttenuationModel;
+use csx::light::Light;
+use dif::types::ColorI;
+use dif::types::Point3F;
+
+const FALLOFF1: f32 = 1.0;
+const FALLOFF2: f32 = 20.0;
+const MODELS: [AttenuationModel; 3] = [
+    AttenuationModel::Linear,
+    AttenuationModel::InverseSquare,
+    AttenuationModel::Smoothstep,
+];
+
+fn intensity(distance: f32, model: AttenuationModel) -> f32 {
+    let light = Light::Omni {
+        position: Point3F::new(0.0, 0.0, 0.0),
+        color: ColorI {
+            r: 255,
+            g: 255,
+            b: 255,
+            a: 255,
+        },
+        falloff1: FALLOFF1,
+        falloff2: FALLOFF2,
+    };
+    light.calculate_intensity(&Point3F::new(distance, 0.0, 0.0), model)
+}
+
+#[test]
+fn every_model_fades_from_full_brightness_to_nothing() {
+    for model in MODELS {
+        assert_eq!(intensity(FALLOFF1, model), 1.0, "{:?} at falloff1", model);
+        assert_eq!(intensity(FALLOFF2, model), 0.0, "{:?} at falloff2", model);
+        assert_eq!(intensity(FALLOFF2 + 1.0, model), 0.0, "{:?} past it", model);
+    }
+}
+
+#[test]
+fn inverse_square_is_darker_than_linear_halfway() {
+    let midpoint = (FALLOFF1 + FALLOFF2) / 2.0;
+    assert!(
+        intensity(midpoint, AttenuationModel::InverseSquare)
+            < intensity(midpoint, AttenuationModel::Linear)
+    );
+}
@@ -0,0 +1,61 @@
+// Builds the BSP of a snapshot scene without a max depth and then capped well above the root, and
+// checks the capped tree stops exactly at the max depth, says so through the progress listener and
+// still converts.
+use csx::bsp::build_bsp;
+use csx::bsp::ConvertConfig;
+use csx::bsp::SplitMethod;
+use csx::builder::NoopListener;
+use csx::builder::RecordingListener;
+use csx::convert_csx_to_dif_with_config;
+use csx::csx::preprocess_csx;
+use csx::csx::try_parse_scene;
+use dif::io::EngineVersion;
+use std::path::Path;
+
+const SCENE: &str = "combined.csx";
+const MAX_DEPTH: u32 = 4;
+
+#[test]
+fn the_max_depth_caps_the_tree() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("snapshots")
+        .join(SCENE);
+    let csx = std::fs::read_to_string(path).unwrap();
+    let mut scene = try_parse_scene(csx.as_bytes()).unwrap();
+    preprocess_csx(&mut scene, false, 0.0, 0.0);
+    let brushes = &scene.detail_levels.detail_level[0]
+        .interior_map
+        .brushes
+        .brush;
+
+    let config = ConvertConfig {
+        split_method: SplitMethod::Exhaustive,
+        ..ConvertConfig::DEFAULT
+    };
+    let (uncapped, _) = build_bsp(brushes, &config, &mut NoopListener);
+    assert!(
+        uncapped.height() - 1 > MAX_DEPTH as i32,
+        "the scene should split deeper than {}",
+        MAX_DEPTH
+    );
+
+    let config = ConvertConfig {
+        bsp_max_depth: Some(MAX_DEPTH),
+        ..config
+    };
+    let mut listener = RecordingListener::new();
+    let (capped, _) = build_bsp(brushes, &config, &mut listener);
+    assert_eq!(capped.height() - 1, MAX_DEPTH as i32);
+    assert!(
+        listener
+            .events
+            .iter()
+            .any(|(_, total, status, _)| *total == 0 && status.contains("capped")),
+        "the progress listener should hear the cap was hit"
+    );
+
+    // The leaves the cap leaves behind still have to export
+    let result =
+        convert_csx_to_dif_with_config(csx, EngineVersion::MBG, 0, &config, &mut NoopListener);
+    assert!(result.is_ok(), "the capped scene should convert");
+}
@@ -0,0 +1,51 @@
+// Builds the sampling BSP of a snapshot scene twice with each of a few seeds, and checks the same
+// seed always builds a tree with the same number of nodes while some of the seeds build different
+// trees. Few samples are rated per node, so the seed decides most of the splitters.
+use csx::bsp::build_bsp;
+use csx::bsp::ConvertConfig;
+use csx::bsp::SplitMethod;
+use csx::builder::NoopListener;
+use csx::csx::preprocess_csx;
+use csx::csx::try_parse_scene;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+const SCENE: &str = "combined.csx";
+const SAMPLE_COUNT: usize = 2;
+const SEEDS: [u64; 6] = [0, 1, 2, 3, 42, 1234];
+
+#[test]
+fn the_seed_picks_the_splitters() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("snapshots")
+        .join(SCENE);
+    let mut scene = try_parse_scene(&std::fs::read(path).unwrap()).unwrap();
+    preprocess_csx(&mut scene, false, 0.0, 0.0);
+    let brushes = &scene.detail_levels.detail_level[0]
+        .interior_map
+        .brushes
+        .brush;
+
+    let mut node_counts = BTreeSet::new();
+    for seed in SEEDS {
+        let config = ConvertConfig {
+            split_method: SplitMethod::Fast,
+            bsp_sample_count: SAMPLE_COUNT,
+            bsp_seed: seed,
+            ..ConvertConfig::DEFAULT
+        };
+        let (first, _) = build_bsp(brushes, &config, &mut NoopListener);
+        let (second, _) = build_bsp(brushes, &config, &mut NoopListener);
+        assert_eq!(
+            first.node_count(),
+            second.node_count(),
+            "seed {} should build the same tree every time",
+            seed
+        );
+        node_counts.insert(first.node_count());
+    }
+    assert!(
+        node_counts.len() >= 2,
+        "all the seeds built the same tree, the seed isn't used"
+    );
+}
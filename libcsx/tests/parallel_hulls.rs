@@ -0,0 +1,65 @@
+// Converts the scenes in the snapshots directory on a single thread and on several threads, and
+// checks the DIFs are byte for byte the same. The convex hulls are checked and their emit strings
+// built on all the threads, so this catches the indices coming out in a different order.
+use csx::bsp::ConvertConfig;
+use csx::bsp::SplitMethod;
+use csx::builder::NoopListener;
+use csx::convert_csx_to_dif_with_config;
+use dif::io::EngineVersion;
+use rayon::ThreadPoolBuilder;
+use std::path::Path;
+
+const THREADS: usize = 8;
+
+fn convert(csx: &str, config: &ConvertConfig, threads: usize) -> Vec<Vec<u8>> {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap();
+    pool.install(|| {
+        match convert_csx_to_dif_with_config(
+            csx.to_string(),
+            EngineVersion::MBG,
+            0,
+            config,
+            &mut NoopListener,
+        ) {
+            Ok((bufs, ..)) => bufs,
+            Err(_) => vec![],
+        }
+    })
+}
+
+#[test]
+fn hulls_export_the_same_on_any_number_of_threads() {
+    // Not MB only, so the hulls get their emit strings. The lightmaps have nothing to do with the
+    // hulls, so they're left out.
+    let config = ConvertConfig {
+        mb_only: false,
+        split_method: SplitMethod::Exhaustive,
+        bake_lighting: false,
+        ..ConvertConfig::DEFAULT
+    };
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("snapshots");
+    let mut paths = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "csx"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let differing = paths
+        .iter()
+        .filter(|path| {
+            let csx = std::fs::read_to_string(path).unwrap();
+            convert(&csx, &config, 1) != convert(&csx, &config, THREADS)
+        })
+        .map(|path| path.file_stem().unwrap().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    assert!(
+        differing.is_empty(),
+        "the DIFs differ between 1 and {} threads: {:?}",
+        THREADS,
+        differing
+    );
+}
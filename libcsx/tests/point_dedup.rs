@@ -0,0 +1,54 @@
+// Dedups 100k points jittered within the epsilon around 10k unique ones, and checks the point map
+// finds exactly the unique points.
+use csx::builder::OrdPoint;
+use csx::builder::PointMap;
+
+const EPSILON: f32 = 1e-3;
+const GRID: usize = 10;
+const LAYERS: usize = 100;
+const COPIES: usize = 10;
+const SPACING: f32 = 3.0;
+
+// A fixed LCG so every run dedups the same points
+fn jitter(seed: &mut u64) -> f32 {
+    *seed = seed
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    let unit = (*seed >> 40) as f32 / (1u64 << 24) as f32;
+    (unit * 2.0 - 1.0) * EPSILON * 0.4
+}
+
+fn points() -> Vec<OrdPoint> {
+    let mut seed = 0;
+    let mut points = vec![];
+    for copy in 0..COPIES {
+        for i in 0..GRID * GRID * LAYERS {
+            let x = (i % GRID) as f32 * SPACING;
+            let y = (i / GRID % GRID) as f32 * SPACING;
+            let z = (i / (GRID * GRID)) as f32 * SPACING;
+            // The first copy is exact, the rest are jittered around it
+            let (jx, jy, jz) = match copy {
+                0 => (0.0, 0.0, 0.0),
+                _ => (jitter(&mut seed), jitter(&mut seed), jitter(&mut seed)),
+            };
+            points.push(OrdPoint {
+                x: x + jx,
+                y: y + jy,
+                z: z + jz,
+                epsilon: EPSILON,
+            });
+        }
+    }
+    points
+}
+
+#[test]
+fn jittered_points_dedup_to_the_unique_ones() {
+    let mut point_map = PointMap::new();
+    for (i, point) in points().iter().enumerate() {
+        if point_map.get(point).is_none() {
+            point_map.insert(point.clone(), i);
+        }
+    }
+    assert_eq!(point_map.len(), GRID * GRID * LAYERS);
+}
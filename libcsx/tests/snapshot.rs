@@ -0,0 +1,364 @@
+// Converts the scenes in the snapshots directory and compares the DIFs against the committed
+// snapshots, so refactors don't change the output by accident. Run with BLESS=1 to regenerate the
+// snapshots after an intended output change.
+use csx::bsp::ConvertConfig;
+use csx::bsp::SplitMethod;
+use csx::builder::export_lightmap_uvs;
+use csx::builder::RecordingListener;
+use csx::convert_csx_to_dif_with_config;
+use csx::lightmap::ShadowBias;
+use dif::dif::Dif;
+use dif::io::EngineVersion;
+use dif::types::BoxF;
+use dif::types::Point3F;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+struct Fixture {
+    name: &'static str,
+    mb_only: bool,
+    max_winding_indices: usize,
+    gi_bounces: u32,
+    sort_materials: bool,
+    // min x, y, z then max x, y, z
+    clip_bounds: Option<[f32; 6]>,
+    weld_tolerance: f32,
+    shadow_samples: u32,
+    shadow_bias: ShadowBias,
+}
+
+impl Fixture {
+    // The default conversion, the fixtures only list what they change
+    const DEFAULT: Fixture = Fixture {
+        name: "",
+        mb_only: true,
+        max_winding_indices: u32::MAX as usize,
+        gi_bounces: 0,
+        sort_materials: false,
+        clip_bounds: None,
+        weld_tolerance: 0.0,
+        shadow_samples: 1,
+        shadow_bias: ShadowBias::World(0.1),
+    };
+}
+
+impl Default for Fixture {
+    fn default() -> Self {
+        Fixture::DEFAULT
+    }
+}
+
+const FIXTURES: &[Fixture] = &[
+    // The default Marble Blast conversion
+    Fixture {
+        name: "simple",
+        ..Fixture::DEFAULT
+    },
+    // Baked lightmaps with an alarm state
+    Fixture {
+        name: "lit",
+        mb_only: false,
+        ..Fixture::DEFAULT
+    },
+    // Flickering and strobing lights, the light state entries must match the emitted state data.
+    // The lightmaps are baked with the omni light, leaving the animated lights out.
+    Fixture {
+        name: "animated",
+        mb_only: false,
+        ..Fixture::DEFAULT
+    },
+    // Lights, a moving platform, a trigger and a main interior split into several DIFs
+    Fixture {
+        name: "combined",
+        mb_only: false,
+        max_winding_indices: 60,
+        ..Fixture::DEFAULT
+    },
+    // A floor corner behind a wall that only the light bounced off the ceiling reaches
+    Fixture {
+        name: "bounce",
+        mb_only: false,
+        gi_bounces: 1,
+        ..Fixture::DEFAULT
+    },
+    // Materials used in reverse alphabetical order, sorted by name
+    Fixture {
+        name: "materials",
+        sort_materials: true,
+        ..Fixture::DEFAULT
+    },
+    // A row of boxes, the clip bounds only take the first half of them
+    Fixture {
+        name: "clipped",
+        clip_bounds: Some([-1.0, -1.0, -1.0, 25.0, 5.0, 5.0]),
+        ..Fixture::DEFAULT
+    },
+    // A box and a box textured as vehicle collision, the DIFs are read back to check the collision
+    Fixture {
+        name: "vehicle",
+        ..Fixture::DEFAULT
+    },
+    // A box mirrored along x next to a plain one, the mirrored box must still face outwards
+    Fixture {
+        name: "mirrored",
+        ..Fixture::DEFAULT
+    },
+    // A moving platform with a trigger brush turned 45 degrees around z, the trigger planes must be
+    // the turned faces of the brush
+    Fixture {
+        name: "trigger",
+        ..Fixture::DEFAULT
+    },
+    // A moving platform whose second path node is turned 90 degrees around z
+    Fixture {
+        name: "waypoints",
+        ..Fixture::DEFAULT
+    },
+    // A lit box with a chamfer too thin to have any area on one of its edges, the face must be
+    // dropped without giving NaN in the report
+    Fixture {
+        name: "sliver",
+        mb_only: false,
+        ..Fixture::DEFAULT
+    },
+    // Two boxes a hair apart, welding moves the wall of the second box onto the first so they
+    // share their points
+    Fixture {
+        name: "welded",
+        weld_tolerance: 1e-3,
+        ..Fixture::DEFAULT
+    },
+    // A box over a lit floor, its shadow has hard edges with a single shadow ray
+    Fixture {
+        name: "hard_shadows",
+        mb_only: false,
+        ..Fixture::DEFAULT
+    },
+    // The same box and floor with the shadow rays spread around the light, the penumbra adds more
+    // shades to the lightmap than the blur of the hard shadow
+    Fixture {
+        name: "soft_shadows",
+        mb_only: false,
+        shadow_samples: 16,
+        ..Fixture::DEFAULT
+    },
+    // A bare floor lit at a low angle with a small bias, every lumel of the top sees the light and
+    // none may shadow itself
+    Fixture {
+        name: "flat_floor",
+        mb_only: false,
+        shadow_bias: ShadowBias::World(0.05),
+        ..Fixture::DEFAULT
+    },
+];
+
+// FNV-1a, unlike the std hashers it's guaranteed to stay the same between Rust versions
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn snapshot(fixture: &Fixture, csx: String) -> String {
+    let config = ConvertConfig {
+        mb_only: fixture.mb_only,
+        split_method: SplitMethod::Exhaustive,
+        max_winding_indices: fixture.max_winding_indices,
+        gi_bounces: fixture.gi_bounces,
+        sort_materials: fixture.sort_materials,
+        weld_tolerance: fixture.weld_tolerance,
+        shadow_samples: fixture.shadow_samples,
+        shadow_bias: fixture.shadow_bias,
+        clip_bounds: fixture.clip_bounds.map(|b| BoxF {
+            min: Point3F::new(b[0], b[1], b[2]),
+            max: Point3F::new(b[3], b[4], b[5]),
+        }),
+        ..ConvertConfig::DEFAULT
+    };
+    let mut listener = RecordingListener::new();
+    let (bufs, reports, skipped_brushes, warnings, _) =
+        match convert_csx_to_dif_with_config(csx, EngineVersion::MBG, 0, &config, &mut listener) {
+            Ok(result) => result,
+            Err(e) => return format!("error: {}\n", e),
+        };
+    // The events without a total are one off notes, like the faces that were skipped
+    let mut lines = listener
+        .events
+        .iter()
+        .filter(|(_, total, _, _)| *total == 0)
+        .map(|(_, _, status, _)| format!("note: {}", status))
+        .collect::<Vec<_>>();
+    for (i, buf) in bufs.iter().enumerate() {
+        lines.push(format!(
+            "dif {}: {} bytes, hash {:016x}",
+            i,
+            buf.len(),
+            fnv1a(buf)
+        ));
+        match Dif::from_bytes(buf) {
+            Ok((dif, _)) => {
+                for (j, way_point) in dif
+                    .interior_path_followers
+                    .iter()
+                    .flat_map(|p| p.way_points.iter())
+                    .enumerate()
+                {
+                    let r = way_point.rotation;
+                    lines.push(format!(
+                        "dif {} way point {}: rotation {:.4} {:.4} {:.4} {:.4}",
+                        i, j, r.v.x, r.v.y, r.v.z, r.s
+                    ));
+                }
+                for (j, itr) in dif.interiors.iter().enumerate() {
+                    lines.push(format!(
+                        "dif {} interior {}: {} points",
+                        i,
+                        j,
+                        itr.points.len()
+                    ));
+                }
+                for (j, itr) in dif
+                    .interiors
+                    .iter()
+                    .chain(dif.sub_objects.iter())
+                    .enumerate()
+                    .filter(|(_, itr)| {
+                        itr.num_light_state_entries as usize != itr.state_datas.len()
+                    })
+                {
+                    lines.push(format!(
+                        "dif {} interior {}: {} light state entries for {} state datas",
+                        i,
+                        j,
+                        itr.num_light_state_entries,
+                        itr.state_datas.len()
+                    ));
+                }
+                for (j, itr) in dif
+                    .interiors
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, itr)| !itr.light_maps.is_empty())
+                {
+                    let surfaces = export_lightmap_uvs(itr);
+                    // The UVs must stay within the packed rect of their surface
+                    let outside = surfaces
+                        .iter()
+                        .flat_map(|s| s.uvs.iter().map(move |uv| (s, uv)))
+                        .filter(|(s, uv)| {
+                            (0..2).any(|k| {
+                                let texel = uv[k] * s.atlas_size[k] as f32;
+                                texel < s.rect[k] as f32
+                                    || texel > (s.rect[k] + s.rect[k + 2]) as f32
+                            })
+                        })
+                        .count();
+                    lines.push(format!(
+                        "dif {} interior {} lightmap uvs: {} surfaces, {} outside their rect",
+                        i,
+                        j,
+                        surfaces.len(),
+                        outside
+                    ));
+                    // The hard shadows only get the few shades the blur gives their edges
+                    for (k, light_map) in itr.light_maps.iter().enumerate() {
+                        let shades = image::load_from_memory(&light_map.light_map.data)
+                            .map(|image| {
+                                image
+                                    .to_rgb8()
+                                    .pixels()
+                                    .map(|p| p[0])
+                                    .collect::<BTreeSet<_>>()
+                                    .len()
+                            })
+                            .unwrap_or(0);
+                        lines.push(format!(
+                            "dif {} interior {} lightmap {}: {} shades",
+                            i, j, k, shades
+                        ));
+                    }
+                }
+                for (j, trigger) in dif.triggers.iter().enumerate() {
+                    let polyhedron = &trigger.polyhedron;
+                    lines.push(format!(
+                        "dif {} trigger {}: {} points, {} edges",
+                        i,
+                        j,
+                        polyhedron.point_list.len(),
+                        polyhedron.edge_list.len()
+                    ));
+                    for plane in polyhedron.plane_list.iter() {
+                        lines.push(format!(
+                            "dif {} trigger {} plane: {:.4} {:.4} {:.4} {:.4}",
+                            i, j, plane.normal.x, plane.normal.y, plane.normal.z, plane.distance
+                        ));
+                    }
+                }
+                if let Some(vehicle_collision) = &dif.vehicle_collision {
+                    lines.push(format!(
+                        "dif {} vehicle collision: {} hulls, {} null surfaces, {} points",
+                        i,
+                        vehicle_collision.convex_hulls.len(),
+                        vehicle_collision.null_surfaces.len(),
+                        vehicle_collision.points.len()
+                    ));
+                }
+            }
+            Err(e) => lines.push(format!("dif {} unreadable: {:?}", i, e)),
+        }
+    }
+    for (i, report) in reports.iter().enumerate() {
+        lines.push(format!(
+            "report {}: hit {}/{}, hit area {}%, balance factor {}",
+            i, report.hit, report.total, report.hit_area_percentage, report.balance_factor
+        ));
+    }
+    for skipped in skipped_brushes.iter() {
+        lines.push(format!(
+            "skipped brush {}: {:?}",
+            skipped.id, skipped.reason
+        ));
+    }
+    for warning in warnings.iter() {
+        lines.push(format!("warning: {}", warning));
+    }
+    lines.join("\n") + "\n"
+}
+
+#[test]
+fn snapshots_match() {
+    let bless = std::env::var("BLESS").is_ok_and(|v| v == "1");
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("snapshots");
+    let mut changed = vec![];
+    for fixture in FIXTURES {
+        let csx = std::fs::read_to_string(dir.join(format!("{}.csx", fixture.name))).unwrap();
+        let actual = snapshot(fixture, csx);
+        let snap_path = dir.join(format!("{}.snap", fixture.name));
+        if bless {
+            std::fs::write(&snap_path, &actual).unwrap();
+            continue;
+        }
+        let expected = std::fs::read_to_string(&snap_path).unwrap_or_default();
+        if actual != expected {
+            println!("{}: changed", fixture.name);
+            for line in expected
+                .lines()
+                .filter(|l| !actual.lines().any(|a| a == *l))
+            {
+                println!("  - {}", line);
+            }
+            for line in actual
+                .lines()
+                .filter(|l| !expected.lines().any(|e| e == *l))
+            {
+                println!("  + {}", line);
+            }
+            changed.push(fixture.name);
+        }
+    }
+    assert!(
+        changed.is_empty(),
+        "the snapshots of {:?} changed, run with BLESS=1 if the changes are intended",
+        changed
+    );
+}
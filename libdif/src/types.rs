@@ -14,7 +14,7 @@ pub type Point2I = Vector2<i32>;
 
 pub type Point3F = Vector3<f32>;
 
-#[derive(Debug, Readable, Writable)]
+#[derive(Debug, Clone, Readable, Writable)]
 pub struct BoxF {
     pub min: Point3F,
     pub max: Point3F,
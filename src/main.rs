@@ -1,17 +1,51 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
 use std::thread;
+use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
 
 use clap::Parser;
 use clap::ValueEnum;
+use csx::bsp::BSPCache;
+use csx::bsp::ConvertConfig;
 use csx::bsp::SplitMethod;
+use csx::builder::export_lightmap_uvs;
+use csx::builder::export_obj;
+use csx::builder::interior_metrics;
+use csx::builder::interior_oriented_bounds;
+use csx::builder::point_visibility_csv;
+use csx::builder::stack_lightmaps;
+use csx::builder::CoordBinMode;
+use csx::builder::NoopListener;
 use csx::builder::ProgressEventListener;
-use csx::convert_csx_to_dif;
-use csx::set_convert_configuration;
+use csx::convert_combined_csx_to_dif_cached;
+use csx::convert_csx_to_proxy_dif;
+use csx::csx::export_brushes_to_map;
+use csx::csx::export_scene_csx;
+use csx::csx::export_scene_json;
+use csx::csx::preprocess_csx;
+use csx::csx::scene_entity_summary;
+use csx::csx::try_parse_scene;
+use csx::csx::ConversionWarning;
+use csx::light::AttenuationModel;
+use csx::lightmap::BlurKind;
+use csx::lightmap::ShadowBias;
+use csx::supported_versions;
+use dif::dif::Dif;
 use dif::io::EngineVersion;
+use dif::types::BoxF;
+use dif::types::PlaneF;
+use dif::types::Point3F;
 use indicatif::MultiProgress;
 use indicatif::ProgressBar;
 use indicatif::ProgressStyle;
+use serde::Serialize;
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum BSPAlgo {
@@ -20,6 +54,7 @@ enum BSPAlgo {
     None,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum EngineVer {
     MBG,
@@ -28,6 +63,40 @@ enum EngineVer {
     T3D,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum CoordBins {
+    Xy,
+    Xz,
+    Yz,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Attenuation {
+    Linear,
+    InverseSquare,
+    Smoothstep,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum ShadowBiasUnit {
+    World,
+    Lumels,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum LightmapBlur {
+    None,
+    Box,
+    Gaussian,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum ProgressFormat {
+    Bars,
+    Json,
+}
+
+#[allow(clippy::from_over_into)]
 impl Into<EngineVersion> for EngineVer {
     fn into(self) -> EngineVersion {
         match self {
@@ -39,6 +108,27 @@ impl Into<EngineVersion> for EngineVer {
     }
 }
 
+impl From<CoordBins> for CoordBinMode {
+    fn from(val: CoordBins) -> Self {
+        match val {
+            CoordBins::Xy => CoordBinMode::XY,
+            CoordBins::Xz => CoordBinMode::XZ,
+            CoordBins::Yz => CoordBinMode::YZ,
+        }
+    }
+}
+
+impl From<Attenuation> for AttenuationModel {
+    fn from(val: Attenuation) -> Self {
+        match val {
+            Attenuation::Linear => AttenuationModel::Linear,
+            Attenuation::InverseSquare => AttenuationModel::InverseSquare,
+            Attenuation::Smoothstep => AttenuationModel::Smoothstep,
+        }
+    }
+}
+
+#[allow(clippy::from_over_into)]
 impl Into<SplitMethod> for BSPAlgo {
     fn into(self) -> SplitMethod {
         match self {
@@ -55,7 +145,11 @@ impl Into<SplitMethod> for BSPAlgo {
 #[command(version = "1.0.9")]
 #[command(about = "Convert Torque Constructor CSX files to Torque DIF files easily!")]
 struct Args {
-    filepath: String,
+    #[arg(
+        required_unless_present = "list_engine_versions",
+        help = "CSX files to convert, glob patterns like \"maps/*.csx\" convert every matching file and a directory converts the CSX files in it"
+    )]
+    filepaths: Vec<String>,
     #[arg(
         short,
         long,
@@ -63,6 +157,13 @@ struct Args {
         default_value = "false"
     )]
     silent: bool,
+    #[arg(
+        value_enum,
+        long,
+        help = "How to show the progress, json writes one object per event to stderr",
+        default_value = "bars"
+    )]
+    progress: Option<ProgressFormat>,
     #[arg(short, long, value_parser = clap::value_parser!(u32).range(0..14), help = "Dif version to export to", default_value = "0")]
     dif_version: Option<u32>,
     #[arg(
@@ -86,6 +187,140 @@ struct Args {
         default_value = "exhaustive"
     )]
     bsp: Option<BSPAlgo>,
+    #[arg(
+        value_enum,
+        long,
+        help = "BSP algorithm to use for the subobjects, defaults to the --bsp algorithm"
+    )]
+    subobject_bsp: Option<BSPAlgo>,
+    #[arg(
+        long,
+        help = "Seed of the splitters the sampling BSP picks, the same seed always builds the same tree",
+        default_value = "42"
+    )]
+    bsp_seed: Option<u64>,
+    #[arg(
+        long,
+        help = "Number of splitters the sampling BSP rates at each node, more builds a better tree slower",
+        default_value = "32"
+    )]
+    bsp_samples: Option<usize>,
+    #[arg(
+        long,
+        help = "Stop splitting the BSP nodes this deep, bounds the build time of the brush soups at the cost of coarser raycasts"
+    )]
+    bsp_max_depth: Option<u32>,
+    #[arg(
+        long,
+        help = "Bake the lightmaps of the subobjects, otherwise they only get the ambient color",
+        default_value = "true"
+    )]
+    subobject_lightmaps: Option<bool>,
+    #[arg(
+        long,
+        help = "Bake the lights and shadows into the lightmaps, otherwise they are filled with the ambient color",
+        default_value = "true"
+    )]
+    bake_lighting: Option<bool>,
+    #[arg(
+        long,
+        help = "Bounce the baked light off the lit surfaces this many times, 0 only bakes the direct light",
+        default_value = "0"
+    )]
+    gi_bounces: Option<u32>,
+    #[arg(
+        long,
+        help = "Average this many shadow rays to points around each light into soft shadows, 1 casts a single ray for hard shadows",
+        default_value = "1"
+    )]
+    shadow_samples: Option<u32>,
+    #[arg(
+        long,
+        help = "Distance off the surfaces the lightmap shadow rays end, in the units of --shadow-bias-unit. Too small speckles the surfaces with self-shadowing, too large detaches the shadows from their casters",
+        default_value = "0.1"
+    )]
+    shadow_bias: Option<f32>,
+    #[arg(
+        value_enum,
+        long,
+        help = "Whether the shadow bias is in world units or a fraction of the lumel size of each surface, the lumels keep the bias in step with the lumel scale",
+        default_value = "world"
+    )]
+    shadow_bias_unit: Option<ShadowBiasUnit>,
+    #[arg(
+        value_enum,
+        long,
+        help = "How the point and omni lights fade out between their inner and outer radii",
+        default_value = "linear"
+    )]
+    attenuation: Option<Attenuation>,
+    #[arg(
+        long,
+        help = "Generate the light direction maps of the lightmaps, for the bumped surfaces of TGEA and T3D"
+    )]
+    light_dir_maps: bool,
+    #[arg(
+        long,
+        value_parser = parse_lightmap_size,
+        help = "Width and height of the lightmap atlases, a power of two from 64 to 2048",
+        default_value = "256"
+    )]
+    lightmap_size: Option<u32>,
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Bake the lightmaps at 1/N resolution and upscale them, faster but blurrier",
+        default_value = "1"
+    )]
+    lightmap_downscale: Option<u32>,
+    #[arg(
+        long,
+        help = "Let the lightmap packer turn the lightmaps of tall surfaces sideways to fit more of them in each atlas",
+        default_value = "false"
+    )]
+    rotate_lightmaps: bool,
+    #[arg(
+        long,
+        help = "Texels of padding around each packed lightmap, so the texture filtering doesn't bleed the neighbouring lightmaps in",
+        default_value = "1"
+    )]
+    lightmap_gutter: Option<u32>,
+    #[arg(
+        value_enum,
+        long,
+        help = "The filter the baked lightmaps are blurred with to hide the seams between the lumels, the gaussian one is softer at large lumel scales",
+        default_value = "box"
+    )]
+    lightmap_blur: Option<LightmapBlur>,
+    #[arg(
+        long,
+        help = "Lumels on each side the lightmap blur averages over",
+        default_value = "1"
+    )]
+    lightmap_blur_radius: Option<u32>,
+    #[arg(
+        long,
+        help = "Standard deviation of the gaussian lightmap blur in lumels",
+        default_value = "1"
+    )]
+    lightmap_blur_sigma: Option<f32>,
+    #[arg(
+        long,
+        help = "Index of the only detail level that gets collision, the others are render only"
+    )]
+    collision_detail_level: Option<usize>,
+    #[arg(
+        value_enum,
+        long,
+        help = "Plane to lay out the coordbins in, defaults to xy which is the only one the stock engines read"
+    )]
+    coord_bin_mode: Option<CoordBins>,
+    #[arg(
+        long,
+        help = "Generate the coordbins the engine finds the convex hulls to collide with through, only disable them for interiors that are never collided with",
+        default_value = "true"
+    )]
+    coord_bins: Option<bool>,
     #[arg(
         long,
         help = "Epsilon for points to be considered the same",
@@ -98,21 +333,353 @@ struct Args {
         default_value = "0.00001"
     )]
     epsilon_plane: Option<f32>,
+    #[arg(
+        long,
+        help = "Cache the BSP and hulls in a sidecar .bspcache file and reuse them if only materials changed",
+        default_value = "false"
+    )]
+    cache: bool,
+    #[arg(
+        long,
+        help = "Reverse the winding of faces whose winding opposes their plane normal",
+        default_value = "false"
+    )]
+    fix_windings: bool,
+    #[arg(
+        long,
+        help = "Snap the vertices to a grid of this size, 0 to disable",
+        default_value = "0"
+    )]
+    snap: Option<f32>,
+    #[arg(
+        long,
+        help = "Snap the face normals within this many degrees of an axis to the axis, 0 to disable",
+        default_value = "0"
+    )]
+    axis_snap: Option<f32>,
+    #[arg(
+        long,
+        help = "Weld the brush vertices within this distance of each other into one, 0 to disable",
+        default_value = "0"
+    )]
+    weld: Option<f32>,
+    #[arg(
+        long,
+        value_parser = parse_clip_bounds,
+        allow_hyphen_values = true,
+        help = "Only convert the brushes overlapping these bounds, as \"MINX MINY MINZ MAXX MAXY MAXZ\", the brushes crossing them are kept whole"
+    )]
+    clip_bounds: Option<(Point3F, Point3F)>,
+    #[arg(
+        long,
+        help = "Put the interiors split off due to the face limit in the main DIF instead of separate DIFs",
+        default_value = "false"
+    )]
+    merge_interiors: bool,
+    #[arg(
+        long,
+        help = "Split the interiors before their winding indices exceed this count",
+        default_value = "4294967295"
+    )]
+    max_winding_indices: Option<usize>,
+    #[arg(
+        long,
+        help = "Put every winding point in the surface fan masks, including the collinear ones the fans skip otherwise",
+        default_value = "false"
+    )]
+    full_fan_mask: bool,
+    #[arg(
+        long,
+        help = "Compute the point visibilities from ambient occlusion instead of marking all points visible",
+        default_value = "false"
+    )]
+    point_visibility: bool,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Comma separated list of materials that don't receive lightmaps"
+    )]
+    unlit: Vec<String>,
+    #[arg(
+        long,
+        value_parser = parse_texgen_override,
+        help = "Force the texgen of a material, as MATERIAL=\"X1 Y1 Z1 D1 X2 Y2 Z2 D2\" with the world space U and V planes, can be repeated"
+    )]
+    texgen_override: Vec<(String, PlaneF, PlaneF)>,
+    #[arg(
+        long,
+        help = "Sort the materials of the DIF by name instead of the order the brushes use them in"
+    )]
+    sort_materials: bool,
+    #[arg(
+        short,
+        long,
+        help = "Print the brushes that were skipped and why, the spawn points and the seed of the sampling BSP",
+        default_value = "false"
+    )]
+    verbose: bool,
+    #[arg(
+        long,
+        help = "Print the surface area, volume and brush count of each interior",
+        default_value = "false"
+    )]
+    stats: bool,
+    #[arg(
+        long,
+        help = "Print the centroid, principal axes and extents of each interior",
+        default_value = "false"
+    )]
+    obb: bool,
+    #[arg(
+        long,
+        help = "Print the number of entities of each classname without converting",
+        default_value = "false"
+    )]
+    inventory: bool,
+    #[arg(
+        long,
+        help = "Print the entities and moving platforms of the scene as JSON without converting",
+        default_value = "false"
+    )]
+    dump_scene: bool,
+    #[arg(
+        long,
+        help = "Write the scene to the given file as CSX after the brushes are moved to world space and the texgens fixed up, without converting"
+    )]
+    dump_preprocessed: Option<String>,
+    #[arg(
+        long,
+        help = "Write the brushes to a Quake .map next to the DIF after they are moved to world space, to inspect them in an idTech editor",
+        default_value = "false"
+    )]
+    export_map: bool,
+    #[arg(
+        long,
+        help = "Print the supported engine versions and their DIF versions",
+        default_value = "false"
+    )]
+    list_engine_versions: bool,
+    #[arg(
+        long,
+        help = "Only write a box interior with the bounds of the scene, textured with the given material"
+    )]
+    proxy: Option<String>,
+    #[arg(
+        long,
+        help = "Warn about likely mistakes in the scene, like lights inside brushes",
+        default_value = "false"
+    )]
+    strict: bool,
+    #[arg(
+        long,
+        help = "Treat the conversion warnings as errors, no DIF is written if there are any",
+        default_value = "false"
+    )]
+    deny_warnings: bool,
+    #[arg(
+        long,
+        help = "File listing the available materials one per line, warns about the materials missing from it, or fails with --strict"
+    )]
+    materials_manifest: Option<String>,
+    #[arg(
+        long,
+        help = "Percentage of the surface area the BSP raycasts must hit for an interior to pass",
+        default_value = "95"
+    )]
+    coverage_threshold: Option<f32>,
+    #[arg(
+        long,
+        help = "Exit with an error if the BSP coverage of any interior is below the coverage threshold",
+        default_value = "false"
+    )]
+    fail_on_low_coverage: bool,
+    #[arg(
+        long,
+        help = "Number of threads to use for the conversion, 0 to use all cores",
+        default_value = "0"
+    )]
+    threads: usize,
+    #[arg(
+        long,
+        help = "Number of files to convert in parallel",
+        default_value = "1"
+    )]
+    jobs: usize,
+    #[arg(
+        long,
+        help = "Keep running and convert the files again whenever they change, a change during a conversion cancels it",
+        default_value = "false"
+    )]
+    watch: bool,
+    #[arg(
+        long,
+        help = "Write the DIFs and the other outputs to this directory instead of next to the CSX, created if missing"
+    )]
+    output_dir: Option<String>,
+    #[arg(
+        long,
+        help = "Write a JSON list of all the files created by the conversion to the given file"
+    )]
+    manifest: Option<String>,
+    #[arg(
+        long,
+        help = "Write the BSP reports of every interior to the given file as JSON"
+    )]
+    report_json: Option<String>,
+    #[arg(
+        long,
+        help = "Also write the lightmaps of each interior stacked into a single PNG, with a JSON of the row each lightmap starts at",
+        default_value = "false"
+    )]
+    stack_lightmaps: bool,
+    #[arg(
+        long,
+        help = "Write the position and visibility of every point of each interior to a CSV next to the DIF",
+        default_value = "false"
+    )]
+    dump_visibility: bool,
+    #[arg(
+        long,
+        help = "Write the lightmap UVs of every surface of each lightmapped interior to a JSON next to the DIF, for baking the lighting in another tool",
+        default_value = "false"
+    )]
+    lightmap_uvs: bool,
+    #[arg(
+        long,
+        help = "Write the triangles of each interior as they are rendered to an OBJ and MTL next to the DIF, for comparing against the CSX in a modeling tool",
+        default_value = "false"
+    )]
+    export_obj: bool,
+    #[arg(
+        long,
+        num_args = 1..,
+        help = "Other CSX files to combine with the converted file into a single DIF, needs a single input file"
+    )]
+    combine: Vec<String>,
+    #[cfg(feature = "trace")]
+    #[arg(
+        long,
+        help = "Write a chrome trace of the conversion to the given file"
+    )]
+    profile: Option<String>,
 }
 
+#[derive(Serialize)]
+struct ManifestEntry {
+    path: String,
+    #[serde(rename = "type")]
+    file_type: &'static str,
+}
+
+// Parses MATERIAL=X1 Y1 Z1 D1 X2 Y2 Z2 D2 into the material and its U and V texgen planes
+fn parse_texgen_override(s: &str) -> Result<(String, PlaneF, PlaneF), String> {
+    let (material, planes) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected MATERIAL=PLANES in \"{}\"", s))?;
+    let numbers = planes
+        .split_whitespace()
+        .map(|v| {
+            v.parse::<f32>()
+                .map_err(|_| format!("invalid number {} in \"{}\"", v, planes))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if numbers.len() != 8 {
+        return Err(format!(
+            "expected 8 numbers in \"{}\", got {}",
+            planes,
+            numbers.len()
+        ));
+    }
+    let plane = |n: &[f32]| PlaneF {
+        normal: Point3F::new(n[0], n[1], n[2]),
+        distance: n[3],
+    };
+    Ok((
+        material.to_string(),
+        plane(&numbers[0..4]),
+        plane(&numbers[4..8]),
+    ))
+}
+
+// Parses a lightmap atlas size, a power of two from 64 to 2048
+fn parse_lightmap_size(s: &str) -> Result<u32, String> {
+    let size = s
+        .parse::<u32>()
+        .map_err(|_| format!("invalid number \"{}\"", s))?;
+    if !size.is_power_of_two() || !(64..=2048).contains(&size) {
+        return Err(format!("{} is not a power of two from 64 to 2048", size));
+    }
+    Ok(size)
+}
+
+// Parses MINX MINY MINZ MAXX MAXY MAXZ into the min and max corners of the bounds
+fn parse_clip_bounds(s: &str) -> Result<(Point3F, Point3F), String> {
+    let numbers = s
+        .split_whitespace()
+        .map(|v| {
+            v.parse::<f32>()
+                .map_err(|_| format!("invalid number {} in \"{}\"", v, s))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if numbers.len() != 6 {
+        return Err(format!(
+            "expected 6 numbers in \"{}\", got {}",
+            s,
+            numbers.len()
+        ));
+    }
+    let min = Point3F::new(numbers[0], numbers[1], numbers[2]);
+    let max = Point3F::new(numbers[3], numbers[4], numbers[5]);
+    if min.x > max.x || min.y > max.y || min.z > max.z {
+        return Err(format!(
+            "the min corner of \"{}\" is above the max corner",
+            s
+        ));
+    }
+    Ok((min, max))
+}
+
+#[derive(Serialize)]
+struct ReportEntry {
+    file: String,
+    interior: usize,
+    hit: i32,
+    total: usize,
+    hit_area_percentage: f32,
+    balance_factor: i32,
+    passed: bool,
+}
+
+#[derive(Serialize)]
+struct StackedLightmap {
+    lightmap: usize,
+    row_offset: u32,
+}
+
+// (stop, current, total, status, finish_status)
+type ProgressMessage = (bool, u32, u32, String, String);
+
 struct ConsoleProgressListener {
-    thread_tx: Option<std::sync::mpsc::Sender<(bool, u32, u32, String, String)>>,
+    thread_tx: Option<std::sync::mpsc::Sender<ProgressMessage>>,
+    progress_bar: MultiProgress,
+    // Put in front of the status of the bars, so the bars of the files converted in parallel
+    // don't get mixed up
+    prefix: String,
 }
 
 impl ConsoleProgressListener {
     fn new() -> Self {
-        ConsoleProgressListener { thread_tx: None }
+        ConsoleProgressListener {
+            thread_tx: None,
+            progress_bar: MultiProgress::new(),
+            prefix: String::new(),
+        }
     }
     fn init(&mut self) -> thread::JoinHandle<()> {
         let (sender, receiver) = std::sync::mpsc::channel();
         self.thread_tx = Some(sender);
+        let progress_bar = self.progress_bar.clone();
         let handler: thread::JoinHandle<_> = thread::spawn(move || {
-            let progress_bar: MultiProgress = MultiProgress::new();
             let mut progress_types: HashMap<String, (ProgressBar, Instant)> = HashMap::new();
             loop {
                 let (stop, current, total, status, finish_status): (
@@ -158,6 +725,21 @@ impl ConsoleProgressListener {
         handler
     }
 
+    // A listener drawing to the same bars, with its status prefixed
+    fn with_prefix(&self, prefix: String) -> Self {
+        ConsoleProgressListener {
+            thread_tx: self.thread_tx.clone(),
+            progress_bar: self.progress_bar.clone(),
+            prefix,
+        }
+    }
+
+    // Prints the lines above the bars without the bars drawing over them
+    fn print(&self, lines: &[String]) {
+        self.progress_bar
+            .suspend(|| lines.iter().for_each(|line| println!("{}", line)));
+    }
+
     fn stop(&self) {
         self.thread_tx
             .as_ref()
@@ -172,68 +754,1396 @@ impl ProgressEventListener for ConsoleProgressListener {
         self.thread_tx
             .as_ref()
             .unwrap()
-            .send((false, current, total, status, finish_status))
+            .send((
+                false,
+                current,
+                total,
+                format!("{}{}", self.prefix, status),
+                format!("{}{}", self.prefix, finish_status),
+            ))
             .unwrap();
     }
 }
 
-struct SilentListener {}
+#[derive(Serialize)]
+struct ProgressEvent<'a> {
+    stage: &'a str,
+    current: u32,
+    total: u32,
+}
 
-impl ProgressEventListener for SilentListener {
-    fn progress(&mut self, _: u32, _: u32, _: String, _: String) {}
+// Writes every progress event as a line of JSON, to stderr for tools wrapping the CLI
+struct JsonProgressListener<W: std::io::Write> {
+    out: W,
 }
 
-fn main() {
-    let args = Args::parse();
-    let filepath = &args.filepath;
-    println!("Converting {}", filepath);
+impl<W: std::io::Write> ProgressEventListener for JsonProgressListener<W> {
+    fn progress(&mut self, current: u32, total: u32, status: String, _finish_status: String) {
+        let event = ProgressEvent {
+            stage: &status,
+            current,
+            total,
+        };
+        writeln!(self.out, "{}", serde_json::to_string(&event).unwrap()).unwrap();
+    }
+}
 
-    let mut listener = ConsoleProgressListener::new();
-    let mut silent_listener = SilentListener {};
-    let join_handler = listener.init();
+// Passes the progress on to another listener, and cancels the conversion once the flag is set
+struct CancellableListener<'a> {
+    inner: &'a mut dyn ProgressEventListener,
+    cancel: &'a AtomicBool,
+}
 
-    let listener_to_pass: &mut dyn ProgressEventListener = if args.silent {
-        &mut silent_listener
-    } else {
-        &mut listener
-    };
+impl ProgressEventListener for CancellableListener<'_> {
+    fn progress(&mut self, current: u32, total: u32, status: String, finish_status: String) {
+        self.inner.progress(current, total, status, finish_status);
+    }
 
-    let reader = std::fs::read_to_string(filepath).unwrap();
-    unsafe {
-        set_convert_configuration(
-            args.mb.unwrap(),
-            args.epsilon_point.unwrap(),
-            args.epsilon_plane.unwrap(),
-            args.bsp.unwrap().into(),
+    fn cancelled(&self) -> bool {
+        self.cancel.load(Ordering::SeqCst)
+    }
+}
+
+// Expands the glob patterns and the directories among the paths, a path matching nothing is kept
+// as is so reading it reports the missing file
+fn expand_paths(patterns: &[String]) -> Vec<String> {
+    patterns
+        .iter()
+        .flat_map(|pattern| {
+            let pattern = if std::path::Path::new(pattern).is_dir() {
+                glob::Pattern::escape(pattern) + "/*.csx"
+            } else {
+                pattern.clone()
+            };
+            let matches = glob::glob(&pattern)
+                .map(|paths| {
+                    paths
+                        .filter_map(|path| path.ok())
+                        .map(|path| path.to_string_lossy().into_owned())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            if matches.is_empty() {
+                vec![pattern]
+            } else {
+                matches
+            }
+        })
+        .collect()
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))
+}
+
+fn write_file(path: &str, data: impl AsRef<[u8]>) -> Result<(), String> {
+    std::fs::write(path, data).map_err(|e| format!("Could not write {}: {}", path, e))
+}
+
+// What a file printed and wrote, printed once the file is done so the output of the files
+// converted in parallel doesn't interleave
+#[derive(Default)]
+struct FileOutput {
+    lines: Vec<String>,
+    manifest: Vec<ManifestEntry>,
+    reports: Vec<ReportEntry>,
+}
+
+fn process_file(
+    args: &Args,
+    config: &ConvertConfig,
+    filepath: &str,
+    listener: &mut dyn ProgressEventListener,
+    output: &mut FileOutput,
+) -> Result<(), String> {
+    if args.inventory {
+        let scene = try_parse_scene(read_file(filepath)?.as_bytes()).map_err(|e| e.to_string())?;
+        scene_entity_summary(&scene)
+            .into_iter()
+            .collect::<BTreeMap<_, _>>()
+            .iter()
+            .for_each(|(classname, count)| output.lines.push(format!("{}: {}", classname, count)));
+        return Ok(());
+    }
+    if args.dump_scene {
+        let mut scene =
+            try_parse_scene(read_file(filepath)?.as_bytes()).map_err(|e| e.to_string())?;
+        // Expand the brush instances so the ones owned by moving platforms are listed too
+        preprocess_csx(&mut scene, false, 0.0, 0.0);
+        output.lines.push(export_scene_json(&scene));
+        return Ok(());
+    }
+    if let Some(dump_path) = &args.dump_preprocessed {
+        let mut scene =
+            try_parse_scene(read_file(filepath)?.as_bytes()).map_err(|e| e.to_string())?;
+        preprocess_csx(
+            &mut scene,
+            config.fix_windings,
+            config.vertex_snap,
+            config.axis_snap_angle,
         );
+        write_file(
+            dump_path,
+            export_scene_csx(&scene).map_err(|e| e.to_string())?,
+        )?;
+        output.manifest.push(ManifestEntry {
+            path: dump_path.clone(),
+            file_type: "preprocessed-scene",
+        });
+        return Ok(());
     }
-    let ret_path = std::path::Path::new(&args.filepath)
-        .with_extension("")
-        .into_os_string()
-        .into_string()
-        .unwrap();
-    let (buf, reports) = convert_csx_to_dif(
-        reader,
-        args.engine_version.unwrap().into(),
-        args.dif_version.unwrap(),
-        listener_to_pass,
-    );
-    buf.iter().enumerate().for_each(|(i, b)| {
-        if i == 0 {
-            std::fs::write(format!("{}.dif", ret_path), b).unwrap();
+
+    let readers = std::iter::once(filepath)
+        .chain(args.combine.iter().map(|path| path.as_str()))
+        .map(read_file)
+        .collect::<Result<Vec<_>, _>>()?;
+    // The outputs are named after the CSX, with the extension stripped
+    let ret_path = match &args.output_dir {
+        Some(output_dir) => std::path::Path::new(output_dir).join(
+            std::path::Path::new(filepath)
+                .file_stem()
+                .unwrap_or_default(),
+        ),
+        None => std::path::Path::new(filepath).with_extension(""),
+    }
+    .into_os_string()
+    .into_string()
+    .unwrap();
+    if let Some(material) = &args.proxy {
+        let dif_path = format!("{}.dif", ret_path);
+        let buf = convert_csx_to_proxy_dif(
+            readers[0].clone(),
+            args.engine_version.unwrap().into(),
+            args.dif_version.unwrap(),
+            material,
+            config,
+        )
+        .map_err(|e| e.to_string())?;
+        write_file(&dif_path, buf)?;
+        output.manifest.push(ManifestEntry {
+            path: dif_path,
+            file_type: "dif",
+        });
+        return Ok(());
+    }
+    // Written before converting, so the brushes can be looked at when the conversion fails
+    if args.export_map {
+        let mut scene = try_parse_scene(readers[0].as_bytes()).map_err(|e| e.to_string())?;
+        preprocess_csx(
+            &mut scene,
+            config.fix_windings,
+            config.vertex_snap,
+            config.axis_snap_angle,
+        );
+        let map_path = format!("{}.map", ret_path);
+        write_file(&map_path, export_brushes_to_map(&scene))?;
+        output.manifest.push(ManifestEntry {
+            path: map_path,
+            file_type: "map",
+        });
+    }
+    let cache_path = format!("{}.bspcache", ret_path);
+    let mut bsp_cache = if args.cache {
+        std::fs::read(&cache_path)
+            .map(|data| BSPCache::from_bytes(&data))
+            .unwrap_or_else(|_| BSPCache::new())
+    } else {
+        BSPCache::new()
+    };
+    let (buf, reports, skipped_brushes, warnings, spawn_points) =
+        convert_combined_csx_to_dif_cached(
+            readers,
+            args.engine_version.unwrap().into(),
+            args.dif_version.unwrap(),
+            config,
+            listener,
+            &mut bsp_cache,
+        )
+        .map_err(|e| e.to_string())?;
+    if args.cache {
+        write_file(&cache_path, bsp_cache.to_bytes())?;
+        output.manifest.push(ManifestEntry {
+            path: cache_path.clone(),
+            file_type: "bsp-cache",
+        });
+    }
+    // The outputs that look at the interiors read the DIFs back once
+    let reads_difs = args.stack_lightmaps
+        || args.lightmap_uvs
+        || args.export_obj
+        || args.dump_visibility
+        || args.stats
+        || args.obb;
+    let difs = if reads_difs {
+        buf.iter()
+            .map(|b| {
+                Dif::from_bytes(b)
+                    .map(|(dif, _)| dif)
+                    .map_err(|e| format!("Could not read back the converted DIF: {}", e))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        vec![]
+    };
+    for (i, b) in buf.iter().enumerate() {
+        let dif_path = if i == 0 {
+            format!("{}.dif", ret_path)
         } else {
-            std::fs::write(format!("{}-{}.dif", ret_path, i), b).unwrap();
+            format!("{}-{}.dif", ret_path, i)
+        };
+        write_file(&dif_path, b)?;
+        output.manifest.push(ManifestEntry {
+            path: dif_path.clone(),
+            file_type: "dif",
+        });
+        if args.stack_lightmaps {
+            let dif = &difs[i];
+            let dif_stem = dif_path.trim_end_matches(".dif");
+            for (j, itr) in dif
+                .interiors
+                .iter()
+                .enumerate()
+                .filter(|(_, itr)| !itr.light_maps.is_empty())
+            {
+                let (png, row_offsets) = stack_lightmaps(itr);
+                let png_path = format!("{}-lightmaps{}.png", dif_stem, j);
+                let json_path = format!("{}-lightmaps{}.json", dif_stem, j);
+                let mapping = row_offsets
+                    .iter()
+                    .enumerate()
+                    .map(|(lightmap, row_offset)| StackedLightmap {
+                        lightmap,
+                        row_offset: *row_offset,
+                    })
+                    .collect::<Vec<_>>();
+                write_file(&png_path, png)?;
+                write_file(&json_path, serde_json::to_string_pretty(&mapping).unwrap())?;
+                output.manifest.push(ManifestEntry {
+                    path: png_path,
+                    file_type: "stacked-lightmaps",
+                });
+                output.manifest.push(ManifestEntry {
+                    path: json_path,
+                    file_type: "stacked-lightmaps-mapping",
+                });
+            }
         }
-    });
-    listener.stop();
-    join_handler.join().unwrap();
+        if args.lightmap_uvs {
+            let dif = &difs[i];
+            let dif_stem = dif_path.trim_end_matches(".dif");
+            for (j, itr) in dif
+                .interiors
+                .iter()
+                .enumerate()
+                .filter(|(_, itr)| !itr.light_maps.is_empty())
+            {
+                let json_path = format!("{}-lightmap-uvs{}.json", dif_stem, j);
+                write_file(
+                    &json_path,
+                    serde_json::to_string_pretty(&export_lightmap_uvs(itr)).unwrap(),
+                )?;
+                output.manifest.push(ManifestEntry {
+                    path: json_path,
+                    file_type: "lightmap-uvs",
+                });
+            }
+        }
+        if args.export_obj {
+            let dif = &difs[i];
+            let dif_stem = dif_path.trim_end_matches(".dif");
+            for (j, itr) in dif.interiors.iter().enumerate() {
+                let obj_path = format!("{}-interior{}.obj", dif_stem, j);
+                let mtl_path = format!("{}-interior{}.mtl", dif_stem, j);
+                let mtl_file = std::path::Path::new(&mtl_path)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned();
+                let (obj, mtl) = export_obj(itr, &mtl_file);
+                write_file(&obj_path, obj)?;
+                write_file(&mtl_path, mtl)?;
+                output.manifest.push(ManifestEntry {
+                    path: obj_path,
+                    file_type: "obj",
+                });
+                output.manifest.push(ManifestEntry {
+                    path: mtl_path,
+                    file_type: "mtl",
+                });
+            }
+        }
+        if args.dump_visibility {
+            let dif = &difs[i];
+            let dif_stem = dif_path.trim_end_matches(".dif");
+            for (j, itr) in dif.interiors.iter().enumerate() {
+                let csv_path = format!("{}-visibility{}.csv", dif_stem, j);
+                write_file(&csv_path, point_visibility_csv(itr))?;
+                output.manifest.push(ManifestEntry {
+                    path: csv_path,
+                    file_type: "point-visibility",
+                });
+            }
+        }
+    }
+    let has_missing_materials = warnings
+        .iter()
+        .any(|w| matches!(w, ConversionWarning::MissingMaterial { .. }));
+    if (args.deny_warnings && !warnings.is_empty()) || (args.strict && has_missing_materials) {
+        warnings.iter().for_each(|w| {
+            output.lines.push(format!("Warning: {}", w));
+        });
+        return Err(format!(
+            "conversion produced {} warnings, no DIF was written",
+            warnings.len()
+        ));
+    }
     // Write the reports
     reports.iter().enumerate().for_each(|(i, r)| {
-        println!("BSP Report {}", i + 1);
-        println!(
+        output.reports.push(ReportEntry {
+            file: filepath.to_string(),
+            interior: i,
+            hit: r.hit,
+            total: r.total,
+            hit_area_percentage: r.hit_area_percentage,
+            balance_factor: r.balance_factor,
+            passed: r.passed,
+        });
+        output.lines.push(format!("BSP Report {}", i + 1));
+        output.lines.push(format!(
             "Raycast Coverage: {}/{} ({}% of surface area)",
             r.hit, r.total, r.hit_area_percentage
-        );
-        println!("Balance Factor: {}", r.balance_factor);
+        ));
+        output
+            .lines
+            .push(format!("Balance Factor: {}", r.balance_factor));
+        output.lines.push(format!(
+            "Coverage Check: {}",
+            if r.passed { "Passed" } else { "Failed" }
+        ));
+    });
+    if args.verbose {
+        skipped_brushes.iter().for_each(|b| {
+            output.lines.push(format!(
+                "Skipped brush {} in detail level {}: {:?}",
+                b.id, b.detail_level, b.reason
+            ));
+        });
+        spawn_points.iter().for_each(|s| {
+            output.lines.push(format!(
+                "{:?} {} at {} {} {}, angles {} {} {}",
+                s.kind,
+                s.classname,
+                s.position.x,
+                s.position.y,
+                s.position.z,
+                s.angles.x,
+                s.angles.y,
+                s.angles.z
+            ));
+        });
+    }
+    if args.stats {
+        difs.iter().enumerate().for_each(|(i, dif)| {
+            dif.interiors
+                .iter()
+                .chain(dif.sub_objects.iter())
+                .enumerate()
+                .for_each(|(j, itr)| {
+                    let metrics = interior_metrics(itr);
+                    output
+                        .lines
+                        .push(format!("Stats for DIF {} Interior {}", i + 1, j + 1));
+                    output
+                        .lines
+                        .push(format!("Brushes: {}", metrics.brush_count));
+                    output
+                        .lines
+                        .push(format!("Surface Area: {}", metrics.surface_area));
+                    output.lines.push(format!("Volume: {}", metrics.volume));
+                });
+        });
+    }
+    if args.obb {
+        output.lines.extend(oriented_bounds_lines(&difs));
+    }
+    if args.fail_on_low_coverage && reports.iter().any(|r| !r.passed) {
+        return Err(format!(
+            "the BSP coverage of an interior is below {}%",
+            args.coverage_threshold.unwrap()
+        ));
+    }
+    Ok(())
+}
+
+// The centroid, principal axes and half extents along them of every interior of the DIFs
+fn oriented_bounds_lines(difs: &[Dif]) -> Vec<String> {
+    let mut lines = vec![];
+    difs.iter().enumerate().for_each(|(i, dif)| {
+        dif.interiors
+            .iter()
+            .chain(dif.sub_objects.iter())
+            .enumerate()
+            .for_each(|(j, itr)| {
+                let (centroid, axes, extents) = interior_oriented_bounds(itr);
+                lines.push(format!(
+                    "Oriented Bounds for DIF {} Interior {}",
+                    i + 1,
+                    j + 1
+                ));
+                lines.push(format!(
+                    "Centroid: {} {} {}",
+                    centroid.x, centroid.y, centroid.z
+                ));
+                for k in 0..3 {
+                    lines.push(format!(
+                        "Axis: {} {} {}, Half Extent: {}",
+                        axes[k].x, axes[k].y, axes[k].z, extents[k]
+                    ));
+                }
+            });
+    });
+    lines
+}
+
+// Records the spans of the conversion into a chrome trace at trace_path, the trace is written out
+// when the guard is dropped
+#[cfg(feature = "trace")]
+fn start_trace(trace_path: &str) -> tracing_chrome::FlushGuard {
+    use tracing_subscriber::prelude::*;
+    let (chrome_layer, guard) = tracing_chrome::ChromeLayerBuilder::new()
+        .file(trace_path)
+        .build();
+    tracing_subscriber::registry().with(chrome_layer).init();
+    guard
+}
+
+// The output doesn't depend on the thread count, this only limits the CPU usage
+fn thread_pool(args: &Args) -> rayon::ThreadPoolBuilder {
+    rayon::ThreadPoolBuilder::new().num_threads(args.threads)
+}
+
+// Converts the files with args.jobs of them in parallel, returning the index of each file with
+// whether it succeeded and its output, in the order of the files
+fn convert_files(
+    args: &Args,
+    config: &ConvertConfig,
+    filepaths: &[String],
+    listener: &ConsoleProgressListener,
+    cancel: &AtomicBool,
+) -> Vec<(usize, bool, FileOutput)> {
+    // Each job takes the next file till there are none left, a bad file only fails itself
+    let next_file = AtomicUsize::new(0);
+    let results = Mutex::new(vec![]);
+    thread::scope(|scope| {
+        for _ in 0..args.jobs.clamp(1, filepaths.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next_file.fetch_add(1, Ordering::SeqCst);
+                let Some(filepath) = filepaths.get(index) else {
+                    break;
+                };
+                let prefix = if filepaths.len() > 1 {
+                    format!("{}: ", filepath)
+                } else {
+                    String::new()
+                };
+                let mut console_listener = listener.with_prefix(prefix);
+                let mut silent_listener = NoopListener;
+                let mut json_listener = JsonProgressListener {
+                    out: std::io::stderr(),
+                };
+                let listener_to_pass: &mut dyn ProgressEventListener = if args.silent {
+                    &mut silent_listener
+                } else if args.progress == Some(ProgressFormat::Json) {
+                    &mut json_listener
+                } else {
+                    &mut console_listener
+                };
+                if !args.inventory && !args.dump_scene && args.dump_preprocessed.is_none() {
+                    listener.print(&[format!("Converting {}", filepath)]);
+                }
+                let mut output = FileOutput::default();
+                if filepaths.len() > 1 {
+                    output.lines.push(format!("{}:", filepath));
+                }
+                let mut cancellable_listener = CancellableListener {
+                    inner: listener_to_pass,
+                    cancel,
+                };
+                let result = process_file(
+                    args,
+                    config,
+                    filepath,
+                    &mut cancellable_listener,
+                    &mut output,
+                );
+                if let Err(e) = &result {
+                    output.lines.push(format!("Error: {}", e));
+                }
+                listener.print(&output.lines);
+                results
+                    .lock()
+                    .unwrap()
+                    .push((index, result.is_ok(), output));
+            });
+        }
     });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _, _)| *index);
+    results
+}
+
+// How often --watch checks the files for changes
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How long the files have to stay unchanged before --watch converts them again, editors often save
+// a file in several writes
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+// The modification times of the files the paths currently expand to, so the files added to a
+// watched directory get picked up too
+fn modification_times(patterns: &[String]) -> HashMap<String, SystemTime> {
+    expand_paths(patterns)
+        .into_iter()
+        .filter_map(|path| {
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+            Some((path, modified))
+        })
+        .collect()
+}
+
+// Collects the files that changed, and only hands them out once none of them changed for the
+// debounce time
+struct ChangeDebouncer {
+    modified: HashMap<String, SystemTime>,
+    changed: BTreeSet<String>,
+    last_change: Option<Instant>,
+}
+
+impl ChangeDebouncer {
+    fn new(modified: HashMap<String, SystemTime>) -> Self {
+        ChangeDebouncer {
+            modified,
+            changed: BTreeSet::new(),
+            last_change: None,
+        }
+    }
+
+    // Records the current modification times, returns whether any file changed since the last
+    // update
+    fn update(&mut self, modified: HashMap<String, SystemTime>, now: Instant) -> bool {
+        let changed = modified
+            .iter()
+            .filter(|(path, time)| self.modified.get(*path) != Some(time))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        self.modified = modified;
+        if changed.is_empty() {
+            return false;
+        }
+        self.changed.extend(changed);
+        self.last_change = Some(now);
+        true
+    }
+
+    // Marks the files as changed again, for the conversions that were cancelled
+    fn requeue(&mut self, paths: &[String], now: Instant) {
+        self.changed.extend(paths.iter().cloned());
+        self.last_change = Some(now);
+    }
+
+    // The changed files, once they stopped changing for the debounce time
+    fn take_settled(&mut self, now: Instant) -> Option<Vec<String>> {
+        match self.last_change {
+            Some(last_change) if now.duration_since(last_change) >= WATCH_DEBOUNCE => {
+                self.last_change = None;
+                Some(std::mem::take(&mut self.changed).into_iter().collect())
+            }
+            _ => None,
+        }
+    }
+}
+
+// Converts the files again whenever they change till the process is stopped. A change during a
+// conversion cancels it, the files are converted again once the changes settle.
+fn watch(args: &Args, config: &ConvertConfig, listener: &ConsoleProgressListener) -> ! {
+    let mut debouncer = ChangeDebouncer::new(modification_times(&args.filepaths));
+    let cancel = AtomicBool::new(false);
+    listener.print(&["Watching for changes, press Ctrl+C to stop".to_string()]);
+    thread::scope(|scope| {
+        let mut running: Option<(thread::ScopedJoinHandle<()>, Vec<String>)> = None;
+        loop {
+            thread::sleep(WATCH_POLL_INTERVAL);
+            let now = Instant::now();
+            let changed = debouncer.update(modification_times(&args.filepaths), now);
+            if let Some((handle, filepaths)) = &running {
+                if !handle.is_finished() {
+                    if changed && !cancel.swap(true, Ordering::SeqCst) {
+                        debouncer.requeue(filepaths, now);
+                    }
+                    continue;
+                }
+            }
+            running = None;
+            if let Some(filepaths) = debouncer.take_settled(now) {
+                cancel.store(false, Ordering::SeqCst);
+                let cancel = &cancel;
+                let files = filepaths.clone();
+                let handle = scope.spawn(move || {
+                    convert_files(args, config, &files, listener, cancel);
+                });
+                running = Some((handle, filepaths));
+            }
+        }
+    })
+}
+
+// The options of the conversions, every file is converted with the same ones
+fn convert_config(args: &Args) -> Result<ConvertConfig, String> {
+    let materials_manifest = match &args.materials_manifest {
+        Some(path) => Some(
+            std::fs::read_to_string(path)
+                .map_err(|e| format!("Could not read the materials manifest {}: {}", path, e))?
+                .lines()
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect(),
+        ),
+        None => None,
+    };
+    Ok(ConvertConfig {
+        mb_only: args.mb.unwrap(),
+        split_method: args.bsp.unwrap().into(),
+        point_epsilon: args.epsilon_point.unwrap(),
+        plane_epsilon: args.epsilon_plane.unwrap(),
+        bsp_sample_count: args.bsp_samples.unwrap(),
+        bsp_seed: args.bsp_seed.unwrap(),
+        bsp_max_depth: args.bsp_max_depth,
+        subobject_split_method: args.subobject_bsp.map(|algo| algo.into()),
+        fix_windings: args.fix_windings,
+        vertex_snap: args.snap.unwrap(),
+        axis_snap_angle: args.axis_snap.unwrap(),
+        weld_tolerance: args.weld.unwrap(),
+        clip_bounds: args.clip_bounds.map(|(min, max)| BoxF { min, max }),
+        max_winding_indices: args
+            .max_winding_indices
+            .unwrap()
+            .clamp(1, u32::MAX as usize),
+        merge_interiors: args.merge_interiors,
+        full_fan_mask: args.full_fan_mask,
+        collision_detail_level: args.collision_detail_level,
+        generate_coordbins: args.coord_bins.unwrap(),
+        coord_bin_mode: args.coord_bin_mode.map(|mode| mode.into()),
+        compute_point_visibility: args.point_visibility,
+        sort_materials: args.sort_materials,
+        texgen_overrides: args.texgen_override.clone(),
+        bake_lighting: args.bake_lighting.unwrap(),
+        subobject_lightmaps: args.subobject_lightmaps.unwrap(),
+        unlit_materials: args.unlit.clone(),
+        gi_bounces: args.gi_bounces.unwrap(),
+        shadow_samples: args.shadow_samples.unwrap(),
+        shadow_bias: match args.shadow_bias_unit.unwrap() {
+            ShadowBiasUnit::World => ShadowBias::World(args.shadow_bias.unwrap()),
+            ShadowBiasUnit::Lumels => ShadowBias::Lumels(args.shadow_bias.unwrap()),
+        },
+        attenuation: args.attenuation.unwrap().into(),
+        generate_dir_maps: args.light_dir_maps,
+        lightmap_size: args.lightmap_size.unwrap(),
+        lightmap_downscale: args.lightmap_downscale.unwrap(),
+        rotate_lightmaps: args.rotate_lightmaps,
+        lightmap_gutter: args.lightmap_gutter.unwrap(),
+        lightmap_blur: match args.lightmap_blur.unwrap() {
+            LightmapBlur::None => BlurKind::None,
+            LightmapBlur::Box => BlurKind::Box(args.lightmap_blur_radius.unwrap()),
+            LightmapBlur::Gaussian => BlurKind::Gaussian {
+                radius: args.lightmap_blur_radius.unwrap(),
+                sigma: args.lightmap_blur_sigma.unwrap(),
+            },
+        },
+        strict: args.strict,
+        deny_warnings: args.deny_warnings,
+        materials_manifest,
+        coverage_threshold: args.coverage_threshold.unwrap(),
+        verbose: args.verbose,
+    })
+}
+
+fn main() {
+    let args = Args::parse();
+    if args.list_engine_versions {
+        supported_versions().iter().for_each(|(engine, versions)| {
+            println!(
+                "{:?}: DIF versions {} to {}",
+                engine,
+                versions.start(),
+                versions.end()
+            )
+        });
+        return;
+    }
+    let filepaths = expand_paths(&args.filepaths);
+    if filepaths.len() > 1 && !args.combine.is_empty() {
+        println!("Error: --combine needs a single input file");
+        std::process::exit(1);
+    }
+    if filepaths.len() > 1 && args.dump_preprocessed.is_some() {
+        println!("Error: --dump-preprocessed needs a single input file");
+        std::process::exit(1);
+    }
+    if let Some(output_dir) = &args.output_dir {
+        if let Err(e) = std::fs::create_dir_all(output_dir) {
+            println!("Error: Could not create {}: {}", output_dir, e);
+            std::process::exit(1);
+        }
+    }
+    let config = match convert_config(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Keep the guard alive till the end so the trace gets flushed
+    #[cfg(feature = "trace")]
+    let _trace_guard = args.profile.as_deref().map(start_trace);
+
+    thread_pool(&args).build_global().unwrap();
+
+    let mut listener = ConsoleProgressListener::new();
+    let join_handler = listener.init();
+
+    let results = convert_files(
+        &args,
+        &config,
+        &filepaths,
+        &listener,
+        &AtomicBool::new(false),
+    );
+    if let Some(manifest_path) = &args.manifest {
+        let manifest = results
+            .iter()
+            .flat_map(|(_, _, output)| &output.manifest)
+            .collect::<Vec<_>>();
+        std::fs::write(
+            manifest_path,
+            serde_json::to_string_pretty(&manifest).unwrap(),
+        )
+        .unwrap();
+    }
+    if let Some(report_path) = &args.report_json {
+        let reports = results
+            .iter()
+            .flat_map(|(_, _, output)| &output.reports)
+            .collect::<Vec<_>>();
+        std::fs::write(report_path, serde_json::to_string_pretty(&reports).unwrap()).unwrap();
+    }
+    let failed = results
+        .iter()
+        .filter(|(_, ok, _)| !ok)
+        .map(|(index, _, _)| &filepaths[*index])
+        .collect::<Vec<_>>();
+    if filepaths.len() > 1 {
+        println!(
+            "Finished {} files, {} succeeded, {} failed",
+            filepaths.len(),
+            filepaths.len() - failed.len(),
+            failed.len()
+        );
+        failed.iter().for_each(|path| println!("Failed: {}", path));
+    }
+    if args.watch {
+        watch(&args, &config, &listener);
+    }
+    listener.stop();
+    join_handler.join().unwrap();
+    if !failed.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(flags: &[&str]) -> ConvertConfig {
+        let args = ["csx3dif"].iter().chain(flags).chain(&["scene.csx"]);
+        convert_config(&Args::try_parse_from(args).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn coord_bins_can_be_disabled() {
+        assert!(config(&[]).generate_coordbins);
+        assert!(!config(&["--coord-bins", "false"]).generate_coordbins);
+    }
+
+    #[test]
+    fn full_fan_mask_can_be_enabled() {
+        assert!(!config(&[]).full_fan_mask);
+        assert!(config(&["--full-fan-mask"]).full_fan_mask);
+    }
+
+    #[test]
+    fn lightmap_downscale_is_at_least_one() {
+        assert_eq!(config(&[]).lightmap_downscale, 1);
+        assert_eq!(config(&["--lightmap-downscale", "4"]).lightmap_downscale, 4);
+        assert!(
+            Args::try_parse_from(["csx3dif", "--lightmap-downscale", "0", "scene.csx"]).is_err()
+        );
+    }
+
+    #[test]
+    fn rotate_lightmaps_can_be_enabled() {
+        assert!(!config(&[]).rotate_lightmaps);
+        assert!(config(&["--rotate-lightmaps"]).rotate_lightmaps);
+    }
+
+    #[test]
+    fn lightmap_gutter_is_configurable() {
+        assert_eq!(config(&[]).lightmap_gutter, 1);
+        assert_eq!(config(&["--lightmap-gutter", "0"]).lightmap_gutter, 0);
+        assert_eq!(config(&["--lightmap-gutter", "3"]).lightmap_gutter, 3);
+    }
+
+    #[test]
+    fn lightmap_size_is_a_power_of_two_from_64_to_2048() {
+        assert_eq!(config(&[]).lightmap_size, 256);
+        assert_eq!(config(&["--lightmap-size", "64"]).lightmap_size, 64);
+        assert_eq!(config(&["--lightmap-size", "2048"]).lightmap_size, 2048);
+        for size in ["32", "300", "4096", "0", "big"] {
+            let args = ["csx3dif", "--lightmap-size", size, "scene.csx"];
+            assert!(Args::try_parse_from(args).is_err(), "{} was accepted", size);
+        }
+    }
+
+    #[test]
+    fn lightmap_blur_takes_its_radius_and_sigma() {
+        assert_eq!(config(&[]).lightmap_blur, BlurKind::Box(1));
+        assert_eq!(
+            config(&["--lightmap-blur", "none"]).lightmap_blur,
+            BlurKind::None
+        );
+        assert_eq!(
+            config(&["--lightmap-blur", "box", "--lightmap-blur-radius", "2"]).lightmap_blur,
+            BlurKind::Box(2)
+        );
+        assert_eq!(
+            config(&[
+                "--lightmap-blur",
+                "gaussian",
+                "--lightmap-blur-radius",
+                "3",
+                "--lightmap-blur-sigma",
+                "1.5"
+            ])
+            .lightmap_blur,
+            BlurKind::Gaussian {
+                radius: 3,
+                sigma: 1.5
+            }
+        );
+    }
+
+    fn modified(files: &[(&str, u64)]) -> HashMap<String, SystemTime> {
+        files
+            .iter()
+            .map(|(path, secs)| {
+                (
+                    path.to_string(),
+                    SystemTime::UNIX_EPOCH + Duration::from_secs(*secs),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn debouncer_coalesces_the_changes_inside_the_window() {
+        let start = Instant::now();
+        let mut debouncer = ChangeDebouncer::new(modified(&[("a.csx", 0), ("b.csx", 0)]));
+        assert!(!debouncer.update(modified(&[("a.csx", 0), ("b.csx", 0)]), start));
+        assert!(debouncer.update(modified(&[("a.csx", 1), ("b.csx", 0)]), start));
+        let later = start + WATCH_DEBOUNCE / 2;
+        assert!(debouncer.update(modified(&[("a.csx", 2), ("b.csx", 1)]), later));
+        // Every change pushes the window back
+        assert_eq!(debouncer.take_settled(later + WATCH_DEBOUNCE / 2), None);
+        assert_eq!(
+            debouncer.take_settled(later + WATCH_DEBOUNCE),
+            Some(vec!["a.csx".to_string(), "b.csx".to_string()])
+        );
+        assert_eq!(debouncer.take_settled(later + WATCH_DEBOUNCE * 2), None);
+    }
+
+    #[test]
+    fn debouncer_fires_again_after_the_window() {
+        let start = Instant::now();
+        let mut debouncer = ChangeDebouncer::new(modified(&[("a.csx", 0)]));
+        assert!(debouncer.update(modified(&[("a.csx", 1)]), start));
+        let settled = start + WATCH_DEBOUNCE;
+        assert_eq!(
+            debouncer.take_settled(settled),
+            Some(vec!["a.csx".to_string()])
+        );
+        let next = settled + WATCH_DEBOUNCE;
+        assert!(debouncer.update(modified(&[("a.csx", 2)]), next));
+        assert_eq!(debouncer.take_settled(next), None);
+        assert_eq!(
+            debouncer.take_settled(next + WATCH_DEBOUNCE),
+            Some(vec!["a.csx".to_string()])
+        );
+    }
+
+    #[test]
+    fn unreadable_materials_manifest_is_an_error() {
+        let path = std::env::temp_dir().join("csx3dif-missing-manifest.txt");
+        let path = path.to_str().unwrap();
+        let args =
+            Args::try_parse_from(["csx3dif", "--materials-manifest", path, "scene.csx"]).unwrap();
+        match convert_config(&args) {
+            Ok(_) => panic!("the missing manifest was read"),
+            Err(error) => assert!(error.contains(path)),
+        }
+    }
+
+    #[test]
+    fn oriented_bounds_of_a_turned_box() {
+        // The 8x4x4 box of the welded scene stretched to 16x6x4, turned 30 degrees around z and
+        // moved by 10 20 0
+        let csx = include_str!("../libcsx/snapshots/welded.csx").replace(
+            "transform=\"1 0 0 0 0 1 0 0 0 0 1 0 0 0 0 1\"",
+            "transform=\"1.7320508 -0.75 0 10 1 1.2990381 0 20 0 0 1 0 0 0 0 1\"",
+        );
+        let (bufs, ..) = csx::convert_csx_to_dif_with_config(
+            csx,
+            EngineVersion::MBG,
+            0,
+            &ConvertConfig::DEFAULT,
+            &mut NoopListener,
+        )
+        .unwrap();
+        let difs = bufs
+            .iter()
+            .map(|buf| Dif::from_bytes(buf).unwrap().0)
+            .collect::<Vec<_>>();
+        let lines = oriented_bounds_lines(&difs);
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "Oriented Bounds for DIF 1 Interior 1");
+
+        let numbers = |line: &str| {
+            line.split([' ', ','])
+                .filter_map(|v| v.parse::<f32>().ok())
+                .collect::<Vec<_>>()
+        };
+        let close = |a: &[f32], b: &[f32]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-3);
+        // The box is centered on its turned and moved center
+        let (c, s) = (30f32.to_radians().cos(), 30f32.to_radians().sin());
+        let centroid = [10.0 + 8.0 * c - 3.0 * s, 20.0 + 8.0 * s + 3.0 * c, 2.0];
+        assert!(close(&numbers(&lines[1]), &centroid));
+        // The axes are the turned box axes from the longest to the shortest, either way round
+        let expected = [
+            ([c, s, 0.0], 8.0),
+            ([-s, c, 0.0], 3.0),
+            ([0.0, 0.0, 1.0], 2.0),
+        ];
+        for (line, (axis, extent)) in lines[2..].iter().zip(expected) {
+            let values = numbers(line);
+            let flipped = axis.map(|v| -v);
+            assert!(close(&values[..3], &axis) || close(&values[..3], &flipped));
+            assert!((values[3] - extent).abs() < 1e-3);
+        }
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn profile_trace_has_the_conversion_spans() {
+        let path = std::env::temp_dir().join("csx3dif-profile-test.json");
+        let guard = start_trace(path.to_str().unwrap());
+        let config = ConvertConfig {
+            mb_only: false,
+            ..ConvertConfig::DEFAULT
+        };
+        csx::convert_csx_to_dif_with_config(
+            include_str!("../libcsx/snapshots/simple.csx").to_string(),
+            EngineVersion::MBG,
+            0,
+            &config,
+            &mut NoopListener,
+        )
+        .unwrap();
+        drop(guard);
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        for span in [
+            "build_bsp",
+            "split",
+            "calc_plane_rating",
+            "export_convex_hull",
+            "compute_lightmaps",
+        ] {
+            assert!(
+                trace.contains(&format!("\"name\":\"{}\"", span)),
+                "no {} span",
+                span
+            );
+        }
+    }
+
+    #[test]
+    fn one_thread_converts_the_same_as_all_cores() {
+        let convert = || {
+            let config = ConvertConfig {
+                mb_only: false,
+                ..ConvertConfig::DEFAULT
+            };
+            csx::convert_csx_to_dif_with_config(
+                include_str!("../libcsx/snapshots/hard_shadows.csx").to_string(),
+                EngineVersion::MBG,
+                0,
+                &config,
+                &mut NoopListener,
+            )
+            .unwrap()
+            .0
+        };
+        let args = Args::try_parse_from(["csx3dif", "--threads", "1", "scene.csx"]).unwrap();
+        let pool = thread_pool(&args).build().unwrap();
+        assert_eq!(pool.current_num_threads(), 1);
+        let single = pool.install(|| {
+            assert_eq!(rayon::current_num_threads(), 1);
+            convert()
+        });
+        assert_eq!(single, convert());
+    }
+
+    #[test]
+    fn manifest_lists_every_written_file() {
+        let dir = std::env::temp_dir().join("csx3dif-manifest-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let scene = dir.join("scene.csx");
+        std::fs::write(&scene, include_str!("../libcsx/snapshots/hard_shadows.csx")).unwrap();
+        let scene = scene.to_str().unwrap();
+        let args = Args::try_parse_from([
+            "csx3dif",
+            "--mb",
+            "false",
+            "--stack-lightmaps",
+            "--lightmap-uvs",
+            "--export-obj",
+            "--dump-visibility",
+            scene,
+        ])
+        .unwrap();
+        let mut output = FileOutput::default();
+        process_file(
+            &args,
+            &convert_config(&args).unwrap(),
+            scene,
+            &mut NoopListener,
+            &mut output,
+        )
+        .unwrap();
+
+        let listed = output
+            .manifest
+            .iter()
+            .map(|entry| entry.path.clone())
+            .collect::<BTreeSet<_>>();
+        let written = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path().to_str().unwrap().to_string())
+            .filter(|path| path != scene)
+            .collect::<BTreeSet<_>>();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(listed.len(), output.manifest.len());
+        assert_eq!(listed, written);
+        let types = output
+            .manifest
+            .iter()
+            .map(|entry| entry.file_type)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            types,
+            BTreeSet::from([
+                "dif",
+                "lightmap-uvs",
+                "mtl",
+                "obj",
+                "point-visibility",
+                "stacked-lightmaps",
+                "stacked-lightmaps-mapping",
+            ])
+        );
+    }
+
+    #[test]
+    fn warnings_fail_the_file_when_denied() {
+        let dir = std::env::temp_dir().join("csx3dif-deny-warnings-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        // A face wound the wrong way round is a warning when the windings aren't fixed
+        let scene = dir.join("scene.csx");
+        let csx = include_str!("../libcsx/snapshots/welded.csx").replacen(
+            "<Indices indices=\"4 5 6 7\" />",
+            "<Indices indices=\"7 6 5 4\" />",
+            1,
+        );
+        std::fs::write(&scene, csx).unwrap();
+        let scene = scene.to_str().unwrap();
+        let convert = |flags: &[&str]| {
+            let args =
+                Args::try_parse_from(["csx3dif"].iter().chain(flags).chain(&[scene])).unwrap();
+            let mut output = FileOutput::default();
+            let result = process_file(
+                &args,
+                &convert_config(&args).unwrap(),
+                scene,
+                &mut NoopListener,
+                &mut output,
+            );
+            let written = dir.join("scene.dif").exists();
+            let _ = std::fs::remove_file(dir.join("scene.dif"));
+            (result, written, output.lines)
+        };
+
+        let (result, written, lines) = convert(&["--deny-warnings"]);
+        assert_eq!(
+            result,
+            Err("conversion produced 1 warnings, no DIF was written".to_string())
+        );
+        assert!(!written);
+        assert!(lines[0].starts_with("Warning: "));
+        let (result, written, _) = convert(&[]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result, Ok(()));
+        assert!(written);
+    }
+
+    #[test]
+    fn low_coverage_fails_the_file_when_asked() {
+        let dir = std::env::temp_dir().join("csx3dif-low-coverage-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let scene = dir.join("scene.csx");
+        std::fs::write(&scene, include_str!("../libcsx/snapshots/simple.csx")).unwrap();
+        let scene = scene.to_str().unwrap();
+        let convert = |flags: &[&str]| {
+            let args =
+                Args::try_parse_from(["csx3dif"].iter().chain(flags).chain(&[scene])).unwrap();
+            process_file(
+                &args,
+                &convert_config(&args).unwrap(),
+                scene,
+                &mut NoopListener,
+                &mut FileOutput::default(),
+            )
+        };
+
+        // A BSP cut off after its first split only covers about a third of the surface area
+        let shallow = ["--bsp-max-depth", "1", "--fail-on-low-coverage"];
+        let results = (
+            convert(&shallow),
+            convert(&[&shallow[..], &["--coverage-threshold", "25"]].concat()),
+            convert(&["--fail-on-low-coverage"]),
+            convert(&["--bsp-max-depth", "1"]),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            results,
+            (
+                Err("the BSP coverage of an interior is below 95%".to_string()),
+                Ok(()),
+                Ok(()),
+                Ok(())
+            )
+        );
+    }
+
+    #[test]
+    fn json_progress_is_one_object_per_line() {
+        let dir = std::env::temp_dir().join("csx3dif-json-progress-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let scene = dir.join("scene.csx");
+        std::fs::write(&scene, include_str!("../libcsx/snapshots/simple.csx")).unwrap();
+        let scene = scene.to_str().unwrap();
+        let args = Args::try_parse_from(["csx3dif", "--progress", "json", scene]).unwrap();
+        let mut listener = JsonProgressListener { out: vec![] };
+        let result = process_file(
+            &args,
+            &convert_config(&args).unwrap(),
+            scene,
+            &mut listener,
+            &mut FileOutput::default(),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result, Ok(()));
+        let events = String::from_utf8(listener.out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert!(!events.is_empty());
+        for event in events.iter() {
+            let object = event.as_object().unwrap();
+            assert_eq!(
+                object.keys().collect::<Vec<_>>(),
+                vec!["current", "stage", "total"]
+            );
+            assert!(event["current"].as_u64().unwrap() <= event["total"].as_u64().unwrap());
+        }
+        let mut stages = events
+            .iter()
+            .map(|e| e["stage"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        stages.dedup();
+        assert_eq!(
+            stages,
+            vec![
+                "Exporting detail level",
+                "Exporting interior",
+                "Exporting convex hulls",
+                "Building BSP"
+            ]
+        );
+    }
+
+    #[test]
+    fn globs_and_directories_expand_to_their_csx_files() {
+        let dir = std::env::temp_dir().join("csx3dif-expand-paths-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["a.csx", "b.csx", "notes.txt"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let expanded = (
+            expand_paths(&[path("*.csx")]),
+            expand_paths(&[dir.to_str().unwrap().to_string()]),
+            expand_paths(&[path("missing.csx"), path("notes.txt")]),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(expanded.0, vec![path("a.csx"), path("b.csx")]);
+        assert_eq!(expanded.1, vec![path("a.csx"), path("b.csx")]);
+        assert_eq!(expanded.2, vec![path("missing.csx"), path("notes.txt")]);
+    }
+
+    #[test]
+    fn a_bad_file_only_fails_itself() {
+        let dir = std::env::temp_dir().join("csx3dif-batch-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let simple = include_str!("../libcsx/snapshots/simple.csx");
+        std::fs::write(dir.join("first.csx"), simple).unwrap();
+        std::fs::write(dir.join("second.csx"), simple).unwrap();
+        let filepaths = ["first.csx", "missing.csx", "second.csx"]
+            .iter()
+            .map(|name| dir.join(name).to_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        let args = Args::try_parse_from(
+            ["csx3dif", "--silent", "--jobs", "2"]
+                .iter()
+                .map(|a| a.to_string())
+                .chain(filepaths.iter().cloned()),
+        )
+        .unwrap();
+        let results = convert_files(
+            &args,
+            &convert_config(&args).unwrap(),
+            &filepaths,
+            &ConsoleProgressListener::new(),
+            &AtomicBool::new(false),
+        );
+        let written = ["first.dif", "second.dif"].map(|name| dir.join(name).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            results
+                .iter()
+                .map(|(index, ok, _)| (*index, *ok))
+                .collect::<Vec<_>>(),
+            vec![(0, true), (1, false), (2, true)]
+        );
+        assert!(results[1].2.lines.last().unwrap().starts_with("Error: "));
+        assert_eq!(written, [true, true]);
+    }
+
+    #[test]
+    fn outputs_go_to_the_output_dir() {
+        let dir = std::env::temp_dir().join("csx3dif-output-dir-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let out = dir.join("out");
+        std::fs::create_dir_all(&out).unwrap();
+        let scene = dir.join("scene.csx");
+        std::fs::write(&scene, include_str!("../libcsx/snapshots/welded.csx")).unwrap();
+        let scene = scene.to_str().unwrap();
+        // The winding index limit splits the boxes into separate DIFs
+        let args = Args::try_parse_from([
+            "csx3dif",
+            "--output-dir",
+            out.to_str().unwrap(),
+            "--max-winding-indices",
+            "30",
+            scene,
+        ])
+        .unwrap();
+        let result = process_file(
+            &args,
+            &convert_config(&args).unwrap(),
+            scene,
+            &mut NoopListener,
+            &mut FileOutput::default(),
+        );
+        let files = |dir: &std::path::Path| {
+            std::fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<BTreeSet<_>>()
+        };
+        let (written, beside) = (files(&out), files(&dir));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            written,
+            BTreeSet::from(["scene.dif".to_string(), "scene-1.dif".to_string()])
+        );
+        assert_eq!(
+            beside,
+            BTreeSet::from(["out".to_string(), "scene.csx".to_string()])
+        );
+    }
+
+    #[test]
+    fn reports_of_every_interior_serialize_to_json() {
+        let dir = std::env::temp_dir().join("csx3dif-report-json-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let scene = dir.join("scene.csx");
+        std::fs::write(&scene, include_str!("../libcsx/snapshots/trigger.csx")).unwrap();
+        let scene = scene.to_str().unwrap();
+        // The floor and the moving platform get a report each
+        let args = Args::try_parse_from(["csx3dif", scene]).unwrap();
+        let mut output = FileOutput::default();
+        let result = process_file(
+            &args,
+            &convert_config(&args).unwrap(),
+            scene,
+            &mut NoopListener,
+            &mut output,
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result, Ok(()));
+        let json = serde_json::to_value(&output.reports).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([0, 1]
+                .iter()
+                .map(|i| serde_json::json!({
+                    "file": scene,
+                    "interior": i,
+                    "hit": 6,
+                    "total": 6,
+                    "hit_area_percentage": 100.0,
+                    "balance_factor": output.reports[*i].balance_factor,
+                    "passed": true,
+                }))
+                .collect::<Vec<_>>())
+        );
+    }
 }